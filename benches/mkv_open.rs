@@ -0,0 +1,76 @@
+// showorder is a binary crate, so the modules being measured are
+// pulled in directly. Opening a file only needs the mkv module, but
+// it depends on the subtitle decoders, which pull in most of the rest.
+#[allow(dead_code)]
+#[path = "../src/error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../src/image.rs"]
+mod image;
+#[allow(dead_code)]
+#[path = "../src/interop.rs"]
+mod interop;
+#[allow(dead_code)]
+#[path = "../src/mkv.rs"]
+mod mkv;
+#[allow(dead_code)]
+#[path = "../src/pgs/mod.rs"]
+mod pgs;
+#[allow(dead_code)]
+#[path = "../src/progress.rs"]
+mod progress;
+#[allow(dead_code)]
+#[path = "../src/srt.rs"]
+mod srt;
+#[allow(dead_code)]
+#[path = "../src/test_utils.rs"]
+mod test_utils;
+#[allow(dead_code)]
+#[path = "../src/text.rs"]
+mod text;
+#[allow(dead_code)]
+#[path = "../src/vob/mod.rs"]
+mod vob;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use mkv::{MkvFile, ParseConfig};
+use test_utils::make_mkv_with_tracks_at_end;
+
+// Around 64MB of blocks between the start of the file and the
+// Tracks element, which a full scan has to read through.
+const NUM_SUBTITLES: usize = 4096;
+const SUBTITLE_SIZE: usize = 16 * 1024;
+
+fn open_benchmark(c: &mut Criterion) {
+    let payload = vec![0u8; SUBTITLE_SIZE];
+    let subtitles = vec![payload.as_slice(); NUM_SUBTITLES];
+    let data = make_mkv_with_tracks_at_end(&subtitles);
+    let path = std::env::temp_dir().join("showorder_mkv_open_benchmark.mkv");
+    std::fs::write(&path, &data).unwrap();
+
+    let mut group = c.benchmark_group("open_mkv_with_tracks_at_end");
+    group.sample_size(10);
+    group.bench_function("seek_head", |b| {
+        b.iter(|| {
+            let mkv =
+                MkvFile::from_path_with_config(black_box(&path), &ParseConfig::default()).unwrap();
+            assert_eq!(mkv.tracks().len(), 1);
+        })
+    });
+    group.bench_function("full_scan", |b| {
+        let config = ParseConfig {
+            scan_entire_file_for_tracks: true,
+        };
+        b.iter(|| {
+            let mkv = MkvFile::from_path_with_config(black_box(&path), &config).unwrap();
+            assert_eq!(mkv.tracks().len(), 1);
+        })
+    });
+    group.finish();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+criterion_group!(benches, open_benchmark);
+criterion_main!(benches);
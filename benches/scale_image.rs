@@ -0,0 +1,97 @@
+// showorder is a binary crate, so the modules being measured are
+// pulled in directly. The image module only needs SubtitleRegion from
+// the vob module, which would otherwise pull in the rest of the crate.
+#[allow(dead_code)]
+#[path = "../src/image.rs"]
+mod image;
+#[allow(dead_code)]
+#[path = "../src/interop.rs"]
+mod interop;
+#[allow(dead_code)]
+#[path = "../src/progress.rs"]
+mod progress;
+mod vob {
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct SubtitleRegion {
+        pub x: u16,
+        pub y: u16,
+        pub width: u16,
+        pub height: u16,
+    }
+}
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use windows::{
+    core::Result,
+    Graphics::Imaging::{
+        BitmapDecoder, BitmapEncoder, BitmapInterpolationMode, BitmapPixelFormat, BitmapTransform,
+        ColorManagementMode, ExifOrientationMode, SoftwareBitmap,
+    },
+    Storage::Streams::InMemoryRandomAccessStream,
+    Win32::System::WinRT::{RoInitialize, RO_INIT_MULTITHREADED},
+};
+
+use image::{load_bgra8_as_bitmap, scale_image};
+
+// A full frame PGS subtitle
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+const SCALE: f32 = 2.0;
+
+// Same as image::scale_image, but the scaling is done by the Windows
+// imaging pipeline. The bitmap is round-tripped through an in-memory png so
+// that a BitmapTransform can be applied while decoding it.
+fn scale_image_winrt(src_bitmap: &SoftwareBitmap, scale: f32) -> Result<SoftwareBitmap> {
+    let width = src_bitmap.PixelWidth()? as f32;
+    let height = src_bitmap.PixelHeight()? as f32;
+    let alpha_mode = src_bitmap.BitmapAlphaMode()?;
+
+    let stream = InMemoryRandomAccessStream::new()?;
+    let encoder = BitmapEncoder::CreateAsync(BitmapEncoder::PngEncoderId()?, &stream)?.get()?;
+    encoder.SetSoftwareBitmap(src_bitmap)?;
+    encoder.FlushAsync()?.get()?;
+    stream.Seek(0)?;
+
+    let decoder = BitmapDecoder::CreateAsync(&stream)?.get()?;
+    let transform = BitmapTransform::new()?;
+    transform.SetScaledWidth((width * scale).ceil() as u32)?;
+    transform.SetScaledHeight((height * scale).ceil() as u32)?;
+    // Matches scale_image
+    transform.SetInterpolationMode(BitmapInterpolationMode::NearestNeighbor)?;
+    decoder
+        .GetSoftwareBitmapTransformedAsync(
+            BitmapPixelFormat::Bgra8,
+            alpha_mode,
+            &transform,
+            ExifOrientationMode::IgnoreExifOrientation,
+            ColorManagementMode::DoNotColorManage,
+        )?
+        .get()
+}
+
+fn scale_benchmark(c: &mut Criterion) {
+    unsafe { RoInitialize(RO_INIT_MULTITHREADED).unwrap() };
+
+    // Mostly transparent, with a band of white text-like pixels near the bottom
+    let mut bytes = vec![0u8; (WIDTH * HEIGHT * 4) as usize];
+    let text_start = (WIDTH * (HEIGHT - 100) * 4) as usize;
+    for (i, pixel) in bytes[text_start..].chunks_exact_mut(4).enumerate() {
+        if i % 3 == 0 {
+            pixel.copy_from_slice(&[255, 255, 255, 255]);
+        }
+    }
+    let bitmap = load_bgra8_as_bitmap(&bytes, WIDTH, HEIGHT).unwrap();
+
+    let mut group = c.benchmark_group("scale_image_1920x1080_2x");
+    group.sample_size(10);
+    group.bench_function("cpu", |b| {
+        b.iter(|| black_box(scale_image(black_box(&bitmap), SCALE).unwrap()))
+    });
+    group.bench_function("winrt", |b| {
+        b.iter(|| black_box(scale_image_winrt(black_box(&bitmap), SCALE).unwrap()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, scale_benchmark);
+criterion_main!(benches);
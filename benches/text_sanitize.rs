@@ -0,0 +1,73 @@
+// showorder is a binary crate, so the modules being measured are
+// pulled in directly. Neither of them depends on Windows APIs.
+#[allow(dead_code)]
+#[path = "../src/string.rs"]
+mod string;
+#[allow(dead_code)]
+#[path = "../src/text.rs"]
+mod text;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use string::{normalize_to_shortest_string, NormalizationStrategy};
+use text::sanitize_text;
+
+// Roughly what comes out of OCR for a typical episode
+const SUBTITLES: [&str; 10] = [
+    "<i>Oh! Oh! W-What happened?</i>",
+    "POPEYE: Let me go! Let me go!",
+    "[GRUNTING] No, no, no! Don't drop me now!",
+    "Oh, man the lifeboats!",
+    "(LAUGHING) Who's the most phenomenal, extraordinary fellow?",
+    "SINBAD: You's Sinbad the Sailor!\n<i>How do you like that, stooges?</i>",
+    "On one of my travels I ran into this. Now, there was a thrill I'd be sorry to miss.",
+    "<font color=\"#ffff00\">Whoa! What's this?</font> Hey, let me down!",
+    "You big overgrown canary! [SQUAWKING]",
+    "- What are you doing?\n- Taking me for a ride or something?",
+];
+
+fn sanitize_benchmark(c: &mut Criterion) {
+    c.bench_function("sanitize_text", |b| {
+        b.iter(|| {
+            for subtitle in SUBTITLES {
+                black_box(sanitize_text(black_box(subtitle)));
+            }
+        })
+    });
+}
+
+fn distance_benchmark(c: &mut Criterion) {
+    let sanitized = SUBTITLES
+        .iter()
+        .map(|subtitle| sanitize_text(subtitle))
+        .collect::<Vec<_>>();
+    let joined = sanitized.join(" ");
+    // Reference subtitles rarely line up exactly with the OCR output
+    let reference = sanitized[1..].join(" ");
+
+    c.bench_function("normalize_to_shortest_string", |b| {
+        b.iter(|| {
+            black_box(normalize_to_shortest_string(
+                black_box(&joined),
+                black_box(&reference),
+                NormalizationStrategy::TruncateToShortest,
+            ))
+        })
+    });
+    c.bench_function("levenshtein", |b| {
+        let (string1, string2) = normalize_to_shortest_string(
+            &joined,
+            &reference,
+            NormalizationStrategy::TruncateToShortest,
+        );
+        b.iter(|| {
+            black_box(levenshtein::levenshtein(
+                black_box(&string1),
+                black_box(&string2),
+            ))
+        })
+    });
+}
+
+criterion_group!(benches, sanitize_benchmark, distance_benchmark);
+criterion_main!(benches);
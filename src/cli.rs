@@ -5,15 +5,95 @@ use std::{
 
 use clap::{Parser, Subcommand};
 
+use crate::{
+    mkv::TrackPreference,
+    string::NormalizationStrategy,
+    vob::{PaletteFormat, SubtitleRegion},
+};
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
     #[clap(short = 'n', long = "max-count", default_value_t = 5)]
     pub max_count: usize,
-    #[clap(short, long)]
+    /// Only OCR every Nth subtitle block from mkv files, e.g. 3 skips two blocks
+    /// after each one that's used. Faster, but matches are less reliable.
+    #[clap(short = 'r', long, default_value_t = 1)]
+    pub sample_rate: usize,
+    #[clap(short, long, conflicts_with = "track-name")]
     pub track_number: Option<u64>,
+    /// Select the track whose name contains this string (case-insensitive)
+    #[clap(short = 'T', long)]
+    pub track_name: Option<String>,
+    /// The maximum distance for a match. When using the similarity metric,
+    /// this is the maximum percentage difference. Distances are much larger
+    /// with --no-sanitize, as punctuation and casing differences count.
     #[clap(short = 'm', long = "max")]
     pub max_distance: Option<usize>,
+    #[clap(short, long)]
+    pub chapter: Option<usize>,
+    /// Only use forced subtitle tracks. If there is more than one, the
+    /// track with the most subtitles is used.
+    #[clap(long)]
+    pub forced_only: bool,
+    /// Which track to use when several match the language: "last", "first", "pgs",
+    /// or "vob". Tracks are considered in file order.
+    #[clap(long, default_value = "last")]
+    pub track_preference: TrackPreference,
+    /// Use a subtitle track with an undetermined language ("und") if none are English
+    #[clap(long)]
+    pub fallback_to_und: bool,
+    /// Look for tracks in the entire mkv file instead of stopping at the first cluster.
+    /// Slower, but needed for files from encoders that write tracks inside clusters.
+    #[clap(long)]
+    pub scan_entire_file: bool,
+    /// Ignore zip files when loading reference srt files
+    #[clap(long)]
+    pub no_zip: bool,
+    /// Fail on malformed entries in reference srt files instead of skipping them
+    #[clap(long)]
+    pub strict_srt: bool,
+    /// How VOB subtitle palettes are stored: "rgb" or "ycbcr". Detected if not given.
+    /// Idx dumps write the palette as RGB when this is given.
+    #[clap(long)]
+    pub vob_palette_format: Option<PaletteFormat>,
+    /// Only use subtitles that overlap this part of the video frame, given as
+    /// "x,y,width,height". Subtitles without a known position are kept.
+    #[clap(long)]
+    pub subtitle_region: Option<SubtitleRegion>,
+    /// Skip subtitle images whose average edge strength (Sobel gradient magnitude)
+    /// is below this. Blurry images tend to OCR poorly. See "list -v" for values.
+    #[clap(long)]
+    pub min_sharpness: Option<f32>,
+    /// Don't clean up the text of subtitles (e.g. removing punctuation and tags)
+    #[clap(long)]
+    pub no_sanitize: bool,
+    /// Also drop lines that only contain stage directions (e.g. "[DOOR CREAKING]"
+    /// or "THUNDER RUMBLING"), for SDH subtitles. All caps lines are kept in
+    /// tracks that are entirely upper case.
+    #[clap(long, conflicts_with = "no-sanitize")]
+    pub sdh: bool,
+    /// Show how far into the file OCR has gotten, when given a single mkv file
+    #[clap(long)]
+    pub progress: bool,
+    /// Rotate subtitle images by 180 degrees before running OCR
+    #[clap(long = "rotate-180")]
+    pub rotate_180: bool,
+    #[clap(long, default_value = "levenshtein")]
+    pub metric: Metric,
+    /// How subtitles of different lengths are compared: "truncate-to-shortest",
+    /// "truncate-to-longest" (keeps the end of the longer one), "pad-shorter", or "none"
+    #[clap(long, default_value = "truncate-to-shortest")]
+    pub normalization: NormalizationStrategy,
+    /// Use the minimum levenshtein distance over windows of the longer subtitle,
+    /// optionally with the window size in characters, e.g. --windowed=40. The window size
+    /// defaults to the length of the shorter subtitle.
+    #[clap(long, require_equals = true, conflicts_with = "normalization")]
+    pub windowed: Option<Option<usize>>,
+    /// Skip past any text at the start of the longer subtitle (e.g. credits that
+    /// were OCR'd but aren't in the srt) before computing the levenshtein distance
+    #[clap(long, conflicts_with = "windowed")]
+    pub align: bool,
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -22,19 +102,110 @@ pub struct Args {
 pub enum Commands {
     ListTracks {
         mkv_path: String,
+        #[clap(long)]
+        chapters: bool,
+        /// Print the tracks as "json" or "csv" instead of human-readable text
+        #[clap(long, conflicts_with_all = &["chapters", "count-blocks"])]
+        format: Option<TrackListFormat>,
+        /// Count the blocks in each track, which reads the whole file
+        #[clap(long)]
+        count_blocks: bool,
+        /// Stop counting blocks after reading this many bytes of block data
+        #[clap(long, requires = "count-blocks")]
+        max_scan_bytes: Option<u64>,
     },
     List {
         file_type: FileType,
         input_path: String,
+        /// Show image metrics for each subtitle (mkv only)
+        #[clap(long, short)]
+        verbose: bool,
+        /// Warn about files whose subtitles don't look English (mkv only)
+        #[clap(long, conflicts_with = "verbose")]
+        check_language: bool,
     },
+    /// Show statistics about the OCR output of mkv files
+    Stats { mkv_path: String },
     Dump {
         dump_type: DumpType,
         mkv_path: String,
         output_path: String,
+        /// How to name the png and bgra8 output files, e.g. "{track}_{index}_{timestamp_ms}.png".
+        /// Supports {index}, {track}, {timestamp_ms}, {width}, {height}, {x}, and {y}.
+        /// The position ({x} and {y}) is only known for VobSub subtitles and is 0 otherwise.
+        #[clap(long)]
+        name_pattern: Option<NamePattern>,
+        /// Print the average glyph color of each image
+        #[clap(long, short)]
+        verbose: bool,
+        /// How long each subtitle is shown for in srt dumps, in milliseconds
+        #[clap(long, default_value_t = 3000)]
+        default_duration: u64,
+        /// Replace srt files that already exist in srt-batch dumps
+        #[clap(long)]
+        overwrite: bool,
     },
     Match {
+        #[clap(required_unless_present = "manifest")]
+        mkv_path: Option<String>,
+        #[clap(required_unless_present = "manifest")]
+        reference_path: Option<String>,
+        /// A json file listing mkv files and their expected srt files, e.g.
+        /// [{"path": "a.mkv", "srt": "a.srt"}]. Instead of searching for
+        /// matches, each listed mapping is verified.
+        #[clap(long, conflicts_with_all = &["mkv-path", "reference-path"])]
+        manifest: Option<String>,
+        /// Used to break ties between reference files with similar distances
+        /// by comparing capture groups in file names (e.g. "S(\d+)E(\d+)")
+        #[clap(long)]
+        filename_regex: Option<String>,
+        /// Mkv files with fewer subtitles than this are skipped
+        #[clap(long, default_value_t = 2)]
+        min_subtitles: usize,
+        /// Don't show confidence grades in the results
+        #[clap(long)]
+        no_grades: bool,
+        /// Explain why the results are or aren't high confidence
+        #[clap(long)]
+        explain: bool,
+        /// Distance ratios below which a match gets an A, B, C, or D grade
+        #[clap(long, default_value = "0.05,0.15,0.30,0.50")]
+        grade_thresholds: GradeThresholds,
+        /// The order to print distances in: "filename", "distance", or "random".
+        /// This doesn't affect which files are matched.
+        #[clap(long, default_value = "filename")]
+        sort_by: SortBy,
+        /// The seed used when sorting randomly
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+        /// How to output the results: "human", "json", "csv", "powershell", or "bash"
+        #[clap(short = 'f', long, default_value = "human")]
+        output_format: MatchOutputFormat,
+        #[clap(long)]
+        output_file: Option<String>,
+        /// Also write just the rename commands to this file, so that it
+        /// can be run directly after reviewing the rest of the output
+        #[clap(long)]
+        output_rename_script: Option<String>,
+        /// The shell the rename script file is for: "powershell" or "bash"
+        #[clap(long, default_value = "powershell", requires = "output-rename-script")]
+        script_format: ScriptFormat,
+    },
+    Diff {
         mkv_path: String,
         reference_path: String,
+        #[clap(long)]
+        output_file: Option<String>,
+    },
+    /// Run OCR on a png or bgra8 file written by the dump command
+    OcrFile {
+        image_path: String,
+        /// The width of a bgra8 file, if its name doesn't include the size
+        #[clap(long, requires = "height")]
+        width: Option<u32>,
+        /// The height of a bgra8 file, if its name doesn't include the size
+        #[clap(long, requires = "width")]
+        height: Option<u32>,
     },
 }
 
@@ -42,7 +213,12 @@ pub enum Commands {
 pub enum DumpType {
     Png,
     Bgra8,
+    AnimatedGif,
     Block,
+    Idx,
+    Palette,
+    Srt,
+    SrtBatch,
 }
 
 pub struct DumpTypeParseError(pub String);
@@ -65,7 +241,12 @@ impl FromStr for DumpType {
         match s {
             "png" => Ok(DumpType::Png),
             "bgra8" => Ok(DumpType::Bgra8),
+            "gif" => Ok(DumpType::AnimatedGif),
             "block" => Ok(DumpType::Block),
+            "idx" => Ok(DumpType::Idx),
+            "palette" => Ok(DumpType::Palette),
+            "srt" => Ok(DumpType::Srt),
+            "srt-batch" => Ok(DumpType::SrtBatch),
             _ => Err(DumpTypeParseError(s.to_string())),
         }
     }
@@ -75,6 +256,8 @@ impl FromStr for DumpType {
 pub enum FileType {
     Mkv,
     Srt,
+    // A VobSub idx file, with its sub file next to it
+    Idx,
 }
 
 pub struct FileTypeParseError(pub String);
@@ -97,7 +280,384 @@ impl FromStr for FileType {
         match s {
             "mkv" => Ok(FileType::Mkv),
             "srt" => Ok(FileType::Srt),
+            "idx" => Ok(FileType::Idx),
             _ => Err(FileTypeParseError(s.to_string())),
         }
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct NamePattern(pub String);
+
+// The values that can be used in a NamePattern
+pub struct NamePatternValues {
+    pub index: usize,
+    pub track: u64,
+    pub timestamp_ms: u64,
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+const NAME_PATTERN_PLACEHOLDERS: [&str; 7] = [
+    "index",
+    "track",
+    "timestamp_ms",
+    "width",
+    "height",
+    "x",
+    "y",
+];
+
+impl NamePattern {
+    pub fn format(&self, values: &NamePatternValues) -> String {
+        self.0
+            .replace("{index}", &values.index.to_string())
+            .replace("{track}", &values.track.to_string())
+            .replace("{timestamp_ms}", &values.timestamp_ms.to_string())
+            .replace("{width}", &values.width.to_string())
+            .replace("{height}", &values.height.to_string())
+            .replace("{x}", &values.x.to_string())
+            .replace("{y}", &values.y.to_string())
+    }
+}
+
+pub struct NamePatternParseError(pub String);
+impl Display for NamePatternParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid name pattern \"{}\". Supported placeholders are {{{}}}.",
+            self.0,
+            NAME_PATTERN_PLACEHOLDERS.join("}, {")
+        )
+    }
+}
+impl Debug for NamePatternParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for NamePatternParseError {}
+
+impl FromStr for NamePattern {
+    type Err = NamePatternParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if rest[..start].contains('}') {
+                return Err(NamePatternParseError(s.to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| NamePatternParseError(s.to_string()))?;
+            let placeholder = &rest[start + 1..start + end];
+            if !NAME_PATTERN_PLACEHOLDERS.contains(&placeholder) {
+                return Err(NamePatternParseError(s.to_string()));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(NamePatternParseError(s.to_string()));
+        }
+        Ok(NamePattern(s.to_string()))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum TrackListFormat {
+    Json,
+    Csv,
+}
+
+pub struct TrackListFormatParseError(pub String);
+impl Display for TrackListFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown track list format \"{}\".", self.0)
+    }
+}
+impl Debug for TrackListFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for TrackListFormatParseError {}
+
+impl FromStr for TrackListFormat {
+    type Err = TrackListFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(TrackListFormat::Json),
+            "csv" => Ok(TrackListFormat::Csv),
+            _ => Err(TrackListFormatParseError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum MatchOutputFormat {
+    Human,
+    Json,
+    Csv,
+    PowerShell,
+    Bash,
+}
+
+pub struct MatchOutputFormatParseError(pub String);
+impl Display for MatchOutputFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown output format \"{}\".", self.0)
+    }
+}
+impl Debug for MatchOutputFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for MatchOutputFormatParseError {}
+
+impl FromStr for MatchOutputFormat {
+    type Err = MatchOutputFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MatchOutputFormat::Human),
+            "json" => Ok(MatchOutputFormat::Json),
+            "csv" => Ok(MatchOutputFormat::Csv),
+            "powershell" => Ok(MatchOutputFormat::PowerShell),
+            "bash" => Ok(MatchOutputFormat::Bash),
+            _ => Err(MatchOutputFormatParseError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ScriptFormat {
+    PowerShell,
+    Bash,
+}
+
+pub struct ScriptFormatParseError(pub String);
+impl Display for ScriptFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown script format \"{}\".", self.0)
+    }
+}
+impl Debug for ScriptFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for ScriptFormatParseError {}
+
+impl FromStr for ScriptFormat {
+    type Err = ScriptFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "powershell" => Ok(ScriptFormat::PowerShell),
+            "bash" => Ok(ScriptFormat::Bash),
+            _ => Err(ScriptFormatParseError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum SortBy {
+    Filename,
+    Distance,
+    Random,
+}
+
+pub struct SortByParseError(pub String);
+impl Display for SortByParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown sort order \"{}\".", self.0)
+    }
+}
+impl Debug for SortByParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for SortByParseError {}
+
+impl FromStr for SortBy {
+    type Err = SortByParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "filename" => Ok(SortBy::Filename),
+            "distance" => Ok(SortBy::Distance),
+            "random" => Ok(SortBy::Random),
+            _ => Err(SortByParseError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Metric {
+    Levenshtein,
+    Similarity,
+    Words,
+}
+
+pub struct MetricParseError(pub String);
+impl Display for MetricParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown metric \"{}\".", self.0)
+    }
+}
+impl Debug for MetricParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for MetricParseError {}
+
+impl FromStr for Metric {
+    type Err = MetricParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "levenshtein" => Ok(Metric::Levenshtein),
+            "similarity" => Ok(Metric::Similarity),
+            "words" => Ok(Metric::Words),
+            _ => Err(MetricParseError(s.to_string())),
+        }
+    }
+}
+
+// The upper bounds (exclusive) of the distance ratio for
+// the A, B, C, and D grades. Anything else is an F.
+#[derive(Copy, Clone, Debug)]
+pub struct GradeThresholds(pub [f32; 4]);
+
+pub struct GradeThresholdsParseError(pub String);
+impl Display for GradeThresholdsParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid grade thresholds \"{}\". Expected 4 ascending, comma-separated values.",
+            self.0
+        )
+    }
+}
+impl Debug for GradeThresholdsParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for GradeThresholdsParseError {}
+
+impl FromStr for GradeThresholds {
+    type Err = GradeThresholdsParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| GradeThresholdsParseError(s.to_string()))?;
+        let thresholds: [f32; 4] = values
+            .try_into()
+            .map_err(|_| GradeThresholdsParseError(s.to_string()))?;
+        if thresholds.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(GradeThresholdsParseError(s.to_string()));
+        }
+        Ok(GradeThresholds(thresholds))
+    }
+}
+
+pub struct TrackPreferenceParseError(pub String);
+impl Display for TrackPreferenceParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown track preference \"{}\".", self.0)
+    }
+}
+impl Debug for TrackPreferenceParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for TrackPreferenceParseError {}
+
+impl FromStr for TrackPreference {
+    type Err = TrackPreferenceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(TrackPreference::LastFound),
+            "first" => Ok(TrackPreference::FirstFound),
+            "pgs" => Ok(TrackPreference::PreferPgs),
+            "vob" => Ok(TrackPreference::PreferVob),
+            _ => Err(TrackPreferenceParseError(s.to_string())),
+        }
+    }
+}
+
+pub struct PaletteFormatParseError(pub String);
+impl Display for PaletteFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown palette format \"{}\".", self.0)
+    }
+}
+impl Debug for PaletteFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for PaletteFormatParseError {}
+
+impl FromStr for PaletteFormat {
+    type Err = PaletteFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb" => Ok(PaletteFormat::Rgb),
+            "ycbcr" => Ok(PaletteFormat::YCbCr),
+            _ => Err(PaletteFormatParseError(s.to_string())),
+        }
+    }
+}
+
+pub struct SubtitleRegionParseError(pub String);
+impl Display for SubtitleRegionParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid subtitle region \"{}\". Expected \"x,y,width,height\".",
+            self.0
+        )
+    }
+}
+impl Debug for SubtitleRegionParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for SubtitleRegionParseError {}
+
+impl FromStr for SubtitleRegion {
+    type Err = SubtitleRegionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| SubtitleRegionParseError(s.to_string()))?;
+        match values.as_slice() {
+            [x, y, width, height] => Ok(SubtitleRegion {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            }),
+            _ => Err(SubtitleRegionParseError(s.to_string())),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::{fmt::Display, path::PathBuf};
+
+use windows::core::{HRESULT, HSTRING};
+
+use crate::vob::IdxParseError;
+
+#[derive(Debug)]
+pub enum ShowOrderError {
+    NotAnMkvFile(String),
+    // The file starts out as an mkv file, but something later on is malformed
+    InvalidMkvData(String),
+    InvalidVobPrivateData(IdxParseError),
+    Io(std::io::Error),
+    InputDirectoryNotFound(PathBuf),
+    OutputDirectoryNotFound(PathBuf),
+}
+
+impl Display for ShowOrderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShowOrderError::NotAnMkvFile(reason) => write!(f, "Not an mkv file ({}).", reason),
+            ShowOrderError::InvalidMkvData(reason) => write!(f, "Invalid mkv data ({}).", reason),
+            ShowOrderError::InvalidVobPrivateData(error) => {
+                write!(f, "Invalid Vob subtitle track private data: {}", error)
+            }
+            ShowOrderError::Io(error) => write!(f, "{}", error),
+            ShowOrderError::InputDirectoryNotFound(path) => write!(
+                f,
+                "The input directory \"{}\" doesn't exist or isn't a directory.",
+                path.display()
+            ),
+            ShowOrderError::OutputDirectoryNotFound(path) => write!(
+                f,
+                "The output directory \"{}\" doesn't exist or isn't a directory.",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ShowOrderError {}
+
+impl From<std::io::Error> for ShowOrderError {
+    fn from(error: std::io::Error) -> Self {
+        ShowOrderError::Io(error)
+    }
+}
+
+// E_FAIL
+const GENERIC_FAILURE: HRESULT = HRESULT(0x80004005u32 as i32);
+// HRESULT_FROM_WIN32(ERROR_PATH_NOT_FOUND)
+const PATH_NOT_FOUND: HRESULT = HRESULT(0x80070003u32 as i32);
+
+// Most of the code works with WinRT, so its errors are what get passed around
+impl From<ShowOrderError> for windows::core::Error {
+    fn from(error: ShowOrderError) -> Self {
+        let code = match &error {
+            ShowOrderError::InputDirectoryNotFound(_)
+            | ShowOrderError::OutputDirectoryNotFound(_) => PATH_NOT_FOUND,
+            _ => GENERIC_FAILURE,
+        };
+        windows::core::Error::new(code, HSTRING::from(error.to_string()))
+    }
+}
@@ -1,11 +1,21 @@
+use std::{collections::HashMap, path::Path};
+
+use nalgebra::SMatrix;
+
 use windows::{
     core::Result,
-    Graphics::Imaging::{BitmapBufferAccessMode, BitmapPixelFormat, SoftwareBitmap},
-    Storage::Streams::Buffer,
+    Graphics::Imaging::{
+        BitmapAlphaMode, BitmapBufferAccessMode, BitmapDecoder, BitmapPixelFormat, SoftwareBitmap,
+    },
+    Storage::{FileAccessMode, StorageFile, Streams::Buffer},
     UI::Color,
 };
 
-use crate::interop::{as_mut_slice, memory_buffer_as_mut_slice, memory_buffer_as_slice};
+use crate::{
+    interop::{as_mut_slice, memory_buffer_as_mut_slice, memory_buffer_as_slice, to_winrt_path},
+    progress,
+    vob::SubtitleRegion,
+};
 
 pub fn scale_image(src_bitmap: &SoftwareBitmap, scale: f32) -> Result<SoftwareBitmap> {
     let width = src_bitmap.PixelWidth()? as usize;
@@ -49,6 +59,215 @@ pub fn scale_image(src_bitmap: &SoftwareBitmap, scale: f32) -> Result<SoftwareBi
     Ok(scaled_bitmap)
 }
 
+// Scales the image uniformly so that it fits within the given bounds.
+// Images that already fit are copied as-is.
+pub fn scale_image_to_fit(
+    src_bitmap: &SoftwareBitmap,
+    max_width: u32,
+    max_height: u32,
+) -> Result<SoftwareBitmap> {
+    let width = src_bitmap.PixelWidth()? as f32;
+    let height = src_bitmap.PixelHeight()? as f32;
+
+    let scale = (max_width as f32 / width).min(max_height as f32 / height);
+    if scale >= 1.0 {
+        SoftwareBitmap::Copy(src_bitmap)
+    } else {
+        scale_image(src_bitmap, scale)
+    }
+}
+
+// Returns false if the bitmap is larger than the given bounds
+// (e.g. the frame size declared by the track).
+pub fn check_dimensions(bitmap: &SoftwareBitmap, max_width: u32, max_height: u32) -> Result<bool> {
+    let width = bitmap.PixelWidth()? as u32;
+    let height = bitmap.PixelHeight()? as u32;
+    if width > max_width || height > max_height {
+        progress::suspend(|| {
+            println!(
+                "Warning! Subtitle image ({}x{}) is larger than the declared frame size ({}x{}).",
+                width, height, max_width, max_height
+            )
+        });
+        Ok(false)
+    } else {
+        Ok(true)
+    }
+}
+
+// Returns the (r, g, b) values for a YCbCr color, as used by PGS
+// palettes and some VobSub palettes.
+pub fn ycbcr_to_rgb(
+    luminance: u8,
+    color_difference_blue: u8,
+    color_difference_red: u8,
+) -> (u8, u8, u8) {
+    type Matrix3x3 = SMatrix<f32, 3, 3>;
+    type Matrix3x1 = SMatrix<f32, 3, 1>;
+
+    static COLOR_CONVERSION_MATRIX: Matrix3x3 = Matrix3x3::new(
+        // https://web.archive.org/web/20180421030430/http://www.equasys.de/colorconversion.html
+        1.164, 0.000, 1.793, 1.164, -0.213, -0.533, 1.164, 2.112,
+        0.000,
+        // https://docs.microsoft.com/en-us/openspecs/windows_protocols/ms-rdprfx/2e1618ed-60d6-4a64-aa5d-0608884861bb
+        //1.0, 0.0, 1.402525, 1.0, -0.343730, -0.714401, 1.0, 1.769905, 0.000013,
+    );
+
+    let values = Matrix3x1::new(
+        (luminance.wrapping_sub(16)) as f32,
+        (color_difference_blue.wrapping_sub(128)) as f32,
+        (color_difference_red.wrapping_sub(128)) as f32,
+    );
+
+    let rgb_values: Matrix3x1 = COLOR_CONVERSION_MATRIX * values;
+    let r = *rgb_values.get((0, 0)).unwrap() as u8;
+    let b = *rgb_values.get((1, 0)).unwrap() as u8;
+    let g = *rgb_values.get((2, 0)).unwrap() as u8;
+    (r, g, b)
+}
+
+// Rotates the image by 180 degrees, for encoders that store
+// their subtitle images upside-down.
+pub fn rotate_180(src_bitmap: &SoftwareBitmap) -> Result<SoftwareBitmap> {
+    let width = src_bitmap.PixelWidth()?;
+    let height = src_bitmap.PixelHeight()?;
+    let format = src_bitmap.BitmapPixelFormat()?;
+    assert_eq!(format, BitmapPixelFormat::Bgra8);
+    let bytes_per_pixel = 4;
+    let bitmap_size = (width * height * bytes_per_pixel) as u32;
+    let buffer = Buffer::Create(bitmap_size)?;
+    buffer.SetLength(bitmap_size)?;
+
+    {
+        let bitmap_buffer = src_bitmap.LockBuffer(BitmapBufferAccessMode::Read)?;
+        let bitmap_ref = bitmap_buffer.CreateReference()?;
+        let src_slice = unsafe { memory_buffer_as_slice(&bitmap_ref)? };
+        let dest_slice = unsafe { as_mut_slice(&buffer)? };
+        // The locked buffer can be larger than the image itself
+        rotate_pixels_180(
+            &src_slice[..bitmap_size as usize],
+            dest_slice,
+            bytes_per_pixel as usize,
+        );
+        bitmap_ref.Close()?;
+        bitmap_buffer.Close()?;
+    }
+
+    SoftwareBitmap::CreateCopyFromBuffer(buffer, BitmapPixelFormat::Bgra8, width, height)
+}
+
+// Reversing the order of the pixels reverses both the order of
+// the rows and the order of the pixels within each row.
+fn rotate_pixels_180(src: &[u8], dest: &mut [u8], bytes_per_pixel: usize) {
+    for (dest_pixel, src_pixel) in dest
+        .chunks_exact_mut(bytes_per_pixel)
+        .zip(src.chunks_exact(bytes_per_pixel).rev())
+    {
+        dest_pixel.copy_from_slice(src_pixel);
+    }
+}
+
+// Averages the color of the pixels with an alpha of at least min_alpha,
+// which gives the "ink" color of the subtitle's glyphs.
+pub fn average_color(bitmap: &SoftwareBitmap, min_alpha: u8) -> Result<Color> {
+    map_bgra8_bytes(bitmap, |bytes| compute_average_color(bytes, min_alpha))
+}
+
+fn compute_average_color(bgra_bytes: &[u8], min_alpha: u8) -> Color {
+    let mut totals = [0u64; 3];
+    let mut count = 0u64;
+    for pixel in bgra_bytes.chunks_exact(4) {
+        if pixel[3] >= min_alpha {
+            totals[0] += pixel[0] as u64;
+            totals[1] += pixel[1] as u64;
+            totals[2] += pixel[2] as u64;
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Color {
+            A: 0,
+            R: 0,
+            G: 0,
+            B: 0,
+        };
+    }
+    Color {
+        A: 255,
+        R: (totals[2] / count) as u8,
+        G: (totals[1] / count) as u8,
+        B: (totals[0] / count) as u8,
+    }
+}
+
+// Loads a png file (e.g. one written by "dump png") as a BGRA8 bitmap.
+pub fn load_png_as_bitmap(path: &Path) -> Result<SoftwareBitmap> {
+    let file = StorageFile::GetFileFromPathAsync(to_winrt_path(path))?.get()?;
+    let stream = file.OpenAsync(FileAccessMode::Read)?.get()?;
+    let decoder = BitmapDecoder::CreateAsync(stream)?.get()?;
+    decoder
+        .GetSoftwareBitmapConvertedAsync(BitmapPixelFormat::Bgra8, BitmapAlphaMode::Premultiplied)?
+        .get()
+}
+
+// Loads the output of "dump bgra8", which doesn't store its own size.
+pub fn load_bgra8_as_bitmap(bytes: &[u8], width: u32, height: u32) -> Result<SoftwareBitmap> {
+    let bytes_per_pixel = 4;
+    let bitmap_size = width * height * bytes_per_pixel;
+    assert_eq!(bytes.len(), bitmap_size as usize);
+    let buffer = Buffer::Create(bitmap_size)?;
+    buffer.SetLength(bitmap_size)?;
+    let dest_slice = unsafe { as_mut_slice(&buffer)? };
+    dest_slice.copy_from_slice(bytes);
+    SoftwareBitmap::CreateCopyFromBuffer(
+        buffer,
+        BitmapPixelFormat::Bgra8,
+        width as i32,
+        height as i32,
+    )
+}
+
+// Converts the image to tightly packed R,G,B bytes, dropping the alpha channel.
+pub fn to_rgb24(bitmap: &SoftwareBitmap) -> Result<Vec<u8>> {
+    map_bgra8_bytes(bitmap, |bytes| reorder_bgra8(bytes, &[2, 1, 0]))
+}
+
+// Converts the image to tightly packed R,G,B,A bytes. The tests use this to
+// compare decoded images against the expected pixels.
+#[cfg(test)]
+pub fn to_rgba32(bitmap: &SoftwareBitmap) -> Result<Vec<u8>> {
+    map_bgra8_bytes(bitmap, |bytes| reorder_bgra8(bytes, &[2, 1, 0, 3]))
+}
+
+fn map_bgra8_bytes<T, F: FnOnce(&[u8]) -> T>(bitmap: &SoftwareBitmap, f: F) -> Result<T> {
+    let width = bitmap.PixelWidth()? as usize;
+    let height = bitmap.PixelHeight()? as usize;
+    let format = bitmap.BitmapPixelFormat()?;
+    assert_eq!(format, BitmapPixelFormat::Bgra8);
+    let bytes_per_pixel = 4;
+
+    let result = {
+        let bitmap_buffer = bitmap.LockBuffer(BitmapBufferAccessMode::Read)?;
+        let bitmap_ref = bitmap_buffer.CreateReference()?;
+        let bytes = unsafe { memory_buffer_as_slice(&bitmap_ref)? };
+        let result = f(&bytes[..width * height * bytes_per_pixel]);
+        bitmap_ref.Close()?;
+        bitmap_buffer.Close()?;
+        result
+    };
+
+    Ok(result)
+}
+
+// Builds each output pixel from the given channels of a BGRA8 pixel
+fn reorder_bgra8(bgra_bytes: &[u8], channels: &[usize]) -> Vec<u8> {
+    let mut result = Vec::with_capacity((bgra_bytes.len() / 4) * channels.len());
+    for pixel in bgra_bytes.chunks_exact(4) {
+        result.extend(channels.iter().map(|channel| pixel[*channel]));
+    }
+    result
+}
+
 pub fn blend_with_color(bitmap: &SoftwareBitmap, color: &Color) -> Result<()> {
     let format = bitmap.BitmapPixelFormat()?;
     assert_eq!(format, BitmapPixelFormat::Bgra8);
@@ -81,3 +300,467 @@ pub fn blend_with_color(bitmap: &SoftwareBitmap, color: &Color) -> Result<()> {
 
     Ok(())
 }
+
+// Windows's OCR likes a solid color background rather than transparent
+pub const OCR_BACKGROUND_COLOR: Color = Color {
+    A: 255,
+    R: 0,
+    G: 0,
+    B: 0,
+};
+
+// Like blend_with_color, but returns a new bitmap instead of modifying
+// the given one. The background's alpha channel is respected.
+pub fn blend_over_solid(bitmap: &SoftwareBitmap, bg: &Color) -> Result<SoftwareBitmap> {
+    let width = bitmap.PixelWidth()?;
+    let height = bitmap.PixelHeight()?;
+    let format = bitmap.BitmapPixelFormat()?;
+    assert_eq!(format, BitmapPixelFormat::Bgra8);
+    let bytes_per_pixel = 4;
+    let bitmap_size = (width * height * bytes_per_pixel) as u32;
+    let buffer = Buffer::Create(bitmap_size)?;
+    buffer.SetLength(bitmap_size)?;
+
+    {
+        let bitmap_buffer = bitmap.LockBuffer(BitmapBufferAccessMode::Read)?;
+        let bitmap_ref = bitmap_buffer.CreateReference()?;
+        let src_slice = unsafe { memory_buffer_as_slice(&bitmap_ref)? };
+        let dest_slice = unsafe { as_mut_slice(&buffer)? };
+        composite_over_color(src_slice, dest_slice, bg);
+        bitmap_ref.Close()?;
+        bitmap_buffer.Close()?;
+    }
+
+    SoftwareBitmap::CreateCopyFromBuffer(buffer, BitmapPixelFormat::Bgra8, width, height)
+}
+
+// Porter-Duff source-over, with the source pixels on top of the color
+fn composite_over_color(src: &[u8], dest: &mut [u8], color: &Color) {
+    let bg_blue = color.B as f32 / 255.0;
+    let bg_green = color.G as f32 / 255.0;
+    let bg_red = color.R as f32 / 255.0;
+    let bg_alpha = color.A as f32 / 255.0;
+
+    for (dest_pixel, src_pixel) in dest.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        let src_alpha = src_pixel[3] as f32 / 255.0;
+        let bg_weight = bg_alpha * (1.0 - src_alpha);
+        let out_alpha = src_alpha + bg_weight;
+        if out_alpha == 0.0 {
+            dest_pixel.copy_from_slice(&[0, 0, 0, 0]);
+            continue;
+        }
+        let blend = |src: u8, bg: f32| {
+            let value = ((src as f32 / 255.0) * src_alpha + bg * bg_weight) / out_alpha;
+            (value * 255.0).round() as u8
+        };
+        dest_pixel[0] = blend(src_pixel[0], bg_blue);
+        dest_pixel[1] = blend(src_pixel[1], bg_green);
+        dest_pixel[2] = blend(src_pixel[2], bg_red);
+        dest_pixel[3] = (out_alpha * 255.0).round() as u8;
+    }
+}
+
+// Images that OCR well tend to be above these values
+pub const MIN_READABLE_EDGE_DENSITY: f32 = 0.1;
+pub const MIN_READABLE_CONTRAST_RATIO: f32 = 3.0;
+
+// The change in luminance between neighboring pixels needed
+// for a pixel to be considered an edge.
+const EDGE_THRESHOLD: f32 = 64.0;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SubtitleImageMetrics {
+    // Fraction of pixels with a high gradient
+    pub edge_density: f32,
+    // Max luminance / min non-zero luminance
+    pub contrast_ratio: f32,
+    // Fraction of pixels with a non-zero alpha
+    pub non_transparent_fraction: f32,
+    // Most common non-transparent color
+    pub dominant_color: Color,
+    // Average Sobel gradient magnitude, see compute_gradient
+    pub sharpness: f32,
+}
+
+impl SubtitleImageMetrics {
+    pub fn is_likely_readable(&self) -> bool {
+        self.edge_density > MIN_READABLE_EDGE_DENSITY
+            && self.contrast_ratio > MIN_READABLE_CONTRAST_RATIO
+    }
+}
+
+pub fn analyze_subtitle_image(bitmap: &SoftwareBitmap) -> Result<SubtitleImageMetrics> {
+    let width = bitmap.PixelWidth()? as usize;
+    let height = bitmap.PixelHeight()? as usize;
+    let format = bitmap.BitmapPixelFormat()?;
+    assert_eq!(format, BitmapPixelFormat::Bgra8);
+
+    let metrics = {
+        let bitmap_buffer = bitmap.LockBuffer(BitmapBufferAccessMode::Read)?;
+        let bitmap_ref = bitmap_buffer.CreateReference()?;
+        let bytes = unsafe { memory_buffer_as_slice(&bitmap_ref)? };
+        let metrics = compute_image_metrics(bytes, width, height);
+        bitmap_ref.Close()?;
+        bitmap_buffer.Close()?;
+        metrics
+    };
+
+    Ok(metrics)
+}
+
+fn compute_image_metrics(bgra_bytes: &[u8], width: usize, height: usize) -> SubtitleImageMetrics {
+    let bytes_per_pixel = 4;
+    let num_pixels = width * height;
+
+    // Transparent pixels are treated as black so that the
+    // edges of the text count towards the edge density.
+    let mut luminances = Vec::with_capacity(num_pixels);
+    let mut num_non_transparent = 0;
+    let mut max_luminance: f32 = 0.0;
+    let mut min_luminance: Option<f32> = None;
+    let mut color_counts: HashMap<(u8, u8, u8, u8), usize> = HashMap::new();
+    for pixel in bgra_bytes.chunks(bytes_per_pixel).take(num_pixels) {
+        let (blue, green, red, alpha) = (pixel[0], pixel[1], pixel[2], pixel[3]);
+        let luminance = (0.2126 * red as f32) + (0.7152 * green as f32) + (0.0722 * blue as f32);
+        luminances.push(luminance * (alpha as f32 / 255.0));
+        if alpha > 0 {
+            num_non_transparent += 1;
+            max_luminance = max_luminance.max(luminance);
+            if luminance > 0.0 {
+                min_luminance = Some(min_luminance.map_or(luminance, |min| min.min(luminance)));
+            }
+            *color_counts.entry((blue, green, red, alpha)).or_insert(0) += 1;
+        }
+    }
+
+    let mut num_edges = 0;
+    for y in 0..height {
+        for x in 0..width {
+            let luminance = luminances[(y * width) + x];
+            let dx = if x + 1 < width {
+                (luminances[(y * width) + x + 1] - luminance).abs()
+            } else {
+                0.0
+            };
+            let dy = if y + 1 < height {
+                (luminances[((y + 1) * width) + x] - luminance).abs()
+            } else {
+                0.0
+            };
+            if dx + dy > EDGE_THRESHOLD {
+                num_edges += 1;
+            }
+        }
+    }
+
+    let fraction = |count: usize| {
+        if num_pixels > 0 {
+            count as f32 / num_pixels as f32
+        } else {
+            0.0
+        }
+    };
+    let contrast_ratio = min_luminance
+        .map(|min_luminance| max_luminance / min_luminance)
+        .unwrap_or(0.0);
+    let dominant_color = color_counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|((blue, green, red, alpha), _)| Color {
+            A: alpha,
+            R: red,
+            G: green,
+            B: blue,
+        })
+        .unwrap_or(Color {
+            A: 0,
+            R: 0,
+            G: 0,
+            B: 0,
+        });
+
+    SubtitleImageMetrics {
+        edge_density: fraction(num_edges),
+        contrast_ratio,
+        non_transparent_fraction: fraction(num_non_transparent),
+        dominant_color,
+        sharpness: compute_average_gradient(bgra_bytes, width, height),
+    }
+}
+
+// Computes the average Sobel gradient magnitude of the image. Sharp
+// text has strong edges and tends to OCR well, blurry or heavily
+// anti-aliased text has weak ones.
+pub fn compute_gradient(bitmap: &SoftwareBitmap) -> Result<f32> {
+    let width = bitmap.PixelWidth()? as usize;
+    let height = bitmap.PixelHeight()? as usize;
+    map_bgra8_bytes(bitmap, |bytes| {
+        compute_average_gradient(bytes, width, height)
+    })
+}
+
+fn compute_average_gradient(bgra_bytes: &[u8], width: usize, height: usize) -> f32 {
+    let bytes_per_pixel = 4;
+    let num_pixels = width * height;
+    if num_pixels == 0 {
+        return 0.0;
+    }
+
+    // Like compute_image_metrics, transparent pixels are treated as black
+    let luminances = bgra_bytes
+        .chunks(bytes_per_pixel)
+        .take(num_pixels)
+        .map(|pixel| {
+            let (blue, green, red, alpha) = (pixel[0], pixel[1], pixel[2], pixel[3]);
+            let luminance = (0.299 * red as f32) + (0.587 * green as f32) + (0.114 * blue as f32);
+            luminance * (alpha as f32 / 255.0)
+        })
+        .collect::<Vec<_>>();
+
+    // Pixels past the edges of the image repeat the closest edge pixel
+    let luminance_at = |x: usize, y: usize, dx: isize, dy: isize| {
+        let x = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+        luminances[(y * width) + x]
+    };
+
+    let mut total = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            let l = |dx, dy| luminance_at(x, y, dx, dy);
+            let gx = (l(1, -1) + 2.0 * l(1, 0) + l(1, 1)) - (l(-1, -1) + 2.0 * l(-1, 0) + l(-1, 1));
+            let gy = (l(-1, 1) + 2.0 * l(0, 1) + l(1, 1)) - (l(-1, -1) + 2.0 * l(0, -1) + l(1, -1));
+            total += (gx * gx + gy * gy).sqrt();
+        }
+    }
+    total / num_pixels as f32
+}
+
+// Pixels with an alpha at or below this are ignored when
+// looking for the subtitle within a bitmap.
+pub const SUBTITLE_REGION_ALPHA_THRESHOLD: u8 = 0;
+
+// Finds the bounding box of the non-transparent pixels in the bitmap.
+// Returns None if the bitmap is fully transparent.
+pub fn detect_subtitle_region(bitmap: &SoftwareBitmap) -> Result<Option<SubtitleRegion>> {
+    let width = bitmap.PixelWidth()? as usize;
+    map_bgra8_bytes(bitmap, |bytes| {
+        find_subtitle_region(bytes, width, SUBTITLE_REGION_ALPHA_THRESHOLD)
+    })
+}
+
+fn find_subtitle_region(
+    bgra_bytes: &[u8],
+    width: usize,
+    alpha_threshold: u8,
+) -> Option<SubtitleRegion> {
+    let bytes_per_pixel = 4;
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for (i, pixel) in bgra_bytes.chunks_exact(bytes_per_pixel).enumerate() {
+        if pixel[3] > alpha_threshold {
+            let x = i % width;
+            let y = i / width;
+            bounds = Some(match bounds {
+                Some((min_x, min_y, max_x, max_y)) => {
+                    (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+                }
+                None => (x, y, x, y),
+            });
+        }
+    }
+    bounds.map(|(min_x, min_y, max_x, max_y)| SubtitleRegion {
+        x: min_x as u16,
+        y: min_y as u16,
+        width: (max_x - min_x + 1) as u16,
+        height: (max_y - min_y + 1) as u16,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subtitle_region_test() {
+        let transparent = [0u8, 0, 0, 0];
+        let faint = [255u8, 255, 255, 16];
+        let white = [255u8, 255, 255, 255];
+        // A 4x3 image with content in the middle two columns of the bottom two rows
+        let pixels = [
+            transparent,
+            transparent,
+            transparent,
+            transparent,
+            transparent,
+            white,
+            transparent,
+            transparent,
+            transparent,
+            faint,
+            white,
+            transparent,
+        ];
+        let bytes = pixels.concat();
+        assert_eq!(
+            find_subtitle_region(&bytes, 4, 0),
+            Some(SubtitleRegion {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2,
+            })
+        );
+        assert_eq!(
+            find_subtitle_region(&bytes, 4, 16),
+            Some(SubtitleRegion {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2,
+            })
+        );
+
+        let bytes = [transparent, transparent, faint, transparent].concat();
+        assert_eq!(
+            find_subtitle_region(&bytes, 2, 0),
+            Some(SubtitleRegion {
+                x: 0,
+                y: 1,
+                width: 1,
+                height: 1,
+            })
+        );
+        assert_eq!(find_subtitle_region(&bytes, 2, 16), None);
+    }
+
+    #[test]
+    fn image_metrics_test() {
+        // A 4x2 image: a white pixel and a gray pixel on a transparent background
+        let transparent = [0u8, 0, 0, 0];
+        let white = [255u8, 255, 255, 255];
+        let gray = [64u8, 64, 64, 255];
+        let pixels = [
+            transparent,
+            white,
+            white,
+            transparent,
+            transparent,
+            gray,
+            transparent,
+            transparent,
+        ];
+        let bytes = pixels.concat();
+        let metrics = compute_image_metrics(&bytes, 4, 2);
+        assert_eq!(metrics.non_transparent_fraction, 0.375);
+        assert!((metrics.contrast_ratio - (255.0 / 64.0)).abs() < 0.001);
+        assert_eq!(metrics.dominant_color.R, 255);
+        assert!(metrics.edge_density > 0.0);
+        assert!(metrics.edge_density < 1.0);
+
+        let metrics = compute_image_metrics(&transparent, 1, 1);
+        assert_eq!(metrics.non_transparent_fraction, 0.0);
+        assert_eq!(metrics.contrast_ratio, 0.0);
+        assert_eq!(metrics.edge_density, 0.0);
+        assert_eq!(metrics.sharpness, 0.0);
+    }
+
+    #[test]
+    fn gradient_test() {
+        // A 5x3 image with a white row on a black background
+        let black = [0u8, 0, 0, 255];
+        let white = [255u8, 255, 255, 255];
+        let bytes = [[black; 5], [white; 5], [black; 5]].concat().concat();
+        assert!(compute_average_gradient(&bytes, 5, 3) >= 255.0);
+
+        // Nothing changes in a solid image
+        let bytes = [white; 15].concat();
+        assert_eq!(compute_average_gradient(&bytes, 5, 3), 0.0);
+        assert_eq!(compute_average_gradient(&[], 0, 0), 0.0);
+    }
+
+    #[test]
+    fn average_color_test() {
+        let red = [0u8, 0, 255, 255];
+        let transparent_blue = [255u8, 0, 0, 0];
+        let bytes = [red, red, transparent_blue].concat();
+        let color = compute_average_color(&bytes, 128);
+        assert_eq!((color.A, color.R, color.G, color.B), (255, 255, 0, 0));
+
+        let color = compute_average_color(&bytes, 0);
+        assert_eq!((color.R, color.G, color.B), (170, 0, 85));
+
+        let color = compute_average_color(&transparent_blue, 1);
+        assert_eq!(color.A, 0);
+    }
+
+    #[test]
+    fn reorder_bgra8_test() {
+        let bgra_bytes = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            reorder_bgra8(&bgra_bytes, &[2, 1, 0]),
+            vec![3, 2, 1, 7, 6, 5]
+        );
+        assert_eq!(
+            reorder_bgra8(&bgra_bytes, &[2, 1, 0, 3]),
+            vec![3, 2, 1, 4, 7, 6, 5, 8]
+        );
+    }
+
+    #[test]
+    fn composite_over_color_test() {
+        // Opaque, half transparent, and fully transparent white pixels
+        let pixels = [255u8, 255, 255, 255, 255, 255, 255, 128, 255, 255, 255, 0];
+        let mut result = [0u8; 12];
+        composite_over_color(&pixels, &mut result, &OCR_BACKGROUND_COLOR);
+        assert_eq!(
+            result,
+            [255, 255, 255, 255, 128, 128, 128, 255, 0, 0, 0, 255]
+        );
+
+        // Nothing to composite over
+        let transparent = Color {
+            A: 0,
+            R: 255,
+            G: 0,
+            B: 0,
+        };
+        composite_over_color(&pixels, &mut result, &transparent);
+        assert_eq!(result, [255, 255, 255, 255, 255, 255, 255, 128, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rotate_180_test() {
+        // 2x2 and 2x3 images, each pixel being a different color
+        for height in [2u8, 3] {
+            let pixels = (0..2 * height)
+                .map(|i| [i, i, i, 255])
+                .collect::<Vec<_>>()
+                .concat();
+            let mut rotated = vec![0u8; pixels.len()];
+            rotate_pixels_180(&pixels, &mut rotated, 4);
+            assert_eq!(&rotated[..4], &pixels[pixels.len() - 4..]);
+            assert_ne!(rotated, pixels);
+            let mut restored = vec![0u8; pixels.len()];
+            rotate_pixels_180(&rotated, &mut restored, 4);
+            assert_eq!(restored, pixels);
+        }
+    }
+
+    #[test]
+    fn rotate_180_bitmap_test() -> Result<()> {
+        let pixels = (0..15u8)
+            .map(|i| [i, i, i, 255])
+            .collect::<Vec<_>>()
+            .concat();
+        let buffer = Buffer::Create(pixels.len() as u32)?;
+        buffer.SetLength(pixels.len() as u32)?;
+        unsafe { as_mut_slice(&buffer)? }.copy_from_slice(&pixels);
+        let bitmap = SoftwareBitmap::CreateCopyFromBuffer(buffer, BitmapPixelFormat::Bgra8, 5, 3)?;
+
+        let rotated = rotate_180(&rotate_180(&bitmap)?)?;
+        let buffer = Buffer::Create(pixels.len() as u32)?;
+        rotated.CopyToBuffer(&buffer)?;
+        assert_eq!(unsafe { as_mut_slice(&buffer)? }, pixels.as_slice());
+        Ok(())
+    }
+}
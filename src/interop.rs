@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use windows::{
     core::{Interface, Result},
     Foundation::IMemoryBufferReference,
@@ -30,3 +32,61 @@ pub unsafe fn memory_buffer_as_mut_slice(buffer: &IMemoryBufferReference) -> Res
     interop.GetBuffer(&mut data, &mut len)?;
     Ok(std::slice::from_raw_parts_mut(data, len as _))
 }
+
+// WinRT storage APIs want absolute paths without the "\\?\" prefix
+// that canonicalize adds on Windows.
+pub fn to_winrt_path(path: &Path) -> String {
+    let path = path.canonicalize().unwrap();
+    let path = path.to_str().unwrap();
+    let path = path.replace("\\\\?\\", "");
+    if path.starts_with("UNC") {
+        path.replacen("UNC", "\\", 1)
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use windows::Graphics::Imaging::{
+        BitmapAlphaMode, BitmapBufferAccessMode, BitmapPixelFormat, SoftwareBitmap,
+    };
+
+    #[test]
+    fn memory_buffer_slices_test() -> Result<()> {
+        let bitmap = SoftwareBitmap::CreateWithAlpha(
+            BitmapPixelFormat::Bgra8,
+            2,
+            2,
+            BitmapAlphaMode::Premultiplied,
+        )?;
+        let bitmap_buffer = bitmap.LockBuffer(BitmapBufferAccessMode::ReadWrite)?;
+        let bitmap_ref = bitmap_buffer.CreateReference()?;
+        {
+            let bytes = unsafe { memory_buffer_as_mut_slice(&bitmap_ref)? };
+            assert!(bytes.len() >= 16);
+            bytes[0] = 0x12;
+            bytes[15] = 0x34;
+        }
+        let bytes = unsafe { memory_buffer_as_slice(&bitmap_ref)? };
+        assert_eq!(bytes[0], 0x12);
+        assert_eq!(bytes[15], 0x34);
+        bitmap_ref.Close()?;
+        bitmap_buffer.Close()?;
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_slice_test() -> Result<()> {
+        let buffer = Buffer::Create(4)?;
+        buffer.SetLength(4)?;
+        {
+            let bytes = unsafe { as_mut_slice(&buffer)? };
+            bytes.copy_from_slice(&[1, 2, 3, 4]);
+        }
+        let bytes = unsafe { as_mut_slice(&buffer)? };
+        assert_eq!(bytes, &[1, 2, 3, 4]);
+        Ok(())
+    }
+}
@@ -1,33 +1,71 @@
 mod cli;
+mod error;
 mod image;
 mod interop;
 mod mkv;
 mod pgs;
+mod progress;
 mod srt;
 mod string;
+#[cfg(test)]
+mod test_utils;
 mod text;
 mod vob;
 
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
-    path::Path,
+    io::{ErrorKind, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use clap::Parser;
-use cli::{Args, Commands, DumpType, FileType};
-use levenshtein::levenshtein;
+use cli::{
+    Args, Commands, DumpType, FileType, GradeThresholds, MatchOutputFormat, Metric, NamePattern,
+    NamePatternValues, ScriptFormat, SortBy, TrackListFormat,
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lazy_static::lazy_static;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use regex::Regex;
 use windows::{
     core::Result,
-    Graphics::Imaging::{BitmapEncoder, BitmapPixelFormat},
-    Storage::{CreationCollisionOption, FileAccessMode, FileIO, StorageFolder, Streams::Buffer},
+    Graphics::Imaging::{BitmapEncoder, BitmapPixelFormat, SoftwareBitmap},
+    Storage::{
+        CreationCollisionOption, FileAccessMode, FileIO, StorageFile, StorageFolder,
+        Streams::Buffer,
+    },
     Win32::System::WinRT::{RoInitialize, RO_INIT_MULTITHREADED},
+    UI::Color,
 };
+use zip::result::ZipError;
 
 use crate::{
-    mkv::{load_first_n_english_subtitles, KnownLanguage, MkvFile},
-    string::normalize_to_shortest_string,
+    error::ShowOrderError,
+    image::{
+        average_color, blend_with_color, load_bgra8_as_bitmap, load_png_as_bitmap,
+        scale_image_to_fit, to_rgb24,
+    },
+    interop::to_winrt_path,
+    mkv::{
+        load_first_n_english_subtitles, load_first_n_idx_subtitles, load_first_n_subtitles_timed,
+        load_first_n_subtitles_with_metrics, load_first_n_subtitles_with_timestamps, ocr_bitmap,
+        select_track, KnownEncoding, KnownLanguage, LoadOptions, MkvFile, OcrOptions, OcrText,
+        ParseConfig, SubtitleFrame, TrackInfo, TrackPreference,
+    },
+    string::{
+        compute_aligned_distance, compute_distance, compute_similarity_score,
+        compute_word_distance, diff_words, levenshtein_windowed, normalize_to_shortest_string,
+        NormalizationStrategy, WordDiff,
+    },
+    text::{analyze_alphabet, extract_words, MIN_ENGLISH_LATIN_FRACTION},
+    vob::{PaletteFormat, SubtitleRegion},
 };
 
 fn main() -> Result<()> {
@@ -37,27 +75,99 @@ fn main() -> Result<()> {
 
     let num_subtitles = args.max_count;
     let track_number = args.track_number;
+    let track_name = args.track_name;
     let max_distance = args.max_distance;
+    let chapter = args.chapter;
+    let parse_config = ParseConfig {
+        scan_entire_file_for_tracks: args.scan_entire_file,
+    };
+    let distance_options = DistanceOptions {
+        metric: args.metric,
+        normalization: args.normalization,
+        windowed: args.windowed,
+        align: args.align,
+    };
+    let load_options = LoadOptions {
+        num_subtitles,
+        sample_rate: args.sample_rate,
+        track_number,
+        track_name: track_name.clone(),
+        chapter,
+        forced_only: args.forced_only,
+        track_preference: args.track_preference,
+        fallback_to_und: args.fallback_to_und,
+        parse_config,
+        vob_palette_format: args.vob_palette_format,
+        subtitle_region: args.subtitle_region,
+        ocr: OcrOptions {
+            rotate_180: args.rotate_180,
+            min_sharpness: args.min_sharpness,
+            no_sanitize: args.no_sanitize,
+            sdh: args.sdh,
+        },
+        debug_logging: debug_logging_enabled(),
+        show_file_progress: args.progress,
+    };
+    let reference_options = ReferenceOptions {
+        num_subtitles,
+        no_zip: args.no_zip,
+        srt: srt::ParseOptions {
+            sanitize: !args.no_sanitize,
+            strict: args.strict_srt,
+            sdh: args.sdh,
+        },
+    };
 
     match args.command {
-        Commands::ListTracks { mkv_path } => {
-            list_tracks(&mkv_path)?;
+        Commands::ListTracks {
+            mkv_path,
+            chapters,
+            format,
+            count_blocks,
+            max_scan_bytes,
+        } => {
+            if let Some(format) = format {
+                list_tracks_formatted(&mkv_path, &parse_config, format);
+            } else {
+                let max_scan_bytes = if count_blocks {
+                    Some(max_scan_bytes)
+                } else {
+                    None
+                };
+                list_tracks(&mkv_path, &parse_config, chapters, max_scan_bytes)?;
+            }
         }
         Commands::List {
             file_type,
             input_path,
+            verbose,
+            check_language,
         } => match file_type {
             FileType::Mkv => {
-                list_mkv_subtitles(&input_path, num_subtitles, track_number)?;
+                if verbose {
+                    list_mkv_subtitles_with_metrics(&input_path, &load_options)?;
+                } else {
+                    list_mkv_subtitles(&input_path, &load_options, check_language)?;
+                }
             }
             FileType::Srt => {
-                list_srt_subtitles(&input_path, num_subtitles)?;
+                list_srt_subtitles(&input_path, &reference_options)?;
+            }
+            FileType::Idx => {
+                list_idx_subtitles(&input_path, &load_options)?;
             }
         },
+        Commands::Stats { mkv_path } => {
+            print_subtitle_stats(&mkv_path, &load_options)?;
+        }
         Commands::Dump {
             dump_type,
             mkv_path,
             output_path,
+            name_pattern,
+            verbose,
+            default_duration,
+            overwrite,
         } => match dump_type {
             DumpType::Png => {
                 dump_subtitle_images(
@@ -66,6 +176,13 @@ fn main() -> Result<()> {
                     &output_path,
                     num_subtitles,
                     track_number,
+                    track_name.as_deref(),
+                    &ImageDumpOptions {
+                        name_pattern: name_pattern.as_ref(),
+                        verbose,
+                        vob_palette_format: load_options.vob_palette_format,
+                        parse_config: &parse_config,
+                    },
                 )?;
             }
             DumpType::Bgra8 => {
@@ -75,47 +192,296 @@ fn main() -> Result<()> {
                     &output_path,
                     num_subtitles,
                     track_number,
+                    track_name.as_deref(),
+                    &ImageDumpOptions {
+                        name_pattern: name_pattern.as_ref(),
+                        verbose,
+                        vob_palette_format: load_options.vob_palette_format,
+                        parse_config: &parse_config,
+                    },
                 )?;
             }
-            DumpType::Block => {
-                dump_subtitle_block_data(&mkv_path, &output_path, num_subtitles, track_number)?
+            DumpType::AnimatedGif => {
+                dump_subtitle_images(
+                    ImageDumpType::AnimatedGif,
+                    &mkv_path,
+                    &output_path,
+                    num_subtitles,
+                    track_number,
+                    track_name.as_deref(),
+                    &ImageDumpOptions {
+                        name_pattern: None,
+                        verbose,
+                        vob_palette_format: load_options.vob_palette_format,
+                        parse_config: &parse_config,
+                    },
+                )?;
             }
+            DumpType::Block => dump_subtitle_block_data(
+                &mkv_path,
+                &output_path,
+                num_subtitles,
+                track_number,
+                track_name.as_deref(),
+                &parse_config,
+            )?,
+            DumpType::Palette => dump_palette(
+                &mkv_path,
+                &output_path,
+                track_number,
+                track_name.as_deref(),
+                load_options.vob_palette_format,
+                &parse_config,
+            )?,
+            DumpType::Idx => dump_idx_data(
+                &mkv_path,
+                &output_path,
+                track_number,
+                track_name.as_deref(),
+                load_options.vob_palette_format,
+                &parse_config,
+            ),
+            DumpType::Srt => dump_srt(&mkv_path, &output_path, &load_options, default_duration)?,
+            DumpType::SrtBatch => dump_srt_batch(
+                &mkv_path,
+                &output_path,
+                &load_options,
+                default_duration,
+                overwrite,
+            )?,
         },
         Commands::Match {
             mkv_path,
             reference_path,
+            manifest,
+            filename_regex,
+            min_subtitles,
+            no_grades,
+            explain,
+            grade_thresholds,
+            sort_by,
+            seed,
+            output_format,
+            output_file,
+            output_rename_script,
+            script_format,
         } => {
+            let grade_thresholds = if no_grades {
+                None
+            } else {
+                Some(&grade_thresholds)
+            };
+            let display_options = MatchDisplayOptions {
+                grade_thresholds,
+                sort_by,
+                seed,
+                explain,
+                output_format,
+                output_file: output_file.as_deref(),
+                rename_script_file: output_rename_script.as_deref(),
+                script_format,
+            };
+            if let Some(manifest) = manifest {
+                audit_manifest(
+                    &manifest,
+                    &load_options,
+                    &reference_options,
+                    distance_options,
+                    max_distance,
+                    &display_options,
+                )?;
+                return Ok(());
+            }
+            let filename_regex = filename_regex.map(|pattern| {
+                Regex::new(&pattern).expect(&format!("Invalid filename regex: \"{}\"", pattern))
+            });
             match_subtitles(
+                &mkv_path.unwrap(),
+                &reference_path.unwrap(),
+                &load_options,
+                &reference_options,
+                distance_options,
+                &MatchOptions {
+                    max_distance,
+                    filename_regex: filename_regex.as_ref(),
+                    min_subtitles,
+                },
+                &display_options,
+            )?;
+        }
+        Commands::Diff {
+            mkv_path,
+            reference_path,
+            output_file,
+        } => {
+            diff_subtitles(
                 &mkv_path,
                 &reference_path,
-                num_subtitles,
-                track_number,
-                max_distance,
+                &load_options,
+                &reference_options,
+                distance_options,
+                output_file.as_deref(),
             )?;
         }
+        Commands::OcrFile {
+            image_path,
+            width,
+            height,
+        } => {
+            let size = width.zip(height);
+            ocr_image_file(&image_path, size, &load_options)?;
+        }
     }
 
     Ok(())
 }
 
-fn list_tracks(mkv_path: &str) -> Result<()> {
-    let file = File::open(mkv_path).unwrap();
-    let mkv = MkvFile::new(file);
+// Blocks are only counted if count_blocks is set, with its value
+// limiting how much of the file is scanned.
+fn list_tracks(
+    mkv_path: &str,
+    parse_config: &ParseConfig,
+    show_chapters: bool,
+    count_blocks: Option<Option<u64>>,
+) -> Result<()> {
+    let mkv = MkvFile::from_path_with_config(mkv_path, parse_config)
+        .unwrap_or_else(|error| panic!("Could not open \"{}\": {}", mkv_path, error));
+    let tracks = mkv.tracks().clone();
+    let chapters = mkv.chapters().clone();
+    let block_counts = count_blocks
+        .map(|max_scan_bytes| mkv.count_blocks(max_scan_bytes))
+        .transpose()?;
+    if let Some(block_counts) = &block_counts {
+        if !block_counts.complete {
+            println!("Stopped counting blocks early, counts are incomplete.");
+        }
+    }
     println!("Found subtitle tracks:");
-    for track_info in mkv.tracks() {
+    for track_info in &tracks {
+        let block_count = block_counts
+            .as_ref()
+            .map(|block_counts| {
+                let count = block_counts
+                    .counts
+                    .get(&track_info.track_number)
+                    .copied()
+                    .unwrap_or(0);
+                format!(" - {} blocks", count)
+            })
+            .unwrap_or_default();
         println!(
-            "  {} - {} ({})",
+            "  {} - {} ({}){}{}{}{}",
             track_info.track_number,
             track_info.language.to_string(),
-            track_info.encoding.to_string()
+            track_info.encoding.description(),
+            if track_info.is_forced {
+                " [Forced]"
+            } else {
+                ""
+            },
+            if let Some(name) = &track_info.name {
+                format!(" \"{}\"", name)
+            } else {
+                String::new()
+            },
+            if let Some(data) = track_info.codec_private_bytes() {
+                format!(" (private data: {} bytes)", data.len())
+            } else {
+                String::new()
+            },
+            block_count
         );
     }
+    if show_chapters {
+        println!("Found chapters:");
+        for (i, chapter) in chapters.iter().enumerate() {
+            let end = if chapter.end_ms == u64::MAX {
+                "end".to_owned()
+            } else {
+                srt::format_srt_timestamp(chapter.end_ms)
+            };
+            println!(
+                "  {} - {} to {} ({})",
+                i + 1,
+                srt::format_srt_timestamp(chapter.start_ms),
+                end,
+                chapter.title.as_deref().unwrap_or("Untitled")
+            );
+        }
+    }
     Ok(())
 }
 
+fn list_tracks_formatted(mkv_path: &str, parse_config: &ParseConfig, format: TrackListFormat) {
+    let mkv = MkvFile::from_path_with_config(mkv_path, parse_config)
+        .unwrap_or_else(|error| panic!("Could not open \"{}\": {}", mkv_path, error));
+    match format {
+        TrackListFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(mkv.tracks()).unwrap());
+        }
+        TrackListFormat::Csv => {
+            println!("{}", TrackInfo::FIELD_NAMES.join(","));
+            for track_info in mkv.tracks() {
+                println!(
+                    "{},{},{},{},{},{}",
+                    track_info.track_number,
+                    escape_csv_field(track_info.language.to_string()),
+                    track_info.encoding_name(),
+                    track_info.is_default,
+                    track_info.is_forced,
+                    escape_csv_field(&track_info.codec_id)
+                );
+            }
+        }
+    }
+}
+
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
 enum ImageDumpType {
     Png,
     Raw,
+    AnimatedGif,
+}
+
+struct ImageDumpOptions<'a> {
+    name_pattern: Option<&'a NamePattern>,
+    verbose: bool,
+    vob_palette_format: Option<PaletteFormat>,
+    parse_config: &'a ParseConfig,
+}
+
+// Pixels more transparent than this are treated as background
+const MIN_GLYPH_ALPHA: u8 = 128;
+
+// GIF frame delays are in units of 10ms. The default is used for the
+// last frame, which has no following subtitle to measure against.
+const DEFAULT_GIF_FRAME_DELAY: u16 = 100;
+// Keeps the size of the animated GIF reasonable
+const MAX_GIF_FRAME_WIDTH: u32 = 960;
+const MAX_GIF_FRAME_HEIGHT: u32 = 540;
+
+const DEFAULT_PNG_NAME_PATTERN: &str = "{index}.png";
+const DEFAULT_RAW_NAME_PATTERN: &str = "{index}size{width}x{height}.bin";
+// Used when we know where the subtitle is placed (VobSub)
+const DEFAULT_POSITIONED_PNG_NAME_PATTERN: &str = "{index}at{x}x{y}.png";
+const DEFAULT_POSITIONED_RAW_NAME_PATTERN: &str = "{index}size{width}x{height}at{x}x{y}.bin";
+
+// The track picked on the command line, or the first English one
+fn find_english_track<'a>(
+    mkv: &'a MkvFile<File>,
+    track_number: Option<u64>,
+    track_name: Option<&str>,
+) -> Option<&'a TrackInfo> {
+    mkv.find_track(track_number, track_name, |tracks| {
+        select_track(tracks, &KnownLanguage::English, TrackPreference::FirstFound)
+    })
 }
 
 fn dump_subtitle_images(
@@ -124,39 +490,57 @@ fn dump_subtitle_images(
     output_path: &str,
     num_subtitles: usize,
     track_number: Option<u64>,
+    track_name: Option<&str>,
+    options: &ImageDumpOptions,
 ) -> Result<()> {
-    let file = File::open(mkv_path).expect(&format!("Could not read from \"{}\"", mkv_path));
-    let mkv = MkvFile::new(file);
-    let iter = if let Some(track_number) = track_number {
-        mkv.subtitle_iter_from_track_number(track_number)?
-    } else {
-        mkv.subtitle_iter(KnownLanguage::English)?
+    let mut mkv = MkvFile::from_path_with_config(mkv_path, options.parse_config)
+        .unwrap_or_else(|error| panic!("Could not open \"{}\": {}", mkv_path, error));
+    if let Some(palette_format) = options.vob_palette_format {
+        mkv.set_vob_palette_format(palette_format)?;
+    }
+    let track_number = match find_english_track(&mkv, track_number, track_name) {
+        Some(track_info) => track_info.track_number,
+        None => {
+            println!("No matching subtitle track found!");
+            return Ok(());
+        }
     };
-    if let Some(iter) = iter {
-        let path = Path::new(output_path).canonicalize().unwrap();
-        let path = path.to_str().unwrap();
-        let path = path.replace("\\\\?\\", "");
-        let path = if path.starts_with("UNC") {
-            path.replacen("UNC", "\\", 1)
-        } else {
-            path
-        };
-        let folder = StorageFolder::GetFolderFromPathAsync(path)?.get()?;
-        for (i, bitmap) in iter.enumerate() {
+    if let Some(mut iter) = mkv.subtitle_iter_from_track_number(track_number)? {
+        let folder = open_output_folder(output_path)?;
+        let mut gif_frames = Vec::new();
+        let mut i = 0;
+        while let Some(frame) = iter.next() {
+            let SubtitleFrame { bitmap, region } = frame?;
+            let name_pattern = if let Some(name_pattern) = options.name_pattern {
+                name_pattern.clone()
+            } else {
+                let pattern = match (&dump_type, region.is_some()) {
+                    (ImageDumpType::Raw, false) => DEFAULT_RAW_NAME_PATTERN,
+                    (ImageDumpType::Raw, true) => DEFAULT_POSITIONED_RAW_NAME_PATTERN,
+                    (_, false) => DEFAULT_PNG_NAME_PATTERN,
+                    (_, true) => DEFAULT_POSITIONED_PNG_NAME_PATTERN,
+                };
+                NamePattern(pattern.to_owned())
+            };
+            let file_name = name_pattern.format(&NamePatternValues {
+                index: i,
+                track: iter.track_number(),
+                timestamp_ms: iter.last_timestamp_ms().unwrap_or(0),
+                width: bitmap.PixelWidth()? as u32,
+                height: bitmap.PixelHeight()? as u32,
+                x: region.map(|region| region.x as u32).unwrap_or(0),
+                y: region.map(|region| region.y as u32).unwrap_or(0),
+            });
+            if options.verbose {
+                let color = average_color(&bitmap, MIN_GLYPH_ALPHA)?;
+                println!(
+                    "{}: average glyph color #{:02X}{:02X}{:02X}",
+                    file_name, color.R, color.G, color.B
+                );
+            }
             match dump_type {
                 ImageDumpType::Png => {
-                    let file = folder
-                        .CreateFileAsync(
-                            format!("{}.png", i),
-                            CreationCollisionOption::ReplaceExisting,
-                        )?
-                        .get()?;
-                    let stream = file.OpenAsync(FileAccessMode::ReadWrite)?.get()?;
-                    let encoder =
-                        BitmapEncoder::CreateAsync(BitmapEncoder::PngEncoderId()?, stream)?
-                            .get()?;
-                    encoder.SetSoftwareBitmap(bitmap)?;
-                    encoder.FlushAsync()?.get()?;
+                    write_png(&folder, &file_name, &bitmap)?;
                 }
                 ImageDumpType::Raw => {
                     let width = bitmap.PixelWidth()?;
@@ -167,85 +551,1148 @@ fn dump_subtitle_images(
                     let bitmap_size = (width * height * bytes_per_pixel) as u32;
                     let buffer = Buffer::Create(bitmap_size)?;
                     bitmap.CopyToBuffer(&buffer)?;
-                    let file = folder
-                        .CreateFileAsync(
-                            format!("{}size{}x{}.bin", i, width, height),
-                            CreationCollisionOption::ReplaceExisting,
-                        )?
-                        .get()?;
+                    let file = create_output_file(&folder, &file_name)?;
                     FileIO::WriteBufferAsync(file, buffer)?.get()?;
                 }
+                ImageDumpType::AnimatedGif => {
+                    // GIFs don't have an alpha channel, so composite over black first
+                    blend_with_color(
+                        &bitmap,
+                        &Color {
+                            R: 0,
+                            G: 0,
+                            B: 0,
+                            A: 255,
+                        },
+                    )?;
+                    let bitmap =
+                        scale_image_to_fit(&bitmap, MAX_GIF_FRAME_WIDTH, MAX_GIF_FRAME_HEIGHT)?;
+                    let width = bitmap.PixelWidth()?;
+                    let height = bitmap.PixelHeight()?;
+                    let rgb_bytes = to_rgb24(&bitmap)?;
+                    gif_frames.push(GifFrame {
+                        width: width as u16,
+                        height: height as u16,
+                        rgb_bytes,
+                        timestamp_ms: iter.last_timestamp_ms(),
+                    });
+                }
             }
 
             if i >= num_subtitles {
                 break;
             }
+            i += 1;
+        }
+
+        if !gif_frames.is_empty() {
+            let stem = Path::new(mkv_path).file_stem().unwrap().to_str().unwrap();
+            let mut gif_path = Path::new(output_path).to_owned();
+            gif_path.push(format!("{}.gif", stem));
+            write_animated_gif(&gif_path, &gif_frames);
         }
     } else {
-        println!("No English subtitles found!");
+        println!("Track {} is not a PGS or VOB subtitle track!", track_number);
+    }
+    Ok(())
+}
+
+fn ocr_image_file(
+    image_path: &str,
+    size: Option<(u32, u32)>,
+    load_options: &LoadOptions,
+) -> Result<()> {
+    let path = Path::new(image_path);
+    let is_png = path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("png"))
+        .unwrap_or(false);
+    let bitmap = if is_png {
+        load_png_as_bitmap(path)?
+    } else {
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let (width, height) = size
+            .or_else(|| parse_raw_image_size(&file_name))
+            .unwrap_or_else(|| {
+                panic!(
+                    "Could not determine the size of \"{}\", use --width and --height",
+                    image_path
+                )
+            });
+        let bytes = std::fs::read(path)
+            .unwrap_or_else(|error| panic!("Could not read \"{}\": {}", image_path, error));
+        let expected_len = (width * height * 4) as usize;
+        if bytes.len() != expected_len {
+            panic!(
+                "Expected {} bytes for a {}x{} bgra8 image, but \"{}\" is {} bytes",
+                expected_len,
+                width,
+                height,
+                image_path,
+                bytes.len()
+            );
+        }
+        load_bgra8_as_bitmap(&bytes, width, height)?
+    };
+    match ocr_bitmap(&bitmap, load_options, KnownLanguage::English)? {
+        OcrText::Text(text) => println!("{}", text),
+        OcrText::Empty => println!("(no text)"),
+        OcrText::Filtered(word) => println!("(filtered: {})", word),
+    }
+    Ok(())
+}
+
+lazy_static! {
+    // Matches the size in the default bgra8 dump file names, e.g. "3size640x80.bin"
+    static ref RAW_IMAGE_SIZE_REGEX: Regex = Regex::new(r"size(\d+)x(\d+)").unwrap();
+}
+
+fn parse_raw_image_size(file_name: &str) -> Option<(u32, u32)> {
+    let captures = RAW_IMAGE_SIZE_REGEX.captures(file_name)?;
+    let width = captures[1].parse().ok()?;
+    let height = captures[2].parse().ok()?;
+    Some((width, height))
+}
+
+fn open_output_folder(output_path: &str) -> Result<StorageFolder> {
+    // GetFolderFromPathAsync fails with a generic "path not found"
+    // error, which doesn't say which path it was looking for.
+    let output_path = Path::new(output_path);
+    if !output_path.is_dir() {
+        return Err(ShowOrderError::OutputDirectoryNotFound(output_path.to_owned()).into());
     }
+    let path = to_winrt_path(output_path);
+    StorageFolder::GetFolderFromPathAsync(path)?.get()
+}
+
+fn create_output_file(folder: &StorageFolder, file_name: &str) -> Result<StorageFile> {
+    folder
+        .CreateFileAsync(file_name, CreationCollisionOption::ReplaceExisting)
+        .and_then(|operation| operation.get())
+        .or_else(|error| {
+            let path = Path::new(&folder.Path()?.to_string()).join(file_name);
+            let message = format!(
+                "Could not create \"{}\": {}",
+                path.display(),
+                error.message()
+            );
+            Err(windows::core::Error::new(error.code(), message.into()))
+        })
+}
+
+fn write_png(folder: &StorageFolder, file_name: &str, bitmap: &SoftwareBitmap) -> Result<()> {
+    let file = create_output_file(folder, file_name)?;
+    let stream = file.OpenAsync(FileAccessMode::ReadWrite)?.get()?;
+    let encoder = BitmapEncoder::CreateAsync(BitmapEncoder::PngEncoderId()?, stream)?.get()?;
+    encoder.SetSoftwareBitmap(bitmap)?;
+    encoder.FlushAsync()?.get()?;
     Ok(())
 }
 
+struct GifFrame {
+    width: u16,
+    height: u16,
+    rgb_bytes: Vec<u8>,
+    timestamp_ms: Option<u64>,
+}
+
+// Each frame is shown until the next subtitle starts
+fn gif_frame_delays(timestamps: &[Option<u64>]) -> Vec<u16> {
+    let mut delays = Vec::with_capacity(timestamps.len());
+    for (i, timestamp) in timestamps.iter().enumerate() {
+        let next = timestamps.get(i + 1).copied().flatten();
+        let delay = match (timestamp, next) {
+            (Some(timestamp), Some(next)) if next > *timestamp => {
+                ((next - timestamp) / 10).clamp(1, u16::MAX as u64) as u16
+            }
+            _ => DEFAULT_GIF_FRAME_DELAY,
+        };
+        delays.push(delay);
+    }
+    delays
+}
+
+fn write_animated_gif(path: &Path, frames: &[GifFrame]) {
+    // The logical screen needs to be big enough to fit every frame
+    let width = frames.iter().map(|frame| frame.width).max().unwrap();
+    let height = frames.iter().map(|frame| frame.height).max().unwrap();
+    let timestamps: Vec<_> = frames.iter().map(|frame| frame.timestamp_ms).collect();
+    let delays = gif_frame_delays(&timestamps);
+
+    let file = File::create(path).expect(&format!("Could not create \"{}\"", path.display()));
+    let mut encoder = gif::Encoder::new(file, width, height, &[]).unwrap();
+    encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+    for (gif_frame, delay) in frames.iter().zip(delays) {
+        let mut frame =
+            gif::Frame::from_rgb_speed(gif_frame.width, gif_frame.height, &gif_frame.rgb_bytes, 10);
+        frame.delay = delay;
+        frame.dispose = gif::DisposalMethod::Background;
+        encoder.write_frame(&frame).unwrap();
+    }
+}
+
 fn dump_subtitle_block_data(
     mkv_path: &str,
     output_path: &str,
     num_subtitles: usize,
     track_number: Option<u64>,
+    track_name: Option<&str>,
+    parse_config: &ParseConfig,
 ) -> Result<()> {
-    let file = File::open(mkv_path).expect(&format!("Could not read from \"{}\"", mkv_path));
-    let mkv = MkvFile::new(file);
-    let iter = if let Some(track_number) = track_number {
-        mkv.block_iter_from_track_number(track_number)
-    } else {
-        mkv.block_iter(KnownLanguage::English)
+    let mkv = MkvFile::from_path_with_config(mkv_path, parse_config)
+        .unwrap_or_else(|error| panic!("Could not open \"{}\": {}", mkv_path, error));
+    let track_number = match find_english_track(&mkv, track_number, track_name) {
+        Some(track_info) => track_info.track_number,
+        None => {
+            println!("No matching subtitle track found!");
+            return Ok(());
+        }
     };
-    if let Some(iter) = iter {
+    if let Some(iter) = mkv.block_iter_from_track_number(track_number) {
         let mut path = Path::new(output_path).to_owned();
         path.push("something");
-        for (i, block) in iter.enumerate() {
+        let mut num_written = 0;
+        for (i, payload) in iter.into_payload_iter().enumerate() {
+            let payload = payload?;
             path.set_file_name(&format!("{}.bin", i));
-            std::fs::write(&path, &block.payload).unwrap();
+            std::fs::write(&path, &payload).unwrap();
+            num_written += 1;
             if i >= num_subtitles {
                 break;
             }
         }
+        println!("Wrote {} blocks.", num_written);
+    }
+    Ok(())
+}
+
+// Writes the OCR'd subtitles as {stem}.srt. The blocks don't give us
+// a duration, so every subtitle is shown for default_duration_ms.
+fn dump_srt(
+    mkv_path: &str,
+    output_path: &str,
+    load_options: &LoadOptions,
+    default_duration_ms: u64,
+) -> Result<()> {
+    let path = srt_output_path(mkv_path, output_path);
+    if let Some(num_written) =
+        write_srt_for_mkv(mkv_path, &path, load_options, default_duration_ms)?
+    {
+        println!("Wrote {} subtitles to \"{}\".", num_written, path.display());
     } else {
         println!("No English subtitles found!");
     }
     Ok(())
 }
 
-fn list_mkv_subtitles(
+fn srt_output_path<P: AsRef<Path>>(mkv_path: P, output_path: &str) -> PathBuf {
+    let stem = mkv_path.as_ref().file_stem().unwrap().to_string_lossy();
+    Path::new(output_path).join(format!("{}.srt", stem))
+}
+
+// Returns the number of subtitles written, or None if the file
+// doesn't have any English subtitles.
+fn write_srt_for_mkv<P: AsRef<Path>>(
+    mkv_path: P,
+    path: &Path,
+    load_options: &LoadOptions,
+    default_duration_ms: u64,
+) -> Result<Option<usize>> {
+    let subtitles =
+        load_first_n_subtitles_with_timestamps(mkv_path, load_options, KnownLanguage::English)?;
+    if let Some(subtitles) = subtitles {
+        let entries = subtitles
+            .into_iter()
+            .map(|(start_ms, text)| srt::SrtEntry {
+                start_ms,
+                end_ms: start_ms + default_duration_ms,
+                text,
+            })
+            .collect::<Vec<_>>();
+        let file = File::create(path).map_err(ShowOrderError::from)?;
+        srt::write_srt(file, &entries).map_err(ShowOrderError::from)?;
+        Ok(Some(entries.len()))
+    } else {
+        Ok(None)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum BatchOutcome {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+// Writes {stem}.srt for every mkv file in input_path. Files that can't
+// be exported are reported in the summary rather than stopping the batch.
+fn dump_srt_batch(
+    input_path: &str,
+    output_path: &str,
+    load_options: &LoadOptions,
+    default_duration_ms: u64,
+    overwrite: bool,
+) -> Result<()> {
+    let input_path = Path::new(input_path);
+    if !input_path.is_dir() {
+        return Err(ShowOrderError::InputDirectoryNotFound(input_path.to_owned()).into());
+    }
+    std::fs::create_dir_all(output_path).map_err(|error| {
+        ShowOrderError::Io(std::io::Error::new(
+            error.kind(),
+            format!("Could not create \"{}\": {}", output_path, error),
+        ))
+    })?;
+    let mut paths = std::fs::read_dir(input_path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .map_err(ShowOrderError::from)?;
+    paths.retain(|p| p.extension().map(|ext| ext == "mkv").unwrap_or(false));
+    paths.sort();
+
+    println!("Exporting subtitles from {} files...", paths.len());
+    let outcomes = paths
+        .par_iter()
+        .map(|mkv_path| {
+            export_srt_for_batch(
+                mkv_path,
+                output_path,
+                load_options,
+                default_duration_ms,
+                overwrite,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut num_succeeded = 0;
+    let mut num_failed = 0;
+    let mut num_skipped = 0;
+    for outcome in outcomes {
+        match outcome {
+            BatchOutcome::Succeeded => num_succeeded += 1,
+            BatchOutcome::Failed => num_failed += 1,
+            BatchOutcome::Skipped => num_skipped += 1,
+        }
+    }
+    println!(
+        "{} succeeded, {} failed, {} skipped.",
+        num_succeeded, num_failed, num_skipped
+    );
+    Ok(())
+}
+
+// Files without English subtitles are skipped, like files that already
+// have an srt unless overwrite is set. Files that can't be read are failed.
+fn export_srt_for_batch(
+    mkv_path: &Path,
+    output_path: &str,
+    load_options: &LoadOptions,
+    default_duration_ms: u64,
+    overwrite: bool,
+) -> BatchOutcome {
+    let path = srt_output_path(mkv_path, output_path);
+    if path.exists() && !overwrite {
+        println!(
+            "Skipping \"{}\", \"{}\" already exists.",
+            mkv_path.display(),
+            path.display()
+        );
+        return BatchOutcome::Skipped;
+    }
+    match write_srt_for_mkv(mkv_path, &path, load_options, default_duration_ms) {
+        Ok(Some(num_written)) => {
+            println!("Wrote {} subtitles to \"{}\".", num_written, path.display());
+            BatchOutcome::Succeeded
+        }
+        Ok(None) => {
+            println!(
+                "Warning! No English subtitles found in \"{}\". Skipping file...",
+                mkv_path.display()
+            );
+            BatchOutcome::Skipped
+        }
+        Err(error) => {
+            println!(
+                "Warning! Could not export subtitles from \"{}\": {}",
+                mkv_path.display(),
+                error
+            );
+            BatchOutcome::Failed
+        }
+    }
+}
+
+fn dump_palette(
     mkv_path: &str,
-    num_subtitles: usize,
+    output_path: &str,
     track_number: Option<u64>,
+    track_name: Option<&str>,
+    vob_palette_format: Option<PaletteFormat>,
+    parse_config: &ParseConfig,
 ) -> Result<()> {
+    let mut mkv = MkvFile::from_path_with_config(mkv_path, parse_config)
+        .unwrap_or_else(|error| panic!("Could not open \"{}\": {}", mkv_path, error));
+    if let Some(palette_format) = vob_palette_format {
+        mkv.set_vob_palette_format(palette_format)?;
+    }
+    let track_info = mkv.find_track(track_number, track_name, |tracks| {
+        tracks.iter().find(|track_info| {
+            track_info.language == KnownLanguage::English
+                && matches!(
+                    track_info.encoding,
+                    KnownEncoding::PGS | KnownEncoding::VOB { .. }
+                )
+        })
+    });
+    let track_number = match track_info {
+        Some(track_info) if track_info.encoding == KnownEncoding::PGS => track_info.track_number,
+        // VobSub tracks only have the one palette, from their idx data
+        Some(TrackInfo {
+            track_number,
+            encoding: KnownEncoding::VOB { palette, .. },
+            ..
+        }) => {
+            let folder = open_output_folder(output_path)?;
+            let file_name = format!("{}_palette.png", track_number);
+            write_png(&folder, &file_name, &pgs::render_colors_swatch(palette)?)?;
+            println!("Wrote \"{}\".", file_name);
+            return Ok(());
+        }
+        Some(track_info) => {
+            println!(
+                "Track {} is not a PGS or VOB subtitle track ({})!",
+                track_info.track_number,
+                track_info.encoding.to_string()
+            );
+            return Ok(());
+        }
+        None => {
+            println!("No matching PGS or VOB subtitle track found!");
+            return Ok(());
+        }
+    };
+
+    if let Some(iter) = mkv.block_iter_from_track_number(track_number) {
+        for (i, payload) in iter.into_payload_iter().enumerate() {
+            if let Some(swatch) = pgs::parse_palette_swatch(&payload?)? {
+                let folder = open_output_folder(output_path)?;
+                let file_name = format!("{}_{}_palette.png", track_number, i);
+                write_png(&folder, &file_name, &swatch)?;
+                println!("Wrote \"{}\".", file_name);
+                return Ok(());
+            }
+        }
+    }
+    println!("No palette found in track {}!", track_number);
+    Ok(())
+}
+
+// If a palette format is given, the palette is written as RGB so that
+// other tools can read it. Otherwise the idx data is written as is.
+fn dump_idx_data(
+    mkv_path: &str,
+    output_path: &str,
+    track_number: Option<u64>,
+    track_name: Option<&str>,
+    vob_palette_format: Option<PaletteFormat>,
+    parse_config: &ParseConfig,
+) {
+    let mut mkv = MkvFile::from_path_with_config(mkv_path, parse_config)
+        .unwrap_or_else(|error| panic!("Could not open \"{}\": {}", mkv_path, error));
+    if let Some(palette_format) = vob_palette_format {
+        mkv.set_vob_palette_format(palette_format)
+            .unwrap_or_else(|error| panic!("Could not read \"{}\": {}", mkv_path, error));
+    }
+    let track_info = mkv.find_track(track_number, track_name, |tracks| {
+        tracks.iter().find(|track_info| {
+            track_info.language == KnownLanguage::English
+                && matches!(track_info.encoding, KnownEncoding::VOB { .. })
+        })
+    });
+    let track_info = if let Some(track_info) = track_info {
+        track_info
+    } else {
+        println!("No matching VOB subtitle track found!");
+        return;
+    };
+    match (&track_info.encoding, track_info.codec_private_bytes()) {
+        (KnownEncoding::VOB { palette, .. }, Some(idx)) if !idx.is_empty() => {
+            let file_stem = Path::new(mkv_path).file_stem().unwrap();
+            let mut path = Path::new(output_path).join(file_stem);
+            path.set_extension("idx");
+            let idx = if vob_palette_format.is_some() {
+                vob::replace_idx_palette(idx, palette).into_bytes()
+            } else {
+                idx.to_vec()
+            };
+            std::fs::write(&path, idx).unwrap_or_else(|error| {
+                panic!("Could not write \"{}\": {}", path.display(), error)
+            });
+            println!("Wrote \"{}\".", path.display());
+        }
+        (KnownEncoding::VOB { .. }, _) => {
+            println!(
+                "Track {} doesn't have any idx data!",
+                track_info.track_number
+            );
+        }
+        _ => {
+            println!(
+                "Track {} is not a VOB subtitle track ({})!",
+                track_info.track_number,
+                track_info.encoding.to_string()
+            );
+        }
+    }
+}
+
+fn list_mkv_subtitles(
+    mkv_path: &str,
+    load_options: &LoadOptions,
+    check_language: bool,
+) -> Result<()> {
+    // Collect subtitles from the file(s)
+    println!("Loading subtitles from mkv files...");
+    let (files, encodings) =
+        process_input_path_with_encodings(&mkv_path, load_options, should_show_progress())?;
+    print_subtitles(&files, Some(&encodings));
+    if check_language {
+        for (file, subtitles) in &files {
+            check_subtitle_language(file, subtitles);
+        }
+    }
+    Ok(())
+}
+
+fn list_idx_subtitles(idx_path: &str, load_options: &LoadOptions) -> Result<()> {
     // Collect subtitles from the file(s)
+    println!("Loading subtitles from idx files...");
+    let files =
+        process_input_path_with_extension(&idx_path, "idx", should_show_progress(), |path| {
+            load_first_n_idx_subtitles(path, load_options)
+        })?;
+    print_subtitles(&files, None);
+    Ok(())
+}
+
+// We only OCR English subtitles, so anything else suggests that
+// the wrong track was picked.
+fn check_subtitle_language(file: &str, subtitles: &[String]) {
+    let file_name = Path::new(file).file_name().unwrap().to_string_lossy();
+    let stats = analyze_alphabet(&subtitles.join(" "));
+    if stats.is_mostly_whitespace() {
+        println!(
+            "Warning! The subtitles of \"{}\" are mostly whitespace. The images may be too low quality to OCR.",
+            file_name
+        );
+    } else if stats.latin_fraction < MIN_ENGLISH_LATIN_FRACTION {
+        println!(
+            "Warning! Only {:.0}% of the letters in the subtitles of \"{}\" are Latin letters. The track may not be English, try picking another with --track-number or --track-name.",
+            stats.latin_fraction * 100.0,
+            file_name
+        );
+    }
+}
+
+fn list_mkv_subtitles_with_metrics(mkv_path: &str, load_options: &LoadOptions) -> Result<()> {
     println!("Loading subtitles from mkv files...");
-    let files = process_input_path(&mkv_path, num_subtitles, track_number)?;
-    print_subtitles(&files);
+    let files = process_input_path_with(&mkv_path, should_show_progress(), |path| {
+        load_first_n_subtitles_with_metrics(path, load_options, KnownLanguage::English)
+    })?;
+    for (file, subtitles) in files {
+        let path = Path::new(&file);
+        println!("{}:", path.file_name().unwrap().to_string_lossy());
+        for (i, (subtitle, metrics, _)) in subtitles.iter().enumerate() {
+            match subtitle {
+                OcrText::Text(subtitle) => println!("  [{}] \"{}\"", i, subtitle),
+                OcrText::Empty => println!("  [{}] (no text)", i),
+                OcrText::Filtered(word) => println!("  [{}] (filtered: {})", i, word),
+            }
+            let color = metrics.dominant_color;
+            println!(
+                "      edge density: {:.3}, contrast ratio: {:.2}, sharpness: {:.1}, non-transparent: {:.3}, dominant color: #{:02X}{:02X}{:02X}{:02X}{}",
+                metrics.edge_density,
+                metrics.contrast_ratio,
+                metrics.sharpness,
+                metrics.non_transparent_fraction,
+                color.A,
+                color.R,
+                color.G,
+                color.B,
+                if metrics.is_likely_readable() {
+                    ""
+                } else {
+                    " (low quality)"
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+// The last bucket holds everything longer than the others
+const HISTOGRAM_BUCKET_SIZE: usize = 10;
+const HISTOGRAM_BUCKETS: usize = 11;
+const HISTOGRAM_BAR_WIDTH: usize = 40;
+
+fn print_subtitle_stats(mkv_path: &str, load_options: &LoadOptions) -> Result<()> {
+    println!("Loading subtitles from mkv files...");
+    let files = process_input_path_with(&mkv_path, should_show_progress(), |path| {
+        load_first_n_subtitles_with_metrics(path, load_options, KnownLanguage::English)
+    })?;
+    for (file, subtitles) in files {
+        let path = Path::new(&file);
+        let lengths = subtitles
+            .iter()
+            .map(|(text, _, _)| match text {
+                OcrText::Text(text) => text.chars().count(),
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        let num_empty = lengths.iter().filter(|length| **length == 0).count();
+        println!("{}:", path.file_name().unwrap().to_string_lossy());
+        println!("  {} subtitles, {} without text", lengths.len(), num_empty);
+        if !subtitles.is_empty() {
+            let total_sharpness = subtitles
+                .iter()
+                .map(|(_, metrics, _)| metrics.sharpness)
+                .sum::<f32>();
+            println!(
+                "  Average sharpness: {:.1}",
+                total_sharpness / subtitles.len() as f32
+            );
+        }
+        println!("  Text lengths:");
+        print_histogram(&text_length_histogram(&lengths));
+        let regions = subtitles
+            .iter()
+            .filter_map(|(_, _, region)| *region)
+            .collect::<Vec<_>>();
+        if let Some((typical, bounds)) = summarize_regions(&regions) {
+            println!(
+                "  Typical region: {}x{} at {},{}",
+                typical.width, typical.height, typical.x, typical.y
+            );
+            println!(
+                "  All subtitles within: {}x{} at {},{}",
+                bounds.width, bounds.height, bounds.x, bounds.y
+            );
+        }
+    }
     Ok(())
 }
 
-fn list_srt_subtitles(srt_path: &str, num_subtitles: usize) -> Result<()> {
+// Returns the median region (each component taken separately)
+// and the smallest region that contains all of them.
+fn summarize_regions(regions: &[SubtitleRegion]) -> Option<(SubtitleRegion, SubtitleRegion)> {
+    if regions.is_empty() {
+        return None;
+    }
+    let median = |component: fn(&SubtitleRegion) -> u16| {
+        let mut values = regions.iter().map(component).collect::<Vec<_>>();
+        values.sort_unstable();
+        values[values.len() / 2]
+    };
+    let typical = SubtitleRegion {
+        x: median(|region| region.x),
+        y: median(|region| region.y),
+        width: median(|region| region.width),
+        height: median(|region| region.height),
+    };
+    let min_x = regions.iter().map(|region| region.x).min().unwrap();
+    let min_y = regions.iter().map(|region| region.y).min().unwrap();
+    let max_x = regions
+        .iter()
+        .map(|region| region.x + region.width)
+        .max()
+        .unwrap();
+    let max_y = regions
+        .iter()
+        .map(|region| region.y + region.height)
+        .max()
+        .unwrap();
+    let bounds = SubtitleRegion {
+        x: min_x,
+        y: min_y,
+        width: max_x - min_x,
+        height: max_y - min_y,
+    };
+    Some((typical, bounds))
+}
+
+// Empty text gets its own bucket, as lots of empty subtitles
+// usually means OCR is failing.
+fn text_length_histogram(lengths: &[usize]) -> [usize; HISTOGRAM_BUCKETS] {
+    let mut buckets = [0; HISTOGRAM_BUCKETS];
+    for length in lengths {
+        let bucket = if *length == 0 {
+            0
+        } else {
+            ((length - 1) / HISTOGRAM_BUCKET_SIZE + 1).min(HISTOGRAM_BUCKETS - 1)
+        };
+        buckets[bucket] += 1;
+    }
+    buckets
+}
+
+fn print_histogram(buckets: &[usize]) {
+    let max_count = buckets.iter().copied().max().unwrap_or(0).max(1);
+    for (i, count) in buckets.iter().enumerate() {
+        let label = if i == 0 {
+            "0".to_owned()
+        } else if i == buckets.len() - 1 {
+            format!("{}+", (i - 1) * HISTOGRAM_BUCKET_SIZE + 1)
+        } else {
+            format!(
+                "{}-{}",
+                (i - 1) * HISTOGRAM_BUCKET_SIZE + 1,
+                i * HISTOGRAM_BUCKET_SIZE
+            )
+        };
+        let bar_len = (count * HISTOGRAM_BAR_WIDTH).div_ceil(max_count);
+        println!(
+            "    {:>6} | {:<width$} {}",
+            label,
+            "#".repeat(bar_len),
+            count,
+            width = HISTOGRAM_BAR_WIDTH
+        );
+    }
+}
+
+fn list_srt_subtitles(srt_path: &str, reference_options: &ReferenceOptions) -> Result<()> {
     // Collect subtitles from the file(s)
     println!("Loading subtitles from srt files...");
-    let files = process_reference_path(&srt_path, num_subtitles)?;
-    print_subtitles(&files);
+    let files = process_reference_path(&srt_path, reference_options)?;
+    print_subtitles(&files, None);
     Ok(())
 }
 
+// How the reference srt files are read
+#[derive(Clone, Debug, Default)]
+struct ReferenceOptions {
+    num_subtitles: usize,
+    no_zip: bool,
+    srt: srt::ParseOptions,
+}
+
+// How the distance between two subtitles is computed
+#[derive(Copy, Clone)]
+struct DistanceOptions {
+    metric: Metric,
+    normalization: NormalizationStrategy,
+    // Only used by the levenshtein metric. The inner value is the window
+    // size, which defaults to the length of the shorter subtitle.
+    windowed: Option<Option<usize>>,
+    // Only used by the levenshtein metric, see string::find_best_alignment
+    align: bool,
+}
+
+// How many characters at a time alignment moves through the longer
+// subtitle. Checking every offset is slow for long subtitles, and
+// being a few characters off only adds a few to the distance.
+const ALIGNMENT_STEP: usize = 4;
+
+struct MatchOptions<'a> {
+    max_distance: Option<usize>,
+    filename_regex: Option<&'a Regex>,
+    min_subtitles: usize,
+}
+
+// Options that only change how the results of a match are shown
+struct MatchDisplayOptions<'a> {
+    grade_thresholds: Option<&'a GradeThresholds>,
+    sort_by: SortBy,
+    seed: u64,
+    explain: bool,
+    output_format: MatchOutputFormat,
+    output_file: Option<&'a str>,
+    // A separate file that only gets the rename commands
+    rename_script_file: Option<&'a str>,
+    script_format: ScriptFormat,
+}
+
+// Check to see if we have high confidence the mapping is correct. High confidence means:
+//   * Each reference file is mapped to only 1 other file
+//   * Mkv files can still be unmapped (e.g. extras)
+struct ConfidenceReport {
+    // Reference files that were mapped more than once, along
+    // with each mkv file (and distance) mapped to them.
+    duplicates: Vec<(String, Vec<(String, usize)>)>,
+    num_mapped: usize,
+    num_files: usize,
+    max_distance: Option<usize>,
+}
+
+impl ConfidenceReport {
+    fn new(mappings: &[FileMapping], num_files: usize, max_distance: Option<usize>) -> Self {
+        let mut ref_file_mappings = HashMap::<&str, Vec<(String, usize)>>::new();
+        for mapping in mappings {
+            ref_file_mappings
+                .entry(&mapping.ref_file)
+                .or_default()
+                .push((mapping.mkv_path.clone(), mapping.distance));
+        }
+        let mut duplicates = ref_file_mappings
+            .into_iter()
+            .filter(|(_, mkv_files)| mkv_files.len() > 1)
+            .map(|(ref_file, mkv_files)| (ref_file.to_owned(), mkv_files))
+            .collect::<Vec<_>>();
+        duplicates.sort();
+        Self {
+            duplicates,
+            num_mapped: mappings.len(),
+            num_files,
+            max_distance,
+        }
+    }
+
+    fn is_high_confidence(&self) -> bool {
+        self.duplicates.is_empty()
+    }
+
+    fn explain(&self) -> String {
+        let file_name = |path: &str| {
+            Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        if self.is_high_confidence() {
+            let distances = if let Some(max_distance) = self.max_distance {
+                format!("All distances below threshold ({}).", max_distance)
+            } else {
+                "No distance threshold was set.".to_owned()
+            };
+            format!(
+                "High confidence because: (1) No reference file was matched to multiple MKV files. (2) {} (3) {} of {} MKV files were mapped.",
+                distances, self.num_mapped, self.num_files
+            )
+        } else {
+            let reasons = self
+                .duplicates
+                .iter()
+                .map(|(ref_file, mkv_files)| {
+                    let mkv_files = mkv_files
+                        .iter()
+                        .map(|(mkv_path, distance)| {
+                            format!("{} (distance {})", file_name(mkv_path), distance)
+                        })
+                        .collect::<Vec<_>>();
+                    let mkv_files = match mkv_files.split_last() {
+                        Some((last, rest)) if !rest.is_empty() => {
+                            format!("{} and {}", rest.join(", "), last)
+                        }
+                        _ => mkv_files.join(""),
+                    };
+                    format!("{} was matched to both {}.", file_name(ref_file), mkv_files)
+                })
+                .collect::<Vec<_>>();
+            format!("Low confidence because: {}", reasons.join(" "))
+        }
+    }
+}
+
+struct FileMapping {
+    mkv_path: String,
+    ref_file: String,
+    distance: usize,
+    criterion: MatchCriterion,
+    grade: Option<char>,
+}
+
+// Status messages would corrupt machine-readable output on stdout, so those
+// formats get them on stderr instead
+fn print_status(output_format: MatchOutputFormat, message: &str) {
+    match output_format {
+        MatchOutputFormat::Human => println!("{}", message),
+        _ => eprintln!("{}", message),
+    }
+}
+
 fn match_subtitles(
     mkv_path: &str,
     ref_path: &str,
-    num_subtitles: usize,
-    track_number: Option<u64>,
-    max_distance: Option<usize>,
+    load_options: &LoadOptions,
+    reference_options: &ReferenceOptions,
+    distance_options: DistanceOptions,
+    match_options: &MatchOptions,
+    display_options: &MatchDisplayOptions,
+) -> Result<()> {
+    let metric = distance_options.metric;
+    let normalization = distance_options.normalization;
+    let max_distance = match_options.max_distance;
+    let filename_regex = match_options.filename_regex;
+    let output_format = display_options.output_format;
+
+    // Collect subtitles from the file(s)
+    print_status(output_format, "Loading subtitles from mkv files...");
+    let files = process_input_path(&mkv_path, load_options, should_show_progress())?;
+
+    // Matching on only a few subtitles isn't reliable, so we skip
+    // files that don't have enough of them.
+    let (files, skipped): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|(_, subtitles)| subtitles.len() >= match_options.min_subtitles);
+    let skipped = skipped
+        .into_iter()
+        .map(|(mkv_path, subtitles)| (mkv_path, subtitles.len()))
+        .collect::<Vec<_>>();
+
+    // If we couldn't find any subtitles, exit
+    if files.is_empty() {
+        if skipped.is_empty() {
+            print_status(output_format, "No English subtitles found!");
+        } else {
+            print_status(output_format, "Not enough English subtitles found!");
+            if let MatchOutputFormat::Human = output_format {
+                write_skipped(&mut std::io::stdout(), &skipped);
+            } else {
+                write_skipped(&mut std::io::stderr(), &skipped);
+            }
+        }
+        return Ok(());
+    }
+
+    // Load reference data
+    print_status(output_format, "Loading reference data...");
+    let ref_files = process_reference_path(&ref_path, reference_options)?;
+
+    // Flatten our data, using the same number of subtitles from each file
+    let num_subtitles = min_subtitle_count(&files).min(min_subtitle_count(&ref_files));
+    let subtitles = flatten_subtitles_n(&files, num_subtitles);
+    let ref_subtitles = flatten_subtitles_n(&ref_files, num_subtitles);
+
+    // Compare subtitles
+    print_status(output_format, "Comparing subtitles...");
+    let distances = compute_distances(&subtitles, &ref_subtitles, distance_options, output_format);
+
+    // Output distances
+    if let MatchOutputFormat::Human = output_format {
+        print_distances(&distances, display_options.sort_by, display_options.seed);
+    }
+
+    // Map files to reference files
+    // While we do this, we also want to know if a reference file
+    // is mapped more than once, and which reference files went unmapped.
+    let text_lengths = subtitles
+        .iter()
+        .chain(ref_subtitles.iter())
+        .map(|(file, subtitle)| {
+            let length = match metric {
+                Metric::Words => extract_words(subtitle).len(),
+                _ => subtitle.chars().count(),
+            };
+            (file.as_str(), length)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut mappings = Vec::<FileMapping>::new();
+    let mut seen_ref_files = HashSet::<&str>::new();
+    for (mkv_path, file_distances) in &distances {
+        let (ref_file, distance, criterion) =
+            select_reference_file(mkv_path, file_distances, max_distance, filename_regex);
+
+        let add = if let Some(max_distance) = max_distance {
+            distance < max_distance
+        } else {
+            true
+        };
+
+        if add {
+            let grade = display_options.grade_thresholds.map(|thresholds| {
+                // Distances are computed after normalizing the strings (usually
+                // truncating to the shorter one), while similarity distances are
+                // already percentages.
+                let mkv_length = text_lengths[mkv_path.as_str()];
+                let ref_length = text_lengths[ref_file.as_str()];
+                let length = match metric {
+                    Metric::Levenshtein => normalization.compared_length(mkv_length, ref_length),
+                    Metric::Words => mkv_length.min(ref_length),
+                    Metric::Similarity => 100,
+                };
+                confidence_grade(distance, length, thresholds)
+            });
+            mappings.push(FileMapping {
+                mkv_path: mkv_path.clone(),
+                ref_file: ref_file.clone(),
+                distance,
+                criterion,
+                grade,
+            });
+            seen_ref_files.insert(ref_file);
+        }
+    }
+
+    // Find the reference files that weren't mapped to anything.
+    let mut unmapped = HashSet::<String>::new();
+    for (ref_file, _) in &ref_subtitles {
+        if !seen_ref_files.contains(ref_file.as_str()) {
+            unmapped.insert(ref_file.clone());
+        }
+    }
+
+    // Make sure we haven't mapped something to the same reference file multiple times.
+    let confidence_report =
+        ConfidenceReport::new(&mappings, distances.len() + skipped.len(), max_distance);
+    let is_high_confidence = confidence_report.is_high_confidence();
+
+    // Output mapping
+    let mut output = create_output_writer(display_options.output_file);
+    let mut unmapped = unmapped.into_iter().collect::<Vec<_>>();
+    unmapped.sort();
+    match display_options.output_format {
+        MatchOutputFormat::Human => {
+            write_mapping(&mut output, &mappings);
+            write_skipped(&mut output, &skipped);
+            write_unmapped(&mut output, &unmapped);
+            if display_options.explain {
+                writeln!(output, "{}", confidence_report.explain()).unwrap();
+            }
+            if is_high_confidence {
+                write!(output, "(High Confidence) ").unwrap();
+            }
+            write_final_mapping(&mut output, &mappings);
+            writeln!(output).unwrap();
+            // No need to repeat the script if it's going to its own file
+            if is_high_confidence && display_options.rename_script_file.is_none() {
+                writeln!(output, "Rename script:").unwrap();
+                write_powershell_rename_script(&mut output, &mappings);
+            }
+        }
+        MatchOutputFormat::Json => {
+            let json = serde_json::json!({
+                "mappings": mappings
+                    .iter()
+                    .map(|mapping| {
+                        serde_json::json!({
+                            "source": mapping.mkv_path,
+                            "target": mapping.ref_file,
+                            "distance": mapping.distance,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                "unmapped": unmapped,
+                "skipped": skipped
+                    .iter()
+                    .map(|(mkv_path, num_subtitles)| {
+                        serde_json::json!({
+                            "source": mkv_path,
+                            "subtitles": num_subtitles,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                "high_confidence": is_high_confidence,
+            });
+            writeln!(output, "{}", serde_json::to_string_pretty(&json).unwrap()).unwrap();
+        }
+        MatchOutputFormat::Csv => {
+            writeln!(output, "source,target,distance").unwrap();
+            for mapping in &mappings {
+                writeln!(
+                    output,
+                    "{},{},{}",
+                    escape_csv_field(&mapping.mkv_path),
+                    escape_csv_field(&mapping.ref_file),
+                    mapping.distance
+                )
+                .unwrap();
+            }
+        }
+        MatchOutputFormat::PowerShell | MatchOutputFormat::Bash => {
+            if !is_high_confidence {
+                write_low_confidence_warning(&mut output);
+            }
+            if let MatchOutputFormat::PowerShell = display_options.output_format {
+                write_powershell_rename_script(&mut output, &mappings);
+            } else {
+                write_bash_rename_script(&mut output, &mappings);
+            }
+        }
+    }
+
+    if let Some(script_file) = display_options.rename_script_file {
+        let mut script =
+            File::create(script_file).expect(&format!("Could not create \"{}\"", script_file));
+        match display_options.script_format {
+            ScriptFormat::Bash => writeln!(script, "#!/usr/bin/env bash").unwrap(),
+            // Windows PowerShell assumes the system code page without a BOM
+            ScriptFormat::PowerShell => script.write_all("\u{FEFF}".as_bytes()).unwrap(),
+        }
+        let date = format_utc_date(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        );
+        writeln!(script, "# Generated by showorder on {} (UTC)", date).unwrap();
+        writeln!(script, "# Mkv path: {}", mkv_path).unwrap();
+        writeln!(script, "# Reference path: {}", ref_path).unwrap();
+        if !is_high_confidence {
+            write_low_confidence_warning(&mut script);
+        }
+        match display_options.script_format {
+            ScriptFormat::PowerShell => write_powershell_rename_script(&mut script, &mappings),
+            ScriptFormat::Bash => write_bash_rename_script(&mut script, &mappings),
+        }
+    }
+
+    Ok(())
+}
+
+// Writes to the given file, or stdout if there isn't one
+fn create_output_writer(output_file: Option<&str>) -> Box<dyn Write> {
+    if let Some(output_file) = output_file {
+        let file =
+            File::create(output_file).expect(&format!("Could not create \"{}\"", output_file));
+        Box::new(file)
+    } else {
+        Box::new(std::io::stdout())
+    }
+}
+
+fn write_low_confidence_warning<W: Write>(output: &mut W) {
+    writeln!(
+        output,
+        "# Warning! Some reference files were matched more than once, check the renames before running this."
+    )
+    .unwrap();
+}
+
+// Formats the date as YYYY-MM-DD, given the time since the unix epoch
+fn format_utc_date(since_epoch: Duration) -> String {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (since_epoch.as_secs() / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn diff_subtitles(
+    mkv_path: &str,
+    ref_path: &str,
+    load_options: &LoadOptions,
+    reference_options: &ReferenceOptions,
+    distance_options: DistanceOptions,
+    output_file: Option<&str>,
 ) -> Result<()> {
+    let normalization = distance_options.normalization;
     // Collect subtitles from the file(s)
     println!("Loading subtitles from mkv files...");
-    let files = process_input_path(&mkv_path, num_subtitles, track_number)?;
+    let files = process_input_path(&mkv_path, load_options, should_show_progress())?;
 
     // If we couldn't find any subtitles, exit
     if files.is_empty() {
@@ -255,145 +1702,551 @@ fn match_subtitles(
 
     // Load reference data
     println!("Loading reference data...");
-    let ref_files = process_reference_path(&ref_path, num_subtitles)?;
+    let ref_files = process_reference_path(&ref_path, reference_options)?;
+
+    // Flatten our data, using the same number of subtitles from each file
+    let num_subtitles = min_subtitle_count(&files).min(min_subtitle_count(&ref_files));
+    let subtitles = flatten_subtitles_n(&files, num_subtitles);
+    let ref_subtitles = flatten_subtitles_n(&ref_files, num_subtitles);
+
+    // Compare subtitles
+    println!("Comparing subtitles...");
+    let distances = compute_distances(
+        &subtitles,
+        &ref_subtitles,
+        distance_options,
+        MatchOutputFormat::Human,
+    );
+
+    // Only use color when a person is likely looking at the output
+    let use_color = output_file.is_none()
+        && std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none();
+    let mut output = create_output_writer(output_file);
+
+    for (mkv_path, subtitle) in &subtitles {
+        // First will be the lowest
+        let (ref_file, distance) = &distances[mkv_path][0];
+        let (_, ref_subtitle) = ref_subtitles
+            .iter()
+            .find(|(file, _)| file == ref_file)
+            .unwrap();
+
+        // Diff what was actually compared
+        let (normalized_subtitle, normalized_ref_subtitle) =
+            normalize_to_shortest_string(subtitle, ref_subtitle, normalization);
+
+        let mkv_file_name = Path::new(mkv_path).file_name().unwrap().to_str().unwrap();
+        let ref_file_name = Path::new(ref_file).file_name().unwrap().to_str().unwrap();
+        writeln!(
+            output,
+            "{} -> {} ({})",
+            mkv_file_name, ref_file_name, distance
+        )
+        .unwrap();
+        writeln!(output, "  OCR:       {}", normalized_subtitle).unwrap();
+        writeln!(output, "  Reference: {}", normalized_ref_subtitle).unwrap();
+
+        let words = extract_words(&normalized_subtitle);
+        let ref_words = extract_words(&normalized_ref_subtitle);
+        let diff = diff_words(&words, &ref_words)
+            .iter()
+            .map(|word| format_word_diff(word, use_color))
+            .collect::<Vec<_>>();
+        writeln!(output, "  Diff:      {}", diff.join(" ")).unwrap();
+        writeln!(output).unwrap();
+    }
+
+    Ok(())
+}
+
+fn format_word_diff(word: &WordDiff, use_color: bool) -> String {
+    match (word, use_color) {
+        (WordDiff::Same(word), _) => word.to_string(),
+        (WordDiff::Added(word), false) => format!("+{}", word),
+        (WordDiff::Removed(word), false) => format!("-{}", word),
+        (WordDiff::Added(word), true) => format!("\x1b[32m+{}\x1b[0m", word),
+        (WordDiff::Removed(word), true) => format!("\x1b[31m-{}\x1b[0m", word),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum MatchCriterion {
+    Distance,
+    Filename,
+}
+
+impl MatchCriterion {
+    fn to_string(&self) -> &str {
+        match self {
+            MatchCriterion::Distance => "distance",
+            MatchCriterion::Filename => "filename",
+        }
+    }
+}
 
-    // Flatten our data
-    let subtitles = flatten_subtitles(&files);
-    let ref_subtitles = flatten_subtitles(&ref_files);
+struct ManifestEntry {
+    mkv_path: PathBuf,
+    srt_path: PathBuf,
+}
 
-    // Compare subtitles
-    println!("Comparing subtitles...");
-    let distances = compute_distances(&&subtitles, &&ref_subtitles);
+// Relative paths in the manifest are relative to the manifest itself.
+fn parse_manifest(data: &str, base_path: &Path) -> serde_json::Result<Vec<ManifestEntry>> {
+    use serde::de::Error;
 
-    // Output distances
-    print_distances(&distances);
+    let value: serde_json::Value = serde_json::from_str(data)?;
+    let entries = value
+        .as_array()
+        .ok_or_else(|| serde_json::Error::custom("Expected the manifest to be an array"))?;
+    let mut result = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let get_path = |name: &str| {
+            entry
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(|value| base_path.join(value))
+                .ok_or_else(|| {
+                    serde_json::Error::custom(format!(
+                        "Expected a \"{}\" string in manifest entry {}",
+                        name, entry
+                    ))
+                })
+        };
+        result.push(ManifestEntry {
+            mkv_path: get_path("path")?,
+            srt_path: get_path("srt")?,
+        });
+    }
+    Ok(result)
+}
 
-    // Map files to reference files
-    // While we do this, we also want to know if a reference file
-    // is mapped more than once, and which reference files went unmapped.
-    let mut mappings = Vec::<(String, String)>::new();
-    let mut seen_ref_files = HashMap::<&str, usize>::new();
-    for (mkv_path, file_distances) in &distances {
-        // First will be the loweset
-        let (ref_file, distance) = &file_distances[0];
+// Verifies each mapping in the manifest rather than searching for
+// the closest reference file.
+fn audit_manifest(
+    manifest_path: &str,
+    load_options: &LoadOptions,
+    reference_options: &ReferenceOptions,
+    distance_options: DistanceOptions,
+    max_distance: Option<usize>,
+    display_options: &MatchDisplayOptions,
+) -> Result<()> {
+    let output_format = display_options.output_format;
+    let metric = distance_options.metric;
+    let normalization = distance_options.normalization;
+    let data = std::fs::read_to_string(manifest_path)
+        .expect(&format!("Could not read from \"{}\"", manifest_path));
+    let base_path = Path::new(manifest_path).parent().unwrap_or(Path::new(""));
+    let entries = parse_manifest(&data, base_path)
+        .unwrap_or_else(|error| panic!("Invalid manifest \"{}\": {}", manifest_path, error));
 
-        let add = if let Some(max_distance) = max_distance {
-            *distance < max_distance
-        } else {
-            true
-        };
+    print_status(
+        output_format,
+        &format!("Verifying {} mappings...", entries.len()),
+    );
+    let results = entries
+        .par_iter()
+        .map(|entry| -> Result<(Option<usize>, Option<char>, bool)> {
+            let subtitles = load_first_n_english_subtitles(&entry.mkv_path, load_options)?;
+            let subtitles = if let Some(subtitles) = subtitles {
+                subtitles
+            } else {
+                return Ok((None, None, false));
+            };
+            let ref_subtitles = srt::parse_n_subtitles(
+                &entry.srt_path,
+                reference_options.num_subtitles,
+                reference_options.srt,
+            )
+            .unwrap_or_else(|error| {
+                panic!(
+                    "Could not parse \"{}\": {}",
+                    entry.srt_path.display(),
+                    error
+                )
+            });
+            let subtitle = subtitles.join(" ");
+            let ref_subtitle = ref_subtitles.join(" ");
+            let distance = compute_metric_distance(&subtitle, &ref_subtitle, distance_options);
+            let grade = display_options.grade_thresholds.map(|thresholds| {
+                let length = match metric {
+                    Metric::Levenshtein => normalization
+                        .compared_length(subtitle.chars().count(), ref_subtitle.chars().count()),
+                    Metric::Words => extract_words(&subtitle)
+                        .len()
+                        .min(extract_words(&ref_subtitle).len()),
+                    Metric::Similarity => 100,
+                };
+                confidence_grade(distance, length, thresholds)
+            });
+            let passed = if let Some(max_distance) = max_distance {
+                distance < max_distance
+            } else {
+                grade != Some('F')
+            };
+            Ok((Some(distance), grade, passed))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let num_failed = results.iter().filter(|(_, _, passed)| !passed).count();
+    let summary = format!(
+        "{} of {} mappings failed verification.",
+        num_failed,
+        entries.len()
+    );
 
-        if add {
-            mappings.push((mkv_path.clone(), ref_file.clone()));
-            let count = seen_ref_files.entry(ref_file).or_insert(0);
-            *count += 1;
+    let mut output = create_output_writer(display_options.output_file);
+    match output_format {
+        MatchOutputFormat::Human => {
+            for (entry, (distance, grade, passed)) in entries.iter().zip(&results) {
+                let mkv_file_name = entry.mkv_path.file_name().unwrap().to_string_lossy();
+                let srt_file_name = entry.srt_path.file_name().unwrap().to_string_lossy();
+                let distance = if let Some(distance) = distance {
+                    distance.to_string()
+                } else {
+                    "no English subtitles".to_owned()
+                };
+                let grade = if let Some(grade) = grade {
+                    format!(" ({})", grade)
+                } else {
+                    String::new()
+                };
+                writeln!(
+                    output,
+                    "  [{}] {} -> {} ({}){}",
+                    if *passed { "OK" } else { "FAILED" },
+                    mkv_file_name,
+                    srt_file_name,
+                    distance,
+                    grade
+                )
+                .unwrap();
+            }
+            writeln!(output, "{}", summary).unwrap();
+        }
+        MatchOutputFormat::Json => {
+            let json = serde_json::json!({
+                "mappings": entries
+                    .iter()
+                    .zip(&results)
+                    .map(|(entry, (distance, grade, passed))| {
+                        serde_json::json!({
+                            "source": entry.mkv_path.to_string_lossy(),
+                            "target": entry.srt_path.to_string_lossy(),
+                            "distance": distance,
+                            "grade": grade,
+                            "passed": passed,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                "failed": num_failed,
+            });
+            writeln!(output, "{}", serde_json::to_string_pretty(&json).unwrap()).unwrap();
+        }
+        MatchOutputFormat::Csv => {
+            writeln!(output, "source,target,distance,passed").unwrap();
+            for (entry, (distance, _, passed)) in entries.iter().zip(&results) {
+                writeln!(
+                    output,
+                    "{},{},{},{}",
+                    escape_csv_field(&entry.mkv_path.to_string_lossy()),
+                    escape_csv_field(&entry.srt_path.to_string_lossy()),
+                    distance
+                        .map(|distance| distance.to_string())
+                        .unwrap_or_default(),
+                    passed
+                )
+                .unwrap();
+            }
+        }
+        // Only the mappings that passed are renamed
+        MatchOutputFormat::PowerShell | MatchOutputFormat::Bash => {
+            let mappings = entries
+                .iter()
+                .zip(&results)
+                .filter(|(_, (_, _, passed))| *passed)
+                .filter_map(|(entry, (distance, grade, _))| {
+                    Some(FileMapping {
+                        mkv_path: entry.mkv_path.to_string_lossy().to_string(),
+                        ref_file: entry.srt_path.to_string_lossy().to_string(),
+                        distance: (*distance)?,
+                        criterion: MatchCriterion::Distance,
+                        grade: *grade,
+                    })
+                })
+                .collect::<Vec<_>>();
+            if let MatchOutputFormat::PowerShell = output_format {
+                write_powershell_rename_script(&mut output, &mappings);
+            } else {
+                write_bash_rename_script(&mut output, &mappings);
+            }
         }
     }
+    if !matches!(output_format, MatchOutputFormat::Human) {
+        print_status(output_format, &summary);
+    }
+    Ok(())
+}
 
-    // Make sure we haven't mapped something to the same reference file multiple times.
-    let mut duplicates = Vec::<(String, usize)>::new();
-    let mut unmapped = HashSet::<String>::new();
-    for (ref_file, _) in &ref_subtitles {
-        let count = *seen_ref_files.get(ref_file.as_str()).unwrap_or(&0);
-        if count == 0 {
-            unmapped.insert(ref_file.clone());
-        } else if count > 1 {
-            duplicates.push((ref_file.clone(), count));
-        }
+fn confidence_grade(distance: usize, length: usize, thresholds: &GradeThresholds) -> char {
+    if length == 0 {
+        return if distance == 0 { 'A' } else { 'F' };
     }
+    let ratio = distance as f32 / length as f32;
+    let GradeThresholds(thresholds) = thresholds;
+    ['A', 'B', 'C', 'D']
+        .iter()
+        .zip(thresholds)
+        .find(|(_, threshold)| ratio < **threshold)
+        .map(|(grade, _)| *grade)
+        .unwrap_or('F')
+}
 
-    // Check to see if we have high confidence the mapping is correct. High confidence means:
-    //   * Each reference file is mapped to only 1 other file
-    //   * Mkv files can still be unmapped (e.g. extras)
-    let is_high_confidence = duplicates.is_empty();
+fn select_reference_file<'a>(
+    mkv_path: &str,
+    file_distances: &'a [(String, usize)],
+    max_distance: Option<usize>,
+    filename_regex: Option<&Regex>,
+) -> (&'a String, usize, MatchCriterion) {
+    // First will be the loweset
+    let (ref_file, distance) = &file_distances[0];
 
-    // Output mapping
-    print_mapping(&mappings);
-    print_unmapped(&unmapped);
-    if is_high_confidence {
-        print!("(High Confidence) ");
-    }
-    print_final_mapping(&mappings);
-    println!("");
-    if is_high_confidence {
-        print_powershell_rename_script(&mappings);
+    // If other reference files are within max_distance of the closest one,
+    // the distance alone is ambiguous. Use the file names to break the tie.
+    if let Some(filename_regex) = filename_regex {
+        let tie_distance = max_distance.unwrap_or(0);
+        let candidates = file_distances
+            .iter()
+            .take_while(|(_, candidate_distance)| candidate_distance - distance <= tie_distance)
+            .collect::<Vec<_>>();
+        if candidates.len() > 1 {
+            if let Some(mkv_key) = extract_filename_key(filename_regex, mkv_path) {
+                let candidate = candidates.into_iter().find(|(candidate_file, _)| {
+                    extract_filename_key(filename_regex, candidate_file).as_ref() == Some(&mkv_key)
+                });
+                if let Some((candidate_file, candidate_distance)) = candidate {
+                    return (
+                        candidate_file,
+                        *candidate_distance,
+                        MatchCriterion::Filename,
+                    );
+                }
+            }
+        }
     }
 
-    Ok(())
+    (ref_file, *distance, MatchCriterion::Distance)
+}
+
+fn extract_filename_key(filename_regex: &Regex, path: &str) -> Option<Vec<String>> {
+    let file_name = Path::new(path).file_name()?.to_str()?;
+    let captures = filename_regex.captures(file_name)?;
+    let key = captures
+        .iter()
+        .skip(1)
+        .map(|capture| {
+            let value = capture.map(|m| m.as_str()).unwrap_or("");
+            // Compare numbers by value so that "E01" and "E1" agree
+            if let Ok(number) = value.parse::<u64>() {
+                number.to_string()
+            } else {
+                value.to_lowercase()
+            }
+        })
+        .collect();
+    Some(key)
+}
+
+// The progress bar is drawn to stderr, so it never ends up in the
+// output. Skip it when it's unlikely anyone is watching.
+fn should_show_progress() -> bool {
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
 }
 
 fn process_input_path<P: AsRef<Path>>(
     path: P,
-    num_subtitles: usize,
-    track_number: Option<u64>,
+    load_options: &LoadOptions,
+    show_progress: bool,
 ) -> Result<Vec<(String, Vec<String>)>> {
+    let (files, _) = process_input_path_with_encodings(path, load_options, show_progress)?;
+    Ok(files)
+}
+
+// Like process_input_path, but also returns the encoding of the track
+// the subtitles were read from (e.g. "PGS") for each file.
+fn process_input_path_with_encodings<P: AsRef<Path>>(
+    path: P,
+    load_options: &LoadOptions,
+    show_progress: bool,
+) -> Result<(Vec<(String, Vec<String>)>, HashMap<String, &'static str>)> {
+    let print_timings = load_options.debug_logging;
+    // Per-file progress would fight with the progress bar for directories
+    let load_options = &LoadOptions {
+        show_file_progress: load_options.show_file_progress && !path.as_ref().is_dir(),
+        ..load_options.clone()
+    };
+    // Keyed by canonical path, to match the paths we get back
+    let encodings = Mutex::new(HashMap::new());
+    let files = process_input_path_with(path, show_progress, |path| {
+        let (loaded, parse_timing, ocr_timing) =
+            load_first_n_subtitles_timed(path, load_options, KnownLanguage::English)?;
+        if print_timings {
+            progress::suspend(|| {
+                eprintln!(
+                    "Debug: \"{}\" took {} ms to parse (EBML header: {} ms, track scan: {} ms) and {} ms to OCR {} frames",
+                    path.display(),
+                    parse_timing.total_ms,
+                    parse_timing.ebml_header_ms,
+                    parse_timing.track_scan_ms,
+                    ocr_timing.total_ocr_ms,
+                    ocr_timing.frames_processed
+                )
+            });
+        }
+        Ok(loaded.map(|loaded| {
+            let path = std::fs::canonicalize(path).unwrap();
+            let path = path.to_str().unwrap().to_owned();
+            encodings.lock().unwrap().insert(path, loaded.encoding);
+            loaded.subtitles
+        }))
+    })?;
+    Ok((files, encodings.into_inner().unwrap()))
+}
+
+// Follows the RUST_LOG convention, e.g. RUST_LOG=debug
+fn debug_logging_enabled() -> bool {
+    std::env::var("RUST_LOG")
+        .map(|value| {
+            let value = value.to_lowercase();
+            value.contains("debug") || value.contains("trace")
+        })
+        .unwrap_or(false)
+}
+
+fn process_input_path_with<P, T, F>(
+    path: P,
+    show_progress: bool,
+    load_subtitles: F,
+) -> Result<Vec<(String, Vec<T>)>>
+where
+    P: AsRef<Path>,
+    T: Send,
+    F: Fn(&Path) -> Result<Option<Vec<T>>> + Sync,
+{
+    process_input_path_with_extension(path, "mkv", show_progress, load_subtitles)
+}
+
+// Only files with the given extension are loaded
+fn process_input_path_with_extension<P, T, F>(
+    path: P,
+    extension: &str,
+    show_progress: bool,
+    load_subtitles: F,
+) -> Result<Vec<(String, Vec<T>)>>
+where
+    P: AsRef<Path>,
+    T: Send,
+    F: Fn(&Path) -> Result<Option<Vec<T>>> + Sync,
+{
     let path = path.as_ref();
     let mut result = Vec::new();
     if path.is_dir() {
         let paths: Vec<_> = std::fs::read_dir(path)
             .unwrap()
-            .map(|p| p.unwrap())
+            .map(|p| p.unwrap().path())
+            .filter(|p| p.extension().map(|ext| ext == extension).unwrap_or(false))
             .collect();
+        let progress = if show_progress {
+            ProgressBar::with_draw_target(Some(paths.len() as u64), ProgressDrawTarget::stderr())
+                .with_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {wide_bar} {pos}/{len} files (eta {eta}) {msg}",
+                    )
+                    .unwrap(),
+                )
+        } else {
+            ProgressBar::hidden()
+        };
+        // Lets the loaders print around the bar
+        let _active_progress = progress::set_active_progress_bar(&progress);
+        // Files finish out of order, so count them ourselves
+        let num_processed = AtomicUsize::new(0);
         result = paths
             .par_iter()
             //.iter()
-            .filter_map(|p| {
-                let path = p.path();
-                if let Some(ext) = path.extension() {
-                    if ext == "mkv" {
-                        if let Some(subtitles) =
-                            load_first_n_english_subtitles(&path, num_subtitles, track_number)
-                                .unwrap()
-                        {
-                            // Sometimes there's a subtitle track with no subtitles in it...
-                            if !subtitles.is_empty() {
-                                let path = std::fs::canonicalize(path).unwrap();
-                                let path = path.to_str().unwrap().to_owned();
-                                return Some((path, subtitles));
-                            }
-                        }
-                    }
-                }
-                None
+            .filter_map(|path| {
+                progress.set_message(path.file_name().unwrap().to_string_lossy().to_string());
+                let loaded = load_file_subtitles(path, &load_subtitles);
+                let processed = num_processed.fetch_add(1, Ordering::SeqCst) + 1;
+                progress.set_position(processed as u64);
+                loaded
             })
             .collect();
+        // Clear the bar so that anything printed afterwards isn't mixed into it
+        progress.finish_and_clear();
     } else if path.exists() && path.is_file() {
         if let Some(ext) = path.extension() {
-            if ext == "mkv" {
-                if let Some(subtitles) =
-                    load_first_n_english_subtitles(&path, num_subtitles, track_number).unwrap()
-                {
-                    // Sometimes there's a subtitle track with no subtitles in it...
-                    if !subtitles.is_empty() {
-                        let path = std::fs::canonicalize(path).unwrap();
-                        let path = path.to_str().unwrap().to_owned();
-                        result.push((path, subtitles));
-                    }
-                }
+            if ext == extension {
+                result.extend(load_file_subtitles(path, &load_subtitles));
             }
         }
     } else {
         panic!("Invalid input path: {:?}", path)
     }
+    // Neither the directory listing nor rayon give us a stable order
+    result.sort_by(|(file1, _), (file2, _)| file1.cmp(file2));
     Ok(result)
 }
 
-fn print_subtitles(files: &Vec<(String, Vec<String>)>) {
+// One bad file shouldn't stop us from processing the rest, so
+// errors are printed and the file is skipped.
+fn load_file_subtitles<T, F>(path: &Path, load_subtitles: &F) -> Option<(String, Vec<T>)>
+where
+    F: Fn(&Path) -> Result<Option<Vec<T>>>,
+{
+    let subtitles = match load_subtitles(path) {
+        Ok(subtitles) => subtitles?,
+        Err(error) => {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Could not load subtitles from \"{}\": {} Skipping file...",
+                    path.display(),
+                    error
+                )
+            });
+            return None;
+        }
+    };
+    // Sometimes there's a subtitle track with no subtitles in it...
+    if subtitles.is_empty() {
+        return None;
+    }
+    let path = std::fs::canonicalize(path).unwrap();
+    let path = path.to_str().unwrap().to_owned();
+    Some((path, subtitles))
+}
+
+fn print_subtitles(
+    files: &[(String, Vec<String>)],
+    encodings: Option<&HashMap<String, &'static str>>,
+) {
     for (file, subtitles) in files {
         let path = Path::new(file);
-        println!("{}:", path.file_name().unwrap().to_string_lossy());
-        for subtitle in subtitles {
-            println!("  \"{}\"", subtitle);
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        match encodings.and_then(|encodings| encodings.get(file)) {
+            Some(encoding) => println!("{} ({}):", file_name, encoding),
+            None => println!("{}:", file_name),
+        }
+        for (i, subtitle) in subtitles.iter().enumerate() {
+            println!("  [{}] \"{}\"", i, subtitle);
         }
     }
 }
 
 fn process_reference_path<P: AsRef<Path>>(
     path: P,
-    num_subtitles: usize,
+    reference_options: &ReferenceOptions,
 ) -> Result<Vec<(String, Vec<String>)>> {
     let path = path.as_ref();
-    let mut result = Vec::new();
+    let mut result: Vec<(String, Vec<String>)>;
     if path.is_dir() {
         let paths: Vec<_> = std::fs::read_dir(path)
             .unwrap()
@@ -402,48 +2255,87 @@ fn process_reference_path<P: AsRef<Path>>(
         result = paths
             .par_iter()
             //.iter()
-            .filter_map(|p| {
-                let path = p.path();
-                if let Some(ext) = path.extension() {
-                    if ext == "srt" {
-                        let subtitles = srt::parse_n_subtitles(&path, num_subtitles);
-                        if !subtitles.is_empty() {
-                            let path = std::fs::canonicalize(path).unwrap();
-                            let path = path.to_str().unwrap().to_owned();
-                            return Some((path, subtitles));
-                        }
-                    }
-                }
-                None
-            })
+            .flat_map(|p| process_reference_file(&p.path(), reference_options))
             .collect();
     } else if path.exists() && path.is_file() {
-        if let Some(ext) = path.extension() {
-            if ext == "srt" {
-                let subtitles = srt::parse_n_subtitles(&path, num_subtitles);
-                if !subtitles.is_empty() {
-                    let path = std::fs::canonicalize(path).unwrap();
-                    let path = path.to_str().unwrap().to_owned();
-                    result.push((path, subtitles));
-                }
-            }
-        }
+        result = process_reference_file(path, reference_options);
     } else {
         panic!("Invalid reference path: {:?}", path)
     }
+    result.sort_by(|(file1, _), (file2, _)| file1.cmp(file2));
     Ok(result)
 }
 
-fn flatten_subtitles(files: &Vec<(String, Vec<String>)>) -> Vec<(String, String)> {
+// Loads either a single srt file or every srt file inside of a zip
+// file. Files inside of a zip file are keyed by their name within it.
+fn process_reference_file(
+    path: &Path,
+    reference_options: &ReferenceOptions,
+) -> Vec<(String, Vec<String>)> {
+    let num_subtitles = reference_options.num_subtitles;
+    let parse_options = reference_options.srt;
+    let mut result = Vec::new();
+    if let Some(ext) = path.extension() {
+        if ext == "srt" {
+            let subtitles = srt::parse_n_subtitles(&path, num_subtitles, parse_options)
+                .unwrap_or_else(|error| {
+                    panic!("Could not parse \"{}\": {}", path.display(), error)
+                });
+            if !subtitles.is_empty() {
+                let path = std::fs::canonicalize(path).unwrap();
+                let path = path.to_str().unwrap().to_owned();
+                result.push((path, subtitles));
+            }
+        } else if ext == "zip" && !reference_options.no_zip {
+            let file = File::open(path).expect(&format!("Could not open \"{}\"", path.display()));
+            match srt::parse_n_subtitles_from_zip(file, num_subtitles, parse_options) {
+                Ok(files) => result = files,
+                // Malformed entries are only errors with --strict-srt, anything
+                // else that's wrong with the archive just skips it
+                Err(ZipError::Io(error))
+                    if parse_options.strict && error.kind() == ErrorKind::InvalidData =>
+                {
+                    panic!("Could not parse \"{}\": {}", path.display(), error)
+                }
+                Err(error) => println!(
+                    "Warning! Could not read \"{}\" as a zip file: {}",
+                    path.display(),
+                    error
+                ),
+            }
+        }
+    }
+    result
+}
+
+// Joins the same number of subtitles from each file: at most n, and no
+// more than the file with the fewest subtitles has. Otherwise the files
+// with fewer subtitles would be further from everything.
+fn flatten_subtitles_n(files: &[(String, Vec<String>)], n: usize) -> Vec<(String, String)> {
+    let n = n.min(min_subtitle_count(files));
     files
         .iter()
-        .map(|(file, subtitle)| (file.clone(), subtitle.join(" ")))
+        .map(|(file, subtitles)| {
+            let subtitles = &subtitles[..subtitles.len().min(n)];
+            (file.clone(), subtitles.join(" "))
+        })
         .collect()
 }
 
-fn print_distances(distances: &HashMap<String, Vec<(String, usize)>>) {
+// Files without any subtitles don't have anything to compare, so they
+// don't count.
+fn min_subtitle_count(files: &[(String, Vec<String>)]) -> usize {
+    files
+        .iter()
+        .map(|(_, subtitles)| subtitles.len())
+        .filter(|count| *count > 0)
+        .min()
+        .unwrap_or(0)
+}
+
+fn print_distances(distances: &HashMap<String, Vec<(String, usize)>>, sort_by: SortBy, seed: u64) {
     println!("Distances:");
-    for (mkv_path, file_distances) in distances {
+    for (mkv_path, file_distances) in sort_distances(distances, sort_by, seed) {
         let path = Path::new(mkv_path);
         println!("{} :", path.file_name().unwrap().to_str().unwrap());
         for (ref_file, distance) in file_distances {
@@ -454,44 +2346,109 @@ fn print_distances(distances: &HashMap<String, Vec<(String, usize)>>) {
     }
 }
 
-fn print_mapping(mapping: &[(String, String)]) {
-    println!("Results:");
-    for (mkv_path, ref_file) in mapping {
-        let mkv_path = Path::new(mkv_path);
-        let ref_path = Path::new(ref_file);
+// Orders the distances for display. Reference files with the same
+// distance are ordered by name so that the output is deterministic.
+fn sort_distances(
+    distances: &HashMap<String, Vec<(String, usize)>>,
+    sort_by: SortBy,
+    seed: u64,
+) -> Vec<(&String, Vec<&(String, usize)>)> {
+    let mut sorted = distances
+        .iter()
+        .map(|(mkv_path, file_distances)| {
+            let mut file_distances = file_distances.iter().collect::<Vec<_>>();
+            file_distances.sort_by(|(file1, distance1), (file2, distance2)| {
+                distance1.cmp(distance2).then_with(|| file1.cmp(file2))
+            });
+            (mkv_path, file_distances)
+        })
+        .collect::<Vec<_>>();
+    // Start from a known order so that shuffling is reproducible
+    sorted.sort_by_key(|(mkv_path, _)| *mkv_path);
+    match sort_by {
+        SortBy::Filename => {}
+        SortBy::Distance => {
+            // The sort is stable, so ties stay sorted by file name
+            sorted.sort_by_key(|(_, file_distances)| {
+                file_distances
+                    .first()
+                    .map(|(_, distance)| *distance)
+                    .unwrap_or(usize::MAX)
+            });
+        }
+        SortBy::Random => {
+            let mut rng = StdRng::seed_from_u64(seed);
+            sorted.shuffle(&mut rng);
+        }
+    }
+    sorted
+}
+
+fn write_mapping<W: Write>(output: &mut W, mapping: &[FileMapping]) {
+    writeln!(output, "Results:").unwrap();
+    for mapping in mapping {
+        let mkv_path = Path::new(&mapping.mkv_path);
+        let ref_path = Path::new(&mapping.ref_file);
         let mkv_file_name = mkv_path.file_name().unwrap().to_str().unwrap();
         let ref_file_name = ref_path.file_name().unwrap().to_str().unwrap();
-        println!("  {} -> {}", mkv_file_name, ref_file_name);
+        let grade = if let Some(grade) = mapping.grade {
+            format!(" ({})", grade)
+        } else {
+            String::new()
+        };
+        writeln!(
+            output,
+            "  {} -> {} ({}){}",
+            mkv_file_name,
+            ref_file_name,
+            mapping.criterion.to_string(),
+            grade
+        )
+        .unwrap();
+    }
+}
+
+fn write_skipped<W: Write>(output: &mut W, skipped: &[(String, usize)]) {
+    for (mkv_path, num_subtitles) in skipped {
+        let mkv_path = Path::new(mkv_path);
+        let mkv_file_name = mkv_path.file_name().unwrap().to_str().unwrap();
+        writeln!(
+            output,
+            "  {} -> SKIPPED (only {} subtitles found)",
+            mkv_file_name, num_subtitles
+        )
+        .unwrap();
     }
 }
 
-fn print_unmapped(unmapped: &HashSet<String>) {
+fn write_unmapped<W: Write>(output: &mut W, unmapped: &[String]) {
     if !unmapped.is_empty() {
-        println!("Unmapped reference files:");
+        writeln!(output, "Unmapped reference files:").unwrap();
         for mkv_path in unmapped {
             let mkv_path = Path::new(mkv_path);
             let mkv_file_name = mkv_path.file_name().unwrap().to_str().unwrap();
-            println!("  {}", mkv_file_name);
+            writeln!(output, "  {}", mkv_file_name).unwrap();
         }
     }
 }
 
-fn print_final_mapping(mapping: &[(String, String)]) {
-    println!("Final mapping:");
-    for (mkv_path, ref_file) in mapping {
-        let mkv_path = Path::new(mkv_path);
-        let ref_path = Path::new(ref_file);
+fn write_final_mapping<W: Write>(output: &mut W, mapping: &[FileMapping]) {
+    writeln!(output, "Final mapping:").unwrap();
+    for mapping in mapping {
+        let mkv_path = Path::new(&mapping.mkv_path);
+        let ref_path = Path::new(&mapping.ref_file);
         let mkv_file_name = mkv_path.file_name().unwrap().to_str().unwrap();
         let ref_file_name = ref_path.file_name().unwrap().to_str().unwrap();
-        println!("  {} -> {}", mkv_file_name, ref_file_name);
+        writeln!(output, "  {} -> {}", mkv_file_name, ref_file_name).unwrap();
     }
 }
 
-fn print_powershell_rename_script(mapping: &[(String, String)]) {
-    println!("Rename script:");
-    for (mkv_path, ref_file) in mapping {
-        let mkv_path = Path::new(mkv_path);
-        let ref_path = Path::new(ref_file);
+// Returns the current and new names of each mkv file that needs to be renamed
+fn compute_renames(mapping: &[FileMapping]) -> Vec<(&str, String)> {
+    let mut renames = Vec::new();
+    for mapping in mapping {
+        let mkv_path = Path::new(&mapping.mkv_path);
+        let ref_path = Path::new(&mapping.ref_file);
         let mkv_file_name = mkv_path.file_name().unwrap().to_str().unwrap();
         let mut ref_file_name = ref_path
             .file_stem()
@@ -501,31 +2458,93 @@ fn print_powershell_rename_script(mapping: &[(String, String)]) {
             .replace(".eng", "");
         ref_file_name.push_str(".mkv");
         if mkv_file_name != ref_file_name {
-            println!(
-                "Rename-Item -Path \"{}\" -NewName \"{}\"",
-                mkv_file_name, ref_file_name
-            );
+            renames.push((mkv_file_name, ref_file_name));
+        }
+    }
+    renames
+}
+
+fn write_powershell_rename_script<W: Write>(output: &mut W, mapping: &[FileMapping]) {
+    // Otherwise non-ASCII file names can get mangled by the console's code page
+    writeln!(output, "$OutputEncoding = [System.Text.Encoding]::UTF8").unwrap();
+    for (mkv_file_name, ref_file_name) in compute_renames(mapping) {
+        writeln!(
+            output,
+            "Rename-Item -Path {} -NewName {}",
+            quote_for_powershell(mkv_file_name),
+            quote_for_powershell(&ref_file_name)
+        )
+        .unwrap();
+    }
+}
+
+fn write_bash_rename_script<W: Write>(output: &mut W, mapping: &[FileMapping]) {
+    for (mkv_file_name, ref_file_name) in compute_renames(mapping) {
+        writeln!(
+            output,
+            "mv -- {} {}",
+            quote_for_bash(mkv_file_name),
+            quote_for_bash(&ref_file_name)
+        )
+        .unwrap();
+    }
+}
+
+// Nothing is expanded within single quotes. PowerShell also treats the
+// curly single quotes as quotes, so those need to be doubled as well.
+fn quote_for_powershell(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            quoted.push(c);
         }
+        quoted.push(c);
     }
+    quoted.push('\'');
+    quoted
+}
+
+fn quote_for_bash(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
 }
 
 fn compute_distances(
     subtitles: &[(String, String)],
     ref_subtitles: &[(String, String)],
+    distance_options: DistanceOptions,
+    output_format: MatchOutputFormat,
 ) -> HashMap<String, Vec<(String, usize)>> {
+    compute_distances_with(
+        subtitles,
+        ref_subtitles,
+        output_format,
+        |subtitle, ref_subtitle| compute_metric_distance(subtitle, ref_subtitle, distance_options),
+    )
+}
+
+// Same as compute_distances, but with the comparison left up to the caller.
+fn compute_distances_with<F>(
+    subtitles: &[(String, String)],
+    ref_subtitles: &[(String, String)],
+    output_format: MatchOutputFormat,
+    mut compare: F,
+) -> HashMap<String, Vec<(String, usize)>>
+where
+    F: FnMut(&str, &str) -> usize,
+{
     let mut distances = HashMap::<String, Vec<(String, usize)>>::new();
     for (file, subtitle) in subtitles {
         let file_path = Path::new(file);
-        println!(
-            "  Inspecting \"{}\"",
-            file_path.file_name().unwrap().to_str().unwrap()
+        print_status(
+            output_format,
+            &format!(
+                "  Inspecting \"{}\"",
+                file_path.file_name().unwrap().to_str().unwrap()
+            ),
         );
         for (ref_file, ref_subtitle) in ref_subtitles {
-            // Normalize to shortest
-            let (normalized_subtitle, normalized_ref_subtitle) =
-                normalize_to_shortest_string(&subtitle, &ref_subtitle);
-
-            let distance = levenshtein(normalized_subtitle, normalized_ref_subtitle);
+            let distance = compare(subtitle, ref_subtitle);
             let matches = distances.entry(file.clone()).or_insert(Vec::new());
             matches.push((ref_file.clone(), distance));
         }
@@ -539,29 +2558,74 @@ fn compute_distances(
     distances
 }
 
+fn compute_metric_distance(subtitle: &str, ref_subtitle: &str, options: DistanceOptions) -> usize {
+    let normalization = options.normalization;
+    match options.metric {
+        Metric::Levenshtein => {
+            if let Some(window_size) = options.windowed {
+                let window_size = window_size
+                    .unwrap_or_else(|| subtitle.chars().count().min(ref_subtitle.chars().count()));
+                levenshtein_windowed(subtitle, ref_subtitle, window_size)
+            } else if options.align {
+                compute_aligned_distance(subtitle, ref_subtitle, ALIGNMENT_STEP, normalization)
+            } else {
+                compute_distance(subtitle, ref_subtitle, normalization)
+            }
+        }
+        Metric::Words => compute_word_distance(subtitle, ref_subtitle),
+        // Express the similarity as a percentage difference so that
+        // it can be treated like any other distance.
+        Metric::Similarity => {
+            let score = compute_similarity_score(subtitle, ref_subtitle, normalization);
+            ((1.0 - score) * 100.0).round() as usize
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::{collections::HashMap, path::Path};
+    use std::{collections::HashMap, path::Path, time::Duration};
     use windows::core::Result;
 
-    use crate::{compute_distances, flatten_subtitles, process_input_path, process_reference_path};
+    use crate::{
+        cli::{GradeThresholds, MatchOutputFormat, Metric, NamePattern, NamePatternValues, SortBy},
+        compute_distances, compute_distances_with, confidence_grade, dump_srt_batch,
+        error::ShowOrderError,
+        escape_csv_field, export_srt_for_batch, flatten_subtitles_n, format_utc_date,
+        gif_frame_delays, load_file_subtitles,
+        mkv::LoadOptions,
+        open_output_folder, parse_manifest, parse_raw_image_size, process_input_path,
+        process_reference_path, quote_for_bash, quote_for_powershell, sort_distances,
+        string::NormalizationStrategy,
+        summarize_regions,
+        test_utils::{make_mkv_with_pgs_subtitles, make_mkv_with_pgs_subtitles_in},
+        text_length_histogram,
+        vob::SubtitleRegion,
+        write_powershell_rename_script, BatchOutcome, ConfidenceReport, DistanceOptions,
+        FileMapping, MatchCriterion, ReferenceOptions, DEFAULT_GIF_FRAME_DELAY,
+        DEFAULT_POSITIONED_PNG_NAME_PATTERN,
+    };
 
     #[test]
+    #[ignore = "needs the sample files in data/popeye and the Windows OCR engine"]
     fn popeye_basic_pgs() -> Result<()> {
         popeye_basic_subfolder(5, "pgs")
     }
 
     #[test]
+    #[ignore = "needs the sample files in data/popeye and the Windows OCR engine"]
     fn popeye_match_pgs() -> Result<()> {
         popeye_match_subfolder(5, "pgs")
     }
 
     #[test]
+    #[ignore = "needs the sample files in data/popeye and the Windows OCR engine"]
     fn popeye_basic_vob() -> Result<()> {
         popeye_basic_subfolder(5, "vob")
     }
 
     #[test]
+    #[ignore = "needs the sample files in data/popeye and the Windows OCR engine"]
     fn popeye_match_vob() -> Result<()> {
         popeye_match_subfolder(5, "vob")
     }
@@ -569,12 +2633,14 @@ mod test {
     fn popeye_basic_subfolder(num_subtitles: usize, subfolder: &str) -> Result<()> {
         let subtitles = process_input_path(
             &format!("data/popeye/mkv/{}", subfolder),
-            num_subtitles,
-            None,
+            &LoadOptions {
+                num_subtitles,
+                ..Default::default()
+            },
+            false,
         )?;
-        let mut subtitles = flatten_subtitles(&subtitles);
+        let subtitles = flatten_subtitles_n(&subtitles, num_subtitles);
         assert_eq!(subtitles.len(), 4);
-        subtitles.sort_by(|(file1, _), (file2, _)| file1.cmp(file2));
         let subtitles = subtitles
             .iter()
             .map(|(file, subtitle)| {
@@ -587,16 +2653,16 @@ mod test {
         // TODO: Reconcile ocr differences between test data
         match subfolder {
             "pgs" => {
-                assert_eq!(iter.next(), Some(&("Title T00-1.mkv", "oh oh wwhat happened ohh let me go let me go let me go nonono dont drop me now oh man the lifeboats")));
-                assert_eq!(iter.next(), Some(&("Title T01-2.mkv", "whos the most phenominal extra ordinary fellow yous sinbad the sailor how do you like that stooges on one of my travels i ran into this now there was a thrill id be sorry to miss")));
-                assert_eq!(iter.next(), Some(&("Title T02-3.mkv", "woah whats this hey let me down you big overgrown canary what are you doing taking me for a ride or something come back to me there you are with gravy")));
-                assert_eq!(iter.next(), Some(&("Title T03-4.mkv", "im sinbad the sailor so hearty and hale i live on an island on the back ofa whale its a whale of an island thats not a bad joke its lord and its master is this handsom bloke")));
+                assert_eq!(iter.next(), Some(&("Title T00-1.mkv", "oh oh wwhat happened ohh let me go let me go let me go nonono don't drop me now oh man the lifeboats")));
+                assert_eq!(iter.next(), Some(&("Title T01-2.mkv", "who's the most phenominal extra ordinary fellow yous sinbad the sailor how do you like that stooges on one of my travels i ran into this now there was a thrill i'd be sorry to miss")));
+                assert_eq!(iter.next(), Some(&("Title T02-3.mkv", "woah what's this hey let me down you big overgrown canary what are you doing taking me for a ride or something come back to me there you are with gravy")));
+                assert_eq!(iter.next(), Some(&("Title T03-4.mkv", "i'm sinbad the sailor so hearty and hale i live on an island on the back ofa whale it's a whale of an island that's not a bad joke its lord and its master is this handsom bloke")));
             }
             "vob" => {
-                assert_eq!(iter.next(), Some(&("Title T00-1.mkv", "ohl ohl w what happened ohh let me go let me go let me go nonono dont drop me now oh man the lifeboats")));
-                assert_eq!(iter.next(), Some(&("Title T01-2.mkv", "whos the most phenom inal extra ordinary how do you like that stooges on one of my travels i ran into this now there was a thrill id be sorry to miss spread out his wings and the sunlight grew dim")));
-                assert_eq!(iter.next(), Some(&("Title T02-3.mkv", "woah whats this hey let me down you big overgrown canary what are you doing taking me for a ride or something there you are with gravy laughter")));
-                assert_eq!(iter.next(), Some(&("Title T03-4.mkv", "i m sinbad the sailor so hearty and i live on an island on the back of a thats not a bad joke its lord and its master is this handsom bloke whos the most remarkable extraordinary")));
+                assert_eq!(iter.next(), Some(&("Title T00-1.mkv", "ohl ohl w what happened ohh let me go let me go let me go nonono don't drop me now oh man the lifeboats")));
+                assert_eq!(iter.next(), Some(&("Title T01-2.mkv", "who's the most phenom inal extra ordinary how do you like that stooges on one of my travels i ran into this now there was a thrill i'd be sorry to miss spread out his wings and the sunlight grew dim")));
+                assert_eq!(iter.next(), Some(&("Title T02-3.mkv", "woah what's this hey let me down you big overgrown canary what are you doing taking me for a ride or something there you are with gravy laughter")));
+                assert_eq!(iter.next(), Some(&("Title T03-4.mkv", "i m sinbad the sailor so hearty and i live on an island on the back of a that's not a bad joke its lord and its master is this handsom bloke who's the most remarkable extraordinary")));
             }
             _ => panic!("Unknown subfolder!"),
         }
@@ -607,14 +2673,33 @@ mod test {
     fn popeye_match_subfolder(num_subtitles: usize, subfolder: &str) -> Result<()> {
         let subtitles = process_input_path(
             &format!("data/popeye/mkv/{}", subfolder),
-            num_subtitles,
-            None,
+            &LoadOptions {
+                num_subtitles,
+                ..Default::default()
+            },
+            false,
+        )?;
+        let subtitles = flatten_subtitles_n(&subtitles, num_subtitles);
+        let ref_subtitles = process_reference_path(
+            "data/popeye/srt",
+            &ReferenceOptions {
+                num_subtitles,
+                ..Default::default()
+            },
         )?;
-        let subtitles = flatten_subtitles(&subtitles);
-        let ref_subtitles = process_reference_path("data/popeye/srt", num_subtitles)?;
-        let ref_subtitles = flatten_subtitles(&ref_subtitles);
+        let ref_subtitles = flatten_subtitles_n(&ref_subtitles, num_subtitles);
 
-        let distances = compute_distances(&subtitles, &ref_subtitles);
+        let distances = compute_distances(
+            &subtitles,
+            &ref_subtitles,
+            DistanceOptions {
+                metric: Metric::Levenshtein,
+                normalization: NormalizationStrategy::default(),
+                windowed: None,
+                align: false,
+            },
+            MatchOutputFormat::Human,
+        );
         let closest: HashMap<_, _> = distances
             .iter()
             .map(|(file, distances)| {
@@ -644,4 +2729,451 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn confidence_grade_test() {
+        let thresholds: GradeThresholds = "0.05,0.15,0.30,0.50".parse().unwrap();
+        assert_eq!(confidence_grade(0, 100, &thresholds), 'A');
+        assert_eq!(confidence_grade(4, 100, &thresholds), 'A');
+        assert_eq!(confidence_grade(5, 100, &thresholds), 'B');
+        assert_eq!(confidence_grade(20, 100, &thresholds), 'C');
+        assert_eq!(confidence_grade(40, 100, &thresholds), 'D');
+        assert_eq!(confidence_grade(50, 100, &thresholds), 'F');
+        assert_eq!(confidence_grade(0, 0, &thresholds), 'A');
+        assert_eq!(confidence_grade(1, 0, &thresholds), 'F');
+
+        assert!("0.1,0.2,0.3".parse::<GradeThresholds>().is_err());
+        assert!("0.5,0.2,0.3,0.4".parse::<GradeThresholds>().is_err());
+    }
+
+    #[test]
+    fn confidence_report_test() {
+        let mapping = |mkv_path: &str, ref_file: &str, distance| FileMapping {
+            mkv_path: mkv_path.to_owned(),
+            ref_file: ref_file.to_owned(),
+            distance,
+            criterion: MatchCriterion::Distance,
+            grade: None,
+        };
+        let mappings = vec![
+            mapping("dir/file1.mkv", "dir/a.srt", 42),
+            mapping("dir/file2.mkv", "dir/a.srt", 45),
+            mapping("dir/file3.mkv", "dir/b.srt", 3),
+        ];
+        let report = ConfidenceReport::new(&mappings, 3, None);
+        assert!(!report.is_high_confidence());
+        assert_eq!(
+            report.explain(),
+            "Low confidence because: a.srt was matched to both file1.mkv (distance 42) and file2.mkv (distance 45)."
+        );
+
+        let report = ConfidenceReport::new(&mappings[1..], 4, Some(50));
+        assert!(report.is_high_confidence());
+        assert_eq!(
+            report.explain(),
+            "High confidence because: (1) No reference file was matched to multiple MKV files. (2) All distances below threshold (50). (3) 2 of 4 MKV files were mapped."
+        );
+    }
+
+    #[test]
+    fn parse_manifest_test() {
+        let data = r#"[
+            {"path": "S01E01.mkv", "srt": "srt/episode 1.srt"},
+            {"path": "/media/S01E02.mkv", "srt": "srt/episode 2.srt"}
+        ]"#;
+        let entries = parse_manifest(data, Path::new("library")).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].mkv_path, Path::new("library/S01E01.mkv"));
+        assert_eq!(entries[0].srt_path, Path::new("library/srt/episode 1.srt"));
+        assert_eq!(entries[1].mkv_path, Path::new("/media/S01E02.mkv"));
+
+        assert!(parse_manifest(r#"{"path": "a.mkv"}"#, Path::new("")).is_err());
+        assert!(parse_manifest(r#"[{"path": "a.mkv"}]"#, Path::new("")).is_err());
+        assert!(parse_manifest("[", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn flatten_subtitles_n_test() {
+        let files = vec![(
+            "a.mkv".to_owned(),
+            vec!["hello there".to_owned(), "general kenobi".to_owned()],
+        )];
+        let ref_files = vec![(
+            "a.srt".to_owned(),
+            vec!["hello there".to_owned(), "you are a bold one".to_owned()],
+        )];
+        assert_eq!(
+            flatten_subtitles_n(&files, 1),
+            vec![("a.mkv".to_owned(), "hello there".to_owned())]
+        );
+        assert_eq!(
+            flatten_subtitles_n(&files, 5),
+            vec![("a.mkv".to_owned(), "hello there general kenobi".to_owned())]
+        );
+
+        // Files with fewer subtitles limit the others
+        let uneven_files = vec![
+            (
+                "a.mkv".to_owned(),
+                vec!["one".to_owned(), "two".to_owned(), "three".to_owned()],
+            ),
+            (
+                "b.mkv".to_owned(),
+                vec!["four".to_owned(), "five".to_owned()],
+            ),
+            ("c.mkv".to_owned(), Vec::new()),
+        ];
+        assert_eq!(
+            flatten_subtitles_n(&uneven_files, 5),
+            vec![
+                ("a.mkv".to_owned(), "one two".to_owned()),
+                ("b.mkv".to_owned(), "four five".to_owned()),
+                ("c.mkv".to_owned(), "".to_owned()),
+            ]
+        );
+        assert_eq!(flatten_subtitles_n(&uneven_files, 1)[1].1, "four");
+
+        let distance = |n| {
+            let distances = compute_distances(
+                &flatten_subtitles_n(&files, n),
+                &flatten_subtitles_n(&ref_files, n),
+                DistanceOptions {
+                    metric: Metric::Levenshtein,
+                    normalization: NormalizationStrategy::default(),
+                    windowed: None,
+                    align: false,
+                },
+                MatchOutputFormat::Human,
+            );
+            distances["a.mkv"][0].1
+        };
+        assert_eq!(distance(1), 0);
+        assert!(distance(2) > 0);
+    }
+
+    #[test]
+    fn escape_csv_field_test() {
+        assert_eq!(escape_csv_field("eng"), "eng");
+        assert_eq!(escape_csv_field("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn format_utc_date_test() {
+        assert_eq!(format_utc_date(Duration::from_secs(0)), "1970-01-01");
+        assert_eq!(
+            format_utc_date(Duration::from_secs(951_782_400)),
+            "2000-02-29"
+        );
+        assert_eq!(
+            format_utc_date(Duration::from_secs(1_704_067_199)),
+            "2023-12-31"
+        );
+    }
+
+    #[test]
+    fn quote_for_powershell_test() {
+        assert_eq!(quote_for_powershell("S01E01.mkv"), "'S01E01.mkv'");
+        assert_eq!(quote_for_powershell("Bob's Show.mkv"), "'Bob''s Show.mkv'");
+        assert_eq!(
+            quote_for_powershell("Bob\u{2019}s Show.mkv"),
+            "'Bob\u{2019}\u{2019}s Show.mkv'"
+        );
+        // Nothing special happens to $ or ` inside single quotes
+        assert_eq!(quote_for_powershell("$100 `n.mkv"), "'$100 `n.mkv'");
+
+        let mapping = FileMapping {
+            mkv_path: "Title T00-1.mkv".to_owned(),
+            ref_file: "Pokémon S01E01.eng.srt".to_owned(),
+            distance: 0,
+            criterion: MatchCriterion::Distance,
+            grade: None,
+        };
+        let mut script = Vec::new();
+        write_powershell_rename_script(&mut script, &[mapping]);
+        assert_eq!(
+            String::from_utf8(script).unwrap(),
+            "$OutputEncoding = [System.Text.Encoding]::UTF8\n\
+             Rename-Item -Path 'Title T00-1.mkv' -NewName 'Pokémon S01E01.mkv'\n"
+        );
+    }
+
+    #[test]
+    fn quote_for_bash_test() {
+        assert_eq!(quote_for_bash("S01E01.mkv"), "'S01E01.mkv'");
+        assert_eq!(quote_for_bash("Bob's Show.mkv"), "'Bob'\\''s Show.mkv'");
+    }
+
+    #[test]
+    fn sort_distances_test() {
+        let distances = HashMap::from([
+            (
+                "b.mkv".to_owned(),
+                vec![("y.srt".to_owned(), 1), ("x.srt".to_owned(), 1)],
+            ),
+            ("a.mkv".to_owned(), vec![("x.srt".to_owned(), 5)]),
+            ("c.mkv".to_owned(), vec![("z.srt".to_owned(), 3)]),
+        ]);
+        let order = |sort_by, seed| {
+            sort_distances(&distances, sort_by, seed)
+                .iter()
+                .map(|(mkv_path, _)| mkv_path.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(order(SortBy::Filename, 0), vec!["a.mkv", "b.mkv", "c.mkv"]);
+        assert_eq!(order(SortBy::Distance, 0), vec!["b.mkv", "c.mkv", "a.mkv"]);
+        assert_eq!(order(SortBy::Random, 7), order(SortBy::Random, 7));
+
+        // Ties are broken by the reference file name
+        let sorted = sort_distances(&distances, SortBy::Distance, 0);
+        let (_, file_distances) = &sorted[0];
+        assert_eq!(file_distances[0].0, "x.srt");
+        assert_eq!(file_distances[1].0, "y.srt");
+    }
+
+    #[test]
+    fn compute_distances_test() {
+        let subtitles = vec![
+            ("a.mkv".to_owned(), "hello".to_owned()),
+            ("b.mkv".to_owned(), "".to_owned()),
+        ];
+        let ref_subtitles = vec![
+            ("different.srt".to_owned(), "vwxyz".to_owned()),
+            ("one_off.srt".to_owned(), "hallo".to_owned()),
+            ("identical.srt".to_owned(), "hello".to_owned()),
+            ("empty.srt".to_owned(), "".to_owned()),
+        ];
+        let distances = compute_distances(
+            &subtitles,
+            &ref_subtitles,
+            DistanceOptions {
+                metric: Metric::Levenshtein,
+                normalization: NormalizationStrategy::TruncateToShortest,
+                windowed: None,
+                align: false,
+            },
+            MatchOutputFormat::Human,
+        );
+        assert_eq!(distances.len(), 2);
+
+        // Sorted by distance, ties keep the order of the reference files.
+        // Truncating to the shortest string means an empty one matches anything.
+        assert_eq!(
+            distances["a.mkv"],
+            vec![
+                ("identical.srt".to_owned(), 0),
+                ("empty.srt".to_owned(), 0),
+                ("one_off.srt".to_owned(), 1),
+                ("different.srt".to_owned(), 5),
+            ]
+        );
+        assert!(distances["b.mkv"]
+            .iter()
+            .all(|(_, distance)| *distance == 0));
+        assert_eq!(distances["b.mkv"].len(), ref_subtitles.len());
+    }
+
+    #[test]
+    fn compute_distances_with_test() {
+        let subtitles = vec![("a.mkv".to_owned(), "abc".to_owned())];
+        let ref_subtitles = vec![
+            ("x.srt".to_owned(), "abcdef".to_owned()),
+            ("y.srt".to_owned(), "abcd".to_owned()),
+        ];
+        let distances = compute_distances_with(
+            &subtitles,
+            &ref_subtitles,
+            MatchOutputFormat::Human,
+            |a, b| a.len().abs_diff(b.len()),
+        );
+        assert_eq!(
+            distances["a.mkv"],
+            vec![("y.srt".to_owned(), 1), ("x.srt".to_owned(), 3)]
+        );
+    }
+
+    #[test]
+    fn text_length_histogram_test() {
+        let buckets = text_length_histogram(&[0, 0, 1, 10, 11, 45, 90, 91, 500]);
+        assert_eq!(buckets, [2, 2, 1, 0, 0, 1, 0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn summarize_regions_test() {
+        let region = |x, y, width, height| SubtitleRegion {
+            x,
+            y,
+            width,
+            height,
+        };
+        let regions = [
+            region(100, 400, 300, 40),
+            region(120, 410, 250, 40),
+            region(90, 20, 320, 80),
+        ];
+        let (typical, bounds) = summarize_regions(&regions).unwrap();
+        assert_eq!(typical, region(100, 400, 300, 40));
+        assert_eq!(bounds, region(90, 20, 320, 430));
+        assert_eq!(summarize_regions(&[]), None);
+    }
+
+    #[test]
+    fn name_pattern_test() {
+        let pattern = "{track}_{index}_{timestamp_ms}.png"
+            .parse::<NamePattern>()
+            .unwrap();
+        let values = NamePatternValues {
+            index: 2,
+            track: 3,
+            timestamp_ms: 1500,
+            width: 640,
+            height: 80,
+            x: 40,
+            y: 400,
+        };
+        assert_eq!(pattern.format(&values), "3_2_1500.png");
+        let pattern = "{index}size{width}x{height}.bin"
+            .parse::<NamePattern>()
+            .unwrap();
+        assert_eq!(pattern.format(&values), "2size640x80.bin");
+        let pattern = DEFAULT_POSITIONED_PNG_NAME_PATTERN
+            .parse::<NamePattern>()
+            .unwrap();
+        assert_eq!(pattern.format(&values), "2at40x400.png");
+
+        assert!("{frame}.png".parse::<NamePattern>().is_err());
+        assert!("{index.png".parse::<NamePattern>().is_err());
+        assert!("index}.png".parse::<NamePattern>().is_err());
+    }
+
+    #[test]
+    fn gif_frame_delays_test() {
+        let delays = gif_frame_delays(&[Some(1000), Some(2500), Some(2510), Some(4000)]);
+        assert_eq!(delays, vec![150, 1, 149, DEFAULT_GIF_FRAME_DELAY]);
+        // Missing or out of order timestamps fall back to the default
+        let delays = gif_frame_delays(&[Some(1000), None, Some(3000), Some(2000)]);
+        assert_eq!(
+            delays,
+            vec![
+                DEFAULT_GIF_FRAME_DELAY,
+                DEFAULT_GIF_FRAME_DELAY,
+                DEFAULT_GIF_FRAME_DELAY,
+                DEFAULT_GIF_FRAME_DELAY
+            ]
+        );
+        assert!(gif_frame_delays(&[]).is_empty());
+    }
+
+    #[test]
+    fn missing_output_folder_test() {
+        let error = open_output_folder("data/missing-output-folder")
+            .err()
+            .unwrap();
+        assert!(error
+            .message()
+            .to_string()
+            .contains("missing-output-folder"));
+    }
+
+    #[test]
+    fn missing_srt_batch_input_folder_test() {
+        let error = dump_srt_batch(
+            "data/missing-input-folder",
+            "data/missing-output-folder",
+            &LoadOptions::default(),
+            2000,
+            false,
+        )
+        .err()
+        .unwrap();
+        assert!(error.message().to_string().contains("missing-input-folder"));
+        assert!(!Path::new("data/missing-output-folder").exists());
+    }
+
+    #[test]
+    fn srt_batch_overwrite_test() {
+        let folder = std::env::temp_dir().join("showorder_srt_batch_overwrite_test");
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        let output_path = folder.to_str().unwrap();
+        let load_options = LoadOptions {
+            num_subtitles: 5,
+            ..Default::default()
+        };
+
+        // The track doesn't have any subtitles, so nothing needs to be OCR'd
+        let mkv_path = folder.join("episode.mkv");
+        std::fs::write(&mkv_path, make_mkv_with_pgs_subtitles(&[])).unwrap();
+        let srt_path = folder.join("episode.srt");
+        std::fs::write(&srt_path, "existing").unwrap();
+        let outcome = export_srt_for_batch(&mkv_path, output_path, &load_options, 2000, false);
+        assert_eq!(outcome, BatchOutcome::Skipped);
+        assert_eq!(std::fs::read_to_string(&srt_path).unwrap(), "existing");
+        let outcome = export_srt_for_batch(&mkv_path, output_path, &load_options, 2000, true);
+        assert_eq!(outcome, BatchOutcome::Succeeded);
+        assert_eq!(std::fs::read_to_string(&srt_path).unwrap(), "");
+
+        // Files without English subtitles are skipped
+        let french_path = folder.join("french.mkv");
+        std::fs::write(&french_path, make_mkv_with_pgs_subtitles_in("fre", &[])).unwrap();
+        let outcome = export_srt_for_batch(&french_path, output_path, &load_options, 2000, false);
+        assert_eq!(outcome, BatchOutcome::Skipped);
+        assert!(!folder.join("french.srt").exists());
+
+        // Files that can't be read are failed
+        let invalid_path = folder.join("invalid.mkv");
+        std::fs::write(&invalid_path, b"not an mkv file").unwrap();
+        let outcome = export_srt_for_batch(&invalid_path, output_path, &load_options, 2000, false);
+        assert_eq!(outcome, BatchOutcome::Failed);
+        assert!(!folder.join("invalid.srt").exists());
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn malformed_input_files_test() -> Result<()> {
+        let folder = std::env::temp_dir().join("showorder_malformed_input_files_test");
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("garbage.mkv"), b"not an mkv file").unwrap();
+        // The PGS segment is cut off partway through
+        let truncated_segment: &[u8] = &[0x14, 0x00, 0x10, 0x00];
+        std::fs::write(
+            folder.join("truncated.mkv"),
+            make_mkv_with_pgs_subtitles(&[truncated_segment]),
+        )
+        .unwrap();
+        let load_options = LoadOptions {
+            num_subtitles: 5,
+            ..Default::default()
+        };
+        assert!(process_input_path(&folder, &load_options, false)?.is_empty());
+        let files = process_input_path(folder.join("truncated.mkv"), &load_options, false)?;
+        assert!(files.is_empty());
+
+        // Errors from the loader skip the file instead of failing everything
+        let path = folder.join("garbage.mkv");
+        let failing = |_: &Path| -> Result<Option<Vec<String>>> {
+            Err(ShowOrderError::InvalidMkvData("test".to_owned()).into())
+        };
+        assert!(load_file_subtitles(&path, &failing).is_none());
+        let loaded = load_file_subtitles(&path, &|_: &Path| -> Result<Option<Vec<i32>>> {
+            Ok(Some(vec![1]))
+        });
+        assert_eq!(loaded.map(|(_, subtitles)| subtitles), Some(vec![1]));
+
+        std::fs::remove_dir_all(&folder).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn raw_image_size_test() {
+        assert_eq!(parse_raw_image_size("2size640x80.bin"), Some((640, 80)));
+        assert_eq!(
+            parse_raw_image_size("2size640x80at40x400.bin"),
+            Some((640, 80))
+        );
+        assert_eq!(parse_raw_image_size("2.bin"), None);
+    }
 }
@@ -1,5 +1,21 @@
-use std::{convert::TryInto, fs::File, io::Read, path::Path};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Add,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
+use byteorder::ReadBytesExt;
+use flate2::read::ZlibDecoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 use webm_iterable::{
     matroska_spec::{Block, EbmlSpecification, MatroskaSpec},
     tags::{TagData, TagPosition},
@@ -11,10 +27,15 @@ use windows::{
 };
 
 use crate::{
-    image::{blend_with_color, scale_image},
-    pgs,
-    text::sanitize_text,
-    vob::{self, parse_idx},
+    error::ShowOrderError,
+    image::{
+        analyze_subtitle_image, blend_over_solid, compute_gradient, detect_subtitle_region,
+        rotate_180, scale_image, SubtitleImageMetrics, OCR_BACKGROUND_COLOR,
+    },
+    pgs, progress,
+    srt::format_srt_timestamp,
+    text::{check_banned_words, is_mixed_case, sanitize_sdh_text, sanitize_text},
+    vob::{self, parse_idx, PaletteFormat, SubtitleRegion, VobSubFile},
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -31,6 +52,11 @@ impl KnownLanguage {
         }
     }
 
+    // Used by tracks whose language wasn't set by the encoder
+    pub fn undetermined() -> KnownLanguage {
+        KnownLanguage::Unknown("und".to_owned())
+    }
+
     pub fn create_winrt_language(&self) -> Result<Option<Language>> {
         match self {
             KnownLanguage::English => Ok(Some(Language::CreateLanguage("en-US")?)),
@@ -58,17 +84,41 @@ pub enum KnownEncoding {
 }
 
 impl KnownEncoding {
-    pub fn from_tag_and_data(tag: &str, data: Option<&[u8]>) -> KnownEncoding {
+    pub fn from_tag_and_data(
+        tag: &str,
+        data: Option<&[u8]>,
+    ) -> std::result::Result<KnownEncoding, ShowOrderError> {
         match tag {
-            "S_HDMV/PGS" => KnownEncoding::PGS,
             "S_VOBSUB" => {
                 if let Some(data) = data {
-                    parse_idx(data)
+                    parse_idx(data, None).map_err(ShowOrderError::InvalidVobPrivateData)
                 } else {
-                    panic!("Expected private data for VOB subtitles!");
+                    Err(ShowOrderError::InvalidMkvData(
+                        "missing private data for VOB subtitles".to_owned(),
+                    ))
                 }
             }
-            _ => KnownEncoding::Unknown(tag.to_owned()),
+            _ => {
+                Ok(Self::try_from_str(tag)
+                    .unwrap_or_else(|codec| KnownEncoding::Unknown(codec.tag)))
+            }
+        }
+    }
+
+    // Only handles encodings that can be identified by their codec id
+    // alone. VOB subtitles also need their idx data, see from_tag_and_data.
+    pub fn try_from_str(tag: &str) -> std::result::Result<KnownEncoding, UnsupportedCodec> {
+        match tag {
+            "S_HDMV/PGS" => Ok(KnownEncoding::PGS),
+            _ => Err(UnsupportedCodec::new(tag)),
+        }
+    }
+
+    // Like to_string, but explains why unknown encodings can't be used
+    pub fn description(&self) -> String {
+        match self {
+            KnownEncoding::Unknown(tag) => UnsupportedCodec::new(tag).to_string(),
+            _ => self.to_string().to_owned(),
         }
     }
 
@@ -81,136 +131,558 @@ impl KnownEncoding {
     }
 }
 
-#[derive(Clone)]
+// A subtitle codec that we can't OCR (yet)
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnsupportedCodec {
+    pub tag: String,
+    // Text subtitles don't need OCR, so they may be supported one day
+    pub is_text_based: bool,
+}
+
+impl UnsupportedCodec {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_owned(),
+            is_text_based: tag.starts_with("S_TEXT/") || tag == "S_SSA" || tag == "S_ASS",
+        }
+    }
+}
+
+impl std::fmt::Display for UnsupportedCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_text_based {
+            write!(f, "unsupported text codec: {}", self.tag)
+        } else {
+            write!(f, "unsupported codec: {}", self.tag)
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedCodec {}
+
+// Which track to use when more than one matches the language. Tracks
+// are considered in file order. LastFound keeps the behavior from before
+// this was configurable, FirstFound (and the fallback for the others)
+// uses the earliest match.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TrackPreference {
+    #[default]
+    LastFound,
+    FirstFound,
+    PreferPgs,
+    PreferVob,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ContentCompression {
+    Zlib,
+    HeaderStripping(Vec<u8>),
+}
+
+impl ContentCompression {
+    pub fn decompress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentCompression::Zlib => {
+                let mut decoder = ZlibDecoder::new(data);
+                let mut decompressed = Vec::new();
+                decoder.read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+            ContentCompression::HeaderStripping(header) => {
+                let mut decompressed = header.clone();
+                decompressed.extend_from_slice(data);
+                Ok(decompressed)
+            }
+        }
+    }
+}
+
+// Compressed data that can't be decompressed means the file is malformed
+fn decompress(
+    compression: &ContentCompression,
+    data: &[u8],
+    description: &str,
+) -> std::result::Result<Vec<u8>, ShowOrderError> {
+    compression.decompress(data).map_err(|error| {
+        ShowOrderError::InvalidMkvData(format!("could not decompress {}: {}", description, error))
+    })
+}
+
+// ContentEncodingScope flags
+const CONTENT_ENCODING_SCOPE_BLOCKS: u64 = 0x1;
+const CONTENT_ENCODING_SCOPE_PRIVATE: u64 = 0x2;
+
+#[derive(Clone, Debug)]
 pub struct TrackInfo {
     pub track_number: u64,
     pub encoding: KnownEncoding,
     pub language: KnownLanguage,
+    pub is_default: bool,
+    pub is_forced: bool,
+    pub name: Option<String>,
+    // The CodecID exactly as it appears in the file
+    pub codec_id: String,
+    // Compression applied to the block payloads of this track
+    pub compression: Option<ContentCompression>,
+    // The (decompressed) CodecPrivate data, e.g. the idx file of VOB
+    // subtitles. Kept around for debugging.
+    pub codec_private: Option<Vec<u8>>,
+}
+
+impl TrackInfo {
+    pub const FIELD_NAMES: [&'static str; 6] = [
+        "track_number",
+        "language",
+        "encoding",
+        "is_default",
+        "is_forced",
+        "codec_id",
+    ];
+
+    // A short name for the encoding, unlike KnownEncoding::to_string
+    // which returns the codec id.
+    pub fn encoding_name(&self) -> &'static str {
+        match &self.encoding {
+            KnownEncoding::PGS => "PGS",
+            KnownEncoding::VOB { .. } => "VOB",
+            KnownEncoding::Unknown(_) => "Unknown",
+        }
+    }
+
+    pub fn codec_private_bytes(&self) -> Option<&[u8]> {
+        self.codec_private.as_deref()
+    }
+}
+
+impl Serialize for TrackInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let [track_number, language, encoding, is_default, is_forced, codec_id] = Self::FIELD_NAMES;
+        let mut state = serializer.serialize_struct("TrackInfo", Self::FIELD_NAMES.len())?;
+        state.serialize_field(track_number, &self.track_number)?;
+        state.serialize_field(language, self.language.to_string())?;
+        state.serialize_field(encoding, self.encoding_name())?;
+        state.serialize_field(is_default, &self.is_default)?;
+        state.serialize_field(is_forced, &self.is_forced)?;
+        state.serialize_field(codec_id, &self.codec_id)?;
+        state.end()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Chapter {
+    pub start_ms: u64,
+    // Chapters without an explicit end run until the next chapter,
+    // or u64::MAX for the last chapter.
+    pub end_ms: u64,
+    pub title: Option<String>,
+}
+
+// Default TimecodeScale according to the mkv spec (1ms)
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+const EBML_HEADER_ID: u64 = 0x1A45DFA3;
+const EBML_VERSION_ID: u64 = 0x4286;
+const EBML_DOC_TYPE_ID: u64 = 0x4282;
+// The EBML header is tiny, anything bigger than this is garbage
+const MAX_EBML_HEADER_SIZE: u64 = 1024;
+
+const SEGMENT_ID: u64 = 0x18538067;
+const SEEK_HEAD_ID: u64 = 0x114D9B74;
+const SEEK_ID: u64 = 0x4DBB;
+const SEEK_ID_ID: u64 = 0x53AB;
+const SEEK_POSITION_ID: u64 = 0x53AC;
+const CLUSTER_ID: u64 = 0x1F43B675;
+const INFO_ID: u64 = 0x1549A966;
+const TRACKS_ID: u64 = 0x1654AE6B;
+const CHAPTERS_ID: u64 = 0x1043A770;
+// Usually only a Void element comes before the SeekHead
+const MAX_ELEMENTS_BEFORE_SEEK_HEAD: usize = 4;
+const MAX_SEEK_HEAD_SIZE: u64 = 64 * 1024;
+
+// The smallest a Block (with its headers) could reasonably be, used
+// to get an upper bound on the number of blocks in a file.
+const MIN_BLOCK_SIZE: u64 = 32;
+
+// Counts the bytes read from the source. WebmIterator doesn't give
+// its reader back, so the count is shared with whoever needs it.
+struct PositionReader<R: Read> {
+    inner: R,
+    position: Arc<AtomicU64>,
+}
+
+impl<R: Read> PositionReader<R> {
+    fn new(inner: R, position: u64) -> Self {
+        Self {
+            inner,
+            position: Arc::new(AtomicU64::new(position)),
+        }
+    }
+}
+
+impl<R: Read> Read for PositionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let num_read = self.inner.read(buf)?;
+        self.position.fetch_add(num_read as u64, Ordering::Relaxed);
+        Ok(num_read)
+    }
 }
 
 pub struct MkvFile<R: Read> {
-    mkv_iter: WebmIterator<R>,
+    mkv_iter: WebmIterator<PositionReader<R>>,
+    // How far into the source WebmIterator has read
+    position: Arc<AtomicU64>,
     track_infos: Vec<TrackInfo>,
+    chapters: Vec<Chapter>,
+    timestamp_scale: u64,
+    source_size: u64,
+    // Only known when opened from a path, used by reset
+    path: Option<PathBuf>,
+    config: ParseConfig,
 }
 
-impl<R: Read> MkvFile<R> {
-    pub fn new(source: R) -> Self {
-        let mut mkv_iter = WebmIterator::new(source, &[MatroskaSpec::TrackEntry]);
-        let mut track_infos = Vec::new();
-        // Read until we hit a Tracks tag. Technically this isn't
-        // correct, as tracks can be described at any time. However,
-        // the files we care about won't do that.
-        for tag in &mut mkv_iter {
-            let tag = tag.as_ref().unwrap();
-            if let Some(spec_tag) = &tag.spec_tag {
-                match spec_tag {
-                    MatroskaSpec::TrackEntry => {
-                        if let TagPosition::FullTag(_id, data) = &tag.tag {
-                            if let TagData::Master(children) = data {
-                                let is_subtitle_track = |tag: &(u64, TagData)| {
-                                    if MatroskaSpec::get_tag_id(&MatroskaSpec::TrackType) == tag.0 {
-                                        if let TagData::UnsignedInt(value) = tag.1 {
-                                            return value == 0x11;
-                                        }
-                                    }
-                                    false
-                                };
-
-                                if children.iter().any(is_subtitle_track) {
-                                    let mut track_number: Option<u64> = None;
-                                    let mut language: Option<String> = None;
-                                    let mut encoding: Option<String> = None;
-                                    let mut private_data: Option<&[u8]> = None;
-                                    for (id, data) in children {
-                                        if let Some((mkv_tag, _)) = MatroskaSpec::get_tag(*id) {
-                                            match mkv_tag {
-                                                MatroskaSpec::TrackNumber => {
-                                                    if let TagData::UnsignedInt(value) = &data {
-                                                        track_number = Some(*value);
-                                                    }
-                                                }
-                                                MatroskaSpec::Language => {
-                                                    // If language has a value, it must have been
-                                                    // from an IETF tag. That means we should ignore
-                                                    // this tag.
-                                                    if language.is_none() {
-                                                        if let TagData::Utf8(value) = &data {
-                                                            language = Some(value.clone());
-                                                        }
-                                                    }
-                                                }
-                                                MatroskaSpec::LanguageIETF => {
-                                                    if let TagData::Utf8(value) = &data {
-                                                        language = Some(value.clone());
-                                                    }
-                                                }
-                                                MatroskaSpec::CodecId => {
-                                                    if let TagData::Utf8(value) = &data {
-                                                        encoding = Some(value.clone());
-                                                    }
-                                                }
-                                                MatroskaSpec::CodecPrivate => {
-                                                    // VOB subtitles will have the idx file in the
-                                                    // private data according to the mkv spec.
-                                                    if let TagData::Binary(value) = &data {
-                                                        private_data = Some(value);
-                                                    }
-                                                }
-                                                _ => {}
-                                            }
-                                        }
-                                    }
-                                    if let Some(track_number) = track_number {
-                                        if let Some(language) = language {
-                                            let language = KnownLanguage::from_tag(&language);
-                                            if let Some(encoding) = encoding {
-                                                let encoding = KnownEncoding::from_tag_and_data(
-                                                    &encoding,
-                                                    private_data,
-                                                );
-                                                let track_info = TrackInfo {
-                                                    track_number,
-                                                    encoding,
-                                                    language,
-                                                };
-                                                track_infos.push(track_info);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    MatroskaSpec::Tracks => {
-                        if !track_infos.is_empty() {
-                            break;
-                        }
-                    }
-                    _ => {
-                        // Skip the tag
+// WebmIterator isn't Debug, so only what we've parsed so far and
+// how far into the source we are is printed.
+impl<R: Read> std::fmt::Debug for MkvFile<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MkvFile")
+            .field("path", &self.path)
+            .field("config", &self.config)
+            .field("track_infos", &self.track_infos)
+            .field("chapters", &self.chapters)
+            .field("timestamp_scale", &self.timestamp_scale)
+            .field("position", &self.position())
+            .field("source_size", &self.source_size)
+            .finish_non_exhaustive()
+    }
+}
+
+// Controls how an mkv file is parsed when it's opened
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ParseConfig {
+    // Some legacy encoders write TrackEntry elements inside of clusters.
+    // Normally we stop looking for tracks at the first cluster, this reads
+    // the entire file (and then rewinds for the blocks) instead.
+    pub scan_entire_file_for_tracks: bool,
+}
+
+// How long each phase of opening an mkv file took
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ParseTiming {
+    pub ebml_header_ms: u64,
+    // Finding and reading the tracks and chapters
+    pub track_scan_ms: u64,
+    pub total_ms: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OcrTiming {
+    pub total_ocr_ms: u64,
+    pub frames_processed: usize,
+}
+
+fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+impl<R: Read + Seek> MkvFile<R> {
+    // Files are opened by path everywhere else, see from_path_with_config
+    #[cfg(test)]
+    pub fn new(source: R) -> std::result::Result<Self, ShowOrderError> {
+        Self::new_timed_with_config(source, &ParseConfig::default()).map(|(file, _)| file)
+    }
+
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn new_with_config(
+        source: R,
+        config: &ParseConfig,
+    ) -> std::result::Result<Self, ShowOrderError> {
+        Self::new_timed_with_config(source, config).map(|(file, _)| file)
+    }
+
+    pub fn new_timed_with_config(
+        mut source: R,
+        config: &ParseConfig,
+    ) -> std::result::Result<(Self, ParseTiming), ShowOrderError> {
+        let total_start = Instant::now();
+        // Make sure this is actually an mkv file before handing it
+        // off to webm_iterable.
+        let start = source.stream_position()?;
+        let source_size = source.seek(SeekFrom::End(0))? - start;
+        source.seek(SeekFrom::Start(start))?;
+        let header_start = Instant::now();
+        validate_ebml_header(&mut source)?;
+        let ebml_header_ms = elapsed_ms(header_start);
+        source.seek(SeekFrom::Start(start))?;
+
+        let scan_start = Instant::now();
+        let mut metadata = MkvMetadata::default();
+        if config.scan_entire_file_for_tracks {
+            // This reads to the end of the file, so the blocks need a second pass
+            {
+                let mut metadata_iter = WebmIterator::new(
+                    &mut source,
+                    &[MatroskaSpec::TrackEntry, MatroskaSpec::ChapterAtom],
+                );
+                read_metadata(&mut metadata_iter, &mut metadata, true)?;
+            }
+            source.seek(SeekFrom::Start(start))?;
+        }
+        let source = PositionReader::new(source, 0);
+        let position = source.position.clone();
+        let mkv_iter = if config.scan_entire_file_for_tracks {
+            WebmIterator::new(source, &[])
+        } else {
+            let mut mkv_iter = WebmIterator::new(
+                source,
+                &[MatroskaSpec::TrackEntry, MatroskaSpec::ChapterAtom],
+            );
+            read_metadata(&mut mkv_iter, &mut metadata, false)?;
+            mkv_iter
+        };
+        let timing = ParseTiming {
+            ebml_header_ms,
+            track_scan_ms: elapsed_ms(scan_start),
+            total_ms: elapsed_ms(total_start),
+        };
+        let mut mkv = Self::from_parts(mkv_iter, position, metadata, source_size);
+        mkv.config = *config;
+        Ok((mkv, timing))
+    }
+}
+
+impl MkvFile<File> {
+    // Unlike new, this will use the SeekHead (if present) to jump
+    // directly to the metadata instead of scanning for it.
+    #[allow(dead_code)] // Commands pass --scan-entire-file along, see from_path_with_config
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::result::Result<Self, ShowOrderError> {
+        Self::from_path_with_config(path, &ParseConfig::default())
+    }
+
+    // The SeekHead only tells us where the Tracks element is, so
+    // it isn't used when scanning the entire file for tracks.
+    pub fn from_path_with_config<P: AsRef<Path>>(
+        path: P,
+        config: &ParseConfig,
+    ) -> std::result::Result<Self, ShowOrderError> {
+        Self::open_timed(path.as_ref(), config).map(|(file, _)| file)
+    }
+
+    // WebmIterator can't be cloned or rewound, so this opens the
+    // file again to get a fresh copy. Only works for files opened
+    // from a path.
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn reset(&self) -> std::result::Result<Self, ShowOrderError> {
+        if let Some(path) = &self.path {
+            Self::from_path_with_config(path, &self.config)
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the mkv file wasn't opened from a path",
+            )
+            .into())
+        }
+    }
+
+    fn open_timed(
+        path: &Path,
+        config: &ParseConfig,
+    ) -> std::result::Result<(Self, ParseTiming), ShowOrderError> {
+        let total_start = Instant::now();
+        let mut file = File::open(path)?;
+        let source_size = file.metadata()?.len();
+        let header_start = Instant::now();
+        validate_ebml_header(&mut file)?;
+        let ebml_header_ms = elapsed_ms(header_start);
+        let scan_start = Instant::now();
+        let seek_table = read_seek_table(&mut file)
+            .ok()
+            .flatten()
+            .filter(|seek_table| seek_table.positions.contains_key(&TRACKS_ID))
+            .filter(|_| !config.scan_entire_file_for_tracks);
+        if let Some(seek_table) = seek_table {
+            let mut metadata = MkvMetadata::default();
+            for id in [INFO_ID, TRACKS_ID, CHAPTERS_ID] {
+                if let Some(position) = seek_table.positions.get(&id) {
+                    let element_start = seek_table.segment_data_start + position;
+                    file.seek(SeekFrom::Start(element_start))?;
+                    if let Some((_, size)) = read_element_header(&mut file)? {
+                        let header_len = file.stream_position()? - element_start;
+                        file.seek(SeekFrom::Start(element_start))?;
+                        let mut mkv_iter = WebmIterator::new(
+                            (&mut file).take(header_len + size),
+                            &[MatroskaSpec::TrackEntry, MatroskaSpec::ChapterAtom],
+                        );
+                        read_metadata(&mut mkv_iter, &mut metadata, false)?;
                     }
                 }
             }
+
+            // The blocks still need to be read from the start of the segment
+            file.seek(SeekFrom::Start(seek_table.segment_data_start))?;
+            let file = PositionReader::new(file, seek_table.segment_data_start);
+            let position = file.position.clone();
+            let mkv_iter = WebmIterator::new(file, &[]);
+            let timing = ParseTiming {
+                ebml_header_ms,
+                track_scan_ms: elapsed_ms(scan_start),
+                total_ms: elapsed_ms(total_start),
+            };
+            let mut mkv = Self::from_parts(mkv_iter, position, metadata, source_size);
+            mkv.path = Some(path.to_owned());
+            mkv.config = *config;
+            Ok((mkv, timing))
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            let (mut mkv, mut timing) = Self::new_timed_with_config(file, config)?;
+            mkv.path = Some(path.to_owned());
+            timing.ebml_header_ms += ebml_header_ms;
+            timing.total_ms = elapsed_ms(total_start);
+            Ok((mkv, timing))
+        }
+    }
+}
+
+impl<R: Read> MkvFile<R> {
+    fn from_parts(
+        mkv_iter: WebmIterator<PositionReader<R>>,
+        position: Arc<AtomicU64>,
+        metadata: MkvMetadata,
+        source_size: u64,
+    ) -> Self {
+        let MkvMetadata {
+            track_infos,
+            mut chapters,
+            timestamp_scale,
+        } = metadata;
+
+        // Chapters without an end time run until the next chapter
+        let next_starts = chapters
+            .iter()
+            .skip(1)
+            .map(|chapter| chapter.start_ms)
+            .collect::<Vec<_>>();
+        for (chapter, next_start) in chapters.iter_mut().zip(next_starts) {
+            if chapter.end_ms == u64::MAX {
+                chapter.end_ms = next_start;
+            }
         }
 
         Self {
             mkv_iter,
+            position,
             track_infos,
+            chapters,
+            timestamp_scale,
+            source_size,
+            path: None,
+            config: ParseConfig::default(),
         }
     }
 
+    // How many bytes of the source have been read so far. This runs
+    // ahead of what's been parsed, as WebmIterator reads in chunks.
+    // Every source is opened through a PositionReader, so this is
+    // always known.
+    pub fn position(&self) -> Option<u64> {
+        Some(self.position.load(Ordering::Relaxed))
+    }
+
+    // The size of the source, measured when it was opened
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn file_size(&self) -> Option<u64> {
+        Some(self.source_size)
+    }
+
     pub fn tracks(&self) -> &Vec<TrackInfo> {
         &self.track_infos
     }
 
-    pub fn subtitle_iter(self, language: KnownLanguage) -> Result<Option<SubtitleIterator<R>>> {
-        // Find a suitable track
-        let mut track = None;
-        for track_info in &self.track_infos {
-            if track_info.language == language {
-                track = Some(track_info.clone());
+    pub fn chapters(&self) -> &Vec<Chapter> {
+        &self.chapters
+    }
+
+    // Re-reads the palettes of VOB tracks using the given format
+    // instead of the detected one.
+    pub fn set_vob_palette_format(
+        &mut self,
+        palette_format: PaletteFormat,
+    ) -> std::result::Result<(), ShowOrderError> {
+        for track_info in &mut self.track_infos {
+            if let (KnownEncoding::VOB { .. }, Some(idx)) =
+                (&track_info.encoding, &track_info.codec_private)
+            {
+                track_info.encoding = parse_idx(idx, Some(palette_format))
+                    .map_err(ShowOrderError::InvalidVobPrivateData)?;
             }
         }
+        Ok(())
+    }
+
+    // Finds the track picked on the command line, by name and then by
+    // number. If neither was given, select_default picks the track.
+    pub fn find_track<'a>(
+        &'a self,
+        track_number: Option<u64>,
+        track_name: Option<&str>,
+        select_default: impl FnOnce(&'a [TrackInfo]) -> Option<&'a TrackInfo>,
+    ) -> Option<&'a TrackInfo> {
+        if let Some(track_name) = track_name {
+            self.track_by_name(track_name)
+        } else if let Some(track_number) = track_number {
+            self.track_infos
+                .iter()
+                .find(|track_info| track_info.track_number == track_number)
+        } else {
+            select_default(&self.track_infos)
+        }
+    }
+
+    fn track_by_name(&self, name: &str) -> Option<&TrackInfo> {
+        let tracks = tracks_by_name(&self.track_infos, name);
+        if tracks.len() > 1 {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Multiple tracks match the name \"{}\", using track {}.",
+                    name, tracks[0].track_number
+                )
+            });
+        }
+        tracks.first().copied()
+    }
+
+    // A rough upper bound on the number of blocks in the given track,
+    // based only on the size of the file. Returns None if the track
+    // doesn't exist.
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn estimate_block_count(&self, track_number: u64) -> Option<usize> {
+        self.track_infos
+            .iter()
+            .find(|track_info| track_info.track_number == track_number)
+            .map(|_| (self.source_size / MIN_BLOCK_SIZE) as usize)
+    }
+
+    // Tries the language first, then tracks with an undetermined
+    // language, and then any subtitle track we can decode.
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn subtitle_iter_any(self, language: KnownLanguage) -> Result<Option<SubtitleIterator<R>>> {
+        let preference = TrackPreference::FirstFound;
+        let track = select_track(&self.track_infos, &language, preference)
+            .or_else(|| {
+                select_track(
+                    &self.track_infos,
+                    &KnownLanguage::undetermined(),
+                    preference,
+                )
+            })
+            .or_else(|| {
+                self.track_infos.iter().find(|track_info| {
+                    matches!(
+                        track_info.encoding,
+                        KnownEncoding::PGS | KnownEncoding::VOB { .. }
+                    )
+                })
+            })
+            .cloned();
         if let Some(track) = track {
             self.subtitle_iter_from_track_info(track)
         } else {
@@ -243,9 +715,14 @@ impl<R: Read> MkvFile<R> {
         let track_number = track_info.track_number;
         match &track_info.encoding {
             KnownEncoding::PGS | KnownEncoding::VOB { .. } => {
+                let compression = track_info.compression.clone();
+                let block_iter = BlockIterator::from_mkv(self, vec![(track_number, compression)]);
                 let subtitle_iter = SubtitleIterator {
                     track_info,
-                    block_iter: BlockIterator::from_webm(track_number, self.mkv_iter),
+                    block_iter,
+                    last_timestamp_ms: None,
+                    pending: VecDeque::new(),
+                    debug_logging: false,
                 };
                 Ok(Some(subtitle_iter))
             }
@@ -253,21 +730,6 @@ impl<R: Read> MkvFile<R> {
         }
     }
 
-    pub fn block_iter(self, language: KnownLanguage) -> Option<BlockIterator<R>> {
-        // Find a suitable track
-        let mut track = None;
-        for track_info in &self.track_infos {
-            if track_info.language == language {
-                track = Some(track_info.clone());
-            }
-        }
-        if let Some(track) = track {
-            Some(self.block_iter_from_track_info(track))
-        } else {
-            None
-        }
-    }
-
     pub fn block_iter_from_track_number(self, track_number: u64) -> Option<BlockIterator<R>> {
         // Find a suitable track
         let mut track = None;
@@ -284,147 +746,1515 @@ impl<R: Read> MkvFile<R> {
     }
 
     fn block_iter_from_track_info(self, track_info: TrackInfo) -> BlockIterator<R> {
-        let track_number = track_info.track_number;
-        BlockIterator::from_webm(track_number, self.mkv_iter)
+        BlockIterator::from_mkv(
+            self,
+            vec![(track_info.track_number, track_info.compression)],
+        )
+    }
+
+    // Reads the blocks of several tracks in a single pass over the file.
+    // Blocks are returned in file order, along with their track number.
+    // Track numbers that aren't in the file are ignored.
+    pub fn block_iter_multi(
+        self,
+        track_numbers: &[u64],
+    ) -> impl Iterator<Item = std::result::Result<(u64, Block), ShowOrderError>> {
+        let tracks = self
+            .track_infos
+            .iter()
+            .filter(|track_info| track_numbers.contains(&track_info.track_number))
+            .map(|track_info| (track_info.track_number, track_info.compression.clone()))
+            .collect();
+        BlockIterator::from_mkv(self, tracks)
+            .map(|result| result.map(|(block, _)| (block.track, block)))
+    }
+
+    // Counts the blocks in each track without decoding any of them. The
+    // scan stops early once max_scan_bytes of (decompressed) block data
+    // has been read.
+    pub fn count_blocks(
+        self,
+        max_scan_bytes: Option<u64>,
+    ) -> std::result::Result<BlockCounts, ShowOrderError> {
+        let track_numbers = self
+            .track_infos
+            .iter()
+            .map(|track_info| track_info.track_number)
+            .collect::<Vec<_>>();
+        let mut counts = HashMap::new();
+        let mut bytes_scanned = 0;
+        for result in self.block_iter_multi(&track_numbers) {
+            let (track_number, block) = result?;
+            bytes_scanned += block.payload.len() as u64;
+            *counts.entry(track_number).or_insert(0) += 1;
+            if let Some(max_scan_bytes) = max_scan_bytes {
+                if bytes_scanned >= max_scan_bytes {
+                    return Ok(BlockCounts {
+                        counts,
+                        complete: false,
+                    });
+                }
+            }
+        }
+        Ok(BlockCounts {
+            counts,
+            complete: true,
+        })
     }
 }
 
-pub struct BlockIterator<R: Read> {
-    track_number: u64,
-    mkv_iter: WebmIterator<R>,
+pub struct BlockCounts {
+    // Keyed by track number
+    pub counts: HashMap<u64, usize>,
+    // False if the scan stopped before the end of the file
+    pub complete: bool,
 }
 
-impl<R: Read> BlockIterator<R> {
-    pub fn from_webm(track_number: u64, mkv_iter: WebmIterator<R>) -> Self {
+struct MkvMetadata {
+    track_infos: Vec<TrackInfo>,
+    chapters: Vec<Chapter>,
+    timestamp_scale: u64,
+}
+
+impl Default for MkvMetadata {
+    fn default() -> Self {
         Self {
-            track_number,
-            mkv_iter,
+            track_infos: Vec::new(),
+            chapters: Vec::new(),
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
         }
     }
 }
 
-impl<R: Read> Iterator for BlockIterator<R> {
-    type Item = Block;
+fn read_metadata<R: Read>(
+    mkv_iter: &mut WebmIterator<R>,
+    metadata: &mut MkvMetadata,
+    scan_entire_file: bool,
+) -> std::result::Result<(), ShowOrderError> {
+    // Unless asked to scan the entire file, read until we hit the first
+    // Cluster tag. Technically this isn't correct, as tracks can be
+    // described at any time. However, most files won't do that.
+    for tag in mkv_iter {
+        let tag = tag.map_err(|error| ShowOrderError::InvalidMkvData(error.to_string()))?;
+        if let Some(spec_tag) = &tag.spec_tag {
+            match spec_tag {
+                MatroskaSpec::TimecodeScale => {
+                    if let TagPosition::FullTag(_id, TagData::UnsignedInt(value)) = &tag.tag {
+                        metadata.timestamp_scale = *value;
+                    }
+                }
+                MatroskaSpec::ChapterAtom => {
+                    if let TagPosition::FullTag(_id, TagData::Master(children)) = &tag.tag {
+                        if let Some(chapter) = parse_chapter_atom(children) {
+                            metadata.chapters.push(chapter);
+                        }
+                    }
+                }
+                MatroskaSpec::TrackEntry => {
+                    if let TagPosition::FullTag(_id, data) = &tag.tag {
+                        if let TagData::Master(children) = data {
+                            let is_subtitle_track = |tag: &(u64, TagData)| {
+                                if MatroskaSpec::get_tag_id(&MatroskaSpec::TrackType) == tag.0 {
+                                    if let TagData::UnsignedInt(value) = tag.1 {
+                                        return value == 0x11;
+                                    }
+                                }
+                                false
+                            };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        for tag in &mut self.mkv_iter {
-            let tag = tag.as_ref().unwrap();
-            if let Some(spec_tag) = &tag.spec_tag {
-                match spec_tag {
-                    MatroskaSpec::Block | MatroskaSpec::SimpleBlock => {
-                        if let TagPosition::FullTag(_id, tag) = tag.tag.clone() {
-                            let block: Block = tag.try_into().unwrap();
-                            if block.track == self.track_number {
-                                return Some(block);
+                            if children.iter().any(is_subtitle_track) {
+                                let mut track_number: Option<u64> = None;
+                                let mut language: Option<String> = None;
+                                let mut encoding: Option<String> = None;
+                                let mut private_data: Option<&[u8]> = None;
+                                // FlagDefault defaults to 1 according to the mkv spec
+                                let mut is_default = true;
+                                let mut is_forced = false;
+                                let mut name: Option<String> = None;
+                                let mut content_encoding: Option<(ContentCompression, u64)> = None;
+                                for (id, data) in children {
+                                    if let Some((mkv_tag, _)) = MatroskaSpec::get_tag(*id) {
+                                        match mkv_tag {
+                                            MatroskaSpec::TrackNumber => {
+                                                if let TagData::UnsignedInt(value) = &data {
+                                                    track_number = Some(*value);
+                                                }
+                                            }
+                                            MatroskaSpec::Language => {
+                                                // If language has a value, it must have been
+                                                // from an IETF tag. That means we should ignore
+                                                // this tag.
+                                                if language.is_none() {
+                                                    if let TagData::Utf8(value) = &data {
+                                                        language = Some(value.clone());
+                                                    }
+                                                }
+                                            }
+                                            MatroskaSpec::LanguageIETF => {
+                                                if let TagData::Utf8(value) = &data {
+                                                    language = Some(value.clone());
+                                                }
+                                            }
+                                            MatroskaSpec::CodecId => {
+                                                if let TagData::Utf8(value) = &data {
+                                                    encoding = Some(value.clone());
+                                                }
+                                            }
+                                            MatroskaSpec::CodecPrivate => {
+                                                // VOB subtitles will have the idx file in the
+                                                // private data according to the mkv spec.
+                                                if let TagData::Binary(value) = &data {
+                                                    private_data = Some(value);
+                                                }
+                                            }
+                                            MatroskaSpec::FlagDefault => {
+                                                if let TagData::UnsignedInt(value) = &data {
+                                                    is_default = *value == 1;
+                                                }
+                                            }
+                                            MatroskaSpec::FlagForced => {
+                                                if let TagData::UnsignedInt(value) = &data {
+                                                    is_forced = *value == 1;
+                                                }
+                                            }
+                                            MatroskaSpec::Name => {
+                                                if let TagData::Utf8(value) = &data {
+                                                    name = Some(value.clone());
+                                                }
+                                            }
+                                            MatroskaSpec::ContentEncodings => {
+                                                if let TagData::Master(encodings) = &data {
+                                                    content_encoding =
+                                                        parse_content_encodings(encodings);
+                                                }
+                                            }
+                                            _ => {}
+                                        }
+                                    }
+                                }
+                                if let Some(track_number) = track_number {
+                                    if let Some(language) = language {
+                                        let language = KnownLanguage::from_tag(&language);
+                                        if let Some(codec_id) = encoding {
+                                            let private_data =
+                                                match (&content_encoding, private_data) {
+                                                    (Some((compression, scope)), Some(data))
+                                                        if scope
+                                                            & CONTENT_ENCODING_SCOPE_PRIVATE
+                                                            != 0 =>
+                                                    {
+                                                        Some(decompress(
+                                                            compression,
+                                                            data,
+                                                            "private data",
+                                                        )?)
+                                                    }
+                                                    (_, data) => data.map(|data| data.to_vec()),
+                                                };
+                                            let encoding = KnownEncoding::from_tag_and_data(
+                                                &codec_id,
+                                                private_data.as_deref(),
+                                            )?;
+                                            let compression = content_encoding
+                                                .filter(|(_, scope)| {
+                                                    scope & CONTENT_ENCODING_SCOPE_BLOCKS != 0
+                                                })
+                                                .map(|(compression, _)| compression);
+                                            // The same track can be described more than once
+                                            if metadata.track_infos.iter().any(|track_info| {
+                                                track_info.track_number == track_number
+                                            }) {
+                                                continue;
+                                            }
+                                            let track_info = TrackInfo {
+                                                track_number,
+                                                encoding,
+                                                language,
+                                                is_default,
+                                                is_forced,
+                                                name,
+                                                codec_id,
+                                                compression,
+                                                codec_private: private_data,
+                                            };
+                                            metadata.track_infos.push(track_info);
+                                        }
+                                    }
+                                }
                             }
                         }
                     }
-                    _ => {}
+                }
+                MatroskaSpec::Cluster => {
+                    // Chapters are usually described after the tracks,
+                    // so we keep going until the blocks start.
+                    if !scan_entire_file && !metadata.track_infos.is_empty() {
+                        break;
+                    }
+                }
+                _ => {
+                    // Skip the tag
                 }
             }
         }
-        None
     }
+    Ok(())
 }
 
-pub struct SubtitleIterator<R: Read> {
-    track_info: TrackInfo,
-    block_iter: BlockIterator<R>,
+// Positions of top-level elements, relative to the start of the segment's data
+struct SeekTable {
+    segment_data_start: u64,
+    positions: HashMap<u64, u64>,
 }
 
-impl<R: Read> Iterator for SubtitleIterator<R> {
-    type Item = SoftwareBitmap;
+fn read_element_header<R: Read>(reader: &mut R) -> std::io::Result<Option<(u64, u64)>> {
+    if let Some(id) = read_vint(reader, true)? {
+        if let Some(size) = read_vint(reader, false)? {
+            return Ok(Some((id, size)));
+        }
+    }
+    Ok(None)
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        for block in &mut self.block_iter {
-            assert_eq!(block.track, self.track_info.track_number);
-            let bitmap = decode_bitmap(&block, &self.track_info).unwrap();
-            if bitmap.is_some() {
-                return bitmap;
+// Expects the reader to be positioned just after the EBML header
+fn read_seek_table<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<SeekTable>> {
+    match read_element_header(reader)? {
+        Some((SEGMENT_ID, _)) => {}
+        _ => return Ok(None),
+    }
+    let segment_data_start = reader.stream_position()?;
+
+    // The SeekHead should be one of the first elements in the segment
+    for _ in 0..MAX_ELEMENTS_BEFORE_SEEK_HEAD {
+        match read_element_header(reader)? {
+            Some((SEEK_HEAD_ID, size)) => {
+                if size > MAX_SEEK_HEAD_SIZE {
+                    return Ok(None);
+                }
+                let mut data = vec![0u8; size as usize];
+                reader.read_exact(&mut data)?;
+                let positions = parse_seek_head(&data)?;
+                return Ok(Some(SeekTable {
+                    segment_data_start,
+                    positions,
+                }));
+            }
+            Some((CLUSTER_ID, _)) | None => break,
+            Some((_, size)) => {
+                reader.seek(SeekFrom::Current(size as i64))?;
             }
         }
-        None
     }
+    Ok(None)
 }
 
-pub fn decode_bitmap(block: &Block, track_info: &TrackInfo) -> Result<Option<SoftwareBitmap>> {
+fn parse_seek_head(data: &[u8]) -> std::io::Result<HashMap<u64, u64>> {
+    let mut positions = HashMap::new();
+    let mut reader = std::io::Cursor::new(data);
+    while (reader.position() as usize) < data.len() {
+        let (id, size) = if let Some(header) = read_element_header(&mut reader)? {
+            header
+        } else {
+            break;
+        };
+        let mut element_data = vec![0u8; size as usize];
+        reader.read_exact(&mut element_data)?;
+        if id != SEEK_ID {
+            continue;
+        }
+
+        let mut seek_id = None;
+        let mut seek_position = None;
+        let mut seek_reader = std::io::Cursor::new(element_data.as_slice());
+        while (seek_reader.position() as usize) < element_data.len() {
+            let (id, size) = if let Some(header) = read_element_header(&mut seek_reader)? {
+                header
+            } else {
+                break;
+            };
+            let mut value = vec![0u8; size as usize];
+            seek_reader.read_exact(&mut value)?;
+            match id {
+                SEEK_ID_ID => seek_id = Some(read_uint(&value)),
+                SEEK_POSITION_ID => seek_position = Some(read_uint(&value)),
+                _ => {}
+            }
+        }
+        if let (Some(seek_id), Some(seek_position)) = (seek_id, seek_position) {
+            // Clusters can have multiple entries, we only care about the first
+            positions.entry(seek_id).or_insert(seek_position);
+        }
+    }
+    Ok(positions)
+}
+
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+fn tracks_by_name<'a>(track_infos: &'a [TrackInfo], name: &str) -> Vec<&'a TrackInfo> {
+    let name = name.to_lowercase();
+    track_infos
+        .iter()
+        .filter(|track_info| {
+            if let Some(track_name) = &track_info.name {
+                track_name.to_lowercase().contains(&name)
+            } else {
+                false
+            }
+        })
+        .collect()
+}
+
+fn read_vint<R: Read>(reader: &mut R, keep_marker: bool) -> std::io::Result<Option<u64>> {
+    let first = reader.read_u8()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return Ok(None);
+    }
+    let mut value = if keep_marker {
+        first as u64
+    } else {
+        first as u64 & (0xFF >> len)
+    };
+    for _ in 1..len {
+        value = (value << 8) | reader.read_u8()? as u64;
+    }
+    Ok(Some(value))
+}
+
+// Returns the EBMLVersion and DocType from the EBML header
+fn read_ebml_header<R: Read>(reader: &mut R) -> std::io::Result<(u64, String)> {
+    let invalid_data =
+        |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_owned());
+
+    if read_vint(reader, true)? != Some(EBML_HEADER_ID) {
+        return Err(invalid_data("missing EBML header"));
+    }
+    let size = read_vint(reader, false)?
+        .filter(|size| *size <= MAX_EBML_HEADER_SIZE)
+        .ok_or_else(|| invalid_data("invalid EBML header size"))?;
+    let mut data = vec![0u8; size as usize];
+    reader.read_exact(&mut data)?;
+
+    // Defaults according to the EBML spec
+    let mut version = 1;
+    let mut doc_type = "matroska".to_owned();
+    let mut header_reader = std::io::Cursor::new(data.as_slice());
+    while (header_reader.position() as usize) < data.len() {
+        let id = read_vint(&mut header_reader, true)?
+            .ok_or_else(|| invalid_data("invalid EBML element id"))?;
+        let size = read_vint(&mut header_reader, false)?
+            .filter(|size| *size <= MAX_EBML_HEADER_SIZE)
+            .ok_or_else(|| invalid_data("invalid EBML element size"))?;
+        let mut value = vec![0u8; size as usize];
+        header_reader.read_exact(&mut value)?;
+        match id {
+            EBML_VERSION_ID => {
+                version = read_uint(&value);
+            }
+            EBML_DOC_TYPE_ID => {
+                doc_type = String::from_utf8_lossy(&value)
+                    .trim_end_matches('\0')
+                    .to_owned();
+            }
+            _ => {}
+        }
+    }
+    Ok((version, doc_type))
+}
+
+fn validate_ebml_header<R: Read>(reader: &mut R) -> std::result::Result<(), ShowOrderError> {
+    let (version, doc_type) = match read_ebml_header(reader) {
+        Ok(header) => header,
+        Err(error) => {
+            return match error.kind() {
+                std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+                    Err(ShowOrderError::NotAnMkvFile(error.to_string()))
+                }
+                _ => Err(ShowOrderError::Io(error)),
+            };
+        }
+    };
+    if version > 1 {
+        return Err(ShowOrderError::NotAnMkvFile(format!(
+            "unsupported EBML version {}",
+            version
+        )));
+    }
+    if doc_type != "matroska" && doc_type != "webm" {
+        return Err(ShowOrderError::NotAnMkvFile(format!(
+            "unsupported DocType \"{}\"",
+            doc_type
+        )));
+    }
+    Ok(())
+}
+
+fn forced_tracks<'a>(track_infos: &'a [TrackInfo], language: &KnownLanguage) -> Vec<&'a TrackInfo> {
+    track_infos
+        .iter()
+        .filter(|track_info| track_info.is_forced && track_info.language == *language)
+        .collect()
+}
+
+pub fn select_track<'a>(
+    track_infos: &'a [TrackInfo],
+    language: &KnownLanguage,
+    preference: TrackPreference,
+) -> Option<&'a TrackInfo> {
+    let mut tracks = track_infos
+        .iter()
+        .filter(|track_info| track_info.language == *language);
+    if preference == TrackPreference::LastFound {
+        return tracks.last();
+    }
+    let is_preferred = |track_info: &&TrackInfo| match preference {
+        TrackPreference::LastFound | TrackPreference::FirstFound => true,
+        TrackPreference::PreferPgs => track_info.encoding == KnownEncoding::PGS,
+        TrackPreference::PreferVob => matches!(track_info.encoding, KnownEncoding::VOB { .. }),
+    };
+    tracks.clone().find(is_preferred).or_else(|| tracks.next())
+}
+
+// Returns the compression and its scope. Encryption isn't supported.
+fn parse_content_encodings(children: &[(u64, TagData)]) -> Option<(ContentCompression, u64)> {
+    for (id, data) in children {
+        if MatroskaSpec::get_tag_id(&MatroskaSpec::ContentEncoding) != *id {
+            continue;
+        }
+        if let TagData::Master(encoding_children) = data {
+            // Defaults according to the mkv spec
+            let mut encoding_type = 0;
+            let mut scope = CONTENT_ENCODING_SCOPE_BLOCKS;
+            let mut compression = None;
+            for (id, data) in encoding_children {
+                if let Some((mkv_tag, _)) = MatroskaSpec::get_tag(*id) {
+                    match mkv_tag {
+                        MatroskaSpec::ContentEncodingType => {
+                            if let TagData::UnsignedInt(value) = data {
+                                encoding_type = *value;
+                            }
+                        }
+                        MatroskaSpec::ContentEncodingScope => {
+                            if let TagData::UnsignedInt(value) = data {
+                                scope = *value;
+                            }
+                        }
+                        MatroskaSpec::ContentCompression => {
+                            if let TagData::Master(compression_children) = data {
+                                compression = parse_content_compression(compression_children);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if encoding_type == 0 {
+                if let Some(compression) = compression {
+                    return Some((compression, scope));
+                }
+            } else {
+                progress::suspend(|| println!("Warning! Encrypted tracks are not supported."));
+            }
+        }
+    }
+    None
+}
+
+fn parse_content_compression(children: &[(u64, TagData)]) -> Option<ContentCompression> {
+    // zlib is the default according to the mkv spec
+    let mut algorithm = 0;
+    let mut settings = Vec::new();
+    for (id, data) in children {
+        if let Some((mkv_tag, _)) = MatroskaSpec::get_tag(*id) {
+            match mkv_tag {
+                MatroskaSpec::ContentCompAlgo => {
+                    if let TagData::UnsignedInt(value) = data {
+                        algorithm = *value;
+                    }
+                }
+                MatroskaSpec::ContentCompSettings => {
+                    if let TagData::Binary(value) = data {
+                        settings = value.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    match algorithm {
+        0 => Some(ContentCompression::Zlib),
+        3 => Some(ContentCompression::HeaderStripping(settings)),
+        _ => {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Unsupported content compression algorithm: {}",
+                    algorithm
+                )
+            });
+            None
+        }
+    }
+}
+
+fn parse_chapter_atom(children: &[(u64, TagData)]) -> Option<Chapter> {
+    let mut start = None;
+    let mut end = None;
+    let mut title = None;
+    for (id, data) in children {
+        if let Some((mkv_tag, _)) = MatroskaSpec::get_tag(*id) {
+            match mkv_tag {
+                MatroskaSpec::ChapterTimeStart => {
+                    if let TagData::UnsignedInt(value) = data {
+                        start = Some(*value);
+                    }
+                }
+                MatroskaSpec::ChapterTimeEnd => {
+                    if let TagData::UnsignedInt(value) = data {
+                        end = Some(*value);
+                    }
+                }
+                MatroskaSpec::ChapterDisplay => {
+                    // Use the first display string we find
+                    if title.is_none() {
+                        if let TagData::Master(display_children) = data {
+                            for (id, data) in display_children {
+                                if MatroskaSpec::get_tag_id(&MatroskaSpec::ChapString) == *id {
+                                    if let TagData::Utf8(value) = data {
+                                        title = Some(value.clone());
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Unlike block timestamps, chapter timestamps are always in nanoseconds
+    let start_ms = start? / 1_000_000;
+    let end_ms = end.map(|end| end / 1_000_000).unwrap_or(u64::MAX);
+    Some(Chapter {
+        start_ms,
+        end_ms,
+        title,
+    })
+}
+
+// The timestamp of a cluster, in units of the segment's TimecodeScale.
+// Block timestamps are stored as an i16 offset relative to this.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClusterTimestamp(u64);
+
+// An absolute timestamp (cluster timestamp + block offset) in units
+// of the segment's TimecodeScale. The only way to build one from a
+// block is to add its offset to a ClusterTimestamp, so relative and
+// absolute timestamps can't be mixed up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MkvTimestamp(i64);
+
+impl Add<i16> for ClusterTimestamp {
+    type Output = MkvTimestamp;
+
+    fn add(self, block_offset: i16) -> MkvTimestamp {
+        let cluster_timestamp = i64::try_from(self.0).unwrap_or(i64::MAX);
+        MkvTimestamp(cluster_timestamp.saturating_add(block_offset as i64))
+    }
+}
+
+impl MkvTimestamp {
+    pub fn to_ms(&self, timestamp_scale: u64) -> f64 {
+        timescale_to_ms(self.0, timestamp_scale)
+    }
+}
+
+// Converts a timestamp in units of TimecodeScale (which is in ns) to ms
+pub fn timescale_to_ms(timestamp: i64, timescale_ns: u64) -> f64 {
+    // Stay in integer space for as long as we can to avoid losing precision
+    if let Some(timestamp_ns) = timestamp.checked_mul(timescale_ns as i64) {
+        timestamp_ns as f64 / 1_000_000.0
+    } else {
+        (timestamp as f64 * timescale_ns as f64) / 1_000_000.0
+    }
+}
+
+// The reverse of timescale_to_ms, rounded to the nearest tick
+#[allow(dead_code)] // Not used by any command yet
+pub fn ms_to_timescale(ms: f64, timescale_ns: u64) -> i64 {
+    ((ms * 1_000_000.0) / timescale_ns as f64).round() as i64
+}
+
+// Formats the timestamp as HH:MM:SS,mmm. Anything before the
+// start of the segment is clamped to 0.
+#[allow(dead_code)] // Not used by any command yet
+pub fn timescale_to_srt_timestamp(timestamp: i64, timescale_ns: u64) -> String {
+    format_srt_timestamp(timescale_to_ms(timestamp, timescale_ns).max(0.0) as u64)
+}
+
+pub struct BlockIterator<R: Read> {
+    // The tracks to return blocks from, and how their blocks are compressed
+    tracks: Vec<(u64, Option<ContentCompression>)>,
+    mkv_iter: WebmIterator<PositionReader<R>>,
+    position: Arc<AtomicU64>,
+    source_size: u64,
+    timestamp_scale: u64,
+    cluster_timestamp: ClusterTimestamp,
+    timestamp_range: Option<(u64, u64)>,
+    // Only every sample_rate-th block is returned
+    sample_rate: usize,
+    count: usize,
+}
+
+impl<R: Read> std::fmt::Debug for BlockIterator<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockIterator")
+            .field(
+                "track_numbers",
+                &self
+                    .tracks
+                    .iter()
+                    .map(|(track_number, _)| *track_number)
+                    .collect::<Vec<_>>(),
+            )
+            .field("blocks_processed", &self.count)
+            .field("cluster_timestamp", &self.cluster_timestamp)
+            .field("timestamp_range", &self.timestamp_range)
+            .field("sample_rate", &self.sample_rate)
+            .field("position", &self.position())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: Read> BlockIterator<R> {
+    fn from_mkv(mkv: MkvFile<R>, tracks: Vec<(u64, Option<ContentCompression>)>) -> Self {
+        Self {
+            tracks,
+            mkv_iter: mkv.mkv_iter,
+            position: mkv.position,
+            source_size: mkv.source_size,
+            timestamp_scale: mkv.timestamp_scale,
+            cluster_timestamp: ClusterTimestamp::default(),
+            timestamp_range: None,
+            sample_rate: 1,
+            count: 0,
+        }
+    }
+
+    // See MkvFile::position
+    pub fn position(&self) -> u64 {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.source_size
+    }
+
+    // The number of blocks read from the tracks so far, including
+    // the ones skipped because of the sample rate
+    pub fn blocks_processed(&self) -> usize {
+        self.count
+    }
+
+    // Only blocks with a timestamp in [start_ms, end_ms) will be returned.
+    pub fn with_timestamp_range(mut self, start_ms: u64, end_ms: u64) -> Self {
+        self.timestamp_range = Some((start_ms, end_ms));
+        self
+    }
+
+    // Returns the first block, then skips sample_rate - 1 blocks after
+    // each one that's returned. 0 and 1 both return every block.
+    pub fn with_sample_rate(mut self, sample_rate: usize) -> Self {
+        self.sample_rate = sample_rate.max(1);
+        self
+    }
+
+    // For callers that only need the (decompressed) block data
+    pub fn into_payload_iter(
+        self,
+    ) -> impl Iterator<Item = std::result::Result<Vec<u8>, ShowOrderError>> {
+        self.map(|result| result.map(|(block, _)| block.payload))
+    }
+}
+
+impl<R: Read> Iterator for BlockIterator<R> {
+    type Item = std::result::Result<(Block, MkvTimestamp), ShowOrderError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let invalid = |error: &dyn std::fmt::Display| {
+            Some(Err(ShowOrderError::InvalidMkvData(error.to_string())))
+        };
+        for tag in &mut self.mkv_iter {
+            let tag = match tag {
+                Ok(tag) => tag,
+                Err(error) => return invalid(&error),
+            };
+            if let Some(spec_tag) = &tag.spec_tag {
+                match spec_tag {
+                    MatroskaSpec::Timecode => {
+                        // Block timestamps are relative to their cluster
+                        if let TagPosition::FullTag(_id, TagData::UnsignedInt(value)) = &tag.tag {
+                            self.cluster_timestamp = ClusterTimestamp(*value);
+                        }
+                    }
+                    MatroskaSpec::Block | MatroskaSpec::SimpleBlock => {
+                        if let TagPosition::FullTag(_id, tag) = tag.tag.clone() {
+                            let mut block: Block = match tag.try_into() {
+                                Ok(block) => block,
+                                Err(error) => return invalid(&error),
+                            };
+                            let track = self
+                                .tracks
+                                .iter()
+                                .find(|(track_number, _)| *track_number == block.track);
+                            if let Some((_, compression)) = track {
+                                let timestamp = self.cluster_timestamp + block.value;
+                                if let Some((start_ms, end_ms)) = self.timestamp_range {
+                                    let timestamp_ms = timestamp.to_ms(self.timestamp_scale);
+                                    if timestamp_ms < start_ms as f64 {
+                                        continue;
+                                    }
+                                    // Clusters are in order, so there's nothing left for us
+                                    if timestamp_ms >= end_ms as f64 {
+                                        return None;
+                                    }
+                                }
+                                // Skipped blocks aren't decompressed
+                                let index = self.count;
+                                self.count += 1;
+                                if index % self.sample_rate != 0 {
+                                    continue;
+                                }
+                                if let Some(compression) = compression {
+                                    match decompress(compression, &block.payload, "block payload") {
+                                        Ok(payload) => block.payload = payload,
+                                        Err(error) => return Some(Err(error)),
+                                    }
+                                }
+                                return Some(Ok((block, timestamp)));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct SubtitleFrame {
+    pub bitmap: SoftwareBitmap,
+    // Where the subtitle is placed within the video frame, if the
+    // track says so (see pgs::PgsFrame).
+    pub region: Option<SubtitleRegion>,
+}
+
+pub struct SubtitleIterator<R: Read> {
+    track_info: TrackInfo,
+    block_iter: BlockIterator<R>,
+    last_timestamp_ms: Option<u64>,
+    // Subtitles (and their timestamps) that were decoded but not
+    // returned yet. A single PGS block can hold several subtitles.
+    pending: VecDeque<(SubtitleFrame, u64)>,
+    debug_logging: bool,
+}
+
+impl<R: Read> SubtitleIterator<R> {
+    pub fn with_debug_logging(self, debug_logging: bool) -> Self {
+        Self {
+            debug_logging,
+            ..self
+        }
+    }
+
+    pub fn with_timestamp_range(self, start_ms: u64, end_ms: u64) -> Self {
+        Self {
+            block_iter: self.block_iter.with_timestamp_range(start_ms, end_ms),
+            ..self
+        }
+    }
+
+    // See BlockIterator::with_sample_rate. Blocks are skipped before
+    // they're decoded, so a block with several subtitles counts once.
+    pub fn with_sample_rate(self, sample_rate: usize) -> Self {
+        Self {
+            block_iter: self.block_iter.with_sample_rate(sample_rate),
+            ..self
+        }
+    }
+
+    pub fn track_number(&self) -> u64 {
+        self.track_info.track_number
+    }
+
+    pub fn encoding_name(&self) -> &'static str {
+        self.track_info.encoding_name()
+    }
+
+    // See MkvFile::position
+    pub fn position(&self) -> u64 {
+        self.block_iter.position()
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.block_iter.file_size()
+    }
+
+    pub fn blocks_processed(&self) -> usize {
+        self.block_iter.blocks_processed()
+    }
+
+    // The timestamp of the last subtitle returned by next
+    pub fn last_timestamp_ms(&self) -> Option<u64> {
+        self.last_timestamp_ms
+    }
+
+    // Skips subtitles that don't overlap the given region
+    // of the video frame. See FilteredSubtitleIterator.
+    pub fn filter_by_region(self, region: SubtitleRegion) -> FilteredSubtitleIterator<R> {
+        FilteredSubtitleIterator {
+            iter: self,
+            region: Some(region),
+        }
+    }
+
+    // Decodes the next subtitle without advancing the iterator
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn peek(&mut self) -> Option<Result<&SubtitleFrame>> {
+        if self.pending.is_empty() {
+            if let Err(error) = self.decode_next() {
+                return Some(Err(error));
+            }
+        }
+        self.pending.front().map(|(frame, _)| Ok(frame))
+    }
+
+    // Decodes blocks until one of them has at least one subtitle,
+    // and queues up every subtitle from that block.
+    fn decode_next(&mut self) -> Result<()> {
+        let timestamp_scale = self.block_iter.timestamp_scale;
+        for result in &mut self.block_iter {
+            let (block, timestamp) = result?;
+            assert_eq!(block.track, self.track_info.track_number);
+            let frames = decode_frames(&block, &self.track_info, self.debug_logging)?;
+            if !frames.is_empty() {
+                let timestamp_ms = timestamp.to_ms(timestamp_scale).max(0.0) as u64;
+                self.pending
+                    .extend(frames.into_iter().map(|frame| (frame, timestamp_ms)));
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for SubtitleIterator<R> {
+    type Item = Result<SubtitleFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_empty() {
+            if let Err(error) = self.decode_next() {
+                return Some(Err(error));
+            }
+        }
+        let (frame, timestamp_ms) = self.pending.pop_front()?;
+        self.last_timestamp_ms = Some(timestamp_ms);
+        Some(Ok(frame))
+    }
+}
+
+// Subtitles without a region are never filtered out, as we
+// can't tell where they're placed.
+pub struct FilteredSubtitleIterator<R: Read> {
+    iter: SubtitleIterator<R>,
+    region: Option<SubtitleRegion>,
+}
+
+impl<R: Read> FilteredSubtitleIterator<R> {
+    pub fn encoding_name(&self) -> &'static str {
+        self.iter.encoding_name()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.iter.position()
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.iter.file_size()
+    }
+
+    pub fn blocks_processed(&self) -> usize {
+        self.iter.blocks_processed()
+    }
+
+    pub fn last_timestamp_ms(&self) -> Option<u64> {
+        self.iter.last_timestamp_ms()
+    }
+}
+
+// Doesn't filter anything
+impl<R: Read> From<SubtitleIterator<R>> for FilteredSubtitleIterator<R> {
+    fn from(iter: SubtitleIterator<R>) -> Self {
+        Self { iter, region: None }
+    }
+}
+
+impl<R: Read> Iterator for FilteredSubtitleIterator<R> {
+    type Item = Result<SubtitleFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let filter = self.region;
+        // Errors are passed along rather than filtered out
+        self.iter.find(|frame| match (&filter, frame) {
+            (
+                Some(filter),
+                Ok(SubtitleFrame {
+                    region: Some(region),
+                    ..
+                }),
+            ) => filter.intersects(region),
+            _ => true,
+        })
+    }
+}
+
+pub fn decode_frame(
+    block: &Block,
+    track_info: &TrackInfo,
+    debug_logging: bool,
+) -> Result<Option<SubtitleFrame>> {
     // We don't handle lacing
-    assert_eq!(block.lacing, None);
+    if block.lacing.is_some() {
+        return Err(
+            ShowOrderError::InvalidMkvData("laced blocks aren't supported".to_owned()).into(),
+        );
+    }
 
-    let bitmap = match &track_info.encoding {
-        KnownEncoding::PGS => pgs::parse_segments(&block.payload)?,
-        KnownEncoding::VOB { palette, .. } => vob::parse_block(&block.payload, &palette)?,
+    let frame = match &track_info.encoding {
+        KnownEncoding::PGS => {
+            pgs::parse_segments(&block.payload, debug_logging)?.map(|frame| SubtitleFrame {
+                bitmap: frame.bitmap,
+                region: frame.region,
+            })
+        }
+        KnownEncoding::VOB {
+            width,
+            height,
+            palette,
+        } => {
+            vob::parse_block(&block.payload, *width, *height, palette)?.map(|frame| SubtitleFrame {
+                bitmap: frame.bitmap,
+                region: Some(frame.region),
+            })
+        }
         _ => None,
     };
-    Ok(bitmap)
+    Ok(frame)
+}
+
+// Same as decode_frame, but returns every subtitle in the block
+pub fn decode_frames(
+    block: &Block,
+    track_info: &TrackInfo,
+    debug_logging: bool,
+) -> Result<Vec<SubtitleFrame>> {
+    match &track_info.encoding {
+        KnownEncoding::PGS => {
+            // We don't handle lacing
+            if block.lacing.is_some() {
+                return Err(ShowOrderError::InvalidMkvData(
+                    "laced blocks aren't supported".to_owned(),
+                )
+                .into());
+            }
+            let frames = pgs::parse_all_segments(&block.payload, debug_logging)?;
+            Ok(frames
+                .into_iter()
+                .map(|frame| SubtitleFrame {
+                    bitmap: frame.bitmap,
+                    region: frame.region,
+                })
+                .collect())
+        }
+        _ => Ok(decode_frame(block, track_info, debug_logging)?
+            .into_iter()
+            .collect()),
+    }
+}
+
+// How each subtitle image is prepared for OCR, and which results are kept
+#[derive(Clone, Debug, Default)]
+pub struct OcrOptions {
+    pub rotate_180: bool,
+    // Skip subtitle images that are blurrier than this, see image::compute_gradient
+    pub min_sharpness: Option<f32>,
+    pub no_sanitize: bool,
+    // Sanitize more aggressively for SDH subtitles
+    pub sdh: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    pub num_subtitles: usize,
+    // Skip this many subtitle blocks, minus one, after each one that's
+    // used. 0 and 1 both use every block.
+    pub sample_rate: usize,
+    pub track_number: Option<u64>,
+    pub track_name: Option<String>,
+    pub chapter: Option<usize>,
+    pub forced_only: bool,
+    pub track_preference: TrackPreference,
+    // Use tracks with an undetermined language if none match
+    pub fallback_to_und: bool,
+    pub parse_config: ParseConfig,
+    pub vob_palette_format: Option<PaletteFormat>,
+    // Skip subtitles positioned outside of this part of the video frame
+    pub subtitle_region: Option<SubtitleRegion>,
+    pub ocr: OcrOptions,
+    pub debug_logging: bool,
+    // Show how far into the file we are while running OCR
+    pub show_file_progress: bool,
+}
+
+// The result of running OCR on a single subtitle image
+#[derive(Clone, Debug, PartialEq)]
+pub enum OcrText {
+    Text(String),
+    Empty,
+    // Dropped because it contained the given banned word
+    Filtered(&'static str),
 }
 
 pub fn load_first_n_english_subtitles<P: AsRef<Path>>(
     path: P,
-    num_subtitles: usize,
-    track_number: Option<u64>,
+    options: &LoadOptions,
 ) -> Result<Option<Vec<String>>> {
-    load_first_n_subtitles(path, num_subtitles, track_number, KnownLanguage::English)
+    load_first_n_subtitles(path, options, KnownLanguage::English)
 }
 
 pub fn load_first_n_subtitles<P: AsRef<Path>>(
     path: P,
-    num_subtitles: usize,
-    track_number: Option<u64>,
+    options: &LoadOptions,
     language: KnownLanguage,
 ) -> Result<Option<Vec<String>>> {
+    load_first_n_subtitles_timed(path, options, language)
+        .map(|(loaded, _, _)| loaded.map(|loaded| loaded.subtitles))
+}
+
+// Subtitles read from a single track, along with the
+// short name of the track's encoding (e.g. "PGS").
+#[derive(Clone, Debug)]
+pub struct LoadedSubtitles {
+    pub subtitles: Vec<String>,
+    pub encoding: &'static str,
+}
+
+pub fn load_first_n_subtitles_timed<P: AsRef<Path>>(
+    path: P,
+    options: &LoadOptions,
+    language: KnownLanguage,
+) -> Result<(Option<LoadedSubtitles>, ParseTiming, OcrTiming)> {
     let winrt_language = language.create_winrt_language()?.unwrap();
+    let (iter, parse_timing) = open_subtitle_iter(path.as_ref(), options, language)?;
+    let engine = create_ocr_engine_with_fallback(&winrt_language)?;
+    if let Some(mut iter) = iter {
+        let (subtitles, ocr_timing) = get_first_n_subtitles(&mut iter, &engine, options)?;
+        let loaded = LoadedSubtitles {
+            subtitles,
+            encoding: iter.encoding_name(),
+        };
+        Ok((Some(loaded), parse_timing, ocr_timing))
+    } else {
+        Ok((None, parse_timing, OcrTiming::default()))
+    }
+}
 
-    let file = File::open(&path).unwrap();
-    let file = MkvFile::new(file);
-    let iter = if let Some(track_number) = track_number {
-        file.subtitle_iter_from_track_number(track_number)?
+// Like load_first_n_subtitles, but also returns the timestamp (in ms)
+// of each subtitle.
+pub fn load_first_n_subtitles_with_timestamps<P: AsRef<Path>>(
+    path: P,
+    options: &LoadOptions,
+    language: KnownLanguage,
+) -> Result<Option<Vec<(u64, String)>>> {
+    let winrt_language = language.create_winrt_language()?.unwrap();
+    let (iter, _) = open_subtitle_iter(path.as_ref(), options, language)?;
+    let engine = create_ocr_engine_with_fallback(&winrt_language)?;
+    if let Some(mut iter) = iter {
+        let mut subtitles = Vec::new();
+        let mut mixed_case = false;
+        while let Some(frame) = iter.next() {
+            let SubtitleFrame { bitmap, .. } = frame?;
+            if let OcrText::Text(text) = process_bitmap(&bitmap, &engine, options, &mut mixed_case)?
+            {
+                let timestamp_ms = iter.last_timestamp_ms().unwrap();
+                subtitles.push((timestamp_ms, text));
+                if subtitles.len() >= options.num_subtitles {
+                    break;
+                }
+            }
+        }
+        Ok(Some(subtitles))
     } else {
-        file.subtitle_iter(language)?
+        Ok(None)
+    }
+}
+
+// Like load_first_n_english_subtitles, but for a VobSub subtitle stream
+// stored as an idx and sub file pair instead of in an mkv file.
+pub fn load_first_n_idx_subtitles<P: AsRef<Path>>(
+    idx_path: P,
+    options: &LoadOptions,
+) -> Result<Option<Vec<String>>> {
+    let idx_path = idx_path.as_ref();
+    let sub_file = match VobSubFile::from_idx_path(idx_path, options.vob_palette_format) {
+        Ok(sub_file) => sub_file,
+        Err(error) => {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Could not open \"{}\": {} Skipping file...",
+                    idx_path.display(),
+                    error
+                )
+            });
+            return Ok(None);
+        }
     };
+    let winrt_language = KnownLanguage::English.create_winrt_language()?.unwrap();
+    let engine = create_ocr_engine_with_fallback(&winrt_language)?;
+    let mut subtitles = Vec::new();
+    let mut mixed_case = false;
+    for subtitle in sub_file.subtitle_iter().map_err(ShowOrderError::from)? {
+        let (_, bitmap) = subtitle?;
+        if let OcrText::Text(text) = process_bitmap(&bitmap, &engine, options, &mut mixed_case)? {
+            subtitles.push(text);
+            if subtitles.len() >= options.num_subtitles {
+                break;
+            }
+        }
+    }
+    Ok(Some(subtitles))
+}
 
-    let engine = OcrEngine::TryCreateFromLanguage(winrt_language)?;
-    if let Some(mut iter) = iter {
-        let subtitles = get_first_n_subtitles(&mut iter, &engine, num_subtitles)?;
+// OCR language packs are optional Windows features
+const OCR_LANGUAGE_INSTALL_COMMAND: &str =
+    "Add-WindowsCapability -Online -Name Language.OCR~~~en-US~0.0.1.0";
+
+// Files are processed in parallel, only warn about the fallback once
+static WARNED_ABOUT_OCR_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+// If the language pack for the given language isn't installed, this
+// falls back to the installed language with the closest tag.
+pub fn create_ocr_engine_with_fallback(language: &Language) -> Result<OcrEngine> {
+    let error = match OcrEngine::TryCreateFromLanguage(language) {
+        Ok(engine) => return Ok(engine),
+        Err(error) => error,
+    };
+    let requested_tag = language.LanguageTag()?.to_string();
+    let available = OcrEngine::AvailableRecognizerLanguages()?;
+    let available_tags = (0..available.Size()?)
+        .map(|i| Ok(available.GetAt(i)?.LanguageTag()?.to_string()))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(i) = closest_language_tag(&requested_tag, &available_tags) {
+        if !WARNED_ABOUT_OCR_FALLBACK.swap(true, Ordering::SeqCst) {
+            progress::suspend(|| {
+                println!(
+                "Warning! The OCR language pack for \"{}\" isn't installed, using \"{}\" instead.",
+                requested_tag, available_tags[i]
+            )
+            });
+        }
+        OcrEngine::TryCreateFromLanguage(&available.GetAt(i as u32)?)
+    } else {
+        progress::suspend(|| {
+            println!(
+            "No OCR language packs are installed. To install one, run \"{}\" in PowerShell as an administrator.",
+            OCR_LANGUAGE_INSTALL_COMMAND
+        )
+        });
+        Err(error)
+    }
+}
+
+// Languages that share a primary subtag (e.g. "en-US" and "en-GB")
+// are always preferred, then the closest tag by edit distance.
+fn closest_language_tag(tag: &str, available_tags: &[String]) -> Option<usize> {
+    let primary_subtag = |tag: &str| tag.split('-').next().unwrap_or("").to_lowercase();
+    let requested_primary = primary_subtag(tag);
+    available_tags
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, available)| {
+            (
+                primary_subtag(available) != requested_primary,
+                levenshtein::levenshtein(&tag.to_lowercase(), &available.to_lowercase()),
+            )
+        })
+        .map(|(i, _)| i)
+}
+
+// Runs OCR on a single image, e.g. one loaded from a previous dump.
+pub fn ocr_bitmap(
+    bitmap: &SoftwareBitmap,
+    options: &LoadOptions,
+    language: KnownLanguage,
+) -> Result<OcrText> {
+    let winrt_language = language.create_winrt_language()?.unwrap();
+    let engine = create_ocr_engine_with_fallback(&winrt_language)?;
+    process_bitmap(bitmap, &engine, options, &mut false)
+}
+
+// Like load_first_n_subtitles, but also returns metrics for each subtitle
+// image (including the ones that didn't produce any text) and where its
+// content sits within the video frame. The region is None when the track
+// doesn't say where the image is placed.
+pub fn load_first_n_subtitles_with_metrics<P: AsRef<Path>>(
+    path: P,
+    options: &LoadOptions,
+    language: KnownLanguage,
+) -> Result<Option<Vec<(OcrText, SubtitleImageMetrics, Option<SubtitleRegion>)>>> {
+    let winrt_language = language.create_winrt_language()?.unwrap();
+    let (iter, _) = open_subtitle_iter(path.as_ref(), options, language)?;
+    let engine = create_ocr_engine_with_fallback(&winrt_language)?;
+    if let Some(iter) = iter {
+        let mut subtitles = Vec::new();
+        let mut num_found = 0;
+        let mut mixed_case = false;
+        for frame in iter {
+            let SubtitleFrame { bitmap, region } = frame?;
+            let metrics = analyze_subtitle_image(&bitmap)?;
+            let content_region = match region {
+                Some(region) => detect_subtitle_region(&bitmap)?.map(|content| SubtitleRegion {
+                    x: region.x + content.x,
+                    y: region.y + content.y,
+                    ..content
+                }),
+                None => None,
+            };
+            let text = process_bitmap(&bitmap, &engine, options, &mut mixed_case)?;
+            if let OcrText::Text(_) = text {
+                num_found += 1;
+            }
+            subtitles.push((text, metrics, content_region));
+            if num_found >= options.num_subtitles {
+                break;
+            }
+        }
         Ok(Some(subtitles))
     } else {
         Ok(None)
     }
 }
 
+fn open_subtitle_iter(
+    path: &Path,
+    options: &LoadOptions,
+    language: KnownLanguage,
+) -> Result<(Option<FilteredSubtitleIterator<File>>, ParseTiming)> {
+    let (mut file, timing) = MkvFile::open_timed(path, &options.parse_config)?;
+    if let Some(palette_format) = options.vob_palette_format {
+        file.set_vob_palette_format(palette_format)?;
+    }
+
+    // Chapters are 1-based
+    let timestamp_range = if let Some(chapter) = options.chapter {
+        if let Some(chapter_info) = chapter
+            .checked_sub(1)
+            .and_then(|index| file.chapters().get(index))
+        {
+            Some((chapter_info.start_ms, chapter_info.end_ms))
+        } else {
+            progress::suspend(|| {
+                println!(
+                    "Warning! \"{}\" does not have chapter {}. Skipping file...",
+                    path.display(),
+                    chapter
+                )
+            });
+            return Ok((None, timing));
+        }
+    } else {
+        None
+    };
+
+    let preference = options.track_preference;
+    let track_info = file.find_track(
+        options.track_number,
+        options.track_name.as_deref(),
+        |tracks| {
+            if options.forced_only {
+                return select_forced_track(
+                    path,
+                    &options.parse_config,
+                    &forced_tracks(tracks, &language),
+                );
+            }
+            let track_info = select_track(tracks, &language, preference);
+            if track_info.is_none() && options.fallback_to_und {
+                let undetermined = KnownLanguage::undetermined();
+                let track_info = select_track(tracks, &undetermined, preference);
+                if track_info.is_some() {
+                    progress::suspend(|| {
+                        println!(
+                            "Warning! Using undetermined-language track for \"{}\".",
+                            path.display()
+                        )
+                    });
+                }
+                return track_info;
+            }
+            track_info
+        },
+    );
+    let track_info = if let Some(track_info) = track_info {
+        track_info.clone()
+    } else {
+        if let Some(track_name) = &options.track_name {
+            progress::suspend(|| {
+                println!(
+                    "Warning! \"{}\" does not have a track named \"{}\". Skipping file...",
+                    path.display(),
+                    track_name
+                )
+            });
+        }
+        return Ok((None, timing));
+    };
+    let iter = file.subtitle_iter_from_track_info(track_info)?.map(|iter| {
+        iter.with_debug_logging(options.debug_logging)
+            .with_sample_rate(options.sample_rate)
+    });
+    let iter = if let Some((start_ms, end_ms)) = timestamp_range {
+        iter.map(|iter| iter.with_timestamp_range(start_ms, end_ms))
+    } else {
+        iter
+    };
+    let iter = iter.map(|iter| match options.subtitle_region {
+        Some(region) => iter.filter_by_region(region),
+        None => iter.into(),
+    });
+    Ok((iter, timing))
+}
+
+// If there's more than one forced track, we use the one with the
+// most subtitles. Counting them takes another pass over the file.
+fn select_forced_track<'a>(
+    path: &Path,
+    config: &ParseConfig,
+    forced_tracks: &[&'a TrackInfo],
+) -> Option<&'a TrackInfo> {
+    if forced_tracks.len() <= 1 {
+        return forced_tracks.first().copied();
+    }
+    let counts = match MkvFile::from_path_with_config(path, config) {
+        Ok(file) => file
+            .count_blocks(None)
+            .map(|block_counts| block_counts.counts)
+            .unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    };
+    track_with_most_blocks(forced_tracks, &counts)
+}
+
+// Ties go to the first track
+fn track_with_most_blocks<'a>(
+    tracks: &[&'a TrackInfo],
+    counts: &HashMap<u64, usize>,
+) -> Option<&'a TrackInfo> {
+    let mut best: Option<(&TrackInfo, usize)> = None;
+    for track_info in tracks {
+        let count = counts.get(&track_info.track_number).copied().unwrap_or(0);
+        if best.map_or(true, |(_, best_count)| count > best_count) {
+            best = Some((track_info, count));
+        }
+    }
+    best.map(|(track_info, _)| track_info)
+}
+
 fn get_first_n_subtitles<R: Read>(
-    iter: &mut SubtitleIterator<R>,
+    iter: &mut FilteredSubtitleIterator<R>,
     engine: &OcrEngine,
-    num_subtitles: usize,
-) -> Result<Vec<String>> {
+    options: &LoadOptions,
+) -> Result<(Vec<String>, OcrTiming)> {
     let mut subtitles = Vec::new();
-    for bitmap in iter {
-        let text = process_bitmap(&bitmap, engine)?;
-        if let Some(text) = text {
-            subtitles.push(text.to_string());
-            if subtitles.len() >= num_subtitles {
+    let mut timing = OcrTiming::default();
+    let mut mixed_case = false;
+    let progress = if options.show_file_progress {
+        ProgressBar::new(iter.file_size()).with_style(
+            ProgressStyle::with_template("[{elapsed_precise}] {wide_bar} {percent}%").unwrap(),
+        )
+    } else {
+        ProgressBar::hidden()
+    };
+    // Files are loaded in parallel without this bar, so only a shown bar
+    // can be the active one
+    let _active_progress = options
+        .show_file_progress
+        .then(|| progress::set_active_progress_bar(&progress));
+    while let Some(frame) = iter.next() {
+        let SubtitleFrame { bitmap, .. } = frame?;
+        progress.set_position(iter.position());
+        let ocr_start = Instant::now();
+        let text = process_bitmap(&bitmap, engine, options, &mut mixed_case)?;
+        timing.total_ocr_ms += elapsed_ms(ocr_start);
+        timing.frames_processed += 1;
+        if let OcrText::Text(text) = text {
+            subtitles.push(text);
+            if subtitles.len() >= options.num_subtitles {
                 break;
             }
         }
     }
-    Ok(subtitles)
+    progress.finish_and_clear();
+    if options.debug_logging {
+        progress::suspend(|| {
+            eprintln!(
+                "Debug: Read {} subtitle blocks to find {} subtitles",
+                iter.blocks_processed(),
+                subtitles.len()
+            )
+        });
+    }
+    Ok((subtitles, timing))
 }
 
-fn process_bitmap(bitmap: &SoftwareBitmap, engine: &OcrEngine) -> Result<Option<String>> {
+// mixed_case is whether any subtitle from the track so far (including this
+// one) had lower case letters. Only --sdh uses it, see text::is_stage_direction.
+fn process_bitmap(
+    bitmap: &SoftwareBitmap,
+    engine: &OcrEngine,
+    options: &LoadOptions,
+    mixed_case: &mut bool,
+) -> Result<OcrText> {
+    if let Some(min_sharpness) = options.ocr.min_sharpness {
+        let sharpness = compute_gradient(bitmap)?;
+        if sharpness < min_sharpness {
+            if options.debug_logging {
+                progress::suspend(|| {
+                    eprintln!(
+                        "Debug: Skipping subtitle with sharpness {:.1} (minimum {:.1})",
+                        sharpness, min_sharpness
+                    )
+                });
+            }
+            return Ok(OcrText::Empty);
+        }
+    }
+
+    let rotated;
+    let bitmap = if options.ocr.rotate_180 {
+        rotated = rotate_180(bitmap)?;
+        &rotated
+    } else {
+        bitmap
+    };
     let width = bitmap.PixelWidth()? as usize;
     let height = bitmap.PixelHeight()? as usize;
 
-    // Windows's OCR likes a solid color background rather than transparent.
     // TODO: Use D2D/D3D?
-    blend_with_color(
-        &bitmap,
-        &Color {
-            R: 0,
-            G: 0,
-            B: 0,
-            A: 255,
-        },
-    )?;
+    let bitmap = &blend_over_solid(bitmap, &OCR_BACKGROUND_COLOR)?;
 
     // Window's OCR engine seems to have a problem with images that are
     // too small. Scaling the image up seems to help.
@@ -441,10 +2271,802 @@ fn process_bitmap(bitmap: &SoftwareBitmap, engine: &OcrEngine) -> Result<Option<
 
     // Skip empty subtitles
     if !text.is_empty() {
-        let text = sanitize_text(&text);
+        if options.ocr.no_sanitize {
+            return Ok(OcrText::Text(text.to_owned()));
+        }
+        if let Some(word) = check_banned_words(text) {
+            if options.debug_logging {
+                progress::suspend(|| {
+                    eprintln!("Debug: Subtitle filtered by banned word '{}'", word)
+                });
+            }
+            return Ok(OcrText::Filtered(word));
+        }
+        let text = if options.ocr.sdh {
+            *mixed_case |= is_mixed_case(text);
+            // Text joins the lines with spaces, so stage directions
+            // have to be found line by line
+            let lines = result.Lines()?;
+            let lines = (0..lines.Size()?)
+                .map(|i| Ok(lines.GetAt(i)?.Text()?.to_string()))
+                .collect::<Result<Vec<_>>>()?;
+            sanitize_sdh_text(&lines, *mixed_case)
+        } else {
+            sanitize_text(text)
+        };
         if !text.is_empty() {
-            return Ok(Some(text));
+            return Ok(OcrText::Text(text));
         }
     }
-    Ok(None)
+    Ok(OcrText::Empty)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{
+        element, make_ebml_header, make_mkv_with_pgs_subtitles, make_mkv_with_track_in_cluster,
+        make_mkv_with_tracks_at_end, make_mkv_with_two_pgs_tracks, make_mkv_with_vob_subtitles,
+        make_pgs_display_set, make_positioned_pgs_display_set, mkv_from_display_sets,
+        pgs_write_end_display_set, pgs_write_object, pgs_write_palette, uint_element,
+        TEST_PGS_POSITION, TEST_SUBTITLE_INTERVAL_MS, TEST_TRACK_NUMBER, TEST_VOB_SIZE,
+    };
+    use std::io::Cursor;
+
+    fn make_track_info(track_number: u64, language: KnownLanguage, is_forced: bool) -> TrackInfo {
+        TrackInfo {
+            track_number,
+            encoding: KnownEncoding::PGS,
+            language,
+            is_default: true,
+            is_forced,
+            name: None,
+            codec_id: "S_HDMV/PGS".to_owned(),
+            compression: None,
+            codec_private: None,
+        }
+    }
+
+    // Picks the track the same way the commands do by default
+    fn subtitle_iter<R: Read + Seek>(
+        mkv: MkvFile<R>,
+        language: KnownLanguage,
+    ) -> Result<Option<SubtitleIterator<R>>> {
+        let track_number = mkv
+            .find_track(None, None, |tracks| {
+                select_track(tracks, &language, TrackPreference::default())
+            })
+            .map(|track_info| track_info.track_number);
+        match track_number {
+            Some(track_number) => mkv.subtitle_iter_from_track_number(track_number),
+            None => Ok(None),
+        }
+    }
+
+    fn block_iter<R: Read + Seek>(
+        mkv: MkvFile<R>,
+        language: KnownLanguage,
+    ) -> Option<BlockIterator<R>> {
+        let track_number =
+            select_track(mkv.tracks(), &language, TrackPreference::default())?.track_number;
+        mkv.block_iter_from_track_number(track_number)
+    }
+
+    #[test]
+    fn forced_track_selection_test() {
+        let track_infos = vec![
+            make_track_info(3, KnownLanguage::English, false),
+            make_track_info(4, KnownLanguage::English, true),
+            make_track_info(5, KnownLanguage::Unknown("fre".to_owned()), true),
+        ];
+        let forced = forced_tracks(&track_infos, &KnownLanguage::English);
+        assert_eq!(forced.len(), 1);
+        assert_eq!(forced[0].track_number, 4);
+    }
+
+    #[test]
+    fn multiple_forced_tracks_test() {
+        let track_infos = vec![
+            make_track_info(4, KnownLanguage::English, true),
+            make_track_info(6, KnownLanguage::English, true),
+        ];
+        let forced = forced_tracks(&track_infos, &KnownLanguage::English);
+        let select = |counts: &HashMap<u64, usize>| {
+            track_with_most_blocks(&forced, counts).map(|track_info| track_info.track_number)
+        };
+        assert_eq!(select(&HashMap::from([(3, 100), (4, 2), (6, 5)])), Some(6));
+        assert_eq!(select(&HashMap::from([(4, 5), (6, 5)])), Some(4));
+        assert_eq!(select(&HashMap::new()), Some(4));
+        assert!(track_with_most_blocks(&[], &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn header_stripping_test() {
+        let compression = ContentCompression::HeaderStripping(vec![0x00, 0x01]);
+        let decompressed = compression.decompress(&[0x02, 0x03]).unwrap();
+        assert_eq!(decompressed, vec![0x00, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn zlib_decompression_test() {
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::Write;
+
+        let data = b"Some subtitle payload".to_vec();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let decompressed = ContentCompression::Zlib.decompress(&compressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn ebml_header_validation_test() {
+        let mut reader = Cursor::new(make_ebml_header("matroska"));
+        assert!(validate_ebml_header(&mut reader).is_ok());
+        let mut reader = Cursor::new(make_ebml_header("webm"));
+        assert!(validate_ebml_header(&mut reader).is_ok());
+
+        let mut reader = Cursor::new(element(0x1A45DFA3, &uint_element(0x4286, 2)));
+        assert!(matches!(
+            validate_ebml_header(&mut reader),
+            Err(ShowOrderError::NotAnMkvFile(_))
+        ));
+        let mut reader = Cursor::new(make_ebml_header("mp4"));
+        assert!(matches!(
+            validate_ebml_header(&mut reader),
+            Err(ShowOrderError::NotAnMkvFile(_))
+        ));
+
+        // The start of an mp4 file
+        let mut reader = Cursor::new(b"\x00\x00\x00\x18ftypmp42".to_vec());
+        assert!(matches!(
+            validate_ebml_header(&mut reader),
+            Err(ShowOrderError::NotAnMkvFile(_))
+        ));
+        let mut reader = Cursor::new(vec![0x1A, 0x45]);
+        assert!(matches!(
+            validate_ebml_header(&mut reader),
+            Err(ShowOrderError::NotAnMkvFile(_))
+        ));
+    }
+
+    #[test]
+    fn track_preference_test() {
+        let mut vob = make_track_info(3, KnownLanguage::English, false);
+        vob.encoding = KnownEncoding::VOB {
+            width: 720,
+            height: 480,
+            palette: Vec::new(),
+        };
+        let pgs = make_track_info(4, KnownLanguage::English, false);
+        let spanish = make_track_info(5, KnownLanguage::Unknown("spa".to_owned()), false);
+        let track_infos = vec![vob, pgs, spanish];
+
+        let select = |preference| {
+            select_track(&track_infos, &KnownLanguage::English, preference)
+                .map(|track_info| track_info.track_number)
+        };
+        assert_eq!(select(TrackPreference::LastFound), Some(4));
+        assert_eq!(select(TrackPreference::FirstFound), Some(3));
+        assert_eq!(select(TrackPreference::PreferPgs), Some(4));
+        assert_eq!(select(TrackPreference::PreferVob), Some(3));
+
+        // Fall back to the first track if the preferred encoding isn't there
+        let track_infos = &track_infos[1..];
+        let track = select_track(
+            track_infos,
+            &KnownLanguage::English,
+            TrackPreference::PreferVob,
+        );
+        assert_eq!(track.map(|track_info| track_info.track_number), Some(4));
+        let track = select_track(
+            track_infos,
+            &KnownLanguage::Unknown("fre".to_owned()),
+            TrackPreference::FirstFound,
+        );
+        assert!(track.is_none());
+    }
+
+    #[test]
+    fn track_preference_file_order_test() {
+        // The earliest of several matching tracks wins, unless
+        // asked for the last one
+        let track_infos = vec![
+            make_track_info(3, KnownLanguage::English, false),
+            make_track_info(4, KnownLanguage::English, false),
+        ];
+        for preference in [
+            TrackPreference::FirstFound,
+            TrackPreference::PreferPgs,
+            TrackPreference::PreferVob,
+        ] {
+            let track = select_track(&track_infos, &KnownLanguage::English, preference);
+            assert_eq!(track.map(|track_info| track_info.track_number), Some(3));
+        }
+        let track = select_track(
+            &track_infos,
+            &KnownLanguage::English,
+            TrackPreference::default(),
+        );
+        assert_eq!(track.map(|track_info| track_info.track_number), Some(4));
+    }
+
+    #[test]
+    fn track_by_name_test() {
+        let mut sdh = make_track_info(3, KnownLanguage::English, false);
+        sdh.name = Some("English (SDH)".to_owned());
+        let mut forced = make_track_info(4, KnownLanguage::English, true);
+        forced.name = Some("Forced".to_owned());
+        let unnamed = make_track_info(5, KnownLanguage::English, false);
+        let track_infos = vec![sdh, forced, unnamed];
+
+        let tracks = tracks_by_name(&track_infos, "sdh");
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].track_number, 3);
+        let tracks = tracks_by_name(&track_infos, "FORCED");
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].track_number, 4);
+        assert!(tracks_by_name(&track_infos, "commentary").is_empty());
+    }
+
+    #[test]
+    fn find_track_test() {
+        let data = make_mkv_with_two_pgs_tracks(&[]);
+        let mkv = MkvFile::new(Cursor::new(data)).unwrap();
+        let french = KnownLanguage::Unknown("fre".to_owned());
+        let find = |track_number, track_name| {
+            mkv.find_track(track_number, track_name, |tracks| {
+                select_track(tracks, &french, TrackPreference::FirstFound)
+            })
+            .map(|track_info| track_info.track_number)
+        };
+        assert_eq!(find(None, None), Some(TEST_TRACK_NUMBER + 1));
+        assert_eq!(find(Some(TEST_TRACK_NUMBER), None), Some(TEST_TRACK_NUMBER));
+        assert_eq!(find(Some(100), None), None);
+        // Names win over numbers, and don't fall back to the default
+        assert_eq!(find(Some(TEST_TRACK_NUMBER), Some("missing")), None);
+    }
+
+    #[test]
+    fn track_info_serialization_test() {
+        let mut track_info = make_track_info(3, KnownLanguage::English, true);
+        track_info.is_default = false;
+        let value = serde_json::to_value(&track_info).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "track_number": 3,
+                "language": "English",
+                "encoding": "PGS",
+                "is_default": false,
+                "is_forced": true,
+                "codec_id": "S_HDMV/PGS",
+            })
+        );
+    }
+
+    #[test]
+    fn closest_language_tag_test() {
+        let available = ["de-DE", "en-GB", "en-US", "fr-FR"]
+            .iter()
+            .map(|tag| tag.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(closest_language_tag("en-US", &available), Some(2));
+        assert_eq!(closest_language_tag("en-AU", &available), Some(1));
+        assert_eq!(closest_language_tag("EN", &available), Some(1));
+        assert_eq!(closest_language_tag("fr-CA", &available), Some(3));
+        assert_eq!(closest_language_tag("en-US", &available[..1]), Some(0));
+        assert_eq!(closest_language_tag("en-US", &[]), None);
+    }
+
+    #[test]
+    fn mkv_timestamp_test() {
+        let cluster_timestamp = ClusterTimestamp(1000);
+        assert_eq!(cluster_timestamp + 0, MkvTimestamp(1000));
+        assert_eq!(cluster_timestamp + 250, MkvTimestamp(1250));
+        assert_eq!(cluster_timestamp + -5, MkvTimestamp(995));
+        // Blocks at the start of the first cluster can come before it
+        assert_eq!(ClusterTimestamp(0) + -10, MkvTimestamp(-10));
+        assert_eq!(
+            ClusterTimestamp(u64::MAX) + i16::MAX,
+            MkvTimestamp(i64::MAX)
+        );
+        assert!(cluster_timestamp + -5 < cluster_timestamp + 0);
+        assert_eq!((cluster_timestamp + 250).to_ms(100_000), 125.0);
+    }
+
+    #[test]
+    fn timescale_conversion_test() {
+        // Scales from the Matroska spec's notes on TimestampScale
+        // (https://www.matroska.org/technical/notes.html)
+        // The default TimecodeScale is 1ms
+        assert_eq!(timescale_to_ms(12345, DEFAULT_TIMESTAMP_SCALE), 12345.0);
+        assert_eq!(ms_to_timescale(12345.0, DEFAULT_TIMESTAMP_SCALE), 12345);
+        // 0.1ms
+        assert_eq!(timescale_to_ms(12345, 100_000), 1234.5);
+        assert_eq!(ms_to_timescale(1234.5, 100_000), 12345);
+        // One tick per sample of 44.1kHz audio, rounded to the nearest ns
+        assert_eq!(timescale_to_ms(44100, 22676), 1000.0116);
+        assert_eq!(ms_to_timescale(1000.0116, 22676), 44100);
+        assert_eq!(timescale_to_ms(-5, DEFAULT_TIMESTAMP_SCALE), -5.0);
+
+        assert_eq!(
+            timescale_to_srt_timestamp(3723, 1_000_000_000),
+            "01:02:03,000"
+        );
+        assert_eq!(
+            timescale_to_srt_timestamp(3_723_004, DEFAULT_TIMESTAMP_SCALE),
+            "01:02:03,004"
+        );
+        assert_eq!(
+            timescale_to_srt_timestamp(-10, DEFAULT_TIMESTAMP_SCALE),
+            "00:00:00,000"
+        );
+    }
+
+    #[test]
+    fn synthetic_pgs_mkv_test() {
+        let payloads: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let data = make_mkv_with_pgs_subtitles(&payloads);
+
+        let mkv = MkvFile::new(Cursor::new(data.clone())).unwrap();
+        assert_eq!(mkv.tracks().len(), 1);
+        let track_info = &mkv.tracks()[0];
+        assert_eq!(track_info.track_number, TEST_TRACK_NUMBER);
+        assert_eq!(track_info.encoding, KnownEncoding::PGS);
+        assert_eq!(track_info.language, KnownLanguage::English);
+        assert!(track_info.is_default);
+        assert_eq!(track_info.codec_id, "S_HDMV/PGS");
+
+        let blocks = block_iter(mkv, KnownLanguage::English)
+            .unwrap()
+            .map(|result| result.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(blocks.len(), payloads.len());
+        for (i, ((block, timestamp), payload)) in blocks.iter().zip(payloads).enumerate() {
+            assert_eq!(block.payload.as_slice(), payload);
+            let expected_ms = i as u64 * TEST_SUBTITLE_INTERVAL_MS;
+            assert_eq!(timestamp.to_ms(DEFAULT_TIMESTAMP_SCALE), expected_ms as f64);
+        }
+
+        let mkv = MkvFile::new(Cursor::new(data)).unwrap();
+        let payloads = mkv
+            .block_iter_from_track_number(TEST_TRACK_NUMBER)
+            .unwrap()
+            .with_timestamp_range(TEST_SUBTITLE_INTERVAL_MS, TEST_SUBTITLE_INTERVAL_MS * 2)
+            .into_payload_iter()
+            .map(|payload| payload.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, vec![b"second".to_vec()]);
+    }
+
+    #[test]
+    fn block_iter_multi_test() {
+        let english = TEST_TRACK_NUMBER;
+        let french = TEST_TRACK_NUMBER + 1;
+        let subtitles: [(u64, &[u8]); 4] = [
+            (english, b"hello"),
+            (french, b"bonjour"),
+            (french, b"au revoir"),
+            (english, b"goodbye"),
+        ];
+        let data = make_mkv_with_two_pgs_tracks(&subtitles);
+
+        let mkv = MkvFile::new(Cursor::new(data.clone())).unwrap();
+        let blocks = mkv
+            .block_iter_multi(&[english, french, 99])
+            .map(|result| result.unwrap())
+            .map(|(track_number, block)| (track_number, block.payload))
+            .collect::<Vec<_>>();
+        let expected = subtitles
+            .iter()
+            .map(|(track_number, payload)| (*track_number, payload.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, expected);
+
+        let mkv = MkvFile::new(Cursor::new(data.clone())).unwrap();
+        let blocks = mkv
+            .block_iter_multi(&[french])
+            .map(|result| result.unwrap())
+            .map(|(_, block)| block.payload)
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![b"bonjour".to_vec(), b"au revoir".to_vec()]);
+
+        let mkv = MkvFile::new(Cursor::new(data)).unwrap();
+        let block_counts = mkv.count_blocks(None).unwrap();
+        assert!(block_counts.complete);
+        assert_eq!(block_counts.counts[&english], 2);
+        assert_eq!(block_counts.counts[&french], 2);
+    }
+
+    #[test]
+    fn block_count_test() {
+        let payloads: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let data = make_mkv_with_pgs_subtitles(&payloads);
+
+        let mkv = MkvFile::new(Cursor::new(data.clone())).unwrap();
+        let estimate = mkv.estimate_block_count(TEST_TRACK_NUMBER).unwrap();
+        assert!(estimate >= payloads.len());
+        assert_eq!(mkv.estimate_block_count(TEST_TRACK_NUMBER + 1), None);
+
+        let mut iter = mkv.block_iter_from_track_number(TEST_TRACK_NUMBER).unwrap();
+        assert_eq!(iter.blocks_processed(), 0);
+        iter.next().unwrap().unwrap();
+        assert_eq!(iter.blocks_processed(), 1);
+        assert_eq!(iter.count(), 2);
+
+        let mkv = MkvFile::new(Cursor::new(data.clone())).unwrap();
+        let block_counts = mkv.count_blocks(None).unwrap();
+        assert!(block_counts.complete);
+        assert_eq!(block_counts.counts[&TEST_TRACK_NUMBER], payloads.len());
+        let mkv = MkvFile::new(Cursor::new(data)).unwrap();
+        let block_counts = mkv.count_blocks(Some(1)).unwrap();
+        assert!(!block_counts.complete);
+        assert_eq!(block_counts.counts[&TEST_TRACK_NUMBER], 1);
+    }
+
+    #[test]
+    fn synthetic_pgs_subtitle_iter_test() -> Result<()> {
+        let subtitles = [make_pgs_display_set(20, 10), make_pgs_display_set(30, 15)];
+
+        let mkv = mkv_from_display_sets(&subtitles);
+        let iter = subtitle_iter(mkv, KnownLanguage::English)?.unwrap();
+        let (x, y) = TEST_PGS_POSITION;
+        let mut sizes = Vec::new();
+        for frame in iter {
+            let frame = frame?;
+            let (width, height) = (frame.bitmap.PixelWidth()?, frame.bitmap.PixelHeight()?);
+            assert_eq!(
+                frame.region,
+                Some(SubtitleRegion {
+                    x,
+                    y,
+                    width: width as u16,
+                    height: height as u16,
+                })
+            );
+            sizes.push((width, height));
+        }
+        assert_eq!(sizes, vec![(20, 10), (30, 15)]);
+        Ok(())
+    }
+
+    #[test]
+    fn filter_by_region_test() -> Result<()> {
+        let subtitles = [make_pgs_display_set(20, 10), make_pgs_display_set(30, 15)];
+
+        // Both subtitles overlap the bottom half of the frame
+        let mkv = mkv_from_display_sets(&subtitles);
+        let region = SubtitleRegion {
+            x: 0,
+            y: 540,
+            width: 1920,
+            height: 540,
+        };
+        let mut iter = subtitle_iter(mkv, KnownLanguage::English)?
+            .unwrap()
+            .filter_by_region(region);
+        assert_eq!(iter.encoding_name(), "PGS");
+        assert_eq!(iter.by_ref().count(), 2);
+        assert_eq!(iter.last_timestamp_ms(), Some(TEST_SUBTITLE_INTERVAL_MS));
+        Ok(())
+    }
+
+    #[test]
+    fn sample_rate_test() -> Result<()> {
+        // Every block has a different width, so we can tell which ones were used
+        let subtitles = (1..=15)
+            .map(|width| make_pgs_display_set(width, 1))
+            .collect::<Vec<_>>();
+
+        // 0 and 1 both use every block
+        for sample_rate in [0, 1] {
+            let mkv = mkv_from_display_sets(&subtitles);
+            let widths = subtitle_iter(mkv, KnownLanguage::English)?
+                .unwrap()
+                .with_sample_rate(sample_rate)
+                .map(|frame| frame?.bitmap.PixelWidth())
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(widths, (1..=15).collect::<Vec<_>>());
+        }
+
+        let mkv = mkv_from_display_sets(&subtitles);
+        let mut iter = subtitle_iter(mkv, KnownLanguage::English)?
+            .unwrap()
+            .with_sample_rate(3);
+        let widths = iter
+            .by_ref()
+            .take(5)
+            .map(|frame| frame?.bitmap.PixelWidth())
+            .collect::<Result<Vec<_>>>()?;
+        // Blocks 0, 3, 6, 9 and 12
+        assert_eq!(widths, vec![1, 4, 7, 10, 13]);
+        assert_eq!(iter.blocks_processed(), 13);
+        assert_eq!(
+            iter.last_timestamp_ms(),
+            Some(12 * TEST_SUBTITLE_INTERVAL_MS)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filter_out_of_region_test() -> Result<()> {
+        // Only the first and last subtitles are at the bottom of the frame
+        let subtitles = [
+            make_positioned_pgs_display_set(20, 10, 100, 1000),
+            make_positioned_pgs_display_set(30, 15, 100, 50),
+            make_positioned_pgs_display_set(40, 20, 100, 1000),
+        ];
+
+        let mkv = mkv_from_display_sets(&subtitles);
+        let region = SubtitleRegion {
+            x: 0,
+            y: 720,
+            width: 1920,
+            height: 360,
+        };
+        let mut iter = subtitle_iter(mkv, KnownLanguage::English)?
+            .unwrap()
+            .filter_by_region(region);
+        let widths = iter
+            .by_ref()
+            .map(|frame| frame?.bitmap.PixelWidth())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(widths, vec![20, 40]);
+        assert_eq!(
+            iter.last_timestamp_ms(),
+            Some(2 * TEST_SUBTITLE_INTERVAL_MS)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_objects_in_block_test() -> Result<()> {
+        let display_set = [
+            pgs_write_palette(&[(1, 0xEB, 0x80, 0x80, 0xFF)]),
+            pgs_write_object(0, 2, 1, &[&[(1, 2)]]),
+            pgs_write_object(1, 3, 1, &[&[(1, 3)]]),
+            pgs_write_end_display_set(),
+        ]
+        .concat();
+        let second = make_pgs_display_set(4, 1);
+
+        let mkv = mkv_from_display_sets(&[display_set, second]);
+        let mut iter = subtitle_iter(mkv, KnownLanguage::English)?.unwrap();
+        assert_eq!(iter.peek().unwrap()?.bitmap.PixelWidth()?, 2);
+        let widths = iter
+            .by_ref()
+            .map(|frame| frame?.bitmap.PixelWidth())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(widths, vec![2, 3, 4]);
+        assert_eq!(iter.last_timestamp_ms(), Some(TEST_SUBTITLE_INTERVAL_MS));
+        Ok(())
+    }
+
+    #[test]
+    fn subtitle_iter_any_test() -> Result<()> {
+        let subtitles = [make_pgs_display_set(20, 10)];
+        let french = KnownLanguage::Unknown("fre".to_owned());
+
+        let mkv = mkv_from_display_sets(&subtitles);
+        assert!(subtitle_iter(mkv, french.clone())?.is_none());
+        // Falls back to the only subtitle track
+        let mkv = mkv_from_display_sets(&subtitles);
+        let mut iter = mkv.subtitle_iter_any(french)?.unwrap();
+        assert_eq!(iter.next().unwrap()?.bitmap.PixelWidth()?, 20);
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_codec_test() {
+        assert_eq!(
+            KnownEncoding::try_from_str("S_HDMV/PGS"),
+            Ok(KnownEncoding::PGS)
+        );
+        let codec = KnownEncoding::try_from_str("S_TEXT/ASS").unwrap_err();
+        assert!(codec.is_text_based);
+        assert_eq!(codec.to_string(), "unsupported text codec: S_TEXT/ASS");
+        let codec = KnownEncoding::try_from_str("S_DVBSUB").unwrap_err();
+        assert!(!codec.is_text_based);
+        assert_eq!(codec.to_string(), "unsupported codec: S_DVBSUB");
+
+        let encoding = KnownEncoding::from_tag_and_data("S_TEXT/UTF8", None).unwrap();
+        assert_eq!(encoding, KnownEncoding::Unknown("S_TEXT/UTF8".to_owned()));
+        assert_eq!(
+            encoding.description(),
+            "unsupported text codec: S_TEXT/UTF8"
+        );
+        assert_eq!(KnownEncoding::PGS.description(), "S_HDMV/PGS");
+    }
+
+    #[test]
+    fn debug_format_test() {
+        let subtitles = [make_pgs_display_set(20, 10)];
+        let mkv = mkv_from_display_sets(&subtitles);
+        let debug = format!("{:?}", mkv);
+        assert!(debug.contains("track_infos"));
+        assert!(debug.contains(&format!("track_number: {}", TEST_TRACK_NUMBER)));
+
+        let mut iter = mkv.block_iter_from_track_number(TEST_TRACK_NUMBER).unwrap();
+        iter.next().unwrap();
+        assert!(format!("{:?}", iter).contains("blocks_processed: 1"));
+    }
+
+    #[test]
+    fn position_test() -> Result<()> {
+        let subtitles = [make_pgs_display_set(20, 10), make_pgs_display_set(30, 15)];
+        let mkv = mkv_from_display_sets(&subtitles);
+        let data_len = mkv.file_size().unwrap();
+        assert!(mkv.position().unwrap() <= data_len);
+        let mut iter = subtitle_iter(mkv, KnownLanguage::English)?.unwrap();
+        let start = iter.position();
+        while iter.next().is_some() {}
+        assert!(iter.position() > start);
+        assert_eq!(iter.position(), iter.file_size());
+        assert_eq!(iter.file_size(), data_len);
+        Ok(())
+    }
+
+    #[test]
+    fn subtitle_iter_peek_test() -> Result<()> {
+        let subtitles = [make_pgs_display_set(20, 10), make_pgs_display_set(30, 15)];
+
+        let mkv = mkv_from_display_sets(&subtitles);
+        let mut iter = subtitle_iter(mkv, KnownLanguage::English)?.unwrap();
+        let first = iter.peek().unwrap()?.bitmap.clone();
+        let second = iter.peek().unwrap()?.bitmap.clone();
+        assert_eq!(first, second);
+        assert_eq!(first.PixelWidth()?, 20);
+
+        let frame = iter.next().unwrap()?;
+        assert_eq!(frame.bitmap, first);
+        assert_eq!(iter.last_timestamp_ms(), Some(0));
+        assert_eq!(iter.peek().unwrap()?.bitmap.PixelWidth()?, 30);
+        let frame = iter.next().unwrap()?;
+        assert_eq!(frame.bitmap.PixelWidth()?, 30);
+        assert_eq!(iter.last_timestamp_ms(), Some(TEST_SUBTITLE_INTERVAL_MS));
+        assert!(iter.peek().is_none());
+        assert!(iter.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reset_test() {
+        let payloads: [&[u8]; 2] = [b"first", b"second"];
+        let path = std::env::temp_dir().join("showorder_reset_test.mkv");
+        std::fs::write(&path, make_mkv_with_pgs_subtitles(&payloads)).unwrap();
+        let config = ParseConfig {
+            scan_entire_file_for_tracks: true,
+        };
+        let mkv = MkvFile::from_path_with_config(&path, &config).unwrap();
+        let reset = mkv.reset().unwrap();
+        assert!(format!("{:?}", reset).contains("scan_entire_file_for_tracks: true"));
+        let counts = reset.count_blocks(None).unwrap().counts;
+        // The original is untouched and reads the same blocks
+        assert_eq!(mkv.count_blocks(None).unwrap().counts, counts);
+        assert_eq!(counts[&TEST_TRACK_NUMBER], payloads.len());
+
+        // Without a path there's nothing to reopen
+        let mkv = MkvFile::new(File::open(&path).unwrap()).unwrap();
+        assert!(mkv.reset().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn synthetic_vob_mkv_test() {
+        let palette = vec![
+            Color {
+                A: 255,
+                R: 0x12,
+                G: 0x34,
+                B: 0x56,
+            },
+            Color {
+                A: 255,
+                R: 0xFF,
+                G: 0xFF,
+                B: 0xFF,
+            },
+        ];
+        let data = make_mkv_with_vob_subtitles(&[], &palette);
+
+        let mkv = MkvFile::new(Cursor::new(data)).unwrap();
+        assert_eq!(mkv.tracks().len(), 1);
+        let (width, height) = TEST_VOB_SIZE;
+        if let KnownEncoding::VOB {
+            width: track_width,
+            height: track_height,
+            palette: track_palette,
+        } = &mkv.tracks()[0].encoding
+        {
+            assert_eq!((*track_width, *track_height), (width, height));
+            assert_eq!(track_palette, &palette);
+            let idx = mkv.tracks()[0].codec_private_bytes().unwrap();
+            assert!(String::from_utf8_lossy(idx).contains("palette:"));
+        } else {
+            panic!("Expected a VOB track");
+        }
+    }
+
+    #[test]
+    fn seek_table_test() {
+        let data = make_mkv_with_tracks_at_end(&[b"first", b"second"]);
+        let mut reader = Cursor::new(data.as_slice());
+        validate_ebml_header(&mut reader).unwrap();
+        let seek_table = read_seek_table(&mut reader).unwrap().unwrap();
+        assert_eq!(seek_table.positions.len(), 2);
+
+        // Each position should point at the start of its element
+        for id in [INFO_ID, TRACKS_ID] {
+            let position = seek_table.segment_data_start + seek_table.positions[&id];
+            reader.set_position(position);
+            let (element_id, _) = read_element_header(&mut reader).unwrap().unwrap();
+            assert_eq!(element_id, id);
+        }
+
+        // Files without a SeekHead don't have a seek table
+        let data = make_mkv_with_pgs_subtitles(&[b"first"]);
+        let mut reader = Cursor::new(data.as_slice());
+        validate_ebml_header(&mut reader).unwrap();
+        assert!(read_seek_table(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn from_path_with_seek_head_test() {
+        let payloads: [&[u8]; 2] = [b"first", b"second"];
+        let data = make_mkv_with_tracks_at_end(&payloads);
+        let path = std::env::temp_dir().join("showorder_from_path_with_seek_head_test.mkv");
+        std::fs::write(&path, data).unwrap();
+
+        let mkv = MkvFile::from_path(&path).unwrap();
+        assert_eq!(mkv.tracks().len(), 1);
+        assert_eq!(mkv.tracks()[0].encoding, KnownEncoding::PGS);
+        let blocks = block_iter(mkv, KnownLanguage::English)
+            .unwrap()
+            .map(|result| result.unwrap().0.payload)
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![b"first".to_vec(), b"second".to_vec()]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn track_in_cluster_test() {
+        let payloads: [&[u8]; 2] = [b"first", b"second"];
+        let data = make_mkv_with_track_in_cluster(&payloads);
+
+        // By default we stop looking for tracks at the first cluster
+        let mkv = MkvFile::new(Cursor::new(data.clone())).unwrap();
+        assert_eq!(mkv.tracks().len(), 1);
+
+        let config = ParseConfig {
+            scan_entire_file_for_tracks: true,
+        };
+        let mkv = MkvFile::new_with_config(Cursor::new(data), &config).unwrap();
+        let track_numbers = mkv
+            .tracks()
+            .iter()
+            .map(|track_info| (track_info.track_number, track_info.language.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            track_numbers,
+            vec![
+                (TEST_TRACK_NUMBER, KnownLanguage::English),
+                (
+                    TEST_TRACK_NUMBER + 1,
+                    KnownLanguage::Unknown("fre".to_owned())
+                ),
+            ]
+        );
+        // The blocks are still there after scanning to the end
+        let blocks = mkv
+            .block_iter_from_track_number(TEST_TRACK_NUMBER)
+            .unwrap()
+            .into_payload_iter()
+            .map(|payload| payload.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(blocks, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
 }
@@ -5,18 +5,23 @@ use windows::Storage::Streams::Buffer;
 use windows::UI::Color;
 
 use crate::interop::as_mut_slice;
+use crate::progress;
 
 use super::types::ObjectDef;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ConvertedPaletteEntry {
     pub id: u8,
     pub color: Color,
 }
 
+// Palette ID 255 is reserved for the (transparent) background,
+// so it's never looked up in the palette.
+pub const BACKGROUND_PALETTE_ID: u8 = 255;
+
 impl ConvertedPaletteEntry {
-    pub const DEFAULT: Self = Self {
-        id: 0,
+    pub const TRANSPARENT: Self = Self {
+        id: BACKGROUND_PALETTE_ID,
         // The OCR APIs really hate transparent black for some reason...
         color: Color {
             A: 0,
@@ -27,6 +32,57 @@ impl ConvertedPaletteEntry {
     };
 }
 
+const SWATCH_CELL_SIZE: usize = 16;
+const SWATCH_CELLS_PER_ROW: usize = 16;
+const CHECKERBOARD_SQUARE_SIZE: usize = 4;
+
+// Draws all 256 palette slots as a 16x16 grid of cells. Colors are
+// composited over a checkerboard so that transparency is visible.
+pub fn render_palette_swatch(palette_data: &[ConvertedPaletteEntry]) -> Result<SoftwareBitmap> {
+    let size = (SWATCH_CELL_SIZE * SWATCH_CELLS_PER_ROW) as u32;
+    let bitmap_size = size * size * 4;
+    let bitmap_buffer = Buffer::Create(bitmap_size)?;
+    bitmap_buffer.SetLength(bitmap_size)?;
+    {
+        let slice = unsafe { as_mut_slice(&bitmap_buffer)? };
+        slice.copy_from_slice(&render_palette_swatch_bytes(palette_data));
+    }
+    let bitmap = SoftwareBitmap::CreateCopyFromBuffer(
+        bitmap_buffer,
+        BitmapPixelFormat::Bgra8,
+        size as i32,
+        size as i32,
+    )?;
+    Ok(bitmap)
+}
+
+fn render_palette_swatch_bytes(palette_data: &[ConvertedPaletteEntry]) -> Vec<u8> {
+    let size = SWATCH_CELL_SIZE * SWATCH_CELLS_PER_ROW;
+    let mut bytes = vec![0u8; size * size * 4];
+    for y in 0..size {
+        for x in 0..size {
+            let palette_id =
+                ((y / SWATCH_CELL_SIZE) * SWATCH_CELLS_PER_ROW) + (x / SWATCH_CELL_SIZE);
+            let color = palette_data
+                .iter()
+                .find(|p| p.id as usize == palette_id)
+                .map(|p| p.color)
+                .unwrap_or(ConvertedPaletteEntry::TRANSPARENT.color);
+            let is_light_square =
+                ((x / CHECKERBOARD_SQUARE_SIZE) + (y / CHECKERBOARD_SQUARE_SIZE)) % 2 == 0;
+            let background = if is_light_square { 255.0 } else { 204.0 };
+            let alpha = color.A as f32 / 255.0;
+            let blend = |value: u8| ((value as f32 * alpha) + (background * (1.0 - alpha))) as u8;
+            let index = ((y * size) + x) * 4;
+            bytes[index] = blend(color.B);
+            bytes[index + 1] = blend(color.G);
+            bytes[index + 2] = blend(color.R);
+            bytes[index + 3] = 255;
+        }
+    }
+    bytes
+}
+
 pub fn decode_image(
     object_def: &ObjectDef,
     color_data_lines: &Vec<Vec<(i32, i32)>>,
@@ -40,15 +96,28 @@ pub fn decode_image(
     {
         let slice = unsafe { as_mut_slice(&bitmap_buffer)? };
         let mut pixel_index = 0;
+        let mut missing_palette_ids = Vec::new();
+        let mut overflowed = false;
         for line in color_data_lines {
             for (palette_id, num) in line {
-                let palette_color = palette_data
-                    .iter()
-                    .find(|p| p.id as i32 == *palette_id)
-                    .unwrap_or(&ConvertedPaletteEntry::DEFAULT);
-                let color = palette_color.color;
+                let color = if *palette_id == BACKGROUND_PALETTE_ID as i32 {
+                    ConvertedPaletteEntry::TRANSPARENT.color
+                } else if let Some(palette_color) =
+                    palette_data.iter().find(|p| p.id as i32 == *palette_id)
+                {
+                    palette_color.color
+                } else {
+                    if !missing_palette_ids.contains(palette_id) {
+                        missing_palette_ids.push(*palette_id);
+                    }
+                    ConvertedPaletteEntry::TRANSPARENT.color
+                };
                 for _ in 0..*num as usize {
                     let index = pixel_index * 4;
+                    if index + 4 > slice.len() {
+                        overflowed = true;
+                        break;
+                    }
                     slice[index + 0] = color.B;
                     slice[index + 1] = color.G;
                     slice[index + 2] = color.R;
@@ -57,6 +126,22 @@ pub fn decode_image(
                 }
             }
         }
+        if overflowed {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Subtitle image has more pixels than fit in {}x{}. Ignoring the rest...",
+                    width, height
+                )
+            });
+        }
+        if !missing_palette_ids.is_empty() {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Subtitle image uses palette IDs that aren't in the palette: {:?}",
+                    missing_palette_ids
+                )
+            });
+        }
     }
     let bitmap = SoftwareBitmap::CreateCopyFromBuffer(
         bitmap_buffer,
@@ -66,3 +151,37 @@ pub fn decode_image(
     )?;
     Ok(bitmap)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn palette_swatch_test() {
+        let red = ConvertedPaletteEntry {
+            id: 1,
+            color: Color {
+                A: 255,
+                R: 255,
+                G: 0,
+                B: 0,
+            },
+        };
+        let bytes = render_palette_swatch_bytes(&[red]);
+        let size = SWATCH_CELL_SIZE * SWATCH_CELLS_PER_ROW;
+        let pixel = |x: usize, y: usize| {
+            let index = ((y * size) + x) * 4;
+            &bytes[index..index + 4]
+        };
+        // Palette ID 1 is the second cell in the first row
+        assert_eq!(pixel(SWATCH_CELL_SIZE, 0), &[0, 0, 255, 255]);
+        assert_eq!(
+            pixel(SWATCH_CELL_SIZE * 2 - 1, SWATCH_CELL_SIZE - 1),
+            &[0, 0, 255, 255]
+        );
+        // Missing entries show the checkerboard
+        assert_eq!(pixel(0, 0), &[255, 255, 255, 255]);
+        assert_eq!(pixel(CHECKERBOARD_SQUARE_SIZE, 0), &[204, 204, 204, 255]);
+        assert_eq!(pixel(size - 1, size - 1), &[255, 255, 255, 255]);
+    }
+}
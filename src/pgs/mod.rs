@@ -3,15 +3,94 @@ mod parsing;
 mod types;
 
 use byteorder::ReadBytesExt;
-use nalgebra::SMatrix;
 use windows::core::Result;
 use windows::Graphics::Imaging::SoftwareBitmap;
 use windows::UI::Color;
 
+use crate::error::ShowOrderError;
+use crate::image::ycbcr_to_rgb;
+use crate::progress;
+use crate::vob::SubtitleRegion;
+
 use self::image::decode_image;
+use self::image::render_palette_swatch;
 use self::image::ConvertedPaletteEntry;
 use self::parsing::PgsDeserializer;
-use self::types::{ObjectDef, PaletteDef, PaletteEntry, SegmentHeader, SegmentType};
+use self::types::{
+    CompositionObject, CompositionObjectCropping, CompositionState, ObjectDef, PaletteDef,
+    PaletteEntry, PresentationComp, SegmentHeader, SegmentType,
+};
+
+type ColorDataLines = Vec<Vec<(i32, i32)>>;
+
+pub struct PgsFrame {
+    pub bitmap: SoftwareBitmap,
+    // Where the image is placed within the video frame. This is None
+    // if none of the display set's composition objects refer to it.
+    pub region: Option<SubtitleRegion>,
+}
+
+struct DecodableObject {
+    object_def: ObjectDef,
+    color_data_lines: ColorDataLines,
+    palette_data: Vec<ConvertedPaletteEntry>,
+    region: Option<SubtitleRegion>,
+}
+
+// Palettes and objects are only valid for the epoch they
+// were defined in. An epoch starts with a presentation
+// composition segment marked as an "epoch start".
+#[derive(Default)]
+struct DisplaySetState {
+    palette: Option<Vec<ConvertedPaletteEntry>>,
+    object: Option<(ObjectDef, ColorDataLines)>,
+    // From the most recent presentation composition segment
+    composition_objects: Vec<(CompositionObject, Option<CompositionObjectCropping>)>,
+}
+
+impl DisplaySetState {
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    // The palette stays around for any other objects in the epoch
+    fn take_decodable(&mut self) -> Option<DecodableObject> {
+        match (self.object.take(), &self.palette) {
+            (Some((object_def, color_data_lines)), Some(palette_data)) => {
+                let region = self.find_region(&object_def);
+                Some(DecodableObject {
+                    object_def,
+                    color_data_lines,
+                    palette_data: palette_data.clone(),
+                    region,
+                })
+            }
+            (object, _) => {
+                self.object = object;
+                None
+            }
+        }
+    }
+
+    // The composition object positions the cropped part of the
+    // object, so the whole image starts that much further up and left.
+    fn find_region(&self, object_def: &ObjectDef) -> Option<SubtitleRegion> {
+        let (composition_object, cropping) = self
+            .composition_objects
+            .iter()
+            .find(|(composition_object, _)| composition_object.object_id == object_def.id)?;
+        let (crop_x, crop_y) = cropping
+            .as_ref()
+            .map(|cropping| (cropping.x, cropping.y))
+            .unwrap_or((0, 0));
+        Some(SubtitleRegion {
+            x: composition_object.x.saturating_sub(crop_x),
+            y: composition_object.y.saturating_sub(crop_y),
+            width: object_def.width,
+            height: object_def.height,
+        })
+    }
+}
 
 // This keeps parsing segments until the end of the data,
 // and will return the first bitmap it's able to construct.
@@ -20,7 +99,7 @@ use self::types::{ObjectDef, PaletteDef, PaletteEntry, SegmentHeader, SegmentTyp
 //          behavior of a small set of test files. Over time
 //          this should more closely follow the spec.
 //          Currently likely to break.
-pub fn parse_segments(data: &[u8]) -> Result<Option<SoftwareBitmap>> {
+pub fn parse_segments(data: &[u8], debug_logging: bool) -> Result<Option<PgsFrame>> {
     // The mkv spec (https://www.matroska.org/technical/subtitles.html) says
     // the PGS segments can be found within the blocks.
     //
@@ -33,46 +112,199 @@ pub fn parse_segments(data: &[u8]) -> Result<Option<SoftwareBitmap>> {
     // The blog post "Presentation Graphic Stream (SUP files) BluRay Subtitle Format" (http://blog.thescorpius.com/index.php/2017/07/15/presentation-graphic-stream-sup-files-bluray-subtitle-format/)
     // describes the PGS segment data. However we don't have the first 10 bytes
     // listed there (magic number, pts, dts).
+    let object = match read_first_object(data, debug_logging) {
+        Ok(object) => object,
+        Err(error) => {
+            warn_undecodable(&error);
+            return Ok(None);
+        }
+    };
+    if let Some(object) = object {
+        Ok(Some(decode_object(&object)?))
+    } else {
+        Ok(None)
+    }
+}
+
+// Like parse_segments, but returns every bitmap in the data instead
+// of stopping at the first one. A display set can hold more than one
+// object, e.g. when two lines of dialog are placed separately.
+pub fn parse_all_segments(data: &[u8], debug_logging: bool) -> Result<Vec<PgsFrame>> {
+    let objects = match read_objects(data, false, debug_logging) {
+        Ok(objects) => objects,
+        Err(error) => {
+            warn_undecodable(&error);
+            return Ok(Vec::new());
+        }
+    };
+    let mut frames = Vec::new();
+    for object in objects {
+        frames.push(decode_object(&object)?);
+    }
+    Ok(frames)
+}
+
+// Malformed blocks are skipped, same as with VobSub
+fn warn_undecodable(error: &std::io::Error) {
+    progress::suspend(|| {
+        println!(
+            "Warning! Could not decode PGS subtitle: {} Skipping...",
+            error
+        )
+    });
+}
+
+fn decode_object(object: &DecodableObject) -> Result<PgsFrame> {
+    let bitmap = decode_image(
+        &object.object_def,
+        &object.color_data_lines,
+        &object.palette_data,
+    )?;
+    Ok(PgsFrame {
+        bitmap,
+        region: object.region,
+    })
+}
+
+// Renders the first palette found in the data as a grid of colors
+pub fn parse_palette_swatch(data: &[u8]) -> Result<Option<SoftwareBitmap>> {
+    if let Some(palette_data) = read_first_palette(data).map_err(ShowOrderError::from)? {
+        let bitmap = render_palette_swatch(&palette_data)?;
+        Ok(Some(bitmap))
+    } else {
+        Ok(None)
+    }
+}
+
+// Draws a palette that doesn't come from a PGS track (e.g. the palette
+// of a VobSub track), using each color's index as its id.
+pub fn render_colors_swatch(colors: &[Color]) -> Result<SoftwareBitmap> {
+    let palette_data = colors
+        .iter()
+        .enumerate()
+        .map(|(id, color)| ConvertedPaletteEntry {
+            id: id as u8,
+            color: *color,
+        })
+        .collect::<Vec<_>>();
+    render_palette_swatch(&palette_data)
+}
+
+fn read_first_palette(data: &[u8]) -> std::io::Result<Option<Vec<ConvertedPaletteEntry>>> {
+    let mut reader = std::io::Cursor::new(data);
+    while !reader.is_at_end() {
+        let segment_header: SegmentHeader = reader.deserialize()?;
+        let segment_data = reader.ref_bytes(segment_header.len as usize)?;
+        if segment_header.ty == SegmentType::PaletteDef {
+            let mut segment_data_reader = std::io::Cursor::new(segment_data);
+            let (_, palettes) = read_palette_def_segment(&mut segment_data_reader)?;
+            let converted = palettes.iter().map(convert_palette_color).collect();
+            return Ok(Some(converted));
+        }
+    }
+    Ok(None)
+}
+
+fn read_first_object(data: &[u8], debug_logging: bool) -> std::io::Result<Option<DecodableObject>> {
+    let objects = read_objects(data, true, debug_logging)?;
+    Ok(objects.into_iter().next())
+}
+
+// Unknown segments are skipped, and only mentioned with debug_logging
+fn read_objects(
+    data: &[u8],
+    first_only: bool,
+    debug_logging: bool,
+) -> std::io::Result<Vec<DecodableObject>> {
+    let mut objects = Vec::new();
     let mut reader = std::io::Cursor::new(data);
-    let mut last_palette_data: Option<Vec<ConvertedPaletteEntry>> = None;
+    let mut state = DisplaySetState::default();
     while !reader.is_at_end() {
-        let segment_header: SegmentHeader = reader.deserialize().unwrap();
+        let segment_header: SegmentHeader = reader.deserialize()?;
+        if let SegmentType::Unknown(ty) = segment_header.ty {
+            if debug_logging {
+                progress::suspend(|| {
+                    eprintln!(
+                        "Debug: Skipping unknown PGS segment type 0x{:X} ({} bytes)",
+                        ty, segment_header.len
+                    )
+                });
+            }
+            reader.ref_bytes(segment_header.len as usize)?;
+            continue;
+        }
         if segment_header.len == 0 {
             if segment_header.ty != SegmentType::EndDisplaySet {
-                panic!(
-                    "Invalid segment size for segment type ({:?}): {}",
-                    segment_header.ty, segment_header.len
-                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid segment size for segment type ({:?}): {}",
+                        segment_header.ty, segment_header.len
+                    ),
+                ));
             }
             continue;
         }
-        let segment_data = reader.ref_bytes(segment_header.len as usize).unwrap();
+        let segment_data = reader.ref_bytes(segment_header.len as usize)?;
         let mut segment_data_reader = std::io::Cursor::new(segment_data);
 
         match segment_header.ty {
+            SegmentType::PresentationComp => {
+                let composition: PresentationComp = segment_data_reader.deserialize()?;
+                if composition.composition_state == CompositionState::EpochStart {
+                    state.reset();
+                }
+                state.composition_objects = read_composition_objects(
+                    &mut segment_data_reader,
+                    composition.num_composition_objects,
+                )?;
+            }
             SegmentType::PaletteDef => {
-                let (_, palettes) = read_palette_def_segment(&mut segment_data_reader).unwrap();
+                let (_, palettes) = read_palette_def_segment(&mut segment_data_reader)?;
                 let mut converted = Vec::new();
                 for entry in palettes {
                     let color = convert_palette_color(&entry);
                     converted.push(color);
                 }
-                last_palette_data = Some(converted);
+                state.palette = Some(converted);
             }
             SegmentType::ObjDataDef => {
-                let (object_def, color_data_lines) =
-                    read_object_def_segment(&mut segment_data_reader).unwrap();
-                if let Some(palette_data) = last_palette_data.as_ref() {
-                    let bitmap = decode_image(&object_def, &color_data_lines, palette_data)?;
-                    return Ok(Some(bitmap));
-                } else {
-                    println!("Warning! Expected to have encountered a palette definition before an object definition. Skipping segment...");
+                let object = read_object_def_segment(&mut segment_data_reader)?;
+                if state.palette.is_none() {
+                    progress::suspend(|| {
+                        println!("Warning! Expected to have encountered a palette definition before an object definition. Waiting for a palette...")
+                    });
                 }
+                state.object = Some(object);
             }
             _ => {}
         }
+
+        if let Some(decodable) = state.take_decodable() {
+            objects.push(decodable);
+            if first_only {
+                break;
+            }
+        }
     }
-    Ok(None)
+    Ok(objects)
+}
+
+fn read_composition_objects(
+    reader: &mut std::io::Cursor<&[u8]>,
+    count: u8,
+) -> std::io::Result<Vec<(CompositionObject, Option<CompositionObjectCropping>)>> {
+    let mut composition_objects = Vec::new();
+    for _ in 0..count {
+        let composition_object: CompositionObject = reader.deserialize()?;
+        let cropping = if composition_object.object_cropped_flag & CompositionObject::CROPPED != 0 {
+            Some(reader.deserialize()?)
+        } else {
+            None
+        };
+        composition_objects.push((composition_object, cropping));
+    }
+    Ok(composition_objects)
 }
 
 fn read_palette_def_segment(
@@ -88,27 +320,11 @@ fn read_palette_def_segment(
 }
 
 fn convert_palette_color(entry: &PaletteEntry) -> ConvertedPaletteEntry {
-    type Matrix3x3 = SMatrix<f32, 3, 3>;
-    type Matrix3x1 = SMatrix<f32, 3, 1>;
-
-    static COLOR_CONVERSION_MATRIX: Matrix3x3 = Matrix3x3::new(
-        // https://web.archive.org/web/20180421030430/http://www.equasys.de/colorconversion.html
-        1.164, 0.000, 1.793, 1.164, -0.213, -0.533, 1.164, 2.112,
-        0.000,
-        // https://docs.microsoft.com/en-us/openspecs/windows_protocols/ms-rdprfx/2e1618ed-60d6-4a64-aa5d-0608884861bb
-        //1.0, 0.0, 1.402525, 1.0, -0.343730, -0.714401, 1.0, 1.769905, 0.000013,
-    );
-
-    let values = Matrix3x1::new(
-        (entry.luminance.wrapping_sub(16)) as f32,
-        (entry.color_difference_blue.wrapping_sub(128)) as f32,
-        (entry.color_difference_red.wrapping_sub(128)) as f32,
+    let (r, g, b) = ycbcr_to_rgb(
+        entry.luminance,
+        entry.color_difference_blue,
+        entry.color_difference_red,
     );
-
-    let rgb_values: Matrix3x1 = COLOR_CONVERSION_MATRIX * values;
-    let r = *rgb_values.get((0, 0)).unwrap() as u8;
-    let b = *rgb_values.get((1, 0)).unwrap() as u8;
-    let g = *rgb_values.get((2, 0)).unwrap() as u8;
     let color = Color {
         A: entry.alpha,
         R: r,
@@ -123,9 +339,9 @@ fn convert_palette_color(entry: &PaletteEntry) -> ConvertedPaletteEntry {
 
 fn read_object_def_segment(
     reader: &mut std::io::Cursor<&[u8]>,
-) -> std::io::Result<(ObjectDef, Vec<Vec<(i32, i32)>>)> {
+) -> std::io::Result<(ObjectDef, ColorDataLines)> {
     let object_def: ObjectDef = reader.deserialize()?;
-    let mut color_data_lines: Vec<Vec<(i32, i32)>> = Vec::new();
+    let mut color_data_lines: ColorDataLines = Vec::new();
     let mut current_line: Vec<(i32, i32)> = Vec::new();
     while !reader.is_at_end() {
         let encoded_byte = reader.read_u8()?;
@@ -174,3 +390,293 @@ fn read_object_def_segment(
     }
     Ok((object_def, color_data_lines))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::to_rgba32;
+    use crate::test_utils::{
+        pgs_write_end_display_set, pgs_write_object, pgs_write_palette, pgs_write_presentation_comp,
+    };
+
+    const EPOCH_START: u8 = 0x80;
+    const NORMAL: u8 = 0x00;
+
+    fn segment(ty: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![ty];
+        data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn presentation_comp(composition_state: u8) -> Vec<u8> {
+        pgs_write_presentation_comp(composition_state, &[])
+    }
+
+    fn palette_def(entry_id: u8, alpha: u8) -> Vec<u8> {
+        segment(0x14, &[0x00, 0x00, entry_id, 0xEB, 0x80, 0x80, alpha])
+    }
+
+    fn object_def() -> Vec<u8> {
+        segment(
+            0x15,
+            &[
+                0x00, 0x00, // id
+                0x00, // version
+                0xC0, // last in sequence flag
+                0x00, 0x00, 0x07, // object data length
+                0x00, 0x01, // width
+                0x00, 0x01, // height
+                0x01, 0x00, 0x00, // one pixel of color 1, end of line
+            ],
+        )
+    }
+
+    fn end_display_set() -> Vec<u8> {
+        segment(0x80, &[])
+    }
+
+    #[test]
+    fn palette_does_not_bleed_across_epochs_test() {
+        let data = [
+            presentation_comp(EPOCH_START),
+            palette_def(1, 0x11),
+            end_display_set(),
+            presentation_comp(EPOCH_START),
+            object_def(),
+            end_display_set(),
+        ]
+        .concat();
+        assert!(read_first_object(&data, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn independent_epoch_palettes_test() {
+        let data = [
+            presentation_comp(EPOCH_START),
+            palette_def(1, 0x11),
+            end_display_set(),
+            presentation_comp(EPOCH_START),
+            palette_def(2, 0x22),
+            object_def(),
+            end_display_set(),
+        ]
+        .concat();
+        let object = read_first_object(&data, false).unwrap().unwrap();
+        assert_eq!(object.object_def.width, 1);
+        assert_eq!(object.object_def.height, 1);
+        assert_eq!(object.color_data_lines, vec![vec![(1, 1)]]);
+        assert_eq!(object.palette_data.len(), 1);
+        assert_eq!(object.palette_data[0].id, 2);
+        assert_eq!(object.palette_data[0].color.A, 0x22);
+    }
+
+    #[test]
+    fn palette_persists_within_epoch_test() {
+        let data = [
+            presentation_comp(EPOCH_START),
+            palette_def(1, 0x11),
+            end_display_set(),
+            presentation_comp(NORMAL),
+            object_def(),
+            end_display_set(),
+        ]
+        .concat();
+        let object = read_first_object(&data, false).unwrap().unwrap();
+        assert_eq!(object.palette_data[0].id, 1);
+        assert_eq!(object.palette_data[0].color.A, 0x11);
+    }
+
+    #[test]
+    fn read_first_palette_test() {
+        let data = [
+            presentation_comp(EPOCH_START),
+            object_def(),
+            palette_def(3, 0x33),
+            palette_def(4, 0x44),
+            end_display_set(),
+        ]
+        .concat();
+        let palette_data = read_first_palette(&data).unwrap().unwrap();
+        assert_eq!(palette_data.len(), 1);
+        assert_eq!(palette_data[0].id, 3);
+        assert_eq!(palette_data[0].color.A, 0x33);
+
+        let data = [presentation_comp(EPOCH_START), end_display_set()].concat();
+        assert!(read_first_palette(&data).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_segments_test() -> Result<()> {
+        let data = [
+            pgs_write_presentation_comp(EPOCH_START, &[(0, 300, 800)]),
+            pgs_write_palette(&[
+                (1, 0x10, 0x80, 0x80, 0xFF), // black
+                (2, 0xEB, 0x80, 0x80, 0xFF), // white
+                (3, 0x10, 0x80, 0x80, 0x80), // half transparent black
+                (4, 0xEB, 0x80, 0x80, 0x00), // transparent white
+            ]),
+            pgs_write_object(0, 2, 2, &[&[(1, 1), (2, 1)], &[(3, 1), (4, 1)]]),
+            pgs_write_end_display_set(),
+        ]
+        .concat();
+        let PgsFrame { bitmap, region } = parse_segments(&data, false)?.unwrap();
+        assert_eq!(
+            region,
+            Some(SubtitleRegion {
+                x: 300,
+                y: 800,
+                width: 2,
+                height: 2,
+            })
+        );
+        assert_eq!(bitmap.PixelWidth()?, 2);
+        assert_eq!(bitmap.PixelHeight()?, 2);
+        // (0xEB - 0x10) * 1.164 truncates to 254
+        #[rustfmt::skip]
+        let expected = vec![
+            0, 0, 0, 255,       254, 254, 254, 255,
+            0, 0, 0, 0x80,      254, 254, 254, 0,
+        ];
+        assert_eq!(to_rgba32(&bitmap)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_all_segments_test() -> Result<()> {
+        let data = [
+            pgs_write_presentation_comp(EPOCH_START, &[(1, 10, 20), (0, 30, 40)]),
+            pgs_write_palette(&[(1, 0xEB, 0x80, 0x80, 0xFF)]),
+            pgs_write_object(0, 2, 1, &[&[(1, 2)]]),
+            pgs_write_object(1, 3, 2, &[&[(1, 3)], &[(1, 3)]]),
+            pgs_write_object(2, 4, 1, &[&[(1, 4)]]),
+            pgs_write_end_display_set(),
+        ]
+        .concat();
+        let frames = parse_all_segments(&data, false)?;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].bitmap.PixelWidth()?, 2);
+        assert_eq!(frames[1].bitmap.PixelWidth()?, 3);
+        assert_eq!(frames[1].bitmap.PixelHeight()?, 2);
+        // Positions are matched up by object id, not by order
+        let positions = frames
+            .iter()
+            .map(|frame| frame.region.map(|region| (region.x, region.y)))
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![Some((30, 40)), Some((10, 20)), None]);
+
+        // parse_segments still only gives back the first one
+        let frame = parse_segments(&data, false)?.unwrap();
+        assert_eq!(frame.bitmap.PixelWidth()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cropped_composition_object_test() {
+        let data = [
+            segment(
+                0x16,
+                &[
+                    0x07,
+                    0x80, // width
+                    0x04,
+                    0x38, // height
+                    0x10, // frame rate
+                    0x00,
+                    0x01, // composition number
+                    EPOCH_START,
+                    0x00, // palette update flag
+                    0x00, // palette id
+                    0x01, // number of composition objects
+                    0x00,
+                    0x00, // object id
+                    0x00, // window id
+                    0x40, // cropped flag
+                    0x00,
+                    0x64, // x
+                    0x00,
+                    0xC8, // y
+                    0x00,
+                    0x0A, // crop x
+                    0x00,
+                    0x14, // crop y
+                    0x00,
+                    0x01, // crop width
+                    0x00,
+                    0x01, // crop height
+                ],
+            ),
+            palette_def(1, 0x11),
+            object_def(),
+            end_display_set(),
+        ]
+        .concat();
+        let object = read_first_object(&data, false).unwrap().unwrap();
+        assert_eq!(
+            object.region,
+            Some(SubtitleRegion {
+                x: 90,
+                y: 180,
+                width: 1,
+                height: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_segment_type_test() {
+        let data = [
+            presentation_comp(EPOCH_START),
+            segment(0x99, &[0x01, 0x02, 0x03]),
+            palette_def(1, 0x11),
+            segment(0x99, &[]),
+            object_def(),
+            end_display_set(),
+        ]
+        .concat();
+        let object = read_first_object(&data, false).unwrap().unwrap();
+        assert_eq!(object.object_def.width, 1);
+        assert_eq!(object.palette_data[0].id, 1);
+
+        let mut reader = std::io::Cursor::new(&[0x99u8, 0x00, 0x03][..]);
+        let header: SegmentHeader = reader.deserialize().unwrap();
+        assert_eq!(header.ty, SegmentType::Unknown(0x99));
+        assert_eq!(header.len, 3);
+    }
+
+    #[test]
+    fn malformed_segments_test() -> Result<()> {
+        // The segment says it's longer than the data we have
+        let data = [palette_def(1, 0x11), vec![0x15, 0x00, 0x10, 0x00]].concat();
+        let error = read_first_object(&data, false).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(parse_segments(&data, false)?.is_none());
+        assert!(parse_all_segments(&data, false)?.is_empty());
+
+        // Only the end of a display set can be empty
+        let data = [segment(0x14, &[]), end_display_set()].concat();
+        let error = read_first_object(&data, false).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let data = [presentation_comp(0x20), palette_def(1, 0x11), object_def()].concat();
+        let error = read_first_object(&data, false).err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(parse_segments(&data, false)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn object_data_length_test() {
+        use super::types::ObjectDataLength;
+
+        let data = [0xFFu8, 0xFF, 0xFF, 0x12];
+        let mut reader = std::io::Cursor::new(&data[..]);
+        let length: ObjectDataLength = reader.deserialize().unwrap();
+        assert_eq!(length.0, ObjectDataLength::MAX);
+        assert_eq!(reader.position(), 3);
+
+        let data = [0x00u8, 0x01];
+        let mut reader = std::io::Cursor::new(&data[..]);
+        assert!(reader.deserialize::<ObjectDataLength>().is_err());
+    }
+}
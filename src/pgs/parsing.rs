@@ -57,7 +57,29 @@ macro_rules! pgs_enum {
                 let value = reader.read_u8()?;
                 match value {
                     $( $value => Ok($name::$variant), )*
-                    _ => panic!("Unknown value: 0x{:X}", value), // TODO: return error
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unknown {} value: 0x{:X}", stringify!($name), value),
+                    )),
+                }
+            }
+        }
+    );
+    // Values that aren't listed are kept as Unknown instead of panicking
+    ( #[unknown] $name:ident { $( $variant:ident = $value:expr ),* $(,)* }) => (
+        #[derive(Debug, PartialEq)]
+        pub enum $name {
+            $( $variant, )*
+            Unknown(u8),
+        }
+
+        impl crate::pgs::parsing::Deserialize for $name {
+            fn deserialize<R: std::io::Read>(reader: &mut dyn std::io::Read) -> std::io::Result<Self> {
+                use byteorder::ReadBytesExt;
+                let value = reader.read_u8()?;
+                match value {
+                    $( $value => Ok($name::$variant), )*
+                    _ => Ok($name::Unknown(value)),
                 }
             }
         }
@@ -78,8 +100,16 @@ impl PgsDeserializer for std::io::Cursor<&[u8]> {
     fn ref_bytes(&mut self, len: usize) -> std::io::Result<&[u8]> {
         let start = self.position() as usize;
         let end = start + len;
-        let slice = &self.get_ref()[start..end];
-        assert_eq!(slice.len(), len);
+        let slice = self.get_ref().get(start..end).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "expected {} bytes, but only {} are left",
+                    len,
+                    self.get_ref().len().saturating_sub(start)
+                ),
+            )
+        })?;
         self.set_position(end as u64);
         Ok(slice)
     }
@@ -1,6 +1,7 @@
 use crate::{pgs_enum, pgs_struct};
 
-pgs_enum! { SegmentType {
+// Discs sometimes contain reserved or newer segment types
+pgs_enum! { #[unknown] SegmentType {
     PaletteDef = 0x14,
     ObjDataDef = 0x15,
     PresentationComp = 0x16,
@@ -13,6 +14,43 @@ pgs_struct! { SegmentHeader {
     len: u16,
 }}
 
+pgs_enum! { CompositionState {
+    Normal = 0x00,
+    AcquisitionPoint = 0x40,
+    EpochStart = 0x80,
+}}
+
+pgs_struct! { PresentationComp {
+    width: u16,
+    height: u16,
+    frame_rate: u8,
+    composition_number: u16,
+    composition_state: CompositionState,
+    palette_update_flag: u8,
+    palette_id: u8,
+    num_composition_objects: u8,
+}}
+
+// Followed by a CompositionObjectCropping when the cropped flag is set
+pgs_struct! { CompositionObject {
+    object_id: u16,
+    window_id: u8,
+    object_cropped_flag: u8,
+    x: u16,
+    y: u16,
+}}
+
+impl CompositionObject {
+    pub const CROPPED: u8 = 0x40;
+}
+
+pgs_struct! { CompositionObjectCropping {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}}
+
 pgs_struct! { PaletteDef {
     palette_id: u8,
     version: u8,
@@ -26,14 +64,24 @@ pgs_struct! { PaletteEntry {
     alpha: u8,
 }}
 
+// The object data length is stored as a 24-bit big-endian value
+// (see "Object Definition Segment" in section 9.14 of the Blu-ray
+// spec, or http://blog.thescorpius.com/index.php/2017/07/15/presentation-graphic-stream-sup-files-bluray-subtitle-format/).
+// Objects larger than that are split across multiple segments.
 #[derive(Debug)]
 pub struct ObjectDataLength(pub u32);
 
+impl ObjectDataLength {
+    pub const MAX: u32 = (1 << 24) - 1;
+}
+
 impl super::parsing::Deserialize for ObjectDataLength {
     fn deserialize<R: std::io::Read>(reader: &mut dyn std::io::Read) -> std::io::Result<Self> {
+        // Read the 3 bytes into the lower 24 bits of a u32
         let mut bytes = [0u8; 4];
         reader.read_exact(&mut bytes[1..])?;
         let value = u32::from_be_bytes(bytes);
+        debug_assert!(value <= ObjectDataLength::MAX);
         Ok(ObjectDataLength(value))
     }
 }
@@ -0,0 +1,41 @@
+use std::sync::Mutex;
+
+use indicatif::ProgressBar;
+use lazy_static::lazy_static;
+
+// Anything printed while a progress bar is drawn ends up mixed into the
+// bar, so output from deep inside the loaders goes through suspend.
+lazy_static! {
+    static ref ACTIVE_PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+}
+
+// Makes the given bar the one that suspend hides, until the
+// returned guard is dropped.
+pub fn set_active_progress_bar(progress: &ProgressBar) -> ActiveProgressBarGuard {
+    let previous = ACTIVE_PROGRESS_BAR
+        .lock()
+        .unwrap()
+        .replace(progress.clone());
+    ActiveProgressBarGuard { previous }
+}
+
+pub struct ActiveProgressBarGuard {
+    previous: Option<ProgressBar>,
+}
+
+impl Drop for ActiveProgressBarGuard {
+    fn drop(&mut self) {
+        *ACTIVE_PROGRESS_BAR.lock().unwrap() = self.previous.take();
+    }
+}
+
+// Hides the active progress bar (if there is one) while f runs
+pub fn suspend<F: FnOnce() -> R, R>(f: F) -> R {
+    // Don't hold the lock while printing, other threads may want to print too
+    let progress = ACTIVE_PROGRESS_BAR.lock().unwrap().clone();
+    if let Some(progress) = progress {
+        progress.suspend(f)
+    } else {
+        f()
+    }
+}
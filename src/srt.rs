@@ -1,28 +1,455 @@
-use std::path::Path;
+use std::{
+    io::{Read, Seek, Write},
+    path::Path,
+};
 
-use crate::text::sanitize_text;
+use lazy_static::lazy_static;
+use regex::Regex;
 
-pub fn parse_n_subtitles<P: AsRef<Path>>(path: P, num_subtitles: usize) -> Vec<String> {
+use crate::{
+    progress,
+    text::{is_mixed_case, is_stage_direction, sanitize_text},
+};
+
+// Compiling this takes ~250µs, which adds up over a zip file full of srt files
+lazy_static! {
+    static ref TIMESTAMP_REGEX: Regex =
+        Regex::new(r"^\d+:\d+:\d+[,.]\d+ --> \d+:\d+:\d+[,.]\d+").unwrap();
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ParseOptions {
+    // When false, the text of each subtitle is left as-is
+    // other than joining its lines.
+    pub sanitize: bool,
+    // Treat malformed entries as errors instead of skipping them
+    pub strict: bool,
+    // Drop lines that are only SDH stage directions, see text::sanitize_sdh_text
+    pub sdh: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            sanitize: true,
+            strict: false,
+            sdh: false,
+        }
+    }
+}
+
+// Warn about a file if more than this percentage of its entries are malformed
+const MALFORMED_ENTRY_WARNING_PERCENTAGE: usize = 5;
+
+pub fn parse_n_subtitles<P: AsRef<Path>>(
+    path: P,
+    num_subtitles: usize,
+    options: ParseOptions,
+) -> std::io::Result<Vec<String>> {
+    let path = path.as_ref();
+    let raw_data = std::fs::read(path)?;
+    parse_n_subtitles_from_data(
+        &raw_data,
+        &path.display().to_string(),
+        num_subtitles,
+        options,
+    )
+}
+
+pub fn parse_n_subtitles_from_reader<R: Read>(
+    mut reader: R,
+    name: &str,
+    num_subtitles: usize,
+    options: ParseOptions,
+) -> std::io::Result<Vec<String>> {
+    let mut raw_data = Vec::new();
+    reader.read_to_end(&mut raw_data)?;
+    parse_n_subtitles_from_data(&raw_data, name, num_subtitles, options)
+}
+
+// Returns the subtitles of each srt file in the archive, keyed
+// by the name of the entry.
+pub fn parse_n_subtitles_from_zip<R: Read + Seek>(
+    reader: R,
+    num_subtitles: usize,
+    options: ParseOptions,
+) -> zip::result::ZipResult<Vec<(String, Vec<String>)>> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    let mut result = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if entry.is_file() && entry.name().ends_with(".srt") {
+            let name = entry.name().to_owned();
+            let subtitles = parse_n_subtitles_from_reader(entry, &name, num_subtitles, options)?;
+            if !subtitles.is_empty() {
+                result.push((name, subtitles));
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn parse_n_subtitles_from_data(
+    raw_data: &[u8],
+    name: &str,
+    num_subtitles: usize,
+    options: ParseOptions,
+) -> std::io::Result<Vec<String>> {
+    let mixed_case = options.sdh && is_mixed_case(&String::from_utf8_lossy(raw_data));
+    parse_n_entries(raw_data, name, num_subtitles, options.strict, |lines| {
+        let text = if options.sdh {
+            lines
+                .iter()
+                .filter(|line| !is_stage_direction(line, mixed_case))
+                .copied()
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            lines.join(" ")
+        };
+        let text = if options.sanitize {
+            sanitize_text(&text)
+        } else {
+            text.trim().to_owned()
+        };
+        if !text.is_empty() {
+            Some(text)
+        } else {
+            None
+        }
+    })
+}
+
+// Unlike parse_n_subtitles, this preserves the line breaks
+// within each subtitle entry.
+#[allow(dead_code)] // Not used by any command yet
+pub fn parse_n_subtitle_lines<P: AsRef<Path>>(
+    path: P,
+    num_subtitles: usize,
+    strict: bool,
+) -> std::io::Result<Vec<Vec<String>>> {
     let path = path.as_ref();
-    let raw_data =
-        std::fs::read(path).expect(&format!("Could not read from \"{}\"", path.display()));
-    let data = String::from_utf8_lossy(&raw_data);
-    let data = data.replace("\r\n", "\n");
-    let chunks = data.split("\n\n");
-
-    let mut subtitles = Vec::new();
-    for chunk in chunks {
+    let raw_data = std::fs::read(path)?;
+    let name = path.display().to_string();
+    parse_n_entries(&raw_data, &name, num_subtitles, strict, |lines| {
+        let lines = lines
+            .iter()
+            .map(|line| sanitize_text(line))
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        if !lines.is_empty() {
+            Some(lines)
+        } else {
+            None
+        }
+    })
+}
+
+// Entries without a timestamp line in the second position are
+// malformed. They are skipped with a warning, or cause an error
+// when strict is set. Some tools write the milliseconds after a '.'
+// instead of a ',', so both are accepted.
+fn parse_n_entries<T, F: Fn(&[&str]) -> Option<T>>(
+    raw_data: &[u8],
+    name: &str,
+    num_entries: usize,
+    strict: bool,
+    process_lines: F,
+) -> std::io::Result<Vec<T>> {
+    let data = String::from_utf8_lossy(raw_data);
+
+    let mut entries = Vec::new();
+    let mut chunk = Vec::new();
+    let mut num_chunks = 0;
+    let mut num_malformed = 0;
+    // Chain an empty line so that the last chunk gets processed
+    for line in split_lines(&data).chain(std::iter::once("")) {
+        if !line.is_empty() {
+            chunk.push(line);
+            continue;
+        }
         if !chunk.is_empty() {
-            let mut parts = chunk.splitn(3, "\n");
-            let text = parts.nth(2).unwrap().replace("\n", " ");
-            let text = sanitize_text(&text);
-            if !text.is_empty() {
-                subtitles.push(text);
-                if subtitles.len() >= num_subtitles {
+            num_chunks += 1;
+            let is_well_formed = chunk
+                .get(1)
+                .map(|line| TIMESTAMP_REGEX.is_match(line.trim()))
+                .unwrap_or(false);
+            if !is_well_formed {
+                if strict {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "Malformed subtitle entry in \"{}\": {:?}",
+                            name,
+                            chunk.join("\n")
+                        ),
+                    ));
+                }
+                progress::suspend(|| {
+                    eprintln!(
+                        "Warning! Skipping malformed subtitle entry in \"{}\": {:?}",
+                        name,
+                        chunk.join("\n")
+                    )
+                });
+                num_malformed += 1;
+                chunk.clear();
+                continue;
+            }
+            // Skip the index and the timestamps
+            if let Some(entry) = process_lines(&chunk[2..]) {
+                entries.push(entry);
+                if entries.len() >= num_entries {
                     break;
                 }
             }
+            chunk.clear();
+        }
+    }
+    if num_malformed * 100 > num_chunks * MALFORMED_ENTRY_WARNING_PERCENTAGE {
+        progress::suspend(|| {
+            eprintln!(
+                "Warning! {} of {} subtitle entries in \"{}\" were malformed.",
+                num_malformed, num_chunks, name
+            )
+        });
+    }
+    Ok(entries)
+}
+
+// A subtitle to be written to an srt file. Line breaks in the
+// text are kept, and written as CRLF like the rest of the file.
+pub struct SrtEntry {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub text: String,
+}
+
+pub fn write_srt<W: Write>(mut writer: W, entries: &[SrtEntry]) -> std::io::Result<()> {
+    for (i, entry) in entries.iter().enumerate() {
+        write!(
+            writer,
+            "{}\r\n{} --> {}\r\n{}\r\n\r\n",
+            i + 1,
+            format_srt_timestamp(entry.start_ms),
+            format_srt_timestamp(entry.end_ms),
+            split_lines(&entry.text).collect::<Vec<_>>().join("\r\n")
+        )?;
+    }
+    Ok(())
+}
+
+// Formats a timestamp as HH:MM:SS,mmm
+pub fn format_srt_timestamp(timestamp_ms: u64) -> String {
+    let hours = timestamp_ms / 3_600_000;
+    let minutes = (timestamp_ms / 60_000) % 60;
+    let seconds = (timestamp_ms / 1000) % 60;
+    let milliseconds = timestamp_ms % 1000;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        hours, minutes, seconds, milliseconds
+    )
+}
+
+// Like str::lines, but also treats a lone '\r' as a line ending.
+fn split_lines(data: &str) -> impl Iterator<Item = &str> {
+    let mut remaining = Some(data);
+    std::iter::from_fn(move || {
+        let data = remaining?;
+        if let Some(index) = data.find(|c| c == '\r' || c == '\n') {
+            let line = &data[..index];
+            let rest = &data[index..];
+            let rest = rest
+                .strip_prefix("\r\n")
+                .or_else(|| rest.strip_prefix('\r'))
+                .or_else(|| rest.strip_prefix('\n'))
+                .unwrap();
+            remaining = if rest.is_empty() { None } else { Some(rest) };
+            Some(line)
+        } else {
+            remaining = None;
+            if data.is_empty() {
+                None
+            } else {
+                Some(data)
+            }
         }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    const TEST_SRT: &str = "1\r\n00:00:01,000 --> 00:00:02,000\r\nHello there.\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\nGeneral Kenobi!\r\n\r\n";
+
+    #[test]
+    fn write_srt_test() {
+        let entries = [
+            SrtEntry {
+                start_ms: 1000,
+                end_ms: 2000,
+                text: "Hello there.".to_owned(),
+            },
+            SrtEntry {
+                start_ms: 3000,
+                end_ms: 4000,
+                text: "General Kenobi!".to_owned(),
+            },
+        ];
+        let mut data = Vec::new();
+        write_srt(&mut data, &entries).unwrap();
+        assert_eq!(String::from_utf8(data).unwrap(), TEST_SRT);
+        assert_eq!(format_srt_timestamp(3_723_004), "01:02:03,004");
+    }
+
+    #[test]
+    fn write_srt_line_breaks_test() {
+        let entries = [SrtEntry {
+            start_ms: 1000,
+            end_ms: 2000,
+            text: "Hello there.\nGeneral Kenobi!".to_owned(),
+        }];
+        let path = std::env::temp_dir().join("showorder_write_srt_line_breaks_test.srt");
+        write_srt(std::fs::File::create(&path).unwrap(), &entries).unwrap();
+        let subtitles = parse_n_subtitle_lines(&path, 5, true).unwrap();
+        assert_eq!(
+            subtitles,
+            vec![vec!["hello there".to_owned(), "general kenobi".to_owned()]]
+        );
+        // The line breaks are only joined when matching
+        let subtitles = parse_n_subtitles(&path, 5, ParseOptions::default()).unwrap();
+        assert_eq!(subtitles, vec!["hello there general kenobi".to_owned()]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_from_zip_test() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        writer
+            .start_file("season 1/episode 1.srt", options)
+            .unwrap();
+        writer.write_all(TEST_SRT.as_bytes()).unwrap();
+        writer.start_file("readme.txt", options).unwrap();
+        writer.write_all(b"Not a subtitle").unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        let files =
+            parse_n_subtitles_from_zip(Cursor::new(data), 5, ParseOptions::default()).unwrap();
+        assert_eq!(
+            files,
+            vec![(
+                "season 1/episode 1.srt".to_owned(),
+                vec!["hello there".to_owned(), "general kenobi".to_owned()]
+            )]
+        );
+    }
+
+    #[test]
+    fn line_endings_test() {
+        let lf = TEST_SRT.replace("\r\n", "\n");
+        let cr = TEST_SRT.replace("\r\n", "\r");
+        let expected = parse_n_subtitles_from_data(
+            TEST_SRT.as_bytes(),
+            "test.srt",
+            5,
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            expected,
+            vec!["hello there".to_owned(), "general kenobi".to_owned()]
+        );
+        assert_eq!(
+            parse_n_subtitles_from_data(lf.as_bytes(), "test.srt", 5, ParseOptions::default())
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_n_subtitles_from_data(cr.as_bytes(), "test.srt", 5, ParseOptions::default())
+                .unwrap(),
+            expected
+        );
+
+        // Mixed line endings, without a trailing blank line
+        let mixed = "1\r00:00:01,000 --> 00:00:02,000\r\nHello there.\n\r\n2\n00:00:03,000 --> 00:00:04,000\rGeneral Kenobi!";
+        assert_eq!(
+            parse_n_subtitles_from_data(mixed.as_bytes(), "test.srt", 5, ParseOptions::default())
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn no_sanitize_test() {
+        assert_eq!(
+            parse_n_subtitles_from_data(
+                TEST_SRT.as_bytes(),
+                "test.srt",
+                5,
+                ParseOptions {
+                    sanitize: false,
+                    ..Default::default()
+                }
+            )
+            .unwrap(),
+            vec!["Hello there.".to_owned(), "General Kenobi!".to_owned()]
+        );
+    }
+
+    #[test]
+    fn malformed_entry_test() {
+        // The second entry is missing its timestamps
+        let data = "1\r\n00:00:01,000 --> 00:00:02,000\r\nHello there.\r\n\r\n2\r\nGeneral Kenobi!\r\n\r\n3\r\n00:00:05,000 --> 00:00:06,000\r\nYou are a bold one.\r\n";
+        assert_eq!(
+            parse_n_subtitles_from_data(data.as_bytes(), "test.srt", 5, ParseOptions::default())
+                .unwrap(),
+            vec!["hello there".to_owned(), "you are a bold one".to_owned()]
+        );
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let error =
+            parse_n_subtitles_from_data(data.as_bytes(), "test.srt", 5, strict).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(parse_n_subtitles_from_data(TEST_SRT.as_bytes(), "test.srt", 5, strict).is_ok());
+
+        // Periods are accepted as the millisecond separator
+        let data = "1\r\n00:00:01.000 --> 00:00:02.000\r\nHello there.\r\n";
+        assert_eq!(
+            parse_n_subtitles_from_data(data.as_bytes(), "test.srt", 5, strict).unwrap(),
+            vec!["hello there".to_owned()]
+        );
+    }
+
+    #[test]
+    fn sdh_test() {
+        let data = "1\r\n00:00:01,000 --> 00:00:02,000\r\nDOOR CREAKING\r\nHello there.\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\n[THUNDER]\r\n\r\n3\r\n00:00:05,000 --> 00:00:06,000\r\nGeneral Kenobi!\r\n";
+        let sdh = ParseOptions {
+            sdh: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_n_subtitles_from_data(data.as_bytes(), "test.srt", 5, sdh).unwrap(),
+            vec!["hello there".to_owned(), "general kenobi".to_owned()]
+        );
+        assert_eq!(
+            parse_n_subtitles_from_data(data.as_bytes(), "test.srt", 5, ParseOptions::default())
+                .unwrap(),
+            vec![
+                "door creaking hello there".to_owned(),
+                "general kenobi".to_owned()
+            ]
+        );
+
+        // All caps lines are dialogue when the whole file is upper case
+        let data = "1\r\n00:00:01,000 --> 00:00:02,000\r\n[THUNDER]\r\nGET INSIDE!\r\n";
+        assert_eq!(
+            parse_n_subtitles_from_data(data.as_bytes(), "test.srt", 5, sdh).unwrap(),
+            vec!["get inside".to_owned()]
+        );
     }
-    subtitles
 }
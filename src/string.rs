@@ -1,4 +1,87 @@
-pub fn normalize_to_shortest_string<'a>(string1: &'a str, string2: &'a str) -> (&'a str, &'a str) {
+use std::{borrow::Cow, fmt::Debug, fmt::Display, str::FromStr};
+
+use levenshtein::levenshtein;
+
+use crate::text::{extract_words, normalize_word_sequence};
+
+// How two strings of different lengths are made comparable
+// before computing the distance between them.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum NormalizationStrategy {
+    // Truncate the longer string to the length of the shorter one
+    #[default]
+    TruncateToShortest,
+    // Like TruncateToShortest, but keeps the end of the longer string
+    TruncateToLongest,
+    // Pad the shorter string with spaces to the length of the longer one
+    PadShorter,
+    // Compare the full strings
+    None,
+}
+
+impl NormalizationStrategy {
+    // The number of characters that are compared for strings of the given lengths
+    pub fn compared_length(&self, len1: usize, len2: usize) -> usize {
+        match self {
+            NormalizationStrategy::TruncateToShortest
+            | NormalizationStrategy::TruncateToLongest => len1.min(len2),
+            NormalizationStrategy::PadShorter | NormalizationStrategy::None => len1.max(len2),
+        }
+    }
+}
+
+pub struct NormalizationStrategyParseError(pub String);
+impl Display for NormalizationStrategyParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown normalization strategy \"{}\".", self.0)
+    }
+}
+impl Debug for NormalizationStrategyParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{}}}", self.0)
+    }
+}
+impl std::error::Error for NormalizationStrategyParseError {}
+
+impl FromStr for NormalizationStrategy {
+    type Err = NormalizationStrategyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate-to-shortest" => Ok(NormalizationStrategy::TruncateToShortest),
+            "truncate-to-longest" => Ok(NormalizationStrategy::TruncateToLongest),
+            "pad-shorter" => Ok(NormalizationStrategy::PadShorter),
+            "none" => Ok(NormalizationStrategy::None),
+            _ => Err(NormalizationStrategyParseError(s.to_string())),
+        }
+    }
+}
+
+pub fn normalize_to_shortest_string<'a>(
+    string1: &'a str,
+    string2: &'a str,
+    strategy: NormalizationStrategy,
+) -> (Cow<'a, str>, Cow<'a, str>) {
+    match strategy {
+        NormalizationStrategy::TruncateToShortest => {
+            let (str1, str2) = truncate_to_shortest(string1, string2);
+            (Cow::Borrowed(str1), Cow::Borrowed(str2))
+        }
+        NormalizationStrategy::TruncateToLongest => {
+            let (str1, str2) = truncate_to_longest(string1, string2);
+            (Cow::Borrowed(str1), Cow::Borrowed(str2))
+        }
+        NormalizationStrategy::PadShorter => pad_shorter(string1, string2),
+        NormalizationStrategy::None => (Cow::Borrowed(string1), Cow::Borrowed(string2)),
+    }
+}
+
+fn truncate_to_shortest<'a>(string1: &'a str, string2: &'a str) -> (&'a str, &'a str) {
+    // Subtitles can end up empty after being sanitized
+    if string1.is_empty() || string2.is_empty() {
+        return ("", "");
+    }
+
     let (string1_len, _) = string1.char_indices().enumerate().last().unwrap();
     let (string2_len, _) = string2.char_indices().enumerate().last().unwrap();
 
@@ -18,7 +101,375 @@ pub fn normalize_to_shortest_string<'a>(string1: &'a str, string2: &'a str) -> (
     (str1, str2)
 }
 
+fn truncate_to_longest<'a>(string1: &'a str, string2: &'a str) -> (&'a str, &'a str) {
+    // Subtitles can end up empty after being sanitized
+    if string1.is_empty() || string2.is_empty() {
+        return ("", "");
+    }
+
+    let string1_len = string1.chars().count();
+    let string2_len = string2.chars().count();
+    if string1_len > string2_len {
+        (substring_from_end(string1, string2_len), string2)
+    } else {
+        (string1, substring_from_end(string2, string1_len))
+    }
+}
+
+fn pad_shorter<'a>(string1: &'a str, string2: &'a str) -> (Cow<'a, str>, Cow<'a, str>) {
+    let string1_len = string1.chars().count();
+    let string2_len = string2.chars().count();
+    let pad = |string: &'a str, len: usize, target_len: usize| -> Cow<'a, str> {
+        if len < target_len {
+            Cow::Owned(format!("{}{}", string, " ".repeat(target_len - len)))
+        } else {
+            Cow::Borrowed(string)
+        }
+    };
+    let len = string1_len.max(string2_len);
+    (
+        pad(string1, string1_len, len),
+        pad(string2, string2_len, len),
+    )
+}
+
+// Returns the last len characters of the string
+fn substring_from_end(string: &str, len: usize) -> &str {
+    if len == 0 {
+        return "";
+    }
+    let start = string
+        .char_indices()
+        .rev()
+        .nth(len - 1)
+        .map(|(start, _)| start)
+        .unwrap_or(0);
+    &string[start..]
+}
+
 fn substring(string: &str, len: usize) -> &str {
-    let (end, _) = string.char_indices().nth(len).unwrap();
+    let end = string
+        .char_indices()
+        .nth(len)
+        .map(|(end, _)| end)
+        .unwrap_or(string.len());
     &string[..end]
 }
+
+pub fn compute_distance(string1: &str, string2: &str, strategy: NormalizationStrategy) -> usize {
+    let (normalized1, normalized2) = normalize_to_shortest_string(string1, string2, strategy);
+    levenshtein(&normalized1, &normalized2)
+}
+
+// The minimum distance between the shorter string and each window of
+// window_size characters in the longer string. This approximates the
+// distance between the two strings at their best alignment. An empty
+// string would match an empty window exactly, so that's left to levenshtein.
+pub fn levenshtein_windowed(string1: &str, string2: &str, window_size: usize) -> usize {
+    let string1_len = string1.chars().count();
+    let string2_len = string2.chars().count();
+    let (longer, longer_len, shorter) = if string1_len >= string2_len {
+        (string1, string1_len, string2)
+    } else {
+        (string2, string2_len, string1)
+    };
+    if window_size >= longer_len || shorter.is_empty() {
+        return levenshtein(string1, string2);
+    }
+
+    let char_starts = longer
+        .char_indices()
+        .map(|(start, _)| start)
+        .chain(std::iter::once(longer.len()))
+        .collect::<Vec<_>>();
+    (0..=longer_len - window_size)
+        .map(|start| {
+            let window = &longer[char_starts[start]..char_starts[start + window_size]];
+            levenshtein(window, shorter)
+        })
+        .min()
+        .unwrap()
+}
+
+// Slides short_str across long_str, step characters at a time, and
+// returns the character offset in long_str where short_str is the
+// closest match. OCR output can start with text (e.g. credits) that
+// isn't in the reference subtitles, or the other way around.
+pub fn find_best_alignment(long_str: &str, short_str: &str, step: usize) -> usize {
+    let long_len = long_str.chars().count();
+    let short_len = short_str.chars().count();
+    if short_len >= long_len {
+        return 0;
+    }
+
+    let char_starts = long_str
+        .char_indices()
+        .map(|(start, _)| start)
+        .chain(std::iter::once(long_str.len()))
+        .collect::<Vec<_>>();
+    (0..=long_len - short_len)
+        .step_by(step.max(1))
+        .min_by_key(|start| {
+            let aligned = &long_str[char_starts[*start]..char_starts[start + short_len]];
+            levenshtein(aligned, short_str)
+        })
+        .unwrap()
+}
+
+// Like compute_distance, but the longer string is first aligned
+// with the shorter one using find_best_alignment.
+pub fn compute_aligned_distance(
+    string1: &str,
+    string2: &str,
+    step: usize,
+    strategy: NormalizationStrategy,
+) -> usize {
+    fn skip_to_alignment<'a>(longer: &'a str, shorter: &str, step: usize) -> &'a str {
+        let offset = find_best_alignment(longer, shorter, step);
+        let start = longer
+            .char_indices()
+            .nth(offset)
+            .map(|(start, _)| start)
+            .unwrap_or(longer.len());
+        &longer[start..]
+    }
+
+    if string1.chars().count() > string2.chars().count() {
+        let aligned = skip_to_alignment(string1, string2, step);
+        compute_distance(aligned, string2, strategy)
+    } else {
+        let aligned = skip_to_alignment(string2, string1, step);
+        compute_distance(string1, aligned, strategy)
+    }
+}
+
+// Like compute_distance, but counts inserted, removed, and
+// substituted words instead of characters.
+pub fn compute_word_distance(string1: &str, string2: &str) -> usize {
+    let words1 = extract_words(string1);
+    let words2 = extract_words(string2);
+    let (normalized1, normalized2) = normalize_word_sequence(&words1, &words2);
+    word_levenshtein(normalized1, normalized2)
+}
+
+fn word_levenshtein(words1: &[&str], words2: &[&str]) -> usize {
+    let mut previous_row = (0..=words2.len()).collect::<Vec<_>>();
+    for (i, word1) in words1.iter().enumerate() {
+        let mut current_row = vec![i + 1; words2.len() + 1];
+        for (j, word2) in words2.iter().enumerate() {
+            let substitution_cost = if word1 == word2 { 0 } else { 1 };
+            current_row[j + 1] = (previous_row[j] + substitution_cost)
+                .min(previous_row[j + 1] + 1)
+                .min(current_row[j] + 1);
+        }
+        previous_row = current_row;
+    }
+    previous_row[words2.len()]
+}
+
+// Returns a value between 0.0 (maximally different) and 1.0 (identical).
+pub fn compute_similarity_score(
+    string1: &str,
+    string2: &str,
+    strategy: NormalizationStrategy,
+) -> f64 {
+    if string1.is_empty() || string2.is_empty() {
+        return if string1 == string2 { 1.0 } else { 0.0 };
+    }
+
+    let (normalized1, normalized2) = normalize_to_shortest_string(string1, string2, strategy);
+    let max_len = normalized1.chars().count().max(normalized2.chars().count());
+    if max_len == 0 {
+        return 1.0;
+    }
+    let distance = levenshtein(&normalized1, &normalized2);
+    1.0 - (distance as f64 / max_len as f64)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WordDiff<'a> {
+    Same(&'a str),
+    Added(&'a str),
+    Removed(&'a str),
+}
+
+pub fn diff_words<'a>(old_words: &[&'a str], new_words: &[&'a str]) -> Vec<WordDiff<'a>> {
+    // Build the longest common subsequence table from the end so
+    // that we can walk it forwards afterwards.
+    let mut lengths = vec![vec![0usize; new_words.len() + 1]; old_words.len() + 1];
+    for i in (0..old_words.len()).rev() {
+        for j in (0..new_words.len()).rev() {
+            lengths[i][j] = if old_words[i] == new_words[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let mut i = 0;
+    let mut j = 0;
+    while i < old_words.len() && j < new_words.len() {
+        if old_words[i] == new_words[j] {
+            diff.push(WordDiff::Same(old_words[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            diff.push(WordDiff::Removed(old_words[i]));
+            i += 1;
+        } else {
+            diff.push(WordDiff::Added(new_words[j]));
+            j += 1;
+        }
+    }
+    for word in &old_words[i..] {
+        diff.push(WordDiff::Removed(word));
+    }
+    for word in &new_words[j..] {
+        diff.push(WordDiff::Added(word));
+    }
+    diff
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_empty_string_test() {
+        assert_eq!(truncate_to_shortest("", "non-empty"), ("", ""));
+        assert_eq!(truncate_to_shortest("non-empty", ""), ("", ""));
+        assert_eq!(truncate_to_shortest("", ""), ("", ""));
+        assert_eq!(
+            compute_distance("", "non-empty", NormalizationStrategy::default()),
+            0
+        );
+        assert_eq!(substring("abc", 0), "");
+        assert_eq!(substring("", 0), "");
+        assert_eq!(substring("abc", 5), "abc");
+    }
+
+    #[test]
+    fn similarity_score_test() {
+        let strategy = NormalizationStrategy::default();
+        assert_eq!(compute_similarity_score("hello", "hello", strategy), 1.0);
+        assert_eq!(compute_similarity_score("", "abc", strategy), 0.0);
+        assert_eq!(compute_similarity_score("abcd", "abxd", strategy), 0.75);
+    }
+
+    #[test]
+    fn normalization_strategy_test() {
+        let normalize = |strategy| {
+            let (str1, str2) = normalize_to_shortest_string("hello there", "hello", strategy);
+            (str1.into_owned(), str2.into_owned())
+        };
+        assert_eq!(
+            normalize(NormalizationStrategy::TruncateToLongest),
+            ("there".to_owned(), "hello".to_owned())
+        );
+        assert_eq!(
+            normalize(NormalizationStrategy::PadShorter),
+            ("hello there".to_owned(), "hello      ".to_owned())
+        );
+        assert_eq!(
+            normalize(NormalizationStrategy::None),
+            ("hello there".to_owned(), "hello".to_owned())
+        );
+        assert_eq!(
+            compute_distance("hello there", "hello", NormalizationStrategy::None),
+            6
+        );
+        assert_eq!(
+            compute_distance("", "abc", NormalizationStrategy::PadShorter),
+            3
+        );
+        assert_eq!(substring_from_end("abc", 0), "");
+        assert_eq!(substring_from_end("abc", 2), "bc");
+        assert_eq!(substring_from_end("abc", 5), "abc");
+        assert_eq!(
+            "pad-shorter".parse::<NormalizationStrategy>().unwrap(),
+            NormalizationStrategy::PadShorter
+        );
+        assert!("shortest".parse::<NormalizationStrategy>().is_err());
+    }
+
+    #[test]
+    fn levenshtein_windowed_test() {
+        assert_eq!(levenshtein_windowed("oh hello there", "hello", 5), 0);
+        assert_eq!(levenshtein_windowed("hello", "oh hello there", 5), 0);
+        assert_eq!(levenshtein_windowed("oh hallo there", "hello", 5), 1);
+        // Windows as big as the longer string are the same as levenshtein
+        assert_eq!(
+            levenshtein_windowed("kitten", "sitting", 7),
+            levenshtein("kitten", "sitting")
+        );
+        assert_eq!(
+            levenshtein_windowed("kitten", "sitting", 100),
+            levenshtein("kitten", "sitting")
+        );
+        assert_eq!(levenshtein_windowed("héllo wörld", "wörld", 5), 0);
+        // Nothing matches an empty string
+        assert_eq!(levenshtein_windowed("hello", "", 0), 5);
+        assert_eq!(levenshtein_windowed("", "hello", 3), 5);
+    }
+
+    #[test]
+    fn find_best_alignment_test() {
+        assert_eq!(find_best_alignment("credits hello there", "hello", 1), 8);
+        assert_eq!(find_best_alignment("hello there", "hello", 1), 0);
+        assert_eq!(find_best_alignment("ab hello", "hello", 1), 3);
+        // Only every other offset is tried
+        assert_eq!(find_best_alignment("ab hello", "hello", 2), 2);
+        assert_eq!(find_best_alignment("héllo wörld", "wörld", 1), 6);
+        assert_eq!(find_best_alignment("hello", "hello there", 1), 0);
+        assert_eq!(find_best_alignment("hello", "", 0), 0);
+
+        let strategy = NormalizationStrategy::TruncateToShortest;
+        assert_eq!(
+            compute_aligned_distance("credits hello there", "hello there", 1, strategy),
+            0
+        );
+        assert_eq!(
+            compute_aligned_distance("hello there", "credits hello there", 1, strategy),
+            0
+        );
+        assert_ne!(
+            compute_distance("credits hello there", "hello there", strategy),
+            0
+        );
+    }
+
+    #[test]
+    fn word_distance_test() {
+        assert_eq!(compute_word_distance("let me go", "let me go"), 0);
+        assert_eq!(compute_word_distance("let me go", "let me down"), 1);
+        assert_eq!(compute_word_distance("let go me", "let me go"), 2);
+        // Only the words up to the length of the shorter text are compared
+        assert_eq!(
+            compute_word_distance("let me go now please", "let me go"),
+            0
+        );
+        assert_eq!(word_levenshtein(&["a", "b", "c"], &["b", "c"]), 1);
+    }
+
+    #[test]
+    fn diff_words_test() {
+        let old = "let me go now".split_whitespace().collect::<Vec<_>>();
+        let new = "let me down now please"
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let diff = diff_words(&old, &new);
+        assert_eq!(
+            diff,
+            vec![
+                WordDiff::Same("let"),
+                WordDiff::Same("me"),
+                WordDiff::Removed("go"),
+                WordDiff::Added("down"),
+                WordDiff::Same("now"),
+                WordDiff::Added("please"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,437 @@
+// Helpers for building minimal mkv files in memory, so that tests
+// don't depend on test data that can't be checked in.
+
+use std::io::Cursor;
+
+use windows::UI::Color;
+
+use crate::mkv::{MkvFile, ParseConfig};
+
+const EBML_ID: u32 = 0x1A45DFA3;
+const EBML_VERSION_ID: u32 = 0x4286;
+const EBML_READ_VERSION_ID: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH_ID: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH_ID: u32 = 0x42F3;
+const DOC_TYPE_ID: u32 = 0x4282;
+const DOC_TYPE_VERSION_ID: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION_ID: u32 = 0x4285;
+const SEGMENT_ID: u32 = 0x18538067;
+const SEEK_HEAD_ID: u32 = 0x114D9B74;
+const SEEK_ID: u32 = 0x4DBB;
+const SEEK_ID_ID: u32 = 0x53AB;
+const SEEK_POSITION_ID: u32 = 0x53AC;
+const INFO_ID: u32 = 0x1549A966;
+const TIMECODE_SCALE_ID: u32 = 0x2AD7B1;
+const MUXING_APP_ID: u32 = 0x4D80;
+const WRITING_APP_ID: u32 = 0x5741;
+const TRACKS_ID: u32 = 0x1654AE6B;
+const TRACK_ENTRY_ID: u32 = 0xAE;
+const TRACK_NUMBER_ID: u32 = 0xD7;
+const TRACK_UID_ID: u32 = 0x73C5;
+const TRACK_TYPE_ID: u32 = 0x83;
+const CODEC_ID_ID: u32 = 0x86;
+const CODEC_PRIVATE_ID: u32 = 0x63A2;
+const LANGUAGE_ID: u32 = 0x22B59C;
+const CLUSTER_ID: u32 = 0x1F43B675;
+const TIMECODE_ID: u32 = 0xE7;
+const BLOCK_GROUP_ID: u32 = 0xA0;
+const BLOCK_ID: u32 = 0xA1;
+const BLOCK_DURATION_ID: u32 = 0x9B;
+
+pub const TEST_TRACK_NUMBER: u64 = 1;
+// Each subtitle gets its own cluster, spaced this far apart
+pub const TEST_SUBTITLE_INTERVAL_MS: u64 = 1000;
+pub const TEST_VOB_SIZE: (u32, u32) = (720, 480);
+// Where make_pgs_display_set places its image within the 1920x1080 frame
+pub const TEST_PGS_POSITION: (u16, u16) = (100, 900);
+
+fn encode_id(id: u32) -> Vec<u8> {
+    id.to_be_bytes()
+        .iter()
+        .skip_while(|byte| **byte == 0)
+        .cloned()
+        .collect()
+}
+
+fn encode_size(size: usize) -> Vec<u8> {
+    let size = size as u64;
+    for len in 1..=8 {
+        // All ones is reserved for unknown sizes
+        if size < (1 << (7 * len)) - 1 {
+            let bytes = (size | (1 << (7 * len))).to_be_bytes();
+            return bytes[8 - len..].to_vec();
+        }
+    }
+    panic!("Element size too large: {}", size);
+}
+
+fn encode_uint(value: u64) -> Vec<u8> {
+    let bytes = value
+        .to_be_bytes()
+        .iter()
+        .skip_while(|byte| **byte == 0)
+        .cloned()
+        .collect::<Vec<_>>();
+    if bytes.is_empty() {
+        vec![0]
+    } else {
+        bytes
+    }
+}
+
+pub fn element(id: u32, data: &[u8]) -> Vec<u8> {
+    let mut result = encode_id(id);
+    result.extend_from_slice(&encode_size(data.len()));
+    result.extend_from_slice(data);
+    result
+}
+
+pub fn uint_element(id: u32, value: u64) -> Vec<u8> {
+    element(id, &encode_uint(value))
+}
+
+pub fn string_element(id: u32, value: &str) -> Vec<u8> {
+    element(id, value.as_bytes())
+}
+
+pub fn make_ebml_header(doc_type: &str) -> Vec<u8> {
+    element(
+        EBML_ID,
+        &[
+            uint_element(EBML_VERSION_ID, 1),
+            uint_element(EBML_READ_VERSION_ID, 1),
+            uint_element(EBML_MAX_ID_LENGTH_ID, 4),
+            uint_element(EBML_MAX_SIZE_LENGTH_ID, 8),
+            string_element(DOC_TYPE_ID, doc_type),
+            uint_element(DOC_TYPE_VERSION_ID, 4),
+            uint_element(DOC_TYPE_READ_VERSION_ID, 2),
+        ]
+        .concat(),
+    )
+}
+
+fn make_block(track_number: u64, payload: &[u8]) -> Vec<u8> {
+    // Track number (as a vint), timestamp offset, and flags (no lacing)
+    let mut data = encode_size(track_number as usize);
+    data.extend_from_slice(&0i16.to_be_bytes());
+    data.push(0);
+    data.extend_from_slice(payload);
+    element(BLOCK_ID, &data)
+}
+
+fn make_info() -> Vec<u8> {
+    element(
+        INFO_ID,
+        &[
+            uint_element(TIMECODE_SCALE_ID, 1_000_000),
+            string_element(MUXING_APP_ID, "showorder"),
+            string_element(WRITING_APP_ID, "showorder"),
+        ]
+        .concat(),
+    )
+}
+
+fn make_track_entry(
+    track_number: u64,
+    codec_id: &str,
+    codec_private: Option<&[u8]>,
+    language: &str,
+) -> Vec<u8> {
+    let mut track_entry = vec![
+        uint_element(TRACK_NUMBER_ID, track_number),
+        uint_element(TRACK_UID_ID, track_number),
+        uint_element(TRACK_TYPE_ID, 0x11),
+        string_element(CODEC_ID_ID, codec_id),
+        string_element(LANGUAGE_ID, language),
+    ];
+    if let Some(codec_private) = codec_private {
+        track_entry.push(element(CODEC_PRIVATE_ID, codec_private));
+    }
+    element(TRACK_ENTRY_ID, &track_entry.concat())
+}
+
+fn make_tracks(codec_id: &str, codec_private: Option<&[u8]>, language: &str) -> Vec<u8> {
+    element(
+        TRACKS_ID,
+        &make_track_entry(TEST_TRACK_NUMBER, codec_id, codec_private, language),
+    )
+}
+
+fn make_clusters(subtitles: &[&[u8]]) -> Vec<u8> {
+    let subtitles = subtitles
+        .iter()
+        .map(|payload| (TEST_TRACK_NUMBER, *payload))
+        .collect::<Vec<_>>();
+    make_clusters_for_tracks(&subtitles)
+}
+
+// Each subtitle is given as (track number, payload)
+fn make_clusters_for_tracks(subtitles: &[(u64, &[u8])]) -> Vec<u8> {
+    subtitles
+        .iter()
+        .enumerate()
+        .map(|(i, (track_number, payload))| {
+            let block_group = element(
+                BLOCK_GROUP_ID,
+                &[
+                    make_block(*track_number, payload),
+                    uint_element(BLOCK_DURATION_ID, TEST_SUBTITLE_INTERVAL_MS / 2),
+                ]
+                .concat(),
+            );
+            element(
+                CLUSTER_ID,
+                &[
+                    uint_element(TIMECODE_ID, i as u64 * TEST_SUBTITLE_INTERVAL_MS),
+                    block_group,
+                ]
+                .concat(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+fn make_mkv(
+    codec_id: &str,
+    codec_private: Option<&[u8]>,
+    language: &str,
+    subtitles: &[&[u8]],
+) -> Vec<u8> {
+    let segment = element(
+        SEGMENT_ID,
+        &[
+            make_info(),
+            make_tracks(codec_id, codec_private, language),
+            make_clusters(subtitles),
+        ]
+        .concat(),
+    );
+    [make_ebml_header("matroska"), segment].concat()
+}
+
+fn make_seek_head(entries: &[(u32, u64)]) -> Vec<u8> {
+    let seeks = entries
+        .iter()
+        .map(|(id, position)| {
+            element(
+                SEEK_ID,
+                &[
+                    element(SEEK_ID_ID, &encode_id(*id)),
+                    // Always use 8 bytes so that the size of the
+                    // SeekHead doesn't depend on the positions.
+                    element(SEEK_POSITION_ID, &position.to_be_bytes()),
+                ]
+                .concat(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .concat();
+    element(SEEK_HEAD_ID, &seeks)
+}
+
+// Places the Tracks element after the clusters, which can only
+// be found quickly by using the SeekHead.
+pub fn make_mkv_with_tracks_at_end(subtitles: &[&[u8]]) -> Vec<u8> {
+    let info = make_info();
+    let clusters = make_clusters(subtitles);
+    let tracks = make_tracks("S_HDMV/PGS", None, "eng");
+
+    let seek_head_len = make_seek_head(&[(INFO_ID, 0), (TRACKS_ID, 0)]).len() as u64;
+    let info_position = seek_head_len;
+    let tracks_position = info_position + (info.len() + clusters.len()) as u64;
+    let seek_head = make_seek_head(&[(INFO_ID, info_position), (TRACKS_ID, tracks_position)]);
+
+    let segment = element(SEGMENT_ID, &[seek_head, info, clusters, tracks].concat());
+    [make_ebml_header("matroska"), segment].concat()
+}
+
+// Like make_mkv_with_pgs_subtitles, but a second (French) track is
+// described inside the first cluster, as some legacy encoders do.
+pub fn make_mkv_with_track_in_cluster(subtitles: &[&[u8]]) -> Vec<u8> {
+    let extra_track = make_track_entry(TEST_TRACK_NUMBER + 1, "S_HDMV/PGS", None, "fre");
+    let cluster = element(
+        CLUSTER_ID,
+        &[uint_element(TIMECODE_ID, 0), extra_track].concat(),
+    );
+    let segment = element(
+        SEGMENT_ID,
+        &[
+            make_info(),
+            make_tracks("S_HDMV/PGS", None, "eng"),
+            cluster,
+            make_clusters(subtitles),
+        ]
+        .concat(),
+    );
+    [make_ebml_header("matroska"), segment].concat()
+}
+
+// An mkv with an English PGS track (TEST_TRACK_NUMBER) and a French one
+// (TEST_TRACK_NUMBER + 1). Each subtitle is given as (track number, payload).
+pub fn make_mkv_with_two_pgs_tracks(subtitles: &[(u64, &[u8])]) -> Vec<u8> {
+    let tracks = element(
+        TRACKS_ID,
+        &[
+            make_track_entry(TEST_TRACK_NUMBER, "S_HDMV/PGS", None, "eng"),
+            make_track_entry(TEST_TRACK_NUMBER + 1, "S_HDMV/PGS", None, "fre"),
+        ]
+        .concat(),
+    );
+    let segment = element(
+        SEGMENT_ID,
+        &[make_info(), tracks, make_clusters_for_tracks(subtitles)].concat(),
+    );
+    [make_ebml_header("matroska"), segment].concat()
+}
+
+pub fn make_mkv_with_pgs_subtitles(subtitles: &[&[u8]]) -> Vec<u8> {
+    make_mkv("S_HDMV/PGS", None, "eng", subtitles)
+}
+
+// Like make_mkv_with_pgs_subtitles, but the track is in the given language
+pub fn make_mkv_with_pgs_subtitles_in(language: &str, subtitles: &[&[u8]]) -> Vec<u8> {
+    make_mkv("S_HDMV/PGS", None, language, subtitles)
+}
+
+pub fn make_mkv_with_vob_subtitles(subtitles: &[&[u8]], palette: &[Color]) -> Vec<u8> {
+    let palette = palette
+        .iter()
+        .map(|color| format!("{:02x}{:02x}{:02x}", color.R, color.G, color.B))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (width, height) = TEST_VOB_SIZE;
+    let idx = format!(
+        "# VobSub index file, v7 (do not modify this line!)\nsize: {}x{}\npalette: {}\n",
+        width, height, palette
+    );
+    make_mkv("S_VOBSUB", Some(idx.as_bytes()), "eng", subtitles)
+}
+
+fn make_pgs_segment(ty: u8, payload: &[u8]) -> Vec<u8> {
+    let mut data = vec![ty];
+    data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+// A palette definition segment. Entries are (id, Y, Cr, Cb, alpha),
+// in the order they're stored.
+pub fn pgs_write_palette(entries: &[(u8, u8, u8, u8, u8)]) -> Vec<u8> {
+    let mut payload = vec![
+        0x00, // palette id
+        0x00, // version
+    ];
+    for (id, luminance, color_difference_red, color_difference_blue, alpha) in entries {
+        payload.extend_from_slice(&[
+            *id,
+            *luminance,
+            *color_difference_red,
+            *color_difference_blue,
+            *alpha,
+        ]);
+    }
+    make_pgs_segment(0x14, &payload)
+}
+
+// A presentation composition segment for a 1920x1080 frame. Objects
+// are (object id, x, y), and none of them are cropped.
+pub fn pgs_write_presentation_comp(composition_state: u8, objects: &[(u16, u16, u16)]) -> Vec<u8> {
+    let mut payload = vec![
+        0x07,
+        0x80, // width
+        0x04,
+        0x38, // height
+        0x10, // frame rate
+        0x00,
+        0x01, // composition number
+        composition_state,
+        0x00, // palette update flag
+        0x00, // palette id
+        objects.len() as u8,
+    ];
+    for (id, x, y) in objects {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&[
+            0x00, // window id
+            0x00, // cropped flag
+        ]);
+        payload.extend_from_slice(&x.to_be_bytes());
+        payload.extend_from_slice(&y.to_be_bytes());
+    }
+    make_pgs_segment(0x16, &payload)
+}
+
+// An object definition segment. Each line is a list of (color, count)
+// runs, which are run-length encoded the same way as real PGS data.
+pub fn pgs_write_object(id: u16, width: u16, height: u16, lines: &[&[(u8, u16)]]) -> Vec<u8> {
+    let mut color_data = Vec::new();
+    for line in lines {
+        for (color, count) in line.iter().copied() {
+            match (color, count) {
+                (color, 1) if color != 0 => color_data.push(color),
+                (0, count) if count < 64 => color_data.extend_from_slice(&[0x00, count as u8]),
+                (0, count) => {
+                    color_data.extend_from_slice(&[0x00, 0x40 | (count >> 8) as u8, count as u8])
+                }
+                (color, count) if count < 64 => {
+                    color_data.extend_from_slice(&[0x00, 0x80 | count as u8, color])
+                }
+                (color, count) => color_data.extend_from_slice(&[
+                    0x00,
+                    0xC0 | (count >> 8) as u8,
+                    count as u8,
+                    color,
+                ]),
+            }
+        }
+        // End of the line
+        color_data.extend_from_slice(&[0x00, 0x00]);
+    }
+    let mut object_def = id.to_be_bytes().to_vec();
+    object_def.extend_from_slice(&[
+        0x00, // version
+        0xC0, // last in sequence flag
+    ]);
+    object_def.extend_from_slice(&((color_data.len() + 4) as u32).to_be_bytes()[1..]);
+    object_def.extend_from_slice(&width.to_be_bytes());
+    object_def.extend_from_slice(&height.to_be_bytes());
+    object_def.extend_from_slice(&color_data);
+    make_pgs_segment(0x15, &object_def)
+}
+
+pub fn pgs_write_end_display_set() -> Vec<u8> {
+    make_pgs_segment(0x80, &[])
+}
+
+// A display set containing a solid white image of the given
+// size, placed at TEST_PGS_POSITION
+pub fn make_pgs_display_set(width: u16, height: u16) -> Vec<u8> {
+    let (x, y) = TEST_PGS_POSITION;
+    make_positioned_pgs_display_set(width, height, x, y)
+}
+
+pub fn make_positioned_pgs_display_set(width: u16, height: u16, x: u16, y: u16) -> Vec<u8> {
+    let presentation_comp = pgs_write_presentation_comp(0x80, &[(0, x, y)]);
+    let palette_def = pgs_write_palette(&[(0x01, 0xEB, 0x80, 0x80, 0xFF)]);
+    let line: &[(u8, u16)] = &[(0x01, width)];
+    let lines = vec![line; height as usize];
+    let object_def = pgs_write_object(0, width, height, &lines);
+    [
+        presentation_comp,
+        palette_def,
+        object_def,
+        pgs_write_end_display_set(),
+    ]
+    .concat()
+}
+
+// Wraps the given display sets in an English PGS track, one block each
+pub fn mkv_from_display_sets(display_sets: &[Vec<u8>]) -> MkvFile<Cursor<Vec<u8>>> {
+    let payloads = display_sets
+        .iter()
+        .map(|display_set| display_set.as_slice())
+        .collect::<Vec<_>>();
+    let data = make_mkv_with_pgs_subtitles(&payloads);
+    MkvFile::new_with_config(Cursor::new(data), &ParseConfig::default()).unwrap()
+}
@@ -1,3 +1,6 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
 static BANNED_WORDS: [&'static str; 6] = [
     "caption",
     "subtitle",
@@ -7,60 +10,491 @@ static BANNED_WORDS: [&'static str; 6] = [
     "correction by",
 ];
 
-trait ContainsAny {
-    fn contains_any(&self, substrings: &[&str]) -> bool;
-}
-
-impl ContainsAny for String {
-    fn contains_any(&self, substrings: &[&str]) -> bool {
-        for substring in substrings {
-            if self.contains(substring) {
-                return true;
-            }
-        }
-        false
-    }
+// Compiling these is much slower than running them, and sanitize_text
+// is called for every subtitle frame.
+lazy_static! {
+    static ref HTML_TAG_REGEX: Regex = Regex::new(r"<.*?>").unwrap();
+    // Annotations (e.g. "[DOOR CREAKING]") can span multiple lines
+    static ref BRACKETS_REGEX: Regex = Regex::new(r"(?s)\[.*?\]").unwrap();
+    static ref PARENTHESES_REGEX: Regex = Regex::new(r"(?s)\(.*?\)").unwrap();
+    // Also removes ASS override tags, e.g. "{\i1}" or "{\c&H0000FF&}"
+    static ref BRACES_REGEX: Regex = Regex::new(r"(?s)\{.*?\}").unwrap();
+    // Left behind when an ASS file is saved as srt without converting it, e.g.
+    // "Dialogue: 0,0:00:01.00,0:00:04.00,Default,,0,0,0,,Hello"
+    static ref ASS_DIALOGUE_PREFIX_REGEX: Regex =
+        Regex::new(r"(?mi)^\s*Dialogue:\s*(?:[^,\n]*,){9}").unwrap();
+    // ASS line breaks ("\N" and "\n") and hard spaces ("\h")
+    static ref ASS_ESCAPE_REGEX: Regex = Regex::new(r"\\[Nnh]").unwrap();
+    static ref SPEAKER_LABEL_REGEX: Regex =
+        Regex::new(r"^\s*([A-Za-z][A-Za-z ]*):(\s+|$)").unwrap();
 }
 
 trait RegexRemove {
-    fn regex_remove(&self, pattern: &str) -> String;
+    fn regex_remove(&self, regex: &Regex) -> String;
 }
 
 impl RegexRemove for String {
-    fn regex_remove(&self, pattern: &str) -> String {
-        let regex = regex::Regex::new(pattern).unwrap();
+    fn regex_remove(&self, regex: &Regex) -> String {
         let result = regex.replace_all(self, "");
         result.to_string()
     }
 }
 
+trait CollapseWhitespace {
+    fn collapse_whitespace(&self) -> Self;
+}
+
+impl CollapseWhitespace for String {
+    fn collapse_whitespace(&self) -> Self {
+        collapse_whitespace(self)
+    }
+}
+
 trait RemovePunctuation {
     fn remove_punctuation(&self) -> Self;
 }
 
 impl RemovePunctuation for String {
+    // Apostrophes within words (e.g. "don't") are kept, so that
+    // contractions don't run into other words (e.g. "its").
     fn remove_punctuation(&self) -> Self {
         let mut result = String::new();
-        for c in self.chars() {
-            if !c.is_ascii_punctuation() {
+        let mut previous = None;
+        let mut chars = self.chars().peekable();
+        while let Some(c) = chars.next() {
+            let is_contraction = c == '\''
+                && previous.map_or(false, char::is_alphabetic)
+                && chars.peek().map_or(false, |next| next.is_alphabetic());
+            if !c.is_ascii_punctuation() || is_contraction {
                 result.push(c);
             }
+            previous = Some(c);
         }
         result
     }
 }
 
-pub fn sanitize_text(text: &str) -> String {
+// Returns the label and the remaining text if the line starts
+// with a speaker label (e.g. "JOHN: Hello"). The colon must be
+// followed by whitespace so that URLs aren't mistaken for labels.
+pub fn detect_speaker_label(text: &str) -> Option<(&str, &str)> {
+    let captures = SPEAKER_LABEL_REGEX.captures(text)?;
+    let label = captures.get(1).unwrap().as_str().trim_end();
+    let rest = &text[captures.get(0).unwrap().end()..];
+    Some((label, rest))
+}
+
+fn remove_speaker_labels(text: &str) -> String {
+    text.split('\n')
+        .map(|line| match detect_speaker_label(line) {
+            Some((_, rest)) => rest,
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Splits already sanitized text into words. Any run of
+// whitespace (including unicode whitespace) separates words.
+pub fn extract_words(text: &str) -> Vec<&str> {
+    text.split(char::is_whitespace)
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+// Trims both word sequences to the length of the shorter one,
+// like normalize_to_shortest_string does for characters.
+pub fn normalize_word_sequence<'a, 'b>(
+    words1: &'a [&'b str],
+    words2: &'a [&'b str],
+) -> (&'a [&'b str], &'a [&'b str]) {
+    let len = words1.len().min(words2.len());
+    (&words1[..len], &words2[..len])
+}
+
+// What kinds of characters make up a piece of text. Whitespace isn't
+// included in the other fractions, so for text in a Latin alphabet the
+// latin fraction should be close to 1.0 even with spaces between words.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AlphabetStats {
+    // The fraction of the letters (rather than all characters) that are
+    // Latin, so that digits and punctuation don't count against English
+    pub latin_fraction: f32,
+    pub digit_fraction: f32,
+    pub punctuation_fraction: f32,
+    pub other_fraction: f32,
+    // The fraction of all characters that are whitespace
+    pub whitespace_fraction: f32,
+}
+
+// Text that should be English but falls below this is likely
+// the result of using the wrong OCR language.
+pub const MIN_ENGLISH_LATIN_FRACTION: f32 = 0.8;
+// OCR of low quality images can produce mostly spaces
+const MAX_WHITESPACE_FRACTION: f32 = 0.5;
+
+impl AlphabetStats {
+    pub fn is_mostly_whitespace(&self) -> bool {
+        self.whitespace_fraction > MAX_WHITESPACE_FRACTION
+    }
+}
+
+pub fn analyze_alphabet(text: &str) -> AlphabetStats {
+    let mut num_chars = 0;
+    let mut num_whitespace = 0;
+    let mut num_latin = 0;
+    let mut num_digits = 0;
+    let mut num_punctuation = 0;
+    let mut num_other = 0;
+    let mut num_other_letters = 0;
+    for c in text.chars() {
+        num_chars += 1;
+        if c.is_whitespace() {
+            num_whitespace += 1;
+        } else if is_latin_letter(c) {
+            num_latin += 1;
+        } else if c.is_ascii_digit() {
+            num_digits += 1;
+        } else if is_punctuation(c) {
+            num_punctuation += 1;
+        } else {
+            num_other += 1;
+            if c.is_alphabetic() {
+                num_other_letters += 1;
+            }
+        }
+    }
+
+    if num_chars == 0 {
+        return AlphabetStats::default();
+    }
+    let whitespace_fraction = num_whitespace as f32 / num_chars as f32;
+    let num_visible = num_chars - num_whitespace;
+    if num_visible == 0 {
+        return AlphabetStats {
+            whitespace_fraction,
+            ..Default::default()
+        };
+    }
+    let fraction = |count: usize| count as f32 / num_visible as f32;
+    let num_letters = num_latin + num_other_letters;
+    let latin_fraction = if num_letters > 0 {
+        num_latin as f32 / num_letters as f32
+    } else {
+        0.0
+    };
+    AlphabetStats {
+        latin_fraction,
+        digit_fraction: fraction(num_digits),
+        punctuation_fraction: fraction(num_punctuation),
+        other_fraction: fraction(num_other),
+        whitespace_fraction,
+    }
+}
+
+// Basic Latin, Latin-1 Supplement, and Latin Extended-A/B letters
+fn is_latin_letter(c: char) -> bool {
+    c.is_ascii_alphabetic() || (c.is_alphabetic() && ('\u{00C0}'..='\u{024F}').contains(&c))
+}
+
+// Includes the non-ASCII punctuation that shows up in subtitles
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || "\u{2018}\u{2019}\u{201C}\u{201D}\u{2026}\u{2013}\u{2014}\u{00A1}\u{00BF}".contains(c)
+}
+
+// Returns the first banned word found in the text, so callers can tell
+// a filtered subtitle apart from one that was empty to begin with.
+pub fn check_banned_words(text: &str) -> Option<&'static str> {
     let lowered = text.to_lowercase();
-    if lowered.contains_any(&BANNED_WORDS) {
+    BANNED_WORDS
+        .iter()
+        .find(|word| lowered.contains(*word))
+        .copied()
+}
+
+pub fn sanitize_text(text: &str) -> String {
+    if check_banned_words(text).is_some() {
         return String::new();
     }
-    lowered
-        .regex_remove(r"<.*?>")
-        .regex_remove(r"\[.*?\]")
-        .regex_remove(r"\(.*?\)")
-        .regex_remove(r"[A-z]+:")
-        .remove_punctuation()
-        .trim()
-        .to_string()
+    let text = ASS_DIALOGUE_PREFIX_REGEX.replace_all(text, "");
+    let text = ASS_ESCAPE_REGEX.replace_all(&text, " ");
+    let lowered = text.to_lowercase();
+    remove_speaker_labels(
+        &lowered
+            .regex_remove(&HTML_TAG_REGEX)
+            .regex_remove(&BRACKETS_REGEX)
+            .regex_remove(&PARENTHESES_REGEX)
+            .regex_remove(&BRACES_REGEX),
+    )
+    .remove_punctuation()
+    .collapse_whitespace()
+    .trim()
+    .to_string()
+}
+
+// More aggressive than sanitize_text for SDH (Subtitles for the Deaf and
+// Hard of Hearing) text, which is usually compared against non-SDH text.
+// Lines that are only stage directions are dropped before sanitizing.
+pub fn sanitize_sdh_text<S: AsRef<str>>(lines: &[S], mixed_case: bool) -> String {
+    let text = lines
+        .iter()
+        .map(|line| line.as_ref())
+        .filter(|line| !is_stage_direction(line, mixed_case))
+        .collect::<Vec<_>>()
+        .join("\n");
+    sanitize_text(&text)
+}
+
+// Whether the text has any lower case letters outside of tags
+pub fn is_mixed_case(text: &str) -> bool {
+    text.to_owned()
+        .regex_remove(&HTML_TAG_REGEX)
+        .regex_remove(&BRACES_REGEX)
+        .chars()
+        .any(char::is_lowercase)
+}
+
+// SDH stage directions either sit entirely within annotations
+// (e.g. "MAN: [GROANS]"), are song lyrics, or are written in
+// all caps (e.g. "DOOR CREAKING"). Some tracks are written entirely
+// in upper case, so all caps lines are only stage directions when
+// the track is mixed case (see is_mixed_case).
+pub fn is_stage_direction(line: &str, mixed_case: bool) -> bool {
+    let line = match detect_speaker_label(line) {
+        Some((_, rest)) => rest,
+        None => line,
+    };
+    let line = line.trim();
+    if line.starts_with('♪') {
+        return true;
+    }
+    let remaining = line
+        .to_owned()
+        .regex_remove(&HTML_TAG_REGEX)
+        .regex_remove(&BRACKETS_REGEX)
+        .regex_remove(&PARENTHESES_REGEX)
+        .regex_remove(&BRACES_REGEX);
+    let num_letters = remaining.chars().filter(|c| c.is_alphabetic()).count();
+    // Short shouts like "NO!" are still dialogue
+    num_letters == 0
+        || (mixed_case && num_letters >= 3 && !remaining.chars().any(char::is_lowercase))
+}
+
+// Removing punctuation and annotations can leave runs of spaces
+// behind, which would otherwise count towards the distance.
+pub fn collapse_whitespace(text: &str) -> String {
+    text.split(char::is_whitespace)
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn speaker_label_detection() {
+        assert_eq!(
+            detect_speaker_label("JOHN: Hello there."),
+            Some(("JOHN", "Hello there."))
+        );
+        assert_eq!(
+            detect_speaker_label("  Mary Ann:  Hi"),
+            Some(("Mary Ann", "Hi"))
+        );
+        assert_eq!(detect_speaker_label("NARRATOR:"), Some(("NARRATOR", "")));
+        assert_eq!(detect_speaker_label("Hello there."), None);
+        assert_eq!(detect_speaker_label("http://example.com"), None);
+        assert_eq!(detect_speaker_label("10:30 already?"), None);
+        assert_eq!(detect_speaker_label("Say it: now"), Some(("Say it", "now")));
+        assert_eq!(detect_speaker_label("[_]: nope"), None);
+    }
+
+    #[test]
+    fn remove_speaker_labels_test() {
+        assert_eq!(remove_speaker_labels("JOHN: hello"), "hello");
+        assert_eq!(
+            remove_speaker_labels("http://example.com"),
+            "http://example.com"
+        );
+        assert_eq!(remove_speaker_labels("at 3:00"), "at 3:00");
+        // Only letters and spaces can make up a label
+        assert_eq!(remove_speaker_labels("[NAME]: hello"), "[NAME]: hello");
+        assert_eq!(remove_speaker_labels("^_^: hello"), "^_^: hello");
+        assert_eq!(
+            remove_speaker_labels("JOHN: hi\nMARY: hello\nok"),
+            "hi\nhello\nok"
+        );
+    }
+
+    #[test]
+    fn sanitize_speaker_labels() {
+        assert_eq!(sanitize_text("JOHN: Hello there."), "hello there");
+        assert_eq!(
+            sanitize_text("JOHN: Where are you?\nMARY: Over here!"),
+            "where are you over here"
+        );
+        // Only labels at the start of a line are removed
+        assert_eq!(
+            sanitize_text("Look, he said: go home"),
+            "look he said go home"
+        );
+    }
+
+    #[test]
+    fn extract_words_test() {
+        assert_eq!(extract_words("let me  go"), vec!["let", "me", "go"]);
+        assert_eq!(
+            extract_words(" let\tme\u{3000}go\n"),
+            vec!["let", "me", "go"]
+        );
+        assert!(extract_words("   ").is_empty());
+
+        let words1 = extract_words("let me go now");
+        let words2 = extract_words("let me down");
+        assert_eq!(
+            normalize_word_sequence(&words1, &words2),
+            (&words1[..3], &words2[..])
+        );
+    }
+
+    #[test]
+    fn collapse_whitespace_test() {
+        assert_eq!(collapse_whitespace("a  b   c"), "a b c");
+        assert_eq!(collapse_whitespace(" a\t\nb "), "a b");
+        assert_eq!(collapse_whitespace("   "), "");
+        assert_eq!(sanitize_text("Hello - world"), "hello world");
+        assert_eq!(sanitize_text("Hello [laughs] there"), "hello there");
+    }
+
+    #[test]
+    fn banned_words_test() {
+        assert_eq!(check_banned_words("Captions by Someone"), Some("caption"));
+        assert_eq!(
+            check_banned_words("Corrected By: foo"),
+            Some("corrected by")
+        );
+        assert_eq!(check_banned_words("let me go"), None);
+        assert_eq!(sanitize_text("Subtitles by foo"), "");
+    }
+
+    #[test]
+    fn remove_punctuation_test() {
+        assert_eq!("don't".to_owned().remove_punctuation(), "don't");
+        assert_eq!("' hello '".to_owned().remove_punctuation(), " hello ");
+        assert_eq!("'hello'".to_owned().remove_punctuation(), "hello");
+        assert_eq!("dogs' bones".to_owned().remove_punctuation(), "dogs bones");
+        assert_eq!(
+            sanitize_text("It's a whale, isn't it?"),
+            "it's a whale isn't it"
+        );
+    }
+
+    #[test]
+    fn sanitize_urls() {
+        assert_eq!(
+            sanitize_text("Visit http://example.com today"),
+            "visit httpexamplecom today"
+        );
+        assert_eq!(sanitize_text("http://example.com"), "httpexamplecom");
+    }
+
+    #[test]
+    fn sanitize_time_codes() {
+        assert_eq!(sanitize_text("Meet me at 10:30."), "meet me at 1030");
+        assert_eq!(sanitize_text("10:30 already?"), "1030 already");
+    }
+
+    #[test]
+    fn sanitize_music_symbols() {
+        // Music symbols aren't ascii punctuation, so they're kept
+        assert_eq!(sanitize_text("♪ La la la ♪"), "♪ la la la ♪");
+        assert_eq!(sanitize_text("♪ SINGER: La la la ♪"), "♪ singer la la la ♪");
+    }
+
+    #[test]
+    fn sanitize_annotations() {
+        assert_eq!(sanitize_text("<i>Hello</i> [door closes]"), "hello");
+        assert_eq!(sanitize_text("(sighs) Fine."), "fine");
+        assert_eq!(sanitize_text("Subtitles by someone"), "");
+        assert_eq!(sanitize_text("{MUSIC} Hello"), "hello");
+        assert_eq!(sanitize_text("[DOOR\nCREAKING] Hello"), "hello");
+        assert_eq!(sanitize_text("(MAN\nLAUGHING)\nHello"), "hello");
+    }
+
+    #[test]
+    fn sanitize_ass_leftovers() {
+        assert_eq!(sanitize_text(r"{\i1}Hello{\i0} there"), "hello there");
+        assert_eq!(sanitize_text(r"{\fn Arial}{\c&H0000FF&}Hello"), "hello");
+        assert_eq!(
+            sanitize_text("Dialogue: 0,0:00:01.00,0:00:04.00,Default,,0,0,0,,Hello, there"),
+            "hello there"
+        );
+        assert_eq!(
+            sanitize_text(r"Dialogue: 0,0:00:01.00,0:00:04.00,Default,,0,0,0,,Hello\Nthere"),
+            "hello there"
+        );
+    }
+
+    #[test]
+    fn sanitize_sdh() {
+        assert!(is_stage_direction("[DOOR CREAKING]", true));
+        assert!(is_stage_direction("MAN: (GROANS)", true));
+        assert!(is_stage_direction("DOOR CREAKING", true));
+        assert!(is_stage_direction("♪ La la la ♪", true));
+        assert!(!is_stage_direction("NO!", true));
+        assert!(!is_stage_direction("JOHN: Hello there.", true));
+        assert!(!is_stage_direction("[sighs] Fine.", true));
+
+        assert_eq!(
+            sanitize_sdh_text(&["THUNDER RUMBLING", "JOHN: Get inside!"], true),
+            "get inside"
+        );
+        assert_eq!(sanitize_sdh_text(&["♪ La la la ♪", "Hello"], true), "hello");
+        assert_eq!(sanitize_sdh_text(&["{APPLAUSE}"], true), "");
+        // Without SDH mode, all caps text is kept
+        assert_eq!(
+            sanitize_text("THUNDER RUMBLING\nJOHN: Get inside!"),
+            "thunder rumbling get inside"
+        );
+
+        // Tracks that are entirely upper case only lose annotations and lyrics
+        assert!(!is_stage_direction("DOOR CREAKING", false));
+        assert!(is_stage_direction("[DOOR CREAKING]", false));
+        assert!(is_stage_direction("♪ LA LA LA ♪", false));
+        assert_eq!(
+            sanitize_sdh_text(&["[THUNDER RUMBLING]", "GET INSIDE!"], false),
+            "get inside"
+        );
+        assert!(is_mixed_case("JOHN: Get inside!"));
+        assert!(!is_mixed_case("<i>GET INSIDE!</i>"));
+        assert!(!is_mixed_case("{\\an8}GET INSIDE!"));
+    }
+
+    #[test]
+    fn analyze_alphabet_test() {
+        // Digits and punctuation don't make text any less English
+        let stats = analyze_alphabet("Hello there, 42!");
+        assert_eq!(stats.latin_fraction, 1.0);
+        assert_eq!(stats.digit_fraction, 2.0 / 14.0);
+        assert_eq!(stats.punctuation_fraction, 2.0 / 14.0);
+        assert_eq!(stats.other_fraction, 0.0);
+        assert!(stats.latin_fraction >= MIN_ENGLISH_LATIN_FRACTION);
+        assert_eq!(analyze_alphabet("42!").latin_fraction, 0.0);
+        // Symbols aren't letters either
+        assert_eq!(analyze_alphabet("♪ la la ♪").latin_fraction, 1.0);
+        assert!(!stats.is_mostly_whitespace());
+
+        assert_eq!(analyze_alphabet("café").latin_fraction, 1.0);
+        let stats = analyze_alphabet("привет мир");
+        assert_eq!(stats.latin_fraction, 0.0);
+        assert_eq!(stats.other_fraction, 1.0);
+        assert_eq!(analyze_alphabet("hello мир").latin_fraction, 5.0 / 8.0);
+
+        assert!(analyze_alphabet("a    b").is_mostly_whitespace());
+        assert!(analyze_alphabet("   ").is_mostly_whitespace());
+        assert_eq!(analyze_alphabet(""), AlphabetStats::default());
+    }
 }
@@ -1,4 +1,9 @@
-use std::io::Read;
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use windows::{
@@ -8,9 +13,86 @@ use windows::{
     UI::Color,
 };
 
-use crate::{interop::as_mut_slice, mkv::KnownEncoding};
+use crate::{
+    error::ShowOrderError,
+    image::{check_dimensions, ycbcr_to_rgb},
+    interop::as_mut_slice,
+    mkv::KnownEncoding,
+    progress,
+};
+
+// How the colors in an idx palette are stored
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PaletteFormat {
+    Rgb,
+    // Stored as Y, Cr, Cb like the palettes in DVD IFO files
+    YCbCr,
+}
+
+// YCbCr palettes keep their chroma values within the video range,
+// while the luma values use most of the range. RGB palettes almost
+// always have a pure black or white in them, so they fail this check.
+fn detect_palette_format(entries: &[(u8, u8, u8)]) -> PaletteFormat {
+    let video_range = 16..=235;
+    let chroma_in_range = entries
+        .iter()
+        .all(|(_, cr, cb)| video_range.contains(cr) && video_range.contains(cb));
+    let min_luma = entries.iter().map(|(y, _, _)| *y).min().unwrap_or(0);
+    let max_luma = entries.iter().map(|(y, _, _)| *y).max().unwrap_or(0);
+    if chroma_in_range && max_luma - min_luma >= 128 {
+        PaletteFormat::YCbCr
+    } else {
+        PaletteFormat::Rgb
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IdxParseError {
+    MissingSize,
+    MissingPalette,
+    InvalidSize(String),
+    InvalidPaletteEntry(String),
+}
+
+impl std::fmt::Display for IdxParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdxParseError::MissingSize => write!(f, "No size found."),
+            IdxParseError::MissingPalette => write!(f, "No palette found."),
+            IdxParseError::InvalidSize(size) => write!(f, "Invalid size \"{}\".", size),
+            IdxParseError::InvalidPaletteEntry(entry) => {
+                write!(f, "Invalid palette entry \"{}\".", entry)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IdxParseError {}
+
+impl From<IdxParseError> for std::io::Error {
+    fn from(error: IdxParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
 
-pub fn parse_idx(data: &[u8]) -> KnownEncoding {
+// If no palette format is given, it is detected from the palette.
+pub fn parse_idx(
+    data: &[u8],
+    palette_format: Option<PaletteFormat>,
+) -> std::result::Result<KnownEncoding, IdxParseError> {
+    let (width, height, palette) = try_parse_idx(data, palette_format)?;
+    Ok(KnownEncoding::VOB {
+        width,
+        height,
+        palette,
+    })
+}
+
+// Returns the frame size and the palette
+pub fn try_parse_idx(
+    data: &[u8],
+    palette_format: Option<PaletteFormat>,
+) -> std::result::Result<(u32, u32, Vec<Color>), IdxParseError> {
     let idx_string = String::from_utf8_lossy(data);
     //println!("{}", idx_string);
     let lines = idx_string.lines();
@@ -31,34 +113,21 @@ pub fn parse_idx(data: &[u8]) -> KnownEncoding {
             let value = value.trim();
             match name {
                 "size" => {
-                    let (width_str, height_str) = value.split_once('x').unwrap();
-                    let width = u32::from_str_radix(width_str, 10).unwrap();
-                    let height = u32::from_str_radix(height_str, 10).unwrap();
-                    size = Some((width, height));
+                    size = Some(
+                        parse_idx_size(value)
+                            .ok_or_else(|| IdxParseError::InvalidSize(value.to_owned()))?,
+                    );
                 }
                 "palette" => {
-                    let mut colors = Vec::new();
+                    let mut entries = Vec::new();
                     let color_strs = value.split(", ");
                     for color_str in color_strs {
-                        assert_eq!(color_str.len(), 6);
-                        // Not sure what the format is, assuming RGB for now
-                        let r_str = &color_str[0..2];
-                        let g_str = &color_str[2..4];
-                        let b_str = &color_str[4..6];
-
-                        let r = u8::from_str_radix(r_str, 16).unwrap();
-                        let g = u8::from_str_radix(g_str, 16).unwrap();
-                        let b = u8::from_str_radix(b_str, 16).unwrap();
-
-                        let color = Color {
-                            A: 255,
-                            R: r,
-                            G: g,
-                            B: b,
-                        };
-                        colors.push(color);
+                        let entry = parse_palette_entry(color_str).ok_or_else(|| {
+                            IdxParseError::InvalidPaletteEntry(color_str.to_owned())
+                        })?;
+                        entries.push(entry);
                     }
-                    palette = Some(colors);
+                    palette = Some(entries);
                 }
                 _ => {
                     //println!("Unknown name: \"{}\"", name);
@@ -67,18 +136,443 @@ pub fn parse_idx(data: &[u8]) -> KnownEncoding {
         }
     }
 
-    let (width, height) = size.expect("Expected size in Vob subtitle track private data");
-    let palette = palette.expect("Expected palette in Vob subtitle track private data");
+    let (width, height) = size.ok_or(IdxParseError::MissingSize)?;
+    let palette = palette.ok_or(IdxParseError::MissingPalette)?;
+    // The color commands index into the palette with a nibble
+    if palette.len() != 16 {
+        let fallback = if palette.len() < 16 {
+            " Colors outside of the palette will be transparent."
+        } else {
+            ""
+        };
+        progress::suspend(|| {
+            println!(
+            "Warning! Expected 16 palette entries in Vob subtitle track private data, found {}.{}",
+            palette.len(),
+            fallback
+        )
+        });
+    }
+    let palette_format = palette_format.unwrap_or_else(|| detect_palette_format(&palette));
+    let palette = palette
+        .iter()
+        .map(|(first, second, third)| {
+            let (r, g, b) = match palette_format {
+                PaletteFormat::Rgb => (*first, *second, *third),
+                PaletteFormat::YCbCr => ycbcr_to_rgb(*first, *third, *second),
+            };
+            Color {
+                A: 255,
+                R: r,
+                G: g,
+                B: b,
+            }
+        })
+        .collect();
 
-    KnownEncoding::VOB {
-        width,
-        height,
-        palette,
+    Ok((width, height, palette))
+}
+
+fn parse_idx_size(value: &str) -> Option<(u32, u32)> {
+    let (width_str, height_str) = value.split_once('x')?;
+    let width = u32::from_str_radix(width_str, 10).ok()?;
+    let height = u32::from_str_radix(height_str, 10).ok()?;
+    Some((width, height))
+}
+
+// Entries are 6 hex digits, e.g. "ff00ff"
+fn parse_palette_entry(value: &str) -> Option<(u8, u8, u8)> {
+    if value.len() != 6 {
+        return None;
+    }
+    let first = u8::from_str_radix(value.get(0..2)?, 16).ok()?;
+    let second = u8::from_str_radix(value.get(2..4)?, 16).ok()?;
+    let third = u8::from_str_radix(value.get(4..6)?, 16).ok()?;
+    Some((first, second, third))
+}
+
+// Rewrites the palette line of the idx data with the given (RGB) colors,
+// leaving everything else as is.
+pub fn replace_idx_palette(data: &[u8], palette: &[Color]) -> String {
+    let idx_string = String::from_utf8_lossy(data);
+    let colors = palette
+        .iter()
+        .map(|color| format!("{:02x}{:02x}{:02x}", color.R, color.G, color.B))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut result = String::with_capacity(idx_string.len());
+    for line in idx_string.lines() {
+        if line.starts_with("palette:") {
+            result.push_str("palette: ");
+            result.push_str(&colors);
+        } else {
+            result.push_str(line);
+        }
+        result.push('\n');
+    }
+    result
+}
+
+// A subtitle listed in an idx file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IdxEntry {
+    pub timestamp_ms: u64,
+    // Byte offset of the subtitle's first packet in the sub file
+    pub filepos: u64,
+}
+
+// Reads the timestamp table of the first stream in an idx file.
+// Entries look like "timestamp: 00:00:01:234, filepos: 000000000".
+pub fn parse_idx_entries(data: &[u8]) -> Vec<IdxEntry> {
+    let idx_string = String::from_utf8_lossy(data);
+    let mut entries = Vec::new();
+    let mut num_streams = 0;
+    for line in idx_string.lines() {
+        if line.starts_with("#") {
+            continue;
+        }
+        if line.starts_with("id:") {
+            num_streams += 1;
+            if num_streams > 1 {
+                break;
+            }
+        } else if let Some(value) = line.strip_prefix("timestamp:") {
+            if let Some(entry) = parse_idx_entry(value) {
+                entries.push(entry);
+            } else {
+                progress::suspend(|| println!("Warning! Could not parse idx entry \"{}\"", line));
+            }
+        }
+    }
+    entries
+}
+
+fn parse_idx_entry(value: &str) -> Option<IdxEntry> {
+    let (timestamp_str, filepos_str) = value.split_once(',')?;
+    let filepos_str = filepos_str.trim().strip_prefix("filepos:")?;
+    let filepos = u64::from_str_radix(filepos_str.trim(), 16).ok()?;
+
+    // Timestamps are in the form of HH:MM:SS:mmm
+    let mut parts = timestamp_str.trim().split(':');
+    let hours: u64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let seconds: u64 = parts.next()?.parse().ok()?;
+    let milliseconds: u64 = parts.next()?.parse().ok()?;
+    let timestamp_ms = (((hours * 60) + minutes) * 60 + seconds) * 1000 + milliseconds;
+
+    Some(IdxEntry {
+        timestamp_ms,
+        filepos,
+    })
+}
+
+// A VobSub subtitle stream stored outside of an mkv file, as a pair
+// of files. The idx file holds the palette, frame size, and where each
+// subtitle is in the sub file. The sub file is an MPEG-PS stream.
+pub struct VobSubFile {
+    sub_path: PathBuf,
+    width: u32,
+    height: u32,
+    palette: Vec<Color>,
+    entries: Vec<IdxEntry>,
+}
+
+impl VobSubFile {
+    // Expects the sub file to be next to the idx file
+    pub fn from_idx_path<P: AsRef<Path>>(
+        idx_path: P,
+        palette_format: Option<PaletteFormat>,
+    ) -> std::io::Result<Self> {
+        let idx_path = idx_path.as_ref();
+        Self::new(idx_path, idx_path.with_extension("sub"), palette_format)
+    }
+
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        idx_path: P,
+        sub_path: Q,
+        palette_format: Option<PaletteFormat>,
+    ) -> std::io::Result<Self> {
+        let idx_data = std::fs::read(idx_path)?;
+        let (width, height, palette) = try_parse_idx(&idx_data, palette_format)?;
+        let entries = parse_idx_entries(&idx_data);
+        Ok(Self {
+            sub_path: sub_path.as_ref().to_owned(),
+            width,
+            height,
+            palette,
+            entries,
+        })
+    }
+
+    pub fn subtitle_iter(&self) -> std::io::Result<SubFileIterator<'_>> {
+        let file = File::open(&self.sub_path)?;
+        Ok(SubFileIterator {
+            sub_file: self,
+            reader: BufReader::new(file),
+            next_entry: 0,
+        })
+    }
+}
+
+pub struct SubFileIterator<'a> {
+    sub_file: &'a VobSubFile,
+    reader: BufReader<File>,
+    next_entry: usize,
+}
+
+// Returns each subtitle's timestamp (in ms) along with its image
+impl<'a> Iterator for SubFileIterator<'a> {
+    type Item = Result<(u64, SoftwareBitmap)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(entry) = self.sub_file.entries.get(self.next_entry) {
+            self.next_entry += 1;
+            let data = match read_subtitle_packet(&mut self.reader, entry.filepos) {
+                Ok(data) => data,
+                Err(error) => return Some(Err(ShowOrderError::from(error).into())),
+            };
+            let frame = match parse_block(
+                &data,
+                self.sub_file.width,
+                self.sub_file.height,
+                &self.sub_file.palette,
+            ) {
+                Ok(frame) => frame,
+                Err(error) => return Some(Err(error)),
+            };
+            if let Some(frame) = frame {
+                return Some(Ok((entry.timestamp_ms, frame.bitmap)));
+            }
+        }
+        None
+    }
+}
+
+const PACK_START_CODE: u32 = 0x000001BA;
+const PRIVATE_STREAM_1_START_CODE: u32 = 0x000001BD;
+
+// A subtitle packet can be split across several PES packets. The
+// first two bytes of the subtitle packet hold its total size. The
+// packets of other subtitle streams can be interleaved with ours, so
+// we only read the ones from the stream the first packet belongs to.
+fn read_subtitle_packet<R: Read + Seek>(reader: &mut R, filepos: u64) -> std::io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(filepos))?;
+    let mut data = Vec::new();
+    let mut stream_id = None;
+    loop {
+        let (substream_id, payload) = read_private_stream_payload(reader, stream_id)?;
+        stream_id = Some(substream_id);
+        data.extend_from_slice(&payload);
+        if data.len() >= 2 {
+            let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+            if data.len() >= size {
+                data.truncate(size);
+                return Ok(data);
+            }
+        }
+    }
+}
+
+// Substreams of private stream 1 that hold subtitles, the
+// others hold audio (e.g. AC-3 starts at 0x80)
+const SUBTITLE_SUBSTREAM_IDS: RangeInclusive<u8> = 0x20..=0x3F;
+
+// Reads packets until it finds a subtitle packet from private stream 1,
+// and returns its substream id and payload. If a substream id is given,
+// packets from other substreams are skipped.
+fn read_private_stream_payload<R: Read>(
+    reader: &mut R,
+    substream_id: Option<u8>,
+) -> std::io::Result<(u8, Vec<u8>)> {
+    loop {
+        let start_code = reader.read_u32::<BigEndian>()?;
+        match start_code {
+            PACK_START_CODE => {
+                // MPEG-2 pack headers have a variable amount of stuffing
+                // at the end, MPEG-1 pack headers are a fixed size.
+                let first_byte = reader.read_u8()?;
+                if first_byte & 0xC0 == 0x40 {
+                    let mut header = [0u8; 9];
+                    reader.read_exact(&mut header)?;
+                    let stuffing_length = (header[8] & 0x07) as u64;
+                    skip_bytes(reader, stuffing_length)?;
+                } else {
+                    skip_bytes(reader, 7)?;
+                }
+            }
+            PRIVATE_STREAM_1_START_CODE => {
+                let packet_length = reader.read_u16::<BigEndian>()? as usize;
+                let mut packet = vec![0u8; packet_length];
+                reader.read_exact(&mut packet)?;
+                // Skip the PES header and the substream id
+                let header_length = *packet.get(2).ok_or_else(invalid_packet_error)? as usize;
+                let packet_substream_id = *packet
+                    .get(3 + header_length)
+                    .ok_or_else(invalid_packet_error)?;
+                if !SUBTITLE_SUBSTREAM_IDS.contains(&packet_substream_id)
+                    || substream_id.map_or(false, |id| id != packet_substream_id)
+                {
+                    continue;
+                }
+                let payload = packet
+                    .get(3 + header_length + 1..)
+                    .ok_or_else(invalid_packet_error)?;
+                return Ok((packet_substream_id, payload.to_vec()));
+            }
+            _ if start_code >> 8 == 0x000001 => {
+                // Some other stream (e.g. padding), skip it
+                let packet_length = reader.read_u16::<BigEndian>()? as u64;
+                skip_bytes(reader, packet_length)?;
+            }
+            _ => return Err(invalid_packet_error()),
+        }
+    }
+}
+
+fn skip_bytes<R: Read>(reader: &mut R, num_bytes: u64) -> std::io::Result<()> {
+    std::io::copy(&mut reader.take(num_bytes), &mut std::io::sink())?;
+    Ok(())
+}
+
+fn invalid_packet_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Invalid MPEG-PS packet in sub file",
+    )
+}
+
+// Where the subtitle is placed within the video frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SubtitleRegion {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl SubtitleRegion {
+    pub fn fits_within(&self, frame_width: u32, frame_height: u32) -> bool {
+        self.x as u32 + self.width as u32 <= frame_width
+            && self.y as u32 + self.height as u32 <= frame_height
+    }
+
+    // Regions that only share an edge don't overlap
+    pub fn intersects(&self, other: &SubtitleRegion) -> bool {
+        let (x1, y1) = (self.x as u32, self.y as u32);
+        let (x2, y2) = (other.x as u32, other.y as u32);
+        x1 < x2 + other.width as u32
+            && x2 < x1 + self.width as u32
+            && y1 < y2 + other.height as u32
+            && y2 < y1 + self.height as u32
+    }
+}
+
+pub struct VobSubFrame {
+    pub bitmap: SoftwareBitmap,
+    pub region: SubtitleRegion,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VobDecodeError {
+    PacketSizeMismatch {
+        declared: usize,
+        actual: usize,
+    },
+    UnknownCommandType(u8),
+    MissingColorPalette,
+    MissingAlphaPalette,
+    MissingSize,
+    MissingImageData,
+    InvalidInterlacedLength {
+        even: usize,
+        odd: usize,
+        total: usize,
+    },
+    PixelOverflow {
+        got: usize,
+        expected: usize,
+    },
+    // A run that fills the rest of the line has to be 0x000 followed by the color
+    InvalidEndOfLineCode(u16),
+}
+
+impl std::fmt::Display for VobDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VobDecodeError::PacketSizeMismatch { declared, actual } => write!(
+                f,
+                "The packet is {} bytes, but its header says {} bytes.",
+                actual, declared
+            ),
+            VobDecodeError::UnknownCommandType(command_type) => {
+                write!(f, "Unknown command type 0x{:X}.", command_type)
+            }
+            VobDecodeError::MissingColorPalette => write!(f, "No color palette found."),
+            VobDecodeError::MissingAlphaPalette => write!(f, "No alpha palette found."),
+            VobDecodeError::MissingSize => write!(f, "No size found."),
+            VobDecodeError::MissingImageData => write!(f, "The image data is incomplete."),
+            VobDecodeError::InvalidInterlacedLength { even, odd, total } => write!(
+                f,
+                "The even ({} bytes) and odd ({} bytes) lines don't fit in the image ({} bytes).",
+                even, odd, total
+            ),
+            VobDecodeError::PixelOverflow { got, expected } => write!(
+                f,
+                "Too many pixels ({} decoded, {} expected).",
+                got, expected
+            ),
+            VobDecodeError::InvalidEndOfLineCode(value) => {
+                write!(f, "Invalid end of line code 0x{:04X}.", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VobDecodeError {}
+
+// Running out of data part way through a block means the image
+// data was cut short.
+impl From<std::io::Error> for VobDecodeError {
+    fn from(_: std::io::Error) -> Self {
+        VobDecodeError::MissingImageData
     }
 }
 
-pub fn parse_block(data: &[u8], palette: &[Color]) -> Result<Option<SoftwareBitmap>> {
-    if let Some((bytes, width, height)) = decode_block(data, palette) {
+#[derive(Debug)]
+pub struct DecodedVobBlock {
+    pub bytes: Vec<u8>,
+    pub region: SubtitleRegion,
+}
+
+pub fn parse_block(
+    data: &[u8],
+    frame_width: u32,
+    frame_height: u32,
+    palette: &[Color],
+) -> Result<Option<VobSubFrame>> {
+    let block = match decode_block(data, palette) {
+        Ok(block) => block,
+        Err(error) => {
+            progress::suspend(|| {
+                println!(
+                    "Warning! Could not decode VobSub subtitle: {} Skipping...",
+                    error
+                )
+            });
+            return Ok(None);
+        }
+    };
+    if let Some(DecodedVobBlock { bytes, region }) = block {
+        if !region.fits_within(frame_width, frame_height) {
+            progress::suspend(|| {
+                println!(
+                "Warning! Subtitle region ({}x{} at {},{}) extends past the declared frame size ({}x{}).",
+                region.width, region.height, region.x, region.y, frame_width, frame_height
+            )
+            });
+        }
+        let width = region.width as usize;
+        let height = region.height as usize;
         let bitmap_size = (width * height * 4) as u32;
         let bitmap_buffer = Buffer::Create(bitmap_size)?;
         bitmap_buffer.SetLength(bitmap_size)?;
@@ -92,26 +586,45 @@ pub fn parse_block(data: &[u8], palette: &[Color]) -> Result<Option<SoftwareBitm
             width as i32,
             height as i32,
         )?;
-        Ok(Some(bitmap))
+        // Subtitles must fit within the frame, anything else is malformed
+        if !check_dimensions(&bitmap, frame_width, frame_height)? {
+            return Ok(None);
+        }
+        Ok(Some(VobSubFrame { bitmap, region }))
     } else {
         Ok(None)
     }
 }
 
-fn parse_two_u12(data: &[u8]) -> (u16, u16) {
+fn parse_two_u12(data: &[u8]) -> Option<(u16, u16)> {
+    let data = data.get(0..3)?;
     let v1_p1 = (data[0] as u16) << 8;
     let v1_p2 = data[1] as u16;
     let v1 = (v1_p1 | v1_p2) >> 4;
     let v2_p1 = (data[1] as u16) << 8;
     let v2_p2 = data[2] as u16;
     let v2 = ((v2_p1 | v2_p2) << 4) >> 4;
-    (v1, v2)
+    Some((v1, v2))
 }
 
-fn compute_size(x1: u16, x2: u16, y1: u16, y2: u16) -> (u16, u16) {
-    let width = x2 - x1 + 1;
-    let height = y2 - y1 + 1;
-    (width, height)
+// The data is in the form of x1, x2, y1, y2, with
+// each value being 3 nibbles in size.
+fn parse_screen_coordinates(data: &[u8]) -> Option<((u16, u16), (u16, u16))> {
+    if data.len() < 6 {
+        return None;
+    }
+    let x = parse_two_u12(&data[0..3])?;
+    let y = parse_two_u12(&data[3..6])?;
+    Some((x, y))
+}
+
+fn compute_region(x1: u16, x2: u16, y1: u16, y2: u16) -> SubtitleRegion {
+    SubtitleRegion {
+        x: x1,
+        y: y1,
+        width: x2 - x1 + 1,
+        height: y2 - y1 + 1,
+    }
 }
 
 fn read_four_nibbles<R: Read>(mut reader: R) -> Option<[usize; 4]> {
@@ -130,21 +643,31 @@ fn read_four_nibbles<R: Read>(mut reader: R) -> Option<[usize; 4]> {
     ])
 }
 
-fn decode_block(block_data: &[u8], palette: &[Color]) -> Option<(Vec<u8>, usize, usize)> {
+fn decode_block(
+    block_data: &[u8],
+    palette: &[Color],
+) -> std::result::Result<Option<DecodedVobBlock>, VobDecodeError> {
     let len = block_data.len();
     let mut reader = std::io::Cursor::new(block_data);
-    let subtitle_packet_size = reader.read_u16::<BigEndian>().unwrap();
-    assert_eq!(len, subtitle_packet_size as usize);
+    let subtitle_packet_size = reader.read_u16::<BigEndian>()? as usize;
+    if len != subtitle_packet_size {
+        return Err(VobDecodeError::PacketSizeMismatch {
+            declared: subtitle_packet_size,
+            actual: len,
+        });
+    }
 
     // http://sam.zoy.org/writings/dvd/subtitles/ and http://dvd.sourceforge.net/spu_notes
     // disagree here, but the zoy source seems to be correct. The size of the data packet includes
     // the bytes we read to determine the size. We subtract that to get the size of the data
     // without the bytes representing the size itself.
-    let data_packet_size = reader.read_u16::<BigEndian>().unwrap() as usize;
+    let data_packet_size = reader.read_u16::<BigEndian>()? as usize;
     let data_packet_data_start = reader.position() as usize;
-    let data_packet_data_size = data_packet_size - data_packet_data_start;
+    let data_packet_data_size = data_packet_size
+        .checked_sub(data_packet_data_start)
+        .ok_or(VobDecodeError::MissingImageData)?;
     let mut data_packet_data = vec![0u8; data_packet_data_size as usize];
-    reader.read_exact(&mut data_packet_data).unwrap();
+    reader.read_exact(&mut data_packet_data)?;
 
     // Parse the command sequences
     loop {
@@ -152,16 +675,16 @@ fn decode_block(block_data: &[u8], palette: &[Color]) -> Option<(Vec<u8>, usize,
         // http://sam.zoy.org/writings/dvd/subtitles/ says that each sequence starts
         // with 2 bytes with the date(?) and 2 bytes with the offest to the next
         // sequence.
-        let _date_data = reader.read_u16::<BigEndian>().unwrap();
-        let next_seq_position = reader.read_u16::<BigEndian>().unwrap() as usize;
+        let _date_data = reader.read_u16::<BigEndian>()?;
+        let next_seq_position = reader.read_u16::<BigEndian>()? as usize;
 
         // Ordering isn't gartunteed, so we must defer the parsing
-        let mut size = None;
+        let mut region = None;
         let mut current_color_palette = None;
         let mut current_alpha_palette = None;
         let mut interlaced_data = None;
         loop {
-            let command_type = reader.read_u8().unwrap();
+            let command_type = reader.read_u8()?;
             //println!("{:X}", command_type);
             match command_type {
                 0x00 => { /* Start subpicture */ }
@@ -169,41 +692,50 @@ fn decode_block(block_data: &[u8], palette: &[Color]) -> Option<(Vec<u8>, usize,
                 0x02 => { /* Stop displaying */ }
                 0x03 => {
                     // Palette information
-                    current_color_palette = Some(read_four_nibbles(&mut reader).unwrap());
+                    current_color_palette = Some(
+                        read_four_nibbles(&mut reader)
+                            .ok_or(VobDecodeError::MissingColorPalette)?,
+                    );
                 }
                 0x04 => {
                     // Alpha information
-                    current_alpha_palette = Some(read_four_nibbles(&mut reader).unwrap());
+                    current_alpha_palette = Some(
+                        read_four_nibbles(&mut reader)
+                            .ok_or(VobDecodeError::MissingAlphaPalette)?,
+                    );
                 }
                 0x05 => {
                     // Screen coordinates
                     let mut data = vec![0u8; 6];
-                    reader.read_exact(&mut data).unwrap();
-
-                    // The data is in the form of x1, x2, y1, y2, with
-                    // each value being 3 nibbles in size.
-                    let (x1, x2) = parse_two_u12(&data[0..3]);
-                    let (y1, y2) = parse_two_u12(&data[3..]);
-                    let (width, height) = compute_size(x1, x2, y1, y2);
-
-                    size = Some((width as usize, height as usize))
+                    reader
+                        .read_exact(&mut data)
+                        .map_err(|_| VobDecodeError::MissingSize)?;
+                    let ((x1, x2), (y1, y2)) =
+                        parse_screen_coordinates(&data).ok_or(VobDecodeError::MissingSize)?;
+                    region = Some(compute_region(x1, x2, y1, y2));
                 }
                 0x06 => {
                     // Image data location
-                    let first_line_position = reader.read_u16::<BigEndian>().unwrap() as usize;
-                    let second_line_position = reader.read_u16::<BigEndian>().unwrap() as usize;
-                    let first_line_position = first_line_position - data_packet_data_start;
-                    let second_line_position = second_line_position - data_packet_data_start;
-                    let even_data = &data_packet_data[first_line_position..second_line_position];
-                    let odd_data = &data_packet_data[second_line_position..];
+                    let first_line_position = reader.read_u16::<BigEndian>()? as usize;
+                    let second_line_position = reader.read_u16::<BigEndian>()? as usize;
+                    let first_line_position = first_line_position
+                        .checked_sub(data_packet_data_start)
+                        .ok_or(VobDecodeError::MissingImageData)?;
+                    let second_line_position = second_line_position
+                        .checked_sub(data_packet_data_start)
+                        .ok_or(VobDecodeError::MissingImageData)?;
+                    let even_data = data_packet_data
+                        .get(first_line_position..second_line_position)
+                        .ok_or(VobDecodeError::MissingImageData)?;
+                    let odd_data = data_packet_data
+                        .get(second_line_position..)
+                        .ok_or(VobDecodeError::MissingImageData)?;
                     interlaced_data = Some((even_data, odd_data));
                 }
                 0xFF => {
                     break;
                 }
-                _ => {
-                    panic!("Unknown command type: 0x{:X}", command_type)
-                }
+                _ => return Err(VobDecodeError::UnknownCommandType(command_type)),
             }
         }
 
@@ -211,38 +743,46 @@ fn decode_block(block_data: &[u8], palette: &[Color]) -> Option<(Vec<u8>, usize,
         if let Some((even_data, odd_data)) = interlaced_data {
             let palette = build_subpalette(
                 &palette,
-                &current_color_palette.expect("No color palette found!"),
-                &current_alpha_palette.expect("No alpha palette found!"),
+                &current_color_palette.ok_or(VobDecodeError::MissingColorPalette)?,
+                &current_alpha_palette.ok_or(VobDecodeError::MissingAlphaPalette)?,
             );
-            let (width, height) = size.expect("No size found!");
+            let region = region.ok_or(VobDecodeError::MissingSize)?;
+            let width = region.width as usize;
+            let height = region.height as usize;
             //println!("Size: {} x {}", width, height);
-            let even_lines_pixels =
-                decode_image(even_data, width, height / 2, &palette);
-            let odd_lines_pixels =
-                decode_image(odd_data, width, height - height / 2, &palette);
-            let bytes =
-                interlace_image(&even_lines_pixels, &odd_lines_pixels, width, height);
-            return Some((bytes, width, height));
+            // Rows are numbered from 0, so with an odd height there's
+            // one more even row than odd row.
+            let even_lines_count = (height + 1) / 2;
+            let odd_lines_count = height / 2;
+            let even_lines_pixels = decode_image(even_data, width, even_lines_count, &palette)?;
+            let odd_lines_pixels = decode_image(odd_data, width, odd_lines_count, &palette)?;
+            let bytes = interlace_image(&even_lines_pixels, &odd_lines_pixels, width, height)?;
+            return Ok(Some(DecodedVobBlock { bytes, region }));
         }
 
         if current_sequence_position == next_seq_position {
             break;
         }
     }
-    None
+    Ok(None)
 }
 
 fn build_subpalette(palette: &[Color], color_info: &[usize], alpha_info: &[usize]) -> Vec<Color> {
     let mut subpalette = Vec::new();
     for (i, color_index) in color_info.iter().enumerate() {
         let original_alpha_value = alpha_info[i];
+        let transparent = Color {
+            A: 0,
+            R: 0,
+            G: 0,
+            B: 0,
+        };
         let color = if original_alpha_value == 0 {
-            Color {
-                A: 0,
-                R: 0,
-                G: 0,
-                B: 0,
-            }
+            transparent
+        } else if *color_index >= palette.len() {
+            // Malformed idx data can give us a short palette. try_parse_idx
+            // warns about it once, rather than for every block.
+            transparent
         } else {
             let palette_color = &palette[*color_index];
             let alpha_value = ((16.min(original_alpha_value + 1) as f32 / 16.0) * 255.0) as usize;
@@ -258,29 +798,44 @@ fn build_subpalette(palette: &[Color], color_info: &[usize], alpha_info: &[usize
     subpalette
 }
 
-fn interlace_image(even_data: &[u8], odd_data: &[u8], width: usize, height: usize) -> Vec<u8> {
+fn interlace_image(
+    even_data: &[u8],
+    odd_data: &[u8],
+    width: usize,
+    height: usize,
+) -> std::result::Result<Vec<u8>, VobDecodeError> {
     let bytes_per_pixel = 4;
     let mut bytes = vec![0u8; width * height * bytes_per_pixel];
-    // TODO: Somtimes we're an entire row short...
-    //assert_eq!(even_data.len() + odd_data.len(), bytes.len());
-    assert!(even_data.len() + odd_data.len() <= bytes.len());
     let stride = width * bytes_per_pixel;
+    // Each half has to fit in its own set of lines
+    let even_lines = (height + 1) / 2;
+    let odd_lines = height / 2;
+    if even_data.len() > even_lines * stride || odd_data.len() > odd_lines * stride {
+        return Err(VobDecodeError::InvalidInterlacedLength {
+            even: even_data.len(),
+            odd: odd_data.len(),
+            total: bytes.len(),
+        });
+    }
+    // decode_image always fills every line
+    debug_assert_eq!(even_data.len() + odd_data.len(), bytes.len());
     for (i, line) in even_data.chunks(stride).enumerate() {
         let interlaced_index = (i * 2) * stride;
-        (&mut bytes[interlaced_index..interlaced_index + stride]).copy_from_slice(line);
+        (&mut bytes[interlaced_index..interlaced_index + line.len()]).copy_from_slice(line);
     }
     for (i, line) in odd_data.chunks(stride).enumerate() {
-        let mut interlaced_index = ((i * 2) + 1) * stride;
-        // TODO: Find the source of my counting bug
-        if interlaced_index == bytes.len() {
-            interlaced_index = interlaced_index - stride;
-        }
-        (&mut bytes[interlaced_index..interlaced_index + stride]).copy_from_slice(line);
+        let interlaced_index = ((i * 2) + 1) * stride;
+        (&mut bytes[interlaced_index..interlaced_index + line.len()]).copy_from_slice(line);
     }
-    bytes
+    Ok(bytes)
 }
 
-fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) -> Vec<u8> {
+fn decode_image(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    palette: &[Color],
+) -> std::result::Result<Vec<u8>, VobDecodeError> {
     let total_pixels = width * height;
     //println!("Decoding image ({} x {}), with {} pixels...", width, height, total_pixels);
     let mut pixels = Vec::new();
@@ -289,16 +844,10 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
         if pixels.len() == total_pixels {
             break;
         } else if pixels.len() > total_pixels {
-            panic!(
-                "Too many pixels! {} > {} ({} * {})",
-                pixels.len(),
-                total_pixels,
-                width,
-                height
-            );
-            //println!("  Too many pixels ({}). Bailing...", pixels.len());
-            //pixels.resize(total_pixels, Color { A: 0, R: 0, G: 0, B: 0 });
-            //break;
+            return Err(VobDecodeError::PixelOverflow {
+                got: pixels.len(),
+                expected: total_pixels,
+            });
         }
 
         let first_nibble = nibble_reader.read_u4();
@@ -315,7 +864,9 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
                 (num_pixels, color)
             }
             0x3 | 0x2 | 0x1 => {
-                let second_nibble = nibble_reader.read_u4().unwrap();
+                let second_nibble = nibble_reader
+                    .read_u4()
+                    .ok_or(VobDecodeError::MissingImageData)?;
                 let value = (first_nibble << 4) | second_nibble;
                 let num_pixels = (value >> 2) as usize;
                 let color = (value & 0x3) as usize;
@@ -323,11 +874,15 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
                 (num_pixels, color)
             }
             0x0 => {
-                let second_nibble = nibble_reader.read_u4().unwrap();
+                let second_nibble = nibble_reader
+                    .read_u4()
+                    .ok_or(VobDecodeError::MissingImageData)?;
                 match second_nibble {
                     0xf | 0xe | 0xd | 0xc | 0xb | 0xa | 0x9 | 0x8 | 0x7 | 0x6 | 0x5 | 0x4 => {
                         let value = (first_nibble << 4) | second_nibble;
-                        let third_nibble = nibble_reader.read_u4().unwrap();
+                        let third_nibble = nibble_reader
+                            .read_u4()
+                            .ok_or(VobDecodeError::MissingImageData)?;
                         let value = ((value as u16) << 4) | third_nibble as u16;
                         let num_pixels = (value >> 2) as usize;
                         let color = (value & 0x3) as usize;
@@ -336,8 +891,12 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
                     }
                     0x3 | 0x2 | 0x1 => {
                         let value = (first_nibble << 4) | second_nibble;
-                        let third_nibble = nibble_reader.read_u4().unwrap();
-                        let fourth_nibble = nibble_reader.read_u4().unwrap();
+                        let third_nibble = nibble_reader
+                            .read_u4()
+                            .ok_or(VobDecodeError::MissingImageData)?;
+                        let fourth_nibble = nibble_reader
+                            .read_u4()
+                            .ok_or(VobDecodeError::MissingImageData)?;
                         let value2 = (third_nibble << 4) | fourth_nibble;
                         let value = (value as u16) << 8 | value2 as u16;
                         let num_pixels = (value >> 2) as usize;
@@ -347,11 +906,17 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
                     }
                     0x0 => {
                         let value = (first_nibble << 4) | second_nibble;
-                        let third_nibble = nibble_reader.read_u4().unwrap();
-                        let fourth_nibble = nibble_reader.read_u4().unwrap();
+                        let third_nibble = nibble_reader
+                            .read_u4()
+                            .ok_or(VobDecodeError::MissingImageData)?;
+                        let fourth_nibble = nibble_reader
+                            .read_u4()
+                            .ok_or(VobDecodeError::MissingImageData)?;
                         let value2 = (third_nibble << 4) | fourth_nibble;
                         let value = (value as u16) << 8 | value2 as u16;
-                        assert_eq!(third_nibble, 0);
+                        if third_nibble != 0 {
+                            return Err(VobDecodeError::InvalidEndOfLineCode(value));
+                        }
                         let color = (value & 0x3) as usize;
                         //nibble_reader.round_to_next_byte();
                         //println!("Fill rest of line with : {}", color);
@@ -373,6 +938,16 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
             nibble_reader.round_to_next_byte();
         }
     }
+    // Running out of image data early leaves the rest of the image transparent
+    pixels.resize(
+        total_pixels,
+        Color {
+            A: 0,
+            R: 0,
+            G: 0,
+            B: 0,
+        },
+    );
 
     let mut bytes = Vec::new();
     for color in pixels {
@@ -381,7 +956,7 @@ fn decode_image(data: &[u8], width: usize, height: usize, palette: &[Color]) ->
         bytes.push(color.R);
         bytes.push(color.A);
     }
-    bytes
+    Ok(bytes)
 }
 
 struct NibbleReader<'a> {
@@ -414,11 +989,52 @@ impl<'a> NibbleReader<'a> {
             self.pos += 1;
         }
     }
+
+    // Go back to the first nibble
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn reset(&mut self) {
+        self.pos = 0;
+    }
+
+    // A new reader over the same data, starting at the given nibble
+    #[allow(dead_code)] // Not used by any command yet
+    pub fn clone_at(&self, pos: usize) -> NibbleReader<'a> {
+        NibbleReader {
+            data: self.data,
+            pos,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::image::to_rgba32;
+
+    #[test]
+    fn subtitle_region_test() {
+        let region: SubtitleRegion = "10, 400,700,60".parse().unwrap();
+        assert_eq!(
+            region,
+            SubtitleRegion {
+                x: 10,
+                y: 400,
+                width: 700,
+                height: 60,
+            }
+        );
+        assert!("10,400,700".parse::<SubtitleRegion>().is_err());
+        assert!("10,400,700,-60".parse::<SubtitleRegion>().is_err());
+
+        let bottom: SubtitleRegion = "0,360,720,120".parse().unwrap();
+        let top: SubtitleRegion = "0,0,720,120".parse().unwrap();
+        assert!(region.intersects(&bottom));
+        assert!(bottom.intersects(&region));
+        assert!(!region.intersects(&top));
+        // Touching isn't overlapping
+        let below: SubtitleRegion = "0,460,720,20".parse().unwrap();
+        assert!(!region.intersects(&below));
+    }
 
     fn test_pos_data(
         data: &[u8],
@@ -428,8 +1044,7 @@ mod test {
         y2_expected: u16,
     ) {
         println!("data: {:02X?}", data);
-        let (x1, x2) = parse_two_u12(&data[0..3]);
-        let (y1, y2) = parse_two_u12(&data[3..]);
+        let ((x1, x2), (y1, y2)) = parse_screen_coordinates(data).unwrap();
         assert_eq!(x1, x1_expected);
         assert_eq!(x2, x2_expected);
         assert_eq!(y1, y1_expected);
@@ -438,8 +1053,8 @@ mod test {
             "x1: {:03X} x2: {:03X} y1: {:03X}, y2: {:03X}",
             x1, x2, y1, y2
         );
-        let (width, height) = compute_size(x1, x2, y1, y2);
-        println!("size: {:03X} x {:03X}", width, height);
+        let region = compute_region(x1, x2, y1, y2);
+        println!("size: {:03X} x {:03X}", region.width, region.height);
     }
 
     #[test]
@@ -459,4 +1074,352 @@ mod test {
             0x1BB,
         );
     }
+
+    #[test]
+    fn parse_u12_short_data_test() {
+        assert_eq!(
+            parse_screen_coordinates(&[0x00u8, 0x02, 0xcf, 0x00, 0x22]),
+            None
+        );
+        assert_eq!(parse_two_u12(&[0x00u8, 0x02]), None);
+    }
+
+    #[test]
+    fn compute_region_test() {
+        let region = compute_region(0x0EA, 0x1E1, 0x1A0, 0x1BB);
+        assert_eq!(
+            region,
+            SubtitleRegion {
+                x: 0x0EA,
+                y: 0x1A0,
+                width: 0x0F8,
+                height: 0x01C,
+            }
+        );
+        assert!(region.fits_within(720, 480));
+        assert!(!region.fits_within(720, 0x1BB));
+        assert!(!region.fits_within(0x1E1, 480));
+        assert!(region.fits_within(0x1E2, 0x1BC));
+    }
+
+    #[test]
+    fn interlace_odd_height_test() {
+        // 1x3 image: rows 0 and 2 are even, row 1 is odd
+        let even_data = [0u8, 0, 0, 0, 2, 2, 2, 2];
+        let odd_data = [1u8, 1, 1, 1];
+        let bytes = interlace_image(&even_data, &odd_data, 1, 3).unwrap();
+        assert_eq!(bytes, vec![0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn nibble_reader_test() {
+        let data = [0x12u8, 0x34];
+        let mut reader = NibbleReader::new(&data);
+        assert_eq!(reader.read_u4(), Some(0x1));
+        reader.round_to_next_byte();
+        assert_eq!(reader.read_u4(), Some(0x3));
+
+        // Reading from a clone leaves the original where it was
+        let mut clone = reader.clone_at(1);
+        assert_eq!(clone.read_u4(), Some(0x2));
+        assert_eq!(reader.read_u4(), Some(0x4));
+        assert_eq!(reader.read_u4(), None);
+        assert_eq!(reader.clone_at(4).read_u4(), None);
+
+        reader.reset();
+        assert_eq!(reader.read_u4(), Some(0x1));
+    }
+
+    #[test]
+    fn decode_error_test() {
+        let even_data = [0u8; 12];
+        let odd_data = [1u8; 4];
+        assert_eq!(
+            interlace_image(&even_data, &odd_data, 1, 3),
+            Err(VobDecodeError::InvalidInterlacedLength {
+                even: 12,
+                odd: 4,
+                total: 12
+            })
+        );
+
+        let palette = [Color {
+            A: 255,
+            R: 255,
+            G: 255,
+            B: 255,
+        }; 4];
+        // 0xF is a run of 3 pixels, which is more than a 1x2 image holds
+        assert_eq!(
+            decode_image(&[0xF0], 1, 2, &palette),
+            Err(VobDecodeError::PixelOverflow {
+                got: 3,
+                expected: 2
+            })
+        );
+
+        // Packet size, data packet size, date, next sequence, command
+        let block = [0x00u8, 0x09, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x07];
+        assert_eq!(
+            decode_block(&block, &palette).unwrap_err(),
+            VobDecodeError::UnknownCommandType(0x07)
+        );
+        // Screen coordinates and image data location, but no palettes
+        let block = [
+            0x00u8, 0x15, 0x00, 0x04, 0x00, 0x00, 0x00, 0x04, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x06, 0x00, 0x04, 0x00, 0x04, 0xFF,
+        ];
+        assert_eq!(
+            decode_block(&block, &palette).unwrap_err(),
+            VobDecodeError::MissingColorPalette
+        );
+        // Cut off part way through the control sequence
+        assert_eq!(
+            decode_block(&[0x00u8, 0x06, 0x00, 0x04, 0x00, 0x00], &palette).unwrap_err(),
+            VobDecodeError::MissingImageData
+        );
+        // The packet size doesn't match the block
+        assert_eq!(
+            decode_block(&[0x00u8, 0x08, 0x00, 0x04, 0x00, 0x00], &palette).unwrap_err(),
+            VobDecodeError::PacketSizeMismatch {
+                declared: 8,
+                actual: 6
+            }
+        );
+        // 0x0010 has a third nibble of 1, which isn't a valid end of line
+        assert_eq!(
+            decode_image(&[0x00, 0x10], 4, 1, &palette),
+            Err(VobDecodeError::InvalidEndOfLineCode(0x0010))
+        );
+        // Running out of data leaves the rest of the image transparent
+        let bytes = decode_image(&[0x70], 1, 2, &palette).unwrap();
+        assert_eq!(bytes, vec![255, 255, 255, 255, 0, 0, 0, 0]);
+    }
+
+    // A 2x2 subtitle at (x, y). The top line uses color 1 and the
+    // bottom line uses color 2, with color 3 transparent.
+    fn make_spu(x: u16, y: u16) -> Vec<u8> {
+        let (x2, y2) = (x + 1, y + 1);
+        let image_data = [0x90u8, 0xA0];
+        let control_sequence_start = (4 + image_data.len()) as u16;
+        let mut control_sequence = vec![0x00, 0x00];
+        // The last sequence points to itself
+        control_sequence.extend_from_slice(&control_sequence_start.to_be_bytes());
+        control_sequence.extend_from_slice(&[0x03, 0x32, 0x10]);
+        control_sequence.extend_from_slice(&[0x04, 0xFF, 0xF0]);
+        control_sequence.extend_from_slice(&[
+            0x05,
+            (x >> 4) as u8,
+            ((x << 4) as u8) | (x2 >> 8) as u8,
+            x2 as u8,
+            (y >> 4) as u8,
+            ((y << 4) as u8) | (y2 >> 8) as u8,
+            y2 as u8,
+        ]);
+        control_sequence.extend_from_slice(&[0x06, 0x00, 0x04, 0x00, 0x05]);
+        control_sequence.extend_from_slice(&[0x01, 0xFF]);
+
+        let packet_size = (4 + image_data.len() + control_sequence.len()) as u16;
+        let mut data = packet_size.to_be_bytes().to_vec();
+        data.extend_from_slice(&control_sequence_start.to_be_bytes());
+        data.extend_from_slice(&image_data);
+        data.extend_from_slice(&control_sequence);
+        data
+    }
+
+    #[test]
+    fn parse_block_test() -> Result<()> {
+        let color = |value: u8| Color {
+            A: 255,
+            R: value,
+            G: value,
+            B: value,
+        };
+        let palette = [color(0x00), color(0x40), color(0x80), color(0xC0)];
+        let block = make_spu(10, 300);
+
+        let VobSubFrame { bitmap, region } = parse_block(&block, 720, 480, &palette)?.unwrap();
+        assert_eq!(
+            region,
+            SubtitleRegion {
+                x: 10,
+                y: 300,
+                width: 2,
+                height: 2
+            }
+        );
+        assert_eq!(bitmap.PixelWidth()?, 2);
+        assert_eq!(bitmap.PixelHeight()?, 2);
+        #[rustfmt::skip]
+        let expected = vec![
+            0x40, 0x40, 0x40, 255,  0x40, 0x40, 0x40, 255,
+            0x80, 0x80, 0x80, 255,  0x80, 0x80, 0x80, 255,
+        ];
+        assert_eq!(to_rgba32(&bitmap)?, expected);
+
+        // Images bigger than the frame are skipped
+        assert!(parse_block(&block, 1, 1, &palette)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn build_subpalette_out_of_range_test() {
+        let red = Color {
+            A: 255,
+            R: 255,
+            G: 0,
+            B: 0,
+        };
+        let palette = [red, red];
+        let subpalette = build_subpalette(&palette, &[3, 1, 0, 3], &[15, 15, 0, 0]);
+        assert_eq!(subpalette.len(), 4);
+        assert_eq!(subpalette[0].A, 0);
+        assert_eq!(subpalette[1], red);
+        assert_eq!(subpalette[2].A, 0);
+        assert_eq!(subpalette[3].A, 0);
+    }
+
+    #[test]
+    fn palette_format_test() {
+        let rgb = b"size: 720x480\npalette: 000000, ffffff, 808080, eb8080\n";
+        let ycbcr = b"size: 720x480\npalette: 108080, eb8080, 7d8080, 515af0\n";
+        assert_eq!(
+            detect_palette_format(&[(0x00, 0x00, 0x00), (0xff, 0xff, 0xff)]),
+            PaletteFormat::Rgb
+        );
+        assert_eq!(
+            detect_palette_format(&[(0x10, 0x80, 0x80), (0xeb, 0x80, 0x80)]),
+            PaletteFormat::YCbCr
+        );
+
+        let palette = |data: &[u8], palette_format| match parse_idx(data, palette_format).unwrap() {
+            KnownEncoding::VOB { palette, .. } => palette,
+            _ => unreachable!(),
+        };
+        let rgb_palette = palette(rgb, None);
+        assert_eq!(
+            (rgb_palette[1].R, rgb_palette[1].G, rgb_palette[1].B),
+            (0xff, 0xff, 0xff)
+        );
+        assert_eq!(rgb_palette[3].R, 0xeb);
+
+        // Black and white in YCbCr
+        let ycbcr_palette = palette(ycbcr, None);
+        assert_eq!(
+            (ycbcr_palette[0].R, ycbcr_palette[0].G, ycbcr_palette[0].B),
+            (0, 0, 0)
+        );
+        assert!(ycbcr_palette[1].R >= 0xfe);
+        assert_eq!(ycbcr_palette, palette(ycbcr, Some(PaletteFormat::YCbCr)));
+
+        // The detection can be overridden
+        let forced_rgb_palette = palette(ycbcr, Some(PaletteFormat::Rgb));
+        assert_eq!(forced_rgb_palette[0].R, 0x10);
+    }
+
+    #[test]
+    fn replace_idx_palette_test() {
+        let idx = b"# VobSub index file, v7 (do not modify this line!)\nsize: 720x480\npalette: 108080, eb8080\nid: en, index: 0\n";
+        let palette = [
+            Color {
+                A: 255,
+                R: 0,
+                G: 0,
+                B: 0,
+            },
+            Color {
+                A: 255,
+                R: 0xff,
+                G: 0xff,
+                B: 0xfe,
+            },
+        ];
+        assert_eq!(
+            replace_idx_palette(idx, &palette),
+            "# VobSub index file, v7 (do not modify this line!)\nsize: 720x480\npalette: 000000, fffffe\nid: en, index: 0\n"
+        );
+    }
+
+    #[test]
+    fn try_parse_idx_test() {
+        let palette = "palette: 000000, ffffff, 808080, ebebeb";
+        assert_eq!(
+            try_parse_idx(palette.as_bytes(), None).unwrap_err(),
+            IdxParseError::MissingSize
+        );
+        assert_eq!(
+            try_parse_idx(b"size: 720x480", None).unwrap_err(),
+            IdxParseError::MissingPalette
+        );
+        let bad_size = format!("size: 720by480\n{}", palette);
+        assert_eq!(
+            try_parse_idx(bad_size.as_bytes(), None).unwrap_err(),
+            IdxParseError::InvalidSize("720by480".to_owned())
+        );
+        let bad_entry = "size: 720x480\npalette: 000000, fffff";
+        assert_eq!(
+            try_parse_idx(bad_entry.as_bytes(), None).unwrap_err(),
+            IdxParseError::InvalidPaletteEntry("fffff".to_owned())
+        );
+        let valid = format!("size: 720x480\n{}", palette);
+        let (width, height, palette) = try_parse_idx(valid.as_bytes(), None).unwrap();
+        assert_eq!((width, height, palette.len()), (720, 480, 4));
+    }
+
+    #[test]
+    fn parse_idx_entries_test() {
+        let idx = b"# VobSub index file, v7 (do not modify this line!)
+size: 720x480
+id: en, index: 0
+timestamp: 00:00:01:234, filepos: 000000000
+timestamp: 01:02:03:004, filepos: 00000a800
+id: fr, index: 1
+timestamp: 00:00:05:000, filepos: 000001000
+";
+        assert_eq!(
+            parse_idx_entries(idx),
+            vec![
+                IdxEntry {
+                    timestamp_ms: 1234,
+                    filepos: 0,
+                },
+                IdxEntry {
+                    timestamp_ms: 3723004,
+                    filepos: 0xa800,
+                },
+            ]
+        );
+    }
+
+    fn make_pes_packet(substream_id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        // MPEG-2 pack header with 2 bytes of stuffing
+        packet.extend_from_slice(&PACK_START_CODE.to_be_bytes());
+        packet.extend_from_slice(&[0x44, 0, 0, 0, 0, 0, 0, 0, 0, 0xFA, 0xFF, 0xFF]);
+        // Private stream 1, with a 2 byte PES header
+        packet.extend_from_slice(&PRIVATE_STREAM_1_START_CODE.to_be_bytes());
+        packet.extend_from_slice(&((payload.len() + 6) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0x81, 0x80, 0x02, 0xAA, 0xBB, substream_id]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn read_subtitle_packet_test() {
+        // A 7 byte subtitle packet split across two PES packets, with
+        // a padding packet, an audio packet, and a packet from another
+        // subtitle stream in between
+        let mut data = vec![0xFFu8; 3];
+        data.extend(make_pes_packet(0x20, &[0x00, 0x07, 0x01, 0x02]));
+        data.extend_from_slice(&[0x00, 0x00, 0x01, 0xBE, 0x00, 0x02, 0xFF, 0xFF]);
+        data.extend(make_pes_packet(0x80, &[0xEE, 0xEE]));
+        data.extend(make_pes_packet(0x21, &[0xDD, 0xDD]));
+        data.extend(make_pes_packet(0x20, &[0x03, 0x04, 0x05, 0x06]));
+        let mut reader = std::io::Cursor::new(data);
+        let packet = read_subtitle_packet(&mut reader, 3).unwrap();
+        assert_eq!(packet, vec![0x00, 0x07, 0x01, 0x02, 0x03, 0x04, 0x05]);
+
+        let mut reader = std::io::Cursor::new(vec![0xFFu8; 8]);
+        assert!(read_subtitle_packet(&mut reader, 0).is_err());
+    }
 }